@@ -0,0 +1,9 @@
+-- Track content size and revision so listings never need to load content
+ALTER TABLE files ADD COLUMN IF NOT EXISTS size BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS revision BIGINT NOT NULL DEFAULT 1;
+
+UPDATE files SET size = octet_length(content);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_files_user_updated ON files(user_id, updated_at);
+CREATE INDEX IF NOT EXISTS idx_files_user_created ON files(user_id, created_at);
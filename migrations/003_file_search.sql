@@ -0,0 +1,23 @@
+-- Full-text index over file names and cell values, rebuilt on every save
+CREATE TABLE IF NOT EXISTS file_search_documents (
+    file_id UUID PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    body TEXT NOT NULL,
+    document TSVECTOR NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- One row per non-empty cell so matches can be reported by coordinate
+CREATE TABLE IF NOT EXISTS file_search_cells (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    coord VARCHAR(16) NOT NULL,
+    row_index INTEGER NOT NULL,
+    col_index INTEGER NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY(file_id, coord)
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_file_search_documents_user ON file_search_documents(user_id);
+CREATE INDEX IF NOT EXISTS idx_file_search_documents_document ON file_search_documents USING GIN(document);
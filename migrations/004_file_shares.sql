@@ -0,0 +1,15 @@
+-- Grants giving another user viewer or editor access to a file
+CREATE TABLE IF NOT EXISTS file_shares (
+    id UUID PRIMARY KEY,
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    grantee_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permission VARCHAR(16) NOT NULL CHECK (permission IN ('viewer', 'editor')),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    UNIQUE(file_id, grantee_id)
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_file_shares_grantee ON file_shares(grantee_id);
+CREATE INDEX IF NOT EXISTS idx_file_shares_file ON file_shares(file_id);
@@ -0,0 +1,18 @@
+-- Tokenized public read-only links to a file
+CREATE TABLE IF NOT EXISTS share_links (
+    id UUID PRIMARY KEY,
+    token VARCHAR(64) UNIQUE NOT NULL,
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    password_hash VARCHAR(255),
+    expires_at TIMESTAMP WITH TIME ZONE,
+    max_views INTEGER,
+    view_count INTEGER NOT NULL DEFAULT 0,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_share_links_owner ON share_links(owner_id);
+CREATE INDEX IF NOT EXISTS idx_share_links_file ON share_links(file_id);
@@ -0,0 +1,9 @@
+-- User-defined organisation attributes on files
+ALTER TABLE files ADD COLUMN IF NOT EXISTS description TEXT NOT NULL DEFAULT '';
+ALTER TABLE files ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE files ADD COLUMN IF NOT EXISTS favorite BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}';
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_files_tags ON files USING GIN(tags);
+CREATE INDEX IF NOT EXISTS idx_files_user_favorite ON files(user_id) WHERE favorite;
@@ -0,0 +1,9 @@
+-- Compressed file content; rows still marked 'identity' are compressed on next read or write
+ALTER TABLE files ADD COLUMN IF NOT EXISTS content_encoding VARCHAR(16) NOT NULL DEFAULT 'identity';
+ALTER TABLE files ADD COLUMN IF NOT EXISTS content_compressed BYTEA;
+ALTER TABLE files ADD COLUMN IF NOT EXISTS stored_size BIGINT NOT NULL DEFAULT 0;
+
+UPDATE files SET stored_size = size WHERE content_encoding = 'identity';
+
+-- Create indexes
+CREATE INDEX IF NOT EXISTS idx_files_identity_encoding ON files(id) WHERE content_encoding = 'identity';
@@ -0,0 +1,9 @@
+-- Per-user data key for the securestore namespace, wrapped by a key derived from the store password
+CREATE TABLE IF NOT EXISTS secure_store_keys (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    kdf_salt BYTEA NOT NULL,
+    wrap_nonce BYTEA NOT NULL,
+    wrapped_key BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
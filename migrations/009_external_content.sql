@@ -0,0 +1,2 @@
+-- Large uploads are kept in object storage; their rows use content_encoding 'external'
+ALTER TABLE files ADD COLUMN IF NOT EXISTS storage_key TEXT;
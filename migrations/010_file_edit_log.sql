@@ -0,0 +1,21 @@
+-- Edit commands applied to a file, in order. A NULL command marks a whole-file save.
+CREATE TABLE IF NOT EXISTS file_edits (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    commands TEXT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (file_id, seq)
+);
+
+-- The file's content after the edit with the same seq, to replay from
+CREATE TABLE IF NOT EXISTS file_snapshots (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    seq BIGINT NOT NULL,
+    content_encoding VARCHAR(16) NOT NULL,
+    content_compressed BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (file_id, seq)
+);
+
+CREATE INDEX IF NOT EXISTS idx_file_edits_created ON file_edits(file_id, created_at);
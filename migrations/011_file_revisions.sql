@@ -0,0 +1,10 @@
+-- Recent saved versions of each file, kept as common ancestors for merging
+-- saves made against an older revision
+CREATE TABLE IF NOT EXISTS file_revisions (
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    revision BIGINT NOT NULL,
+    content_encoding VARCHAR(16) NOT NULL,
+    content_compressed BYTEA NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (file_id, revision)
+);
@@ -0,0 +1,18 @@
+-- Ranges of a sheet published as read-only JSON, with the last rendering
+-- cached against the file revision it was made from
+CREATE TABLE IF NOT EXISTS published_ranges (
+    id UUID PRIMARY KEY,
+    token VARCHAR(64) UNIQUE NOT NULL,
+    file_id UUID NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    range_name VARCHAR(255) NOT NULL,
+    api_key_hash VARCHAR(64) NOT NULL,
+    cached_revision BIGINT,
+    cached_etag VARCHAR(64),
+    cached_body TEXT,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_published_ranges_owner ON published_ranges(owner_id);
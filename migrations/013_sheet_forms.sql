@@ -0,0 +1,15 @@
+-- Public forms that append a row to a sheet for each submission. A file has
+-- at most one form; its definition maps fields to columns
+CREATE TABLE IF NOT EXISTS sheet_forms (
+    id UUID PRIMARY KEY,
+    token VARCHAR(64) UNIQUE NOT NULL,
+    file_id UUID UNIQUE NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    definition JSONB NOT NULL,
+    submissions BIGINT NOT NULL DEFAULT 0,
+    last_submitted_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_sheet_forms_owner ON sheet_forms(owner_id);
@@ -0,0 +1,22 @@
+-- Sheet templates, stored compressed like files. System-wide templates
+-- have no owner and are installed by the server at startup
+CREATE TABLE IF NOT EXISTS templates (
+    id UUID PRIMARY KEY,
+    owner_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    category VARCHAR(100) NOT NULL DEFAULT '',
+    description TEXT NOT NULL DEFAULT '',
+    preview TEXT NOT NULL DEFAULT '',
+    placeholders TEXT[] NOT NULL DEFAULT '{}',
+    content_encoding VARCHAR(16) NOT NULL,
+    content_compressed BYTEA NOT NULL,
+    size BIGINT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Names are unique per owner, and among system templates
+CREATE UNIQUE INDEX IF NOT EXISTS idx_templates_owner_name
+    ON templates((COALESCE(owner_id, '00000000-0000-0000-0000-000000000000')), name);
+CREATE INDEX IF NOT EXISTS idx_templates_category ON templates(category);
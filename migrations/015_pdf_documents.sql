@@ -0,0 +1,15 @@
+-- Generated PDFs, kept apart from files: they are binary, so nothing that
+-- reads files as text may see them
+CREATE TABLE IF NOT EXISTS pdf_documents (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    -- The sheet printed, if it was stored; its viewers may read the PDF
+    source_path TEXT,
+    storage_key TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    revision BIGINT NOT NULL DEFAULT 1,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (user_id, path)
+);
+
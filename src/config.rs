@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::env;
+use std::net::IpAddr;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
@@ -11,6 +12,9 @@ pub struct AppConfig {
     pub s3_bucket: String,
     pub ses_from_email: String,
     pub cookie_secret: String,
+    /// Proxies whose `X-Forwarded-For` is believed, from `TRUSTED_PROXIES`,
+    /// a comma-separated list of addresses.
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl AppConfig {
@@ -28,6 +32,17 @@ impl AppConfig {
                 .unwrap_or_else(|_| "aspiring.investments@gmail.com".to_string()),
             cookie_secret: env::var("COOKIE_SECRET")
                 .unwrap_or_else(|_| "11oETzKXQAGaYdkL5gEmGeJJFuYh7EQnp2XdTP1o/Vo=".to_string()),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .map(|proxy| {
+                    proxy
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid TRUSTED_PROXIES entry {}", proxy))
+                })
+                .collect::<anyhow::Result<_>>()?,
         })
     }
 }
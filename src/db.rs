@@ -1,21 +1,94 @@
-use crate::models::{FileData, InAppPurchase, User};
-use chrono::Utc;
-use sqlx::PgPool;
+use crate::models::{
+    FileAttributes, FileAttributesUpdate, FileCursor, FileData, FileEdit, FileListOptions,
+    FileMeta, FileMetaPage, FileShare, FileSort, FileStorageInfo, FormDefinition, InAppPurchase,
+    PdfDocument, PublishedRange, SearchHit, SecureStoreKey, ShareLink, SharePermission, SheetForm,
+    SortOrder, StorageStats, Template, TemplateMeta, User,
+};
+use crate::services::collab::Edit;
+use crate::services::compression::{self, ContentEncoding};
+use crate::services::search;
+use crate::services::storage::StorageService;
+use crate::utils::{escape_like, glob_to_like};
+use crate::vfs::VfsPath;
+use chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
+/// Live edits between snapshots of a file's content.
+const SNAPSHOT_INTERVAL: i64 = 100;
+/// Edits kept in a file's log before the oldest are compacted away.
+const MAX_LOG_EDITS: i64 = 10_000;
+/// Saved versions of a file kept to merge stale saves against.
+const MAX_REVISIONS: i64 = 50;
+
 #[derive(Clone)]
 pub struct Database {
     pool: PgPool,
+    storage: StorageService,
+}
+
+/// A `files` row as stored, before its content is decoded.
+struct StoredFile {
+    id: Uuid,
+    user_id: Uuid,
+    path: String,
+    content: String,
+    content_encoding: String,
+    content_compressed: Option<Vec<u8>>,
+    storage_key: Option<String>,
+    size: i64,
+    revision: i64,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+/// Whether the content after edit `last` is snapshotted, given the latest
+/// snapshot before it.
+fn snapshot_due(last: i64, snapshot: i64, replaced: bool) -> bool {
+    replaced || last - snapshot >= SNAPSHOT_INTERVAL
+}
+
+/// History is dropped before the latest snapshot at or before this, so at
+/// least `MAX_LOG_EDITS` edits can always be replayed.
+fn history_cutoff(last: i64) -> i64 {
+    last - MAX_LOG_EDITS
+}
+
+impl StoredFile {
+    fn into_file_data(self) -> anyhow::Result<FileData> {
+        let content = compression::decode(
+            &self.content_encoding,
+            self.content,
+            self.content_compressed,
+        )?;
+
+        Ok(FileData {
+            id: self.id,
+            user_id: self.user_id,
+            path: self.path,
+            content,
+            size: self.size,
+            revision: self.revision,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        })
+    }
 }
 
 impl Database {
-    pub async fn new(database_url: &str) -> anyhow::Result<Self> {
+    pub async fn new(database_url: &str, storage: StorageService) -> anyhow::Result<Self> {
         let pool = PgPool::connect(database_url).await?;
 
         // Run migrations
         sqlx::migrate!("./migrations").run(&pool).await?;
 
-        Ok(Self { pool })
+        Ok(Self { pool, storage })
+    }
+
+    pub fn storage(&self) -> &StorageService {
+        &self.storage
     }
 
     // User operations
@@ -51,6 +124,18 @@ impl Database {
         Ok(user)
     }
 
+    pub async fn get_user_by_id(&self, user_id: Uuid) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as!(
+            User,
+            "SELECT id, email, password_hash, dongle, created_at, updated_at FROM users WHERE id = $1",
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
     pub async fn update_user_password(
         &self,
         user_id: Uuid,
@@ -85,149 +170,1990 @@ impl Database {
     pub async fn create_file(
         &self,
         user_id: Uuid,
-        path: &str,
+        path: &VfsPath,
         content: &str,
     ) -> anyhow::Result<FileData> {
-        let file = sqlx::query_as!(
-            FileData,
+        self.create_file_if_absent(user_id, path, content)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("{} already exists", path))
+    }
+
+    /// Creates a file unless one has the path already, checked as it is
+    /// created. `None`, writing nothing, if one does.
+    pub async fn create_file_if_absent(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        content: &str,
+    ) -> anyhow::Result<Option<FileData>> {
+        let compressed = compression::compress(content)?;
+        let mut tx = self.pool.begin().await?;
+
+        let row = sqlx::query!(
             r#"
-            INSERT INTO files (id, user_id, path, content, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6)
-            RETURNING id, user_id, path, content, created_at, updated_at
+            INSERT INTO files (id, user_id, path, content, content_encoding, content_compressed,
+                               size, stored_size, created_at, updated_at)
+            VALUES ($1, $2, $3, '', $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (user_id, path) DO NOTHING
+            RETURNING id, revision, created_at, updated_at
             "#,
             Uuid::new_v4(),
             user_id,
-            path,
-            content,
+            path.as_str(),
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            content.len() as i64,
+            compressed.len() as i64,
             Utc::now(),
             Utc::now()
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        Self::index_file(&mut tx, row.id, user_id, path, content).await?;
+        Self::store_revision(&mut tx, row.id, row.revision, &compressed).await?;
+        tx.commit().await?;
+
+        debug!(
+            "Stored {} ({} bytes, {} compressed)",
+            path.as_str(),
+            content.len(),
+            compressed.len()
+        );
+
+        Ok(Some(FileData {
+            id: row.id,
+            user_id,
+            path: path.to_string(),
+            content: content.to_string(),
+            size: content.len() as i64,
+            revision: row.revision,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        }))
+    }
+
+    pub async fn get_file(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+    ) -> anyhow::Result<Option<FileData>> {
+        let stored = sqlx::query_as!(
+            StoredFile,
+            r#"
+            SELECT id, user_id, path, content, content_encoding, content_compressed, storage_key,
+                   size, revision, created_at, updated_at
+            FROM files WHERE user_id = $1 AND path = $2
+            "#,
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(file)
+        let Some(mut stored) = stored else {
+            return Ok(None);
+        };
+
+        if stored.content_encoding == ContentEncoding::External.as_str() {
+            let key = stored
+                .storage_key
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("external file {} has no storage key", stored.id))?;
+            stored.content = String::from_utf8(self.storage.get_object(&key).await?)?;
+            stored.content_encoding = ContentEncoding::Identity.as_str().to_string();
+            return Ok(Some(stored.into_file_data()?));
+        }
+
+        if stored.content_encoding == ContentEncoding::Identity.as_str() {
+            let db = self.clone();
+            let (file_id, content) = (stored.id, stored.content.clone());
+            tokio::spawn(async move {
+                if let Err(err) = db.compress_legacy_content(file_id, &content).await {
+                    warn!("Failed to compress file {}: {}", file_id, err);
+                }
+            });
+        }
+
+        Ok(Some(stored.into_file_data()?))
     }
 
-    pub async fn get_file(&self, user_id: Uuid, path: &str) -> anyhow::Result<Option<FileData>> {
-        let file = sqlx::query_as!(
-            FileData,
-            "SELECT id, user_id, path, content, created_at, updated_at FROM files WHERE user_id = $1 AND path = $2",
+    /// The id of a file, without reading its content.
+    pub async fn get_file_id(&self, user_id: Uuid, path: &VfsPath) -> anyhow::Result<Option<Uuid>> {
+        let id = sqlx::query_scalar!(
+            "SELECT id FROM files WHERE user_id = $1 AND path = $2",
             user_id,
-            path
+            path.as_str()
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(file)
+        Ok(id)
+    }
+
+    pub async fn file_exists(&self, user_id: Uuid, path: &VfsPath) -> anyhow::Result<bool> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM files WHERE user_id = $1 AND path = $2) AS "exists!""#,
+            user_id,
+            path.as_str()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(exists)
+    }
+
+    /// Rewrites a row stored before compression was introduced. Skipped if the
+    /// row has been saved (and therefore compressed) in the meantime.
+    async fn compress_legacy_content(&self, file_id: Uuid, content: &str) -> anyhow::Result<()> {
+        let compressed = compression::compress(content)?;
+
+        sqlx::query!(
+            r#"
+            UPDATE files SET content = '', content_encoding = $1, content_compressed = $2,
+                             stored_size = $3
+            WHERE id = $4 AND content_encoding = $5
+            "#,
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            compressed.len() as i64,
+            file_id,
+            ContentEncoding::Identity.as_str()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        debug!(
+            "Compressed legacy file {} ({} bytes, {} compressed)",
+            file_id,
+            content.len(),
+            compressed.len()
+        );
+
+        Ok(())
     }
 
     pub async fn update_file(
         &self,
         user_id: Uuid,
-        path: &str,
+        path: &VfsPath,
         content: &str,
     ) -> anyhow::Result<()> {
-        sqlx::query!(
-            "UPDATE files SET content = $1, updated_at = $2 WHERE user_id = $3 AND path = $4",
-            content,
-            Utc::now(),
+        self.write_file(user_id, path, content, None).await?;
+        Ok(())
+    }
+
+    /// Saves over a file only if it is still at `revision`, the one `content`
+    /// was made from. Returns false, writing nothing, if it has moved on.
+    pub async fn update_file_at(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        content: &str,
+        revision: i64,
+    ) -> anyhow::Result<bool> {
+        self.write_file(user_id, path, content, Some(revision))
+            .await
+    }
+
+    async fn write_file(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        content: &str,
+        revision: Option<i64>,
+    ) -> anyhow::Result<bool> {
+        let compressed = compression::compress(content)?;
+        let mut tx = self.pool.begin().await?;
+
+        let updated =
+            Self::write_content(&mut tx, user_id, path, content, &compressed, revision).await?;
+        let written = updated.is_some();
+        if let Some((file_id, _)) = &updated {
+            Self::log_file_save(&mut tx, *file_id, &compressed).await?;
+        }
+        tx.commit().await?;
+
+        if let Some(key) = updated.and_then(|(_, storage_key)| storage_key) {
+            self.remove_object(&key).await;
+        }
+
+        debug!(
+            "Stored {} ({} bytes, {} compressed)",
+            path.as_str(),
+            content.len(),
+            compressed.len()
+        );
+
+        Ok(written)
+    }
+
+    /// Rewrites a file with `edit`, keeping its row locked from reading to
+    /// writing so that concurrent rewrites can't lose each other's changes.
+    /// Nothing is written if `edit` fails. `None` if there is no such file.
+    pub async fn modify_file<E>(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        edit: impl FnOnce(&str) -> Result<String, E>,
+    ) -> anyhow::Result<Option<Result<(), E>>> {
+        let mut tx = self.pool.begin().await?;
+        let stored = sqlx::query_as!(
+            StoredFile,
+            r#"
+            SELECT id, user_id, path, content, content_encoding, content_compressed, storage_key,
+                   size, revision, created_at, updated_at
+            FROM files WHERE user_id = $1 AND path = $2
+            FOR UPDATE
+            "#,
             user_id,
-            path
+            path.as_str()
         )
-        .execute(&self.pool)
+        .fetch_optional(&mut *tx)
         .await?;
 
+        let Some(mut stored) = stored else {
+            return Ok(None);
+        };
+        if stored.content_encoding == ContentEncoding::External.as_str() {
+            let key = stored
+                .storage_key
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("external file {} has no storage key", stored.id))?;
+            stored.content = String::from_utf8(self.storage.get_object(&key).await?)?;
+            stored.content_encoding = ContentEncoding::Identity.as_str().to_string();
+        }
+        let current = stored.into_file_data()?;
+
+        let content = match edit(&current.content) {
+            Ok(content) => content,
+            Err(err) => return Ok(Some(Err(err))),
+        };
+        let compressed = compression::compress(&content)?;
+        let updated =
+            Self::write_content(&mut tx, user_id, path, &content, &compressed, None).await?;
+        if let Some((file_id, _)) = &updated {
+            Self::log_file_save(&mut tx, *file_id, &compressed).await?;
+        }
+        tx.commit().await?;
+
+        if let Some(key) = updated.and_then(|(_, storage_key)| storage_key) {
+            self.remove_object(&key).await;
+        }
+
+        Ok(Some(Ok(())))
+    }
+
+    /// Logs a whole-file save, if the file is edited live and so keeps a log.
+    async fn log_file_save(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        compressed: &[u8],
+    ) -> anyhow::Result<()> {
+        let last = sqlx::query_scalar!(
+            "SELECT MAX(seq) FROM file_edits WHERE file_id = $1",
+            file_id
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        if let Some(last) = last {
+            Self::insert_file_edit(tx, file_id, last + 1, None, None, Utc::now()).await?;
+            Self::compact_file_log(tx, file_id, last + 1, compressed, true).await?;
+        }
         Ok(())
     }
 
-    pub async fn delete_file(&self, user_id: Uuid, path: &str) -> anyhow::Result<()> {
-        sqlx::query!(
-            "DELETE FROM files WHERE user_id = $1 AND path = $2",
+    /// Replaces a file's content, returning its id and the object it was
+    /// stored in before, for the caller to remove once committed. With a
+    /// `revision`, nothing is written unless the file is still at it.
+    async fn write_content(
+        tx: &mut Transaction<'_, Postgres>,
+        user_id: Uuid,
+        path: &VfsPath,
+        content: &str,
+        compressed: &[u8],
+        revision: Option<i64>,
+    ) -> anyhow::Result<Option<(Uuid, Option<String>)>> {
+        let updated = sqlx::query!(
+            r#"
+            UPDATE files f SET content = '', content_encoding = $1, content_compressed = $2,
+                               storage_key = NULL, size = $3, stored_size = $4,
+                               revision = f.revision + 1, updated_at = $5
+            FROM (SELECT id, storage_key FROM files
+                  WHERE user_id = $6 AND path = $7 AND ($8::BIGINT IS NULL OR revision = $8)
+                  FOR UPDATE) old
+            WHERE f.id = old.id
+            RETURNING f.id, f.revision, old.storage_key
+            "#,
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            content.len() as i64,
+            compressed.len() as i64,
+            Utc::now(),
             user_id,
-            path
+            path.as_str(),
+            revision
         )
-        .execute(&self.pool)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        Self::index_file(tx, updated.id, user_id, path, content).await?;
+        Self::store_revision(tx, updated.id, updated.revision, compressed).await?;
+        Ok(Some((updated.id, updated.storage_key)))
+    }
+
+    pub async fn storage_stats(&self, user_id: Uuid) -> anyhow::Result<StorageStats> {
+        let stats = sqlx::query_as!(
+            StorageStats,
+            r#"
+            SELECT COUNT(*) AS "files!",
+                   COALESCE(SUM(size), 0)::BIGINT AS "size!",
+                   COALESCE(SUM(stored_size), 0)::BIGINT AS "stored_size!",
+                   COUNT(*) FILTER (WHERE content_encoding = 'identity') AS "uncompressed_files!"
+            FROM files WHERE user_id = $1
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
         .await?;
 
+        Ok(stats)
+    }
+
+    pub async fn delete_file(&self, user_id: Uuid, path: &VfsPath) -> anyhow::Result<()> {
+        let storage_key = sqlx::query_scalar!(
+            "DELETE FROM files WHERE user_id = $1 AND path = $2 RETURNING storage_key",
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        if let Some(key) = storage_key {
+            self.remove_object(&key).await;
+        }
+
         Ok(())
     }
 
-    pub async fn list_files(
+    pub async fn get_file_storage(
         &self,
         user_id: Uuid,
-        path_prefix: &str,
-    ) -> anyhow::Result<Vec<FileData>> {
-        let files = sqlx::query_as!(
-            FileData,
-            "SELECT id, user_id, path, content, created_at, updated_at FROM files WHERE user_id = $1 AND path LIKE $2",
+        path: &VfsPath,
+    ) -> anyhow::Result<Option<FileStorageInfo>> {
+        let info = sqlx::query_as!(
+            FileStorageInfo,
+            r#"
+            SELECT size AS "size!", revision AS "revision!", updated_at, storage_key
+            FROM files WHERE user_id = $1 AND path = $2
+            "#,
             user_id,
-            format!("{}%", path_prefix)
+            path.as_str()
         )
-        .fetch_all(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(files)
+        Ok(info)
     }
 
-    // In-app purchase operations
-    pub async fn get_or_create_purchase(
+    /// Points `path` at an object already uploaded under `storage_key`,
+    /// creating the row if needed. `indexed` is the text to index for search,
+    /// if the upload was small enough to keep.
+    pub async fn store_external_file(
         &self,
         user_id: Uuid,
-        app_name: &str,
-    ) -> anyhow::Result<InAppPurchase> {
-        // Try to get existing purchase
-        if let Some(purchase) = sqlx::query_as!(
-            InAppPurchase,
-            "SELECT id, user_id, app_name, owned, consumed, created_at, updated_at FROM in_app_purchases WHERE user_id = $1 AND app_name = $2",
+        path: &VfsPath,
+        storage_key: &str,
+        size: i64,
+        indexed: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        // Created unless the file exists, however recently, and then replaced
+        let created = sqlx::query_scalar!(
+            r#"
+            INSERT INTO files (id, user_id, path, content, content_encoding, storage_key,
+                               size, stored_size, created_at, updated_at)
+            VALUES ($1, $2, $3, '', $4, $5, $6, $6, $7, $7)
+            ON CONFLICT (user_id, path) DO NOTHING
+            RETURNING id
+            "#,
+            Uuid::new_v4(),
             user_id,
-            app_name
+            path.as_str(),
+            ContentEncoding::External.as_str(),
+            storage_key,
+            size,
+            Utc::now()
         )
-        .fetch_optional(&self.pool)
-        .await?
-        {
-            return Ok(purchase);
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let (file_id, replaced_key) = match created {
+            Some(file_id) => (file_id, None),
+            None => {
+                let existing = sqlx::query!(
+                    "SELECT id, storage_key FROM files WHERE user_id = $1 AND path = $2 FOR UPDATE",
+                    user_id,
+                    path.as_str()
+                )
+                .fetch_optional(&mut *tx)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("{} was deleted while uploading", path))?;
+                sqlx::query!(
+                    r#"
+                    UPDATE files SET content = '', content_encoding = $1, content_compressed = NULL,
+                                     storage_key = $2, size = $3, stored_size = $3,
+                                     revision = revision + 1, updated_at = $4
+                    WHERE id = $5
+                    "#,
+                    ContentEncoding::External.as_str(),
+                    storage_key,
+                    size,
+                    Utc::now(),
+                    existing.id
+                )
+                .execute(&mut *tx)
+                .await?;
+                // Uploads aren't kept as snapshots, so replay can't reach
+                // past one: the log starts again with the next live edit
+                sqlx::query!("DELETE FROM file_edits WHERE file_id = $1", existing.id)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query!("DELETE FROM file_snapshots WHERE file_id = $1", existing.id)
+                    .execute(&mut *tx)
+                    .await?;
+                (existing.id, existing.storage_key)
+            }
+        };
+
+        Self::index_file(&mut tx, file_id, user_id, path, indexed.unwrap_or_default()).await?;
+        tx.commit().await?;
+
+        debug!("Stored {} externally ({} bytes)", path, size);
+
+        if let Some(key) = replaced_key {
+            self.remove_object(&key).await;
         }
 
-        // Create new purchase
-        let purchase = sqlx::query_as!(
-            InAppPurchase,
+        Ok(())
+    }
+
+    pub async fn get_pdf(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+    ) -> anyhow::Result<Option<PdfDocument>> {
+        let pdf = sqlx::query_as!(
+            PdfDocument,
             r#"
-            INSERT INTO in_app_purchases (id, user_id, app_name, owned, consumed, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING id, user_id, app_name, owned, consumed, created_at, updated_at
+            SELECT source_path, storage_key, size, revision
+            FROM pdf_documents WHERE user_id = $1 AND path = $2
             "#,
-            Uuid::new_v4(),
             user_id,
-            app_name,
-            5, // Default 5 saves
-            0, // Default 0 consumed
-            Utc::now(),
-            Utc::now()
+            path.as_str()
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(purchase)
+        Ok(pdf)
     }
 
-    pub async fn update_purchase(
+    /// Points `path` at a PDF already uploaded under `storage_key`,
+    /// replacing the PDF there. Returns false, and stores nothing, if a file
+    /// has the path: PDFs never replace files.
+    pub async fn store_pdf(
         &self,
         user_id: Uuid,
-        app_name: &str,
-        owned: i32,
-        consumed: i32,
-    ) -> anyhow::Result<()> {
+        path: &VfsPath,
+        source: Option<&VfsPath>,
+        storage_key: &str,
+        size: i64,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let file = sqlx::query_scalar!(
+            "SELECT id FROM files WHERE user_id = $1 AND path = $2",
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if file.is_some() {
+            return Ok(false);
+        }
+
+        let previous = sqlx::query_scalar!(
+            "SELECT storage_key FROM pdf_documents WHERE user_id = $1 AND path = $2 FOR UPDATE",
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let now = Utc::now();
         sqlx::query!(
-            "UPDATE in_app_purchases SET owned = $1, consumed = $2, updated_at = $3 WHERE user_id = $4 AND app_name = $5",
-            owned,
-            consumed,
-            Utc::now(),
+            r#"
+            INSERT INTO pdf_documents (user_id, path, source_path, storage_key, size, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $6)
+            ON CONFLICT (user_id, path) DO UPDATE
+            SET source_path = EXCLUDED.source_path, storage_key = EXCLUDED.storage_key,
+                size = EXCLUDED.size, revision = pdf_documents.revision + 1,
+                updated_at = EXCLUDED.updated_at
+            "#,
             user_id,
-            app_name
+            path.as_str(),
+            source.map(|source| source.as_str()),
+            storage_key,
+            size,
+            now
         )
-        .execute(&self.pool)
+        .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
-        Ok(())
+        if let Some(key) = previous {
+            self.remove_object(&key).await;
+        }
+
+        Ok(true)
+    }
+
+    /// Deletes an object that no row points at any more. Failures only leave
+    /// an orphaned object behind, so they are logged rather than returned.
+    async fn remove_object(&self, key: &str) {
+        if let Err(err) = self.storage.delete_object(key).await {
+            warn!("Failed to delete stored object {}: {}", key, err);
+        }
+    }
+
+    pub async fn list_file_meta(
+        &self,
+        user_id: Uuid,
+        dir: &VfsPath,
+        options: &FileListOptions,
+    ) -> anyhow::Result<FileMetaPage> {
+        let prefix = escape_like(&dir.dir_prefix());
+        let (sort_column, at_column) = match options.sort {
+            FileSort::Name => ("path", None),
+            FileSort::Created => ("created_at", Some("created_at")),
+            FileSort::Updated => ("updated_at", Some("updated_at")),
+        };
+        let (direction, comparison) = match options.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT path, size, revision, description, tags, favorite, created_at, updated_at FROM files WHERE user_id = ",
+        );
+        query.push_bind(user_id);
+        query.push(" AND path LIKE ");
+        query.push_bind(format!("{}%", prefix));
+        query.push(" ESCAPE '\\'");
+
+        if let Some(pattern) = &options.name_pattern {
+            query.push(" AND path ILIKE ");
+            query.push_bind(format!("{}{}", prefix, glob_to_like(pattern)));
+            query.push(" ESCAPE '\\'");
+        }
+
+        if let Some(tag) = &options.tag {
+            query.push(" AND tags @> ");
+            query.push_bind(vec![tag.clone()]);
+        }
+
+        if let Some(favorite) = options.favorite {
+            query.push(" AND favorite = ");
+            query.push_bind(favorite);
+        }
+
+        if let Some(since) = options.modified_since {
+            query.push(" AND updated_at >= ");
+            query.push_bind(since);
+        }
+
+        if let Some(cursor) = &options.cursor {
+            if !cursor.fits(options.sort, options.order) {
+                anyhow::bail!("cursor is from a listing in another order");
+            }
+            match (at_column, cursor.at) {
+                (Some(column), Some(at)) => {
+                    query.push(format!(" AND ({}, path) {} (", column, comparison));
+                    query.push_bind(at);
+                    query.push(", ");
+                    query.push_bind(cursor.path.clone());
+                    query.push(")");
+                }
+                _ => {
+                    query.push(format!(" AND path {} ", comparison));
+                    query.push_bind(cursor.path.clone());
+                }
+            }
+        }
+
+        query.push(format!(" ORDER BY {} {}", sort_column, direction));
+        if at_column.is_some() {
+            query.push(format!(", path {}", direction));
+        }
+
+        // Fetch one extra row to find out whether another page follows
+        if let Some(limit) = options.limit {
+            query.push(" LIMIT ");
+            query.push_bind(limit + 1);
+        }
+
+        let mut files: Vec<FileMeta> = query.build_query_as().fetch_all(&self.pool).await?;
+
+        let next_cursor = match options.limit {
+            Some(limit) if files.len() as i64 > limit => {
+                files.truncate(limit as usize);
+                files.last().map(|last| FileCursor {
+                    sort: options.sort,
+                    order: options.order,
+                    path: last.path.clone(),
+                    at: match options.sort {
+                        FileSort::Name => None,
+                        FileSort::Created => Some(last.created_at),
+                        FileSort::Updated => Some(last.updated_at),
+                    },
+                })
+            }
+            _ => None,
+        };
+
+        Ok(FileMetaPage { files, next_cursor })
+    }
+
+    pub async fn get_file_attributes(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+    ) -> anyhow::Result<Option<FileAttributes>> {
+        let attributes = sqlx::query_as!(
+            FileAttributes,
+            "SELECT description, tags, favorite, metadata FROM files WHERE user_id = $1 AND path = $2",
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(attributes)
+    }
+
+    pub async fn update_file_attributes(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        update: &FileAttributesUpdate,
+    ) -> anyhow::Result<Option<FileAttributes>> {
+        let attributes = sqlx::query_as!(
+            FileAttributes,
+            r#"
+            UPDATE files
+            SET description = COALESCE($1, description),
+                tags = COALESCE($2, tags),
+                favorite = COALESCE($3, favorite),
+                metadata = COALESCE($4, metadata)
+            WHERE user_id = $5 AND path = $6
+            RETURNING description, tags, favorite, metadata
+            "#,
+            update.description,
+            update.tags.as_deref(),
+            update.favorite,
+            update.metadata,
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(attributes)
+    }
+
+    // Share operations
+    pub async fn upsert_share(
+        &self,
+        file_id: Uuid,
+        owner_id: Uuid,
+        grantee_id: Uuid,
+        permission: SharePermission,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_shares (id, file_id, owner_id, grantee_id, permission, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (file_id, grantee_id) DO UPDATE
+            SET permission = EXCLUDED.permission, updated_at = EXCLUDED.updated_at
+            "#,
+            Uuid::new_v4(),
+            file_id,
+            owner_id,
+            grantee_id,
+            permission.as_str(),
+            Utc::now(),
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_share(&self, file_id: Uuid, grantee_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM file_shares WHERE file_id = $1 AND grantee_id = $2",
+            file_id,
+            grantee_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_share_permission(
+        &self,
+        file_id: Uuid,
+        grantee_id: Uuid,
+    ) -> anyhow::Result<Option<SharePermission>> {
+        let permission = sqlx::query_scalar!(
+            "SELECT permission FROM file_shares WHERE file_id = $1 AND grantee_id = $2",
+            file_id,
+            grantee_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(permission.and_then(|permission| SharePermission::parse(&permission)))
+    }
+
+    pub async fn list_shares_for_file(&self, file_id: Uuid) -> anyhow::Result<Vec<FileShare>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT f.path, o.email AS owner_email, g.email AS grantee_email, s.permission,
+                   s.created_at, s.updated_at
+            FROM file_shares s
+            JOIN files f ON f.id = s.file_id
+            JOIN users o ON o.id = s.owner_id
+            JOIN users g ON g.id = s.grantee_id
+            WHERE s.file_id = $1
+            ORDER BY g.email
+            "#,
+            file_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(FileShare {
+                    file_path: row.path,
+                    owner_email: row.owner_email,
+                    grantee_email: row.grantee_email,
+                    permission: SharePermission::parse(&row.permission)?,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn list_shared_with(&self, grantee_id: Uuid) -> anyhow::Result<Vec<FileShare>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT f.path, o.email AS owner_email, g.email AS grantee_email, s.permission,
+                   s.created_at, s.updated_at
+            FROM file_shares s
+            JOIN files f ON f.id = s.file_id
+            JOIN users o ON o.id = s.owner_id
+            JOIN users g ON g.id = s.grantee_id
+            WHERE s.grantee_id = $1
+            ORDER BY o.email, f.path
+            "#,
+            grantee_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(FileShare {
+                    file_path: row.path,
+                    owner_email: row.owner_email,
+                    grantee_email: row.grantee_email,
+                    permission: SharePermission::parse(&row.permission)?,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                })
+            })
+            .collect())
+    }
+
+    // Share link operations
+    pub async fn create_share_link(
+        &self,
+        file_id: Uuid,
+        owner_id: Uuid,
+        token: &str,
+        password_hash: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+        max_views: Option<i32>,
+    ) -> anyhow::Result<ShareLink> {
+        let link = sqlx::query_as!(
+            ShareLink,
+            r#"
+            WITH link AS (
+                INSERT INTO share_links (id, token, file_id, owner_id, password_hash, expires_at,
+                                         max_views, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                RETURNING *
+            )
+            SELECT link.id, link.token, link.file_id, link.owner_id, f.path AS file_path,
+                   link.password_hash, link.expires_at, link.max_views, link.view_count,
+                   link.revoked_at, link.created_at
+            FROM link JOIN files f ON f.id = link.file_id
+            "#,
+            Uuid::new_v4(),
+            token,
+            file_id,
+            owner_id,
+            password_hash,
+            expires_at,
+            max_views,
+            Utc::now(),
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(link)
+    }
+
+    pub async fn get_share_link(&self, token: &str) -> anyhow::Result<Option<ShareLink>> {
+        let link = sqlx::query_as!(
+            ShareLink,
+            r#"
+            SELECT l.id, l.token, l.file_id, l.owner_id, f.path AS file_path, l.password_hash,
+                   l.expires_at, l.max_views, l.view_count, l.revoked_at, l.created_at
+            FROM share_links l JOIN files f ON f.id = l.file_id
+            WHERE l.token = $1
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(link)
+    }
+
+    pub async fn list_share_links(&self, owner_id: Uuid) -> anyhow::Result<Vec<ShareLink>> {
+        let links = sqlx::query_as!(
+            ShareLink,
+            r#"
+            SELECT l.id, l.token, l.file_id, l.owner_id, f.path AS file_path, l.password_hash,
+                   l.expires_at, l.max_views, l.view_count, l.revoked_at, l.created_at
+            FROM share_links l JOIN files f ON f.id = l.file_id
+            WHERE l.owner_id = $1
+            ORDER BY l.created_at DESC
+            "#,
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(links)
+    }
+
+    pub async fn revoke_share_link(&self, owner_id: Uuid, token: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE share_links SET revoked_at = $1, updated_at = $1 WHERE owner_id = $2 AND token = $3 AND revoked_at IS NULL",
+            Utc::now(),
+            owner_id,
+            token
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Counts one view, unless the link has meanwhile been revoked, expired
+    /// or used up. Returns whether the view was allowed.
+    pub async fn record_share_link_view(&self, link_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE share_links SET view_count = view_count + 1, updated_at = $2
+            WHERE id = $1
+              AND revoked_at IS NULL
+              AND (expires_at IS NULL OR expires_at > $2)
+              AND (max_views IS NULL OR view_count < max_views)
+            "#,
+            link_id,
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Published range operations
+    pub async fn create_published_range(
+        &self,
+        file_id: Uuid,
+        owner_id: Uuid,
+        token: &str,
+        range_name: &str,
+        api_key_hash: &str,
+    ) -> anyhow::Result<PublishedRange> {
+        let published = sqlx::query_as!(
+            PublishedRange,
+            r#"
+            WITH published AS (
+                INSERT INTO published_ranges (id, token, file_id, owner_id, range_name,
+                                              api_key_hash, created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $7)
+                RETURNING *
+            )
+            SELECT p.id, p.token, p.owner_id, f.path AS file_path, p.range_name, p.api_key_hash,
+                   p.revoked_at, p.created_at
+            FROM published p JOIN files f ON f.id = p.file_id
+            "#,
+            Uuid::new_v4(),
+            token,
+            file_id,
+            owner_id,
+            range_name,
+            api_key_hash,
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(published)
+    }
+
+    pub async fn get_published_range(&self, token: &str) -> anyhow::Result<Option<PublishedRange>> {
+        let published = sqlx::query_as!(
+            PublishedRange,
+            r#"
+            SELECT p.id, p.token, p.owner_id, f.path AS file_path, p.range_name, p.api_key_hash,
+                   p.revoked_at, p.created_at
+            FROM published_ranges p JOIN files f ON f.id = p.file_id
+            WHERE p.token = $1
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(published)
+    }
+
+    pub async fn list_published_ranges(
+        &self,
+        owner_id: Uuid,
+    ) -> anyhow::Result<Vec<PublishedRange>> {
+        let published = sqlx::query_as!(
+            PublishedRange,
+            r#"
+            SELECT p.id, p.token, p.owner_id, f.path AS file_path, p.range_name, p.api_key_hash,
+                   p.revoked_at, p.created_at
+            FROM published_ranges p JOIN files f ON f.id = p.file_id
+            WHERE p.owner_id = $1
+            ORDER BY p.created_at DESC
+            "#,
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(published)
+    }
+
+    pub async fn revoke_published_range(
+        &self,
+        owner_id: Uuid,
+        token: &str,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE published_ranges SET revoked_at = $1, updated_at = $1, cached_body = NULL
+            WHERE owner_id = $2 AND token = $3 AND revoked_at IS NULL
+            "#,
+            Utc::now(),
+            owner_id,
+            token
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// The cached ETag and body, if they were made from the file's current
+    /// revision.
+    pub async fn get_published_cache(
+        &self,
+        published_id: Uuid,
+    ) -> anyhow::Result<Option<(String, String)>> {
+        let cached = sqlx::query!(
+            r#"
+            SELECT p.cached_etag AS "etag!", p.cached_body AS "body!"
+            FROM published_ranges p JOIN files f ON f.id = p.file_id
+            WHERE p.id = $1 AND p.cached_revision = f.revision
+              AND p.cached_etag IS NOT NULL AND p.cached_body IS NOT NULL
+            "#,
+            published_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(cached.map(|cached| (cached.etag, cached.body)))
+    }
+
+    /// Caches a rendering made from `revision`, unless a newer one is cached.
+    pub async fn set_published_cache(
+        &self,
+        published_id: Uuid,
+        revision: i64,
+        etag: &str,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE published_ranges SET cached_revision = $1, cached_etag = $2, cached_body = $3
+            WHERE id = $4 AND (cached_revision IS NULL OR cached_revision <= $1)
+            "#,
+            revision,
+            etag,
+            body,
+            published_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Sheet form operations
+    /// Creates the form for a file, or replaces its definition and keeps
+    /// its token if it has one.
+    pub async fn upsert_sheet_form(
+        &self,
+        file_id: Uuid,
+        owner_id: Uuid,
+        token: &str,
+        definition: &FormDefinition,
+    ) -> anyhow::Result<SheetForm> {
+        let form = sqlx::query_as!(
+            SheetForm,
+            r#"
+            WITH form AS (
+                INSERT INTO sheet_forms (id, token, file_id, owner_id, definition,
+                                         created_at, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $6)
+                ON CONFLICT (file_id) DO UPDATE SET definition = $5, updated_at = $6
+                RETURNING *
+            )
+            SELECT s.id, s.token, s.owner_id, f.path AS file_path,
+                   s.definition AS "definition: Json<FormDefinition>", s.submissions,
+                   s.last_submitted_at, s.created_at, s.updated_at
+            FROM form s JOIN files f ON f.id = s.file_id
+            "#,
+            Uuid::new_v4(),
+            token,
+            file_id,
+            owner_id,
+            Json(definition) as _,
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(form)
+    }
+
+    pub async fn get_sheet_form(&self, token: &str) -> anyhow::Result<Option<SheetForm>> {
+        let form = sqlx::query_as!(
+            SheetForm,
+            r#"
+            SELECT s.id, s.token, s.owner_id, f.path AS file_path,
+                   s.definition AS "definition: Json<FormDefinition>", s.submissions,
+                   s.last_submitted_at, s.created_at, s.updated_at
+            FROM sheet_forms s JOIN files f ON f.id = s.file_id
+            WHERE s.token = $1
+            "#,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(form)
+    }
+
+    pub async fn list_sheet_forms(&self, owner_id: Uuid) -> anyhow::Result<Vec<SheetForm>> {
+        let forms = sqlx::query_as!(
+            SheetForm,
+            r#"
+            SELECT s.id, s.token, s.owner_id, f.path AS file_path,
+                   s.definition AS "definition: Json<FormDefinition>", s.submissions,
+                   s.last_submitted_at, s.created_at, s.updated_at
+            FROM sheet_forms s JOIN files f ON f.id = s.file_id
+            WHERE s.owner_id = $1
+            ORDER BY f.path
+            "#,
+            owner_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(forms)
+    }
+
+    pub async fn delete_sheet_form(&self, owner_id: Uuid, path: &VfsPath) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM sheet_forms s USING files f
+            WHERE s.file_id = f.id AND s.owner_id = $1 AND f.user_id = $1 AND f.path = $2
+            "#,
+            owner_id,
+            path.as_str()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn record_form_submission(&self, form_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE sheet_forms SET submissions = submissions + 1, last_submitted_at = $1
+            WHERE id = $2
+            "#,
+            Utc::now(),
+            form_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // Template operations
+    /// Saves a template, replacing its owner's template of the same name.
+    /// `owner_id` is `None` for a system-wide template.
+    pub async fn upsert_template(
+        &self,
+        owner_id: Option<Uuid>,
+        meta: &TemplateMeta,
+        preview: &str,
+        placeholders: &[String],
+        content: &str,
+    ) -> anyhow::Result<Template> {
+        let compressed = compression::compress(content)?;
+        let template = sqlx::query_as!(
+            Template,
+            r#"
+            INSERT INTO templates (id, owner_id, name, title, category, description, preview,
+                                   placeholders, content_encoding, content_compressed, size,
+                                   created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $12)
+            ON CONFLICT ((COALESCE(owner_id, '00000000-0000-0000-0000-000000000000')), name)
+            DO UPDATE SET title = $4, category = $5, description = $6, preview = $7,
+                          placeholders = $8, content_encoding = $9, content_compressed = $10,
+                          size = $11, updated_at = $12
+            RETURNING id, owner_id, owner_id IS NULL AS "system!", name, title, category,
+                      description, preview, placeholders, size, created_at, updated_at
+            "#,
+            Uuid::new_v4(),
+            owner_id,
+            meta.name,
+            meta.title,
+            meta.category,
+            meta.description,
+            preview,
+            placeholders,
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            content.len() as i64,
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(template)
+    }
+
+    /// The system-wide templates and the user's own, optionally in one
+    /// category.
+    pub async fn list_templates(
+        &self,
+        user_id: Uuid,
+        category: Option<&str>,
+    ) -> anyhow::Result<Vec<Template>> {
+        let templates = sqlx::query_as!(
+            Template,
+            r#"
+            SELECT id, owner_id, owner_id IS NULL AS "system!", name, title, category,
+                   description, preview, placeholders, size, created_at, updated_at
+            FROM templates
+            WHERE (owner_id IS NULL OR owner_id = $1) AND ($2::TEXT IS NULL OR category = $2)
+            ORDER BY category, owner_id IS NOT NULL, title
+            "#,
+            user_id,
+            category
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(templates)
+    }
+
+    /// A system-wide template or one of the user's own, with its content.
+    pub async fn get_template(
+        &self,
+        user_id: Uuid,
+        template_id: Uuid,
+    ) -> anyhow::Result<Option<(Template, String)>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT id, owner_id, owner_id IS NULL AS "system!", name, title, category,
+                   description, preview, placeholders, size, created_at, updated_at,
+                   content_encoding, content_compressed
+            FROM templates
+            WHERE id = $1 AND (owner_id IS NULL OR owner_id = $2)
+            "#,
+            template_id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let content = compression::decode(
+            &row.content_encoding,
+            String::new(),
+            Some(row.content_compressed),
+        )?;
+        let template = Template {
+            id: row.id,
+            owner_id: row.owner_id,
+            system: row.system,
+            name: row.name,
+            title: row.title,
+            category: row.category,
+            description: row.description,
+            preview: row.preview,
+            placeholders: row.placeholders,
+            size: row.size,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        };
+        Ok(Some((template, content)))
+    }
+
+    pub async fn delete_template(&self, owner_id: Uuid, template_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM templates WHERE id = $1 AND owner_id = $2",
+            template_id,
+            owner_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Secure store key operations
+    pub async fn get_secure_store_key(
+        &self,
+        user_id: Uuid,
+    ) -> anyhow::Result<Option<SecureStoreKey>> {
+        let key = sqlx::query_as!(
+            SecureStoreKey,
+            "SELECT user_id, kdf_salt, wrap_nonce, wrapped_key, created_at, updated_at FROM secure_store_keys WHERE user_id = $1",
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(key)
+    }
+
+    /// Returns false if the user already has a key.
+    pub async fn create_secure_store_key(
+        &self,
+        user_id: Uuid,
+        kdf_salt: &[u8],
+        wrap_nonce: &[u8],
+        wrapped_key: &[u8],
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO secure_store_keys (user_id, kdf_salt, wrap_nonce, wrapped_key, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id) DO NOTHING
+            "#,
+            user_id,
+            kdf_salt,
+            wrap_nonce,
+            wrapped_key,
+            Utc::now(),
+            Utc::now()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_secure_store_key(
+        &self,
+        user_id: Uuid,
+        kdf_salt: &[u8],
+        wrap_nonce: &[u8],
+        wrapped_key: &[u8],
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE secure_store_keys SET kdf_salt = $1, wrap_nonce = $2, wrapped_key = $3, updated_at = $4 WHERE user_id = $5",
+            kdf_salt,
+            wrap_nonce,
+            wrapped_key,
+            Utc::now(),
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Keeps a copy of a saved revision, dropping the oldest beyond
+    /// `MAX_REVISIONS`.
+    async fn store_revision(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        revision: i64,
+        compressed: &[u8],
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_revisions (file_id, revision, content_encoding, content_compressed, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (file_id, revision) DO NOTHING
+            "#,
+            file_id,
+            revision,
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            Utc::now()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM file_revisions WHERE file_id = $1 AND revision <= $2",
+            file_id,
+            revision - MAX_REVISIONS
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// A file's content as saved at `revision`, if it is still kept.
+    pub async fn get_file_revision(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        revision: i64,
+    ) -> anyhow::Result<Option<String>> {
+        let stored = sqlx::query!(
+            r#"
+            SELECT r.content_encoding, r.content_compressed
+            FROM file_revisions r JOIN files f ON f.id = r.file_id
+            WHERE f.user_id = $1 AND f.path = $2 AND r.revision = $3
+            "#,
+            user_id,
+            path.as_str(),
+            revision
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        stored
+            .map(|stored| {
+                compression::decode(
+                    &stored.content_encoding,
+                    String::new(),
+                    Some(stored.content_compressed),
+                )
+            })
+            .transpose()
+    }
+
+    // Edit log operations
+
+    /// Logs edits made live and saves the content after them, if the file is
+    /// still at `revision`, the one the edits were made on. The log starts
+    /// with a snapshot of the content before the first edit, at seq 0.
+    /// Returns the file's new revision, or `None`, writing nothing, if it
+    /// has moved on or been deleted.
+    pub async fn append_file_edits(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        edits: &[Edit],
+        content: &str,
+        revision: i64,
+    ) -> anyhow::Result<Option<i64>> {
+        let compressed = compression::compress(content)?;
+        let mut tx = self.pool.begin().await?;
+
+        let existing = sqlx::query!(
+            r#"
+            SELECT id, content, content_encoding, content_compressed, storage_key, revision
+            FROM files WHERE user_id = $1 AND path = $2 FOR UPDATE
+            "#,
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(existing) = existing.filter(|existing| existing.revision == revision) else {
+            return Ok(None);
+        };
+
+        let last = sqlx::query_scalar!(
+            "SELECT MAX(seq) FROM file_edits WHERE file_id = $1",
+            existing.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut seq = match last {
+            Some(last) => last,
+            None => {
+                let previous = if existing.content_encoding == ContentEncoding::External.as_str() {
+                    let key = existing.storage_key.as_deref().ok_or_else(|| {
+                        anyhow::anyhow!("external file {} has no storage key", existing.id)
+                    })?;
+                    String::from_utf8(self.storage.get_object(key).await?)?
+                } else {
+                    compression::decode(
+                        &existing.content_encoding,
+                        existing.content,
+                        existing.content_compressed,
+                    )?
+                };
+                let previous = compression::compress(&previous)?;
+                Self::insert_file_snapshot(&mut tx, existing.id, 0, &previous).await?;
+                0
+            }
+        };
+
+        for edit in edits {
+            seq += 1;
+            Self::insert_file_edit(
+                &mut tx,
+                existing.id,
+                seq,
+                Some(edit.user_id),
+                edit.commands.as_deref(),
+                edit.created_at,
+            )
+            .await?;
+        }
+
+        let updated =
+            Self::write_content(&mut tx, user_id, path, content, &compressed, None).await?;
+        let replaced = edits.last().is_some_and(|edit| edit.commands.is_none());
+        Self::compact_file_log(&mut tx, existing.id, seq, &compressed, replaced).await?;
+        tx.commit().await?;
+
+        if let Some(key) = updated.and_then(|(_, storage_key)| storage_key) {
+            self.remove_object(&key).await;
+        }
+
+        debug!(
+            "Logged {} edits to {} (seq {})",
+            edits.len(),
+            path.as_str(),
+            seq
+        );
+
+        // The row was locked at `revision`, and written once
+        Ok(Some(revision + 1))
+    }
+
+    async fn insert_file_edit(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        seq: i64,
+        user_id: Option<Uuid>,
+        commands: Option<&str>,
+        created_at: DateTime<Utc>,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_edits (file_id, seq, user_id, commands, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            file_id,
+            seq,
+            user_id,
+            commands,
+            created_at
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn insert_file_snapshot(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        seq: i64,
+        compressed: &[u8],
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO file_snapshots (file_id, seq, content_encoding, content_compressed, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            file_id,
+            seq,
+            ContentEncoding::Zstd.as_str(),
+            compressed,
+            Utc::now()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Snapshots the content after edit `last` if it replaced the file or
+    /// enough edits have gone by since the last snapshot, then drops history
+    /// older than the last snapshot `MAX_LOG_EDITS` back.
+    async fn compact_file_log(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        last: i64,
+        compressed: &[u8],
+        replaced: bool,
+    ) -> anyhow::Result<()> {
+        let snapshot = sqlx::query_scalar!(
+            "SELECT MAX(seq) FROM file_snapshots WHERE file_id = $1",
+            file_id
+        )
+        .fetch_one(&mut **tx)
+        .await?
+        .unwrap_or(0);
+        if snapshot_due(last, snapshot, replaced) {
+            Self::insert_file_snapshot(tx, file_id, last, compressed).await?;
+        }
+
+        let cutoff = sqlx::query_scalar!(
+            "SELECT MAX(seq) FROM file_snapshots WHERE file_id = $1 AND seq <= $2",
+            file_id,
+            history_cutoff(last)
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        if let Some(cutoff) = cutoff {
+            sqlx::query!(
+                "DELETE FROM file_edits WHERE file_id = $1 AND seq <= $2",
+                file_id,
+                cutoff
+            )
+            .execute(&mut **tx)
+            .await?;
+            sqlx::query!(
+                "DELETE FROM file_snapshots WHERE file_id = $1 AND seq < $2",
+                file_id,
+                cutoff
+            )
+            .execute(&mut **tx)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    /// The edit log kept for a file: the seq its history starts at and the
+    /// last edit's. `None` if it has never been edited live.
+    pub async fn get_file_log_range(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+    ) -> anyhow::Result<Option<(i64, i64)>> {
+        let range = sqlx::query!(
+            r#"
+            SELECT (SELECT MIN(seq) FROM file_snapshots s WHERE s.file_id = f.id) AS first,
+                   (SELECT MAX(seq) FROM file_edits e WHERE e.file_id = f.id) AS last
+            FROM files f WHERE f.user_id = $1 AND f.path = $2
+            "#,
+            user_id,
+            path.as_str()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(range.and_then(|range| Some((range.first?, range.last?))))
+    }
+
+    /// Edits after `since`, oldest first.
+    pub async fn list_file_edits(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        since: i64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<FileEdit>> {
+        let edits = sqlx::query_as!(
+            FileEdit,
+            r#"
+            SELECT e.seq, e.user_id, e.commands, e.created_at
+            FROM file_edits e JOIN files f ON f.id = e.file_id
+            WHERE f.user_id = $1 AND f.path = $2 AND e.seq > $3
+            ORDER BY e.seq
+            LIMIT $4
+            "#,
+            user_id,
+            path.as_str(),
+            since,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(edits)
+    }
+
+    /// The last edit made at or before `at`.
+    pub async fn get_file_edit_seq_at(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<Option<i64>> {
+        let seq = sqlx::query_scalar!(
+            r#"
+            SELECT GREATEST(
+                (SELECT MAX(seq) FROM file_edits e WHERE e.file_id = f.id AND e.created_at <= $3),
+                (SELECT MIN(seq) FROM file_snapshots s WHERE s.file_id = f.id AND s.created_at <= $3)
+            )
+            FROM files f WHERE f.user_id = $1 AND f.path = $2
+            "#,
+            user_id,
+            path.as_str(),
+            at
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(seq)
+    }
+
+    /// The latest snapshot at or before `seq`, with its seq.
+    pub async fn get_file_snapshot(
+        &self,
+        user_id: Uuid,
+        path: &VfsPath,
+        seq: i64,
+    ) -> anyhow::Result<Option<(i64, String)>> {
+        let snapshot = sqlx::query!(
+            r#"
+            SELECT s.seq, s.content_encoding, s.content_compressed
+            FROM file_snapshots s JOIN files f ON f.id = s.file_id
+            WHERE f.user_id = $1 AND f.path = $2 AND s.seq <= $3
+            ORDER BY s.seq DESC
+            LIMIT 1
+            "#,
+            user_id,
+            path.as_str(),
+            seq
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(snapshot) = snapshot else {
+            return Ok(None);
+        };
+        let content = compression::decode(
+            &snapshot.content_encoding,
+            String::new(),
+            Some(snapshot.content_compressed),
+        )?;
+        Ok(Some((snapshot.seq, content)))
+    }
+
+    // Search operations
+    async fn index_file(
+        tx: &mut Transaction<'_, Postgres>,
+        file_id: Uuid,
+        user_id: Uuid,
+        path: &VfsPath,
+        content: &str,
+    ) -> anyhow::Result<()> {
+        // Secure store content is ciphertext and must not leak into the index
+        if path.is_secure_store() {
+            return Ok(());
+        }
+
+        let document = search::build_document(path.as_str(), content);
+
+        // 'simple' keeps numbers and non-English text searchable as typed
+        sqlx::query!(
+            r#"
+            INSERT INTO file_search_documents (file_id, user_id, name, body, document, updated_at)
+            VALUES ($1, $2, $3, $4,
+                    setweight(to_tsvector('simple', $3), 'A') || setweight(to_tsvector('simple', $4), 'B'),
+                    $5)
+            ON CONFLICT (file_id) DO UPDATE
+            SET name = EXCLUDED.name, body = EXCLUDED.body, document = EXCLUDED.document,
+                updated_at = EXCLUDED.updated_at
+            "#,
+            file_id,
+            user_id,
+            document.name,
+            document.body,
+            Utc::now()
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM file_search_cells WHERE file_id = $1", file_id)
+            .execute(&mut **tx)
+            .await?;
+
+        let mut coords = Vec::with_capacity(document.cells.len());
+        let mut rows = Vec::with_capacity(document.cells.len());
+        let mut cols = Vec::with_capacity(document.cells.len());
+        let mut values = Vec::with_capacity(document.cells.len());
+        for cell in document.cells {
+            coords.push(cell.coord);
+            rows.push(cell.row);
+            cols.push(cell.col);
+            values.push(cell.value);
+        }
+
+        sqlx::query!(
+            r#"
+            INSERT INTO file_search_cells (file_id, coord, row_index, col_index, value)
+            SELECT $1, * FROM UNNEST($2::varchar[], $3::int[], $4::int[], $5::text[])
+            "#,
+            file_id,
+            &coords,
+            &rows,
+            &cols,
+            &values
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Indexes files saved before there was a search index, or missed by it,
+    /// a batch at a time. Files in object storage are left out: only their
+    /// upload indexed them. Returns how many files were indexed.
+    pub async fn index_unindexed_files(&self) -> anyhow::Result<u64> {
+        let mut indexed = 0;
+        let mut after = Uuid::nil();
+        loop {
+            let batch = sqlx::query_as!(
+                StoredFile,
+                r#"
+                SELECT f.id, f.user_id, f.path, f.content, f.content_encoding, f.content_compressed,
+                       f.storage_key, f.size, f.revision, f.created_at, f.updated_at
+                FROM files f
+                LEFT JOIN file_search_documents d ON d.file_id = f.id
+                WHERE d.file_id IS NULL AND f.id > $1 AND f.content_encoding <> $2
+                  AND f.path NOT LIKE 'home/securestore/%'
+                ORDER BY f.id
+                LIMIT 100
+                "#,
+                after,
+                ContentEncoding::External.as_str()
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            let Some(last) = batch.last() else {
+                return Ok(indexed);
+            };
+            after = last.id;
+
+            for stored in batch {
+                let file = match stored.into_file_data() {
+                    Ok(file) => file,
+                    Err(err) => {
+                        warn!("Failed to read file for indexing: {}", err);
+                        continue;
+                    }
+                };
+                let path = VfsPath::from_stored(file.path);
+                let mut tx = self.pool.begin().await?;
+                Self::index_file(&mut tx, file.id, file.user_id, &path, &file.content).await?;
+                tx.commit().await?;
+                indexed += 1;
+            }
+        }
+    }
+
+    pub async fn search_files(
+        &self,
+        user_id: Uuid,
+        query: &str,
+        limit: i64,
+    ) -> anyhow::Result<Vec<SearchHit>> {
+        // Matches are marked for `search::highlight`, which escapes the rest
+        let hits = sqlx::query_as!(
+            SearchHit,
+            r#"
+            SELECT f.path AS "path!",
+                   ts_rank(d.document, q) AS "rank!",
+                   ts_headline('simple', translate(d.body, chr(2) || chr(3), ''), q,
+                               'StartSel=' || chr(2) || ', StopSel=' || chr(3)
+                               || ', MaxFragments=3, FragmentDelimiter=" ... "') AS "snippet!",
+                   ARRAY(
+                       SELECT c.coord FROM file_search_cells c
+                       WHERE c.file_id = d.file_id AND to_tsvector('simple', c.value) @@ q
+                       ORDER BY c.row_index, c.col_index
+                   ) AS "cells!: Vec<String>"
+            FROM file_search_documents d
+            JOIN files f ON f.id = d.file_id
+            CROSS JOIN websearch_to_tsquery('simple', $2) q
+            WHERE d.user_id = $1 AND d.document @@ q
+            ORDER BY 2 DESC, 1
+            LIMIT $3
+            "#,
+            user_id,
+            query,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(hits)
+    }
+
+    // In-app purchase operations
+    pub async fn get_or_create_purchase(
+        &self,
+        user_id: Uuid,
+        app_name: &str,
+    ) -> anyhow::Result<InAppPurchase> {
+        // Try to get existing purchase
+        if let Some(purchase) = sqlx::query_as!(
+            InAppPurchase,
+            "SELECT id, user_id, app_name, owned, consumed, created_at, updated_at FROM in_app_purchases WHERE user_id = $1 AND app_name = $2",
+            user_id,
+            app_name
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        {
+            return Ok(purchase);
+        }
+
+        // Create new purchase
+        let purchase = sqlx::query_as!(
+            InAppPurchase,
+            r#"
+            INSERT INTO in_app_purchases (id, user_id, app_name, owned, consumed, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING id, user_id, app_name, owned, consumed, created_at, updated_at
+            "#,
+            Uuid::new_v4(),
+            user_id,
+            app_name,
+            5, // Default 5 saves
+            0, // Default 0 consumed
+            Utc::now(),
+            Utc::now()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(purchase)
+    }
+
+    pub async fn update_purchase(
+        &self,
+        user_id: Uuid,
+        app_name: &str,
+        owned: i32,
+        consumed: i32,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE in_app_purchases SET owned = $1, consumed = $2, updated_at = $3 WHERE user_id = $4 AND app_name = $5",
+            owned,
+            consumed,
+            Utc::now(),
+            user_id,
+            app_name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_logs_are_snapshotted_and_compacted() {
+        // The file's log as `compact_file_log` keeps it, saving a whole file
+        // every so often
+        let mut snapshots = vec![0];
+        let mut first_edit = 1;
+        for last in 1..=3 * MAX_LOG_EDITS {
+            let replaced = last % 777 == 0;
+            if snapshot_due(last, *snapshots.last().unwrap(), replaced) {
+                snapshots.push(last);
+            }
+            if let Some(&cutoff) = snapshots
+                .iter()
+                .rev()
+                .find(|&&seq| seq <= history_cutoff(last))
+            {
+                snapshots.retain(|&seq| seq >= cutoff);
+                first_edit = first_edit.max(cutoff + 1);
+            }
+
+            // Every edit kept can be replayed from a snapshot no further
+            // back than an interval
+            let first = snapshots[0];
+            assert_eq!(first_edit, first + 1);
+            assert!(last - first >= MAX_LOG_EDITS.min(last), "{}", last);
+            assert!(last - first < MAX_LOG_EDITS + SNAPSHOT_INTERVAL, "{}", last);
+            for pair in snapshots.windows(2) {
+                assert!(pair[1] - pair[0] <= SNAPSHOT_INTERVAL);
+            }
+            assert!(last - snapshots.last().unwrap() < SNAPSHOT_INTERVAL);
+        }
+        assert!(snapshot_due(5, 4, true));
+        assert!(!snapshot_due(5, 4, false));
     }
 }
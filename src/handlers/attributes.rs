@@ -0,0 +1,231 @@
+use crate::{
+    models::{ApiResponse, FileAttributes, FileAttributesUpdate},
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+    Form,
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_METADATA_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct AttributesQuery {
+    pub fname: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttributesForm {
+    pub fname: String,
+    pub description: Option<String>,
+    /// Comma-separated; an empty string clears all tags.
+    pub tags: Option<String>,
+    pub favorite: Option<bool>,
+    /// JSON object replacing the stored metadata.
+    pub metadata: Option<String>,
+}
+
+pub async fn get_attributes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<AttributesQuery>,
+) -> Result<Json<ApiResponse<FileAttributes>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    match state.db.get_file_attributes(user_id, &file_path).await {
+        Ok(Some(attributes)) => Ok(Json(ApiResponse::success(attributes))),
+        Ok(None) => Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn update_attributes(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<AttributesForm>,
+) -> Result<Json<ApiResponse<FileAttributes>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let update = match parse_update(form) {
+        Ok(update) => update,
+        Err(message) => return Ok(Json(ApiResponse::error(message.to_string()))),
+    };
+
+    match state
+        .db
+        .update_file_attributes(user_id, &file_path, &update)
+        .await
+    {
+        Ok(Some(attributes)) => Ok(Json(ApiResponse::success(attributes))),
+        Ok(None) => Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn parse_update(form: AttributesForm) -> Result<FileAttributesUpdate, &'static str> {
+    if form
+        .description
+        .as_ref()
+        .is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LENGTH)
+    {
+        return Err("Description is too long");
+    }
+
+    let tags = match form.tags {
+        Some(tags) => {
+            let mut parsed: Vec<String> = Vec::new();
+            for tag in tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+                if tag.chars().count() > MAX_TAG_LENGTH {
+                    return Err("Tag is too long");
+                }
+                if !parsed.iter().any(|existing| existing == tag) {
+                    parsed.push(tag.to_string());
+                }
+            }
+            if parsed.len() > MAX_TAGS {
+                return Err("Too many tags");
+            }
+            Some(parsed)
+        }
+        None => None,
+    };
+
+    let metadata = match form.metadata {
+        Some(metadata) => {
+            if metadata.len() > MAX_METADATA_BYTES {
+                return Err("Metadata is too large");
+            }
+            match serde_json::from_str::<serde_json::Value>(&metadata) {
+                Ok(value) if value.is_object() => Some(value),
+                _ => return Err("Metadata must be a JSON object"),
+            }
+        }
+        None => None,
+    };
+
+    Ok(FileAttributesUpdate {
+        description: form.description,
+        tags,
+        favorite: form.favorite,
+        metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form() -> AttributesForm {
+        AttributesForm {
+            fname: "a.msc".to_string(),
+            description: None,
+            tags: None,
+            favorite: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn repeated_tags_are_kept_once_but_case_variants_are_distinct() {
+        let update = parse_update(AttributesForm {
+            tags: Some(" work, Work,work ,, home".to_string()),
+            ..form()
+        })
+        .unwrap();
+        assert_eq!(update.tags.unwrap(), ["work", "Work", "home"]);
+
+        // Only distinct tags count towards the limit
+        let repeated = vec!["same"; MAX_TAGS + 1].join(",");
+        let update = parse_update(AttributesForm {
+            tags: Some(repeated),
+            ..form()
+        })
+        .unwrap();
+        assert_eq!(update.tags.unwrap(), ["same"]);
+
+        let cleared = parse_update(AttributesForm {
+            tags: Some(String::new()),
+            ..form()
+        })
+        .unwrap();
+        assert_eq!(cleared.tags.unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn oversized_attributes_are_refused() {
+        let many: Vec<String> = (0..=MAX_TAGS).map(|n| format!("tag{}", n)).collect();
+        let too_many = AttributesForm {
+            tags: Some(many.join(",")),
+            ..form()
+        };
+        assert_eq!(parse_update(too_many).err(), Some("Too many tags"));
+
+        let long_tag = AttributesForm {
+            tags: Some("é".repeat(MAX_TAG_LENGTH + 1)),
+            ..form()
+        };
+        assert_eq!(parse_update(long_tag).err(), Some("Tag is too long"));
+        let longest_tag = AttributesForm {
+            tags: Some("é".repeat(MAX_TAG_LENGTH)),
+            ..form()
+        };
+        assert!(parse_update(longest_tag).is_ok());
+
+        let long_description = AttributesForm {
+            description: Some("x".repeat(MAX_DESCRIPTION_LENGTH + 1)),
+            ..form()
+        };
+        assert_eq!(
+            parse_update(long_description).err(),
+            Some("Description is too long")
+        );
+
+        let large_metadata = AttributesForm {
+            metadata: Some(format!(r#"{{"a":"{}"}}"#, "x".repeat(MAX_METADATA_BYTES))),
+            ..form()
+        };
+        assert_eq!(
+            parse_update(large_metadata).err(),
+            Some("Metadata is too large")
+        );
+    }
+
+    #[test]
+    fn metadata_must_be_a_json_object() {
+        for metadata in ["[1, 2]", "\"text\"", "42", "null", "{not json"] {
+            let update = AttributesForm {
+                metadata: Some(metadata.to_string()),
+                ..form()
+            };
+            assert_eq!(
+                parse_update(update).err(),
+                Some("Metadata must be a JSON object"),
+                "{}",
+                metadata
+            );
+        }
+
+        let update = parse_update(AttributesForm {
+            metadata: Some(r#"{"client": "Acme", "year": 2024}"#.to_string()),
+            favorite: Some(true),
+            ..form()
+        })
+        .unwrap();
+        assert_eq!(update.metadata.unwrap()["client"], "Acme");
+        assert_eq!(update.favorite, Some(true));
+    }
+}
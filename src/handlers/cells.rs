@@ -0,0 +1,399 @@
+use crate::{
+    handlers::{recalc::load_sheet, save::modify_sheet, share::resolve_owner},
+    models::{ApiResponse, SharePermission},
+    socialcalc::{
+        self,
+        commands::{self, CellAttribute, Command},
+        encode_for_save, CellCoord, CellRange, DataType, Sheet, Value,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Ranges read or written at once are limited to this many cells.
+const MAX_RANGE_CELLS: u64 = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct CellsQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+    /// `B2` or `A1:C10`.
+    pub range: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CellsUpdate {
+    pub fname: String,
+    pub owner: Option<String>,
+    pub range: String,
+    /// One array per row of `range`, one entry per column. An entry is a
+    /// number, string, boolean or `null` to clear the cell, or an object
+    /// with `value` or `formula` and an optional `format`.
+    pub values: Vec<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RangeData {
+    pub range: String,
+    /// Row by row, every cell of the range.
+    pub cells: Vec<CellData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CellData {
+    pub coord: String,
+    /// `empty`, `value`, `text`, `formula` or `constant`.
+    #[serde(rename = "type")]
+    pub datatype: &'static str,
+    pub valuetype: String,
+    /// The computed value: a number, text, or an error such as `#DIV/0!`.
+    pub value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// The value as the sheet displays it.
+    pub display: String,
+}
+
+/// Reads the cells of a range, recalculated.
+pub async fn get_cells(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<CellsQuery>,
+) -> Result<Json<ApiResponse<RangeData>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let range = match parse_range(&query.range) {
+        Ok(range) => range,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+
+    let (_, mut save) = match load_sheet(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Ok(loaded) => loaded,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+    socialcalc::recalc(&mut save.sheet);
+
+    Ok(Json(ApiResponse::success(range_data(&save.sheet, range))))
+}
+
+/// Writes values, formulas and formats to a range, recalculates and saves
+/// the sheet as a new revision. Returns the range as written. A file open
+/// for live editing is written through its room, which saves the revision
+/// a moment later, behind the edits made before it.
+pub async fn update_cells(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(update): Json<CellsUpdate>,
+) -> Result<Json<ApiResponse<RangeData>>, StatusCode> {
+    let file_path = match VfsPath::home(&update.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let range = match parse_range(&update.range) {
+        Ok(range) => range,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+    if update.values.len() != range.rows() as usize
+        || update
+            .values
+            .iter()
+            .any(|row| row.len() != range.cols() as usize)
+    {
+        return Ok(Json(ApiResponse::error(format!(
+            "values must be {} rows of {} cells",
+            range.rows(),
+            range.cols()
+        ))));
+    }
+
+    let Some(owner_id) = resolve_owner(
+        &state,
+        user_id,
+        update.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    else {
+        return Ok(Json(ApiResponse::error("File not found".to_string())));
+    };
+
+    let mut lines = Vec::new();
+    for (row, values) in (range.start.row..).zip(&update.values) {
+        for (col, input) in (range.start.col..).zip(values) {
+            let coord = CellCoord::new(col, row);
+            match cell_commands(coord, input) {
+                Ok(cell) => lines.extend(cell),
+                Err(message) => {
+                    return Ok(Json(ApiResponse::error(format!("{}: {}", coord, message))))
+                }
+            }
+        }
+    }
+    let cmds = lines.join("\n");
+    let parsed = match commands::parse(&cmds) {
+        Ok(parsed) => parsed,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    // Through the room if the file is open, so live edits aren't lost
+    let mut written = None;
+    let modified = modify_sheet(&state, owner_id, user_id, &file_path, |save| {
+        check_writable(&save.sheet, &parsed)?;
+        commands::apply(&mut save.sheet, &parsed);
+        socialcalc::recalc(&mut save.sheet);
+        written = Some(range_data(&save.sheet, range));
+        Ok(true)
+    })
+    .await?;
+    match (modified, written) {
+        (Some(Ok(_)), Some(written)) => Ok(Json(ApiResponse::success(written))),
+        (Some(Err(message)), _) => Ok(Json(ApiResponse::error(message))),
+        _ => Ok(Json(ApiResponse::error("File not found".to_string()))),
+    }
+}
+
+/// Fails if the commands would write to a read-only cell anywhere in their
+/// ranges.
+fn check_writable(sheet: &Sheet, parsed: &[Command]) -> Result<(), String> {
+    let readonly = parsed.iter().find_map(|command| match command {
+        Command::SetCells(target, _) => sheet
+            .cells
+            .iter()
+            .find(|(coord, cell)| cell.readonly && target.contains(**coord))
+            .map(|(coord, _)| *coord),
+        _ => None,
+    });
+    match readonly {
+        Some(coord) => Err(format!("{} is read-only", coord)),
+        None => Ok(()),
+    }
+}
+
+fn parse_range(range: &str) -> Result<CellRange, String> {
+    let range = CellRange::parse(range).ok_or_else(|| "Invalid range".to_string())?;
+    if u64::from(range.rows()) * u64::from(range.cols()) > MAX_RANGE_CELLS {
+        return Err(format!("Ranges are limited to {} cells", MAX_RANGE_CELLS));
+    }
+    Ok(range)
+}
+
+/// The `set` commands that write one entry of `values` to `coord`.
+fn cell_commands(coord: CellCoord, input: &serde_json::Value) -> Result<Vec<String>, String> {
+    let (value, formula, format) = match input {
+        serde_json::Value::Object(fields) => {
+            if let Some(field) = fields
+                .keys()
+                .find(|field| !matches!(field.as_str(), "value" | "formula" | "format"))
+            {
+                return Err(format!("unknown field '{}'", field));
+            }
+            let formula = match fields.get("formula") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(serde_json::Value::String(formula)) => Some(formula.as_str()),
+                Some(_) => return Err("formula must be a string".to_string()),
+            };
+            let format = match fields.get("format") {
+                None | Some(serde_json::Value::Null) => None,
+                Some(serde_json::Value::String(format)) => Some(format.as_str()),
+                Some(_) => return Err("format must be a string".to_string()),
+            };
+            (fields.get("value"), formula, format)
+        }
+        value => (Some(value), None, None),
+    };
+
+    let line_break = |text: &str| text.contains(['\r', '\n']);
+    let contents = match (formula, value) {
+        (Some(_), Some(value)) if !value.is_null() => {
+            return Err("give a value or a formula, not both".to_string())
+        }
+        (Some(formula), _) => {
+            let formula = formula.strip_prefix('=').unwrap_or(formula).trim();
+            if line_break(formula) {
+                return Err("formulas cannot contain line breaks".to_string());
+            }
+            socialcalc::check_workbook_formula(formula).map_err(|err| err.to_string())?;
+            Some(format!("formula {}", formula))
+        }
+        (None, None) => None,
+        (None, Some(value)) => Some(match value {
+            serde_json::Value::Null => "empty".to_string(),
+            serde_json::Value::Number(n) => format!("value n {}", n),
+            serde_json::Value::Bool(b) => format!("value nl {}", u8::from(*b)),
+            serde_json::Value::String(text) => format!("text t {}", encode_for_save(text)),
+            _ => return Err("values must be numbers, strings, booleans or null".to_string()),
+        }),
+    };
+
+    let mut lines = Vec::new();
+    if let Some(contents) = &contents {
+        lines.push(format!("set {} {}", coord, contents));
+    }
+    if let Some(format) = format {
+        if line_break(format) {
+            return Err("formats cannot contain line breaks".to_string());
+        }
+        let attribute = if contents.as_deref().is_some_and(|c| c.starts_with("text ")) {
+            CellAttribute::TextValueFormat
+        } else {
+            CellAttribute::NonTextValueFormat
+        };
+        lines.push(format!("set {} {} {}", coord, attribute.name(), format));
+    }
+    Ok(lines)
+}
+
+fn range_data(sheet: &Sheet, range: CellRange) -> RangeData {
+    let empty = Default::default();
+    let cells = range
+        .coords()
+        .map(|coord| {
+            let cell = sheet.cells.get(&coord).unwrap_or(&empty);
+            let value = Value::from_cell(cell);
+            let format = match value {
+                Value::Text(_) => cell.textvalueformat,
+                _ => cell.nontextvalueformat,
+            };
+            CellData {
+                coord: coord.to_string(),
+                datatype: match cell.datatype {
+                    DataType::Empty => "empty",
+                    DataType::Value => "value",
+                    DataType::Text => "text",
+                    DataType::Formula => "formula",
+                    DataType::Constant => "constant",
+                },
+                valuetype: cell.valuetype.clone(),
+                value: match value {
+                    Value::Blank => serde_json::Value::Null,
+                    Value::Number(n, _) => serde_json::json!(n),
+                    Value::Text(text) => serde_json::Value::String(text),
+                    Value::Error(error) => serde_json::Value::String(error.as_str().to_string()),
+                },
+                formula: (cell.datatype == DataType::Formula).then(|| cell.formula.clone()),
+                format: format.and_then(|n| sheet.valueformats.get(&n)).cloned(),
+                display: socialcalc::display_text(sheet, cell),
+            }
+        })
+        .collect();
+
+    RangeData {
+        range: range.to_string(),
+        cells,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write(sheet: &mut Sheet, coord: &str, input: serde_json::Value) -> Result<(), String> {
+        let lines = cell_commands(CellCoord::parse(coord).unwrap(), &input)?;
+        let parsed = commands::parse(&lines.join("\n")).map_err(|err| err.to_string())?;
+        check_writable(sheet, &parsed)?;
+        commands::apply(sheet, &parsed);
+        socialcalc::recalc(sheet);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_values_formulas_and_formats() {
+        let mut sheet = Sheet::default();
+        write(&mut sheet, "A1", json!(2.5)).unwrap();
+        write(&mut sheet, "A2", json!("line one\nsecond: line")).unwrap();
+        write(&mut sheet, "A3", json!(true)).unwrap();
+        write(
+            &mut sheet,
+            "B1",
+            json!({"formula": "=A1*2", "format": "0.00"}),
+        )
+        .unwrap();
+
+        let data = range_data(&sheet, CellRange::parse("A1:B3").unwrap());
+        let cell = |coord: &str| data.cells.iter().find(|c| c.coord == coord).unwrap();
+        assert_eq!(cell("A1").value, json!(2.5));
+        assert_eq!(cell("A2").value, json!("line one\nsecond: line"));
+        assert_eq!(cell("A3").valuetype, "nl");
+        assert_eq!(cell("B1").formula.as_deref(), Some("A1*2"));
+        assert_eq!(cell("B1").display, "5.00");
+        assert_eq!(cell("B2").datatype, "empty");
+
+        write(&mut sheet, "A1", json!(null)).unwrap();
+        let data = range_data(&sheet, CellRange::parse("A1").unwrap());
+        assert_eq!(data.cells[0].datatype, "empty");
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let error = |input| cell_commands(coord("A1"), &input).unwrap_err();
+        assert_eq!(
+            error(json!({"value": 1, "formula": "2"})),
+            "give a value or a formula, not both"
+        );
+        assert_eq!(error(json!({"colour": "red"})), "unknown field 'colour'");
+        assert_eq!(
+            error(json!([1])),
+            "values must be numbers, strings, booleans or null"
+        );
+        assert_eq!(
+            error(json!({"formula": "\"a\nb\""})),
+            "formulas cannot contain line breaks"
+        );
+        assert_eq!(
+            error(json!({"value": 1, "format": "0\nset A2 text t x"})),
+            "formats cannot contain line breaks"
+        );
+        assert!(error(json!({"formula": "NOSUCH(1)"})).contains("NOSUCH"));
+
+        assert!(parse_range("A1:CV100").is_ok());
+        assert_eq!(
+            parse_range("A1:CV101").unwrap_err(),
+            "Ranges are limited to 10000 cells"
+        );
+        assert_eq!(parse_range("nope").unwrap_err(), "Invalid range");
+    }
+
+    #[test]
+    fn read_only_cells_are_not_written() {
+        let mut sheet = Sheet::parse("cell:A1:v:1:ro:yes").unwrap();
+        assert_eq!(
+            write(&mut sheet, "A1", json!(2)).unwrap_err(),
+            "A1 is read-only"
+        );
+        assert_eq!(sheet.cells[&coord("A1")].datavalue, "1");
+        write(&mut sheet, "A2", json!(2)).unwrap();
+
+        // Anywhere in a range, not only at its corner
+        let sheet = Sheet::parse("cell:B3:v:1:ro:yes").unwrap();
+        let parsed = commands::parse("set A2:B3 value n 5").unwrap();
+        assert_eq!(
+            check_writable(&sheet, &parsed).unwrap_err(),
+            "B3 is read-only"
+        );
+    }
+
+    fn coord(coord: &str) -> CellCoord {
+        CellCoord::parse(coord).unwrap()
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    db::Database,
+    handlers::{recalc::load_sheet_at, share::resolve_owner},
+    models::{ApiResponse, SharePermission},
+    services::collab::{
+        ClientMessage, Edit, Hub, RoomKey, ServerMessage, Session, StoredSheet, MAX_COMMAND_BYTES,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Query, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct CollabQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+}
+
+/// The hub for `AppState`, saving rooms back to the database.
+pub fn hub(db: Database) -> Hub {
+    Hub::new(
+        move |key: RoomKey,
+              edits: Vec<Edit>,
+              content: String,
+              revision: i64|
+              -> BoxFuture<'static, anyhow::Result<Option<i64>>> {
+            let db = db.clone();
+            Box::pin(async move {
+                db.append_file_edits(key.owner_id, &key.path, &edits, &content, revision)
+                    .await
+            })
+        },
+    )
+}
+
+/// Opens a live editing session on a sheet. Editors may send commands;
+/// viewers only follow along and share their cursor.
+pub async fn collab_socket(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<CollabQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::<()>::error(err.to_string())).into_response()),
+    };
+
+    let can_edit = resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    .is_some();
+    let required = if can_edit {
+        SharePermission::Editor
+    } else {
+        SharePermission::Viewer
+    };
+    let (owner_id, save, file_revision) = match load_sheet_at(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        required,
+    )
+    .await?
+    {
+        Ok(loaded) => loaded,
+        Err(message) => return Ok(Json(ApiResponse::<()>::error(message)).into_response()),
+    };
+
+    let name = match state.db.get_user_by_id(user_id).await {
+        Ok(user) => user.map(|user| user.email).unwrap_or_default(),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    // Revisions continue the file's edit log, so a client that drops out
+    // can catch up from /edits
+    let revision = match state.db.get_file_log_range(owner_id, &file_path).await {
+        Ok(range) => range.map_or(0, |(_, last)| last as u64),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let key = RoomKey {
+        owner_id,
+        path: file_path,
+    };
+    Ok(ws
+        .max_message_size(MAX_COMMAND_BYTES + 1024)
+        .on_upgrade(move |socket| async move {
+            let stored = StoredSheet {
+                save,
+                file_revision,
+                logged: revision,
+            };
+            let session = state.collab.join(key, user_id, name, can_edit, stored);
+            run_session(socket, session).await;
+        }))
+}
+
+async fn run_session(mut socket: WebSocket, mut session: Session) {
+    loop {
+        tokio::select! {
+            outgoing = session.recv() => {
+                let Some(message) = outgoing else {
+                    break;
+                };
+                if send(&mut socket, &message).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => session.handle(message),
+                        Err(err) => {
+                            let error = ServerMessage::Error {
+                                id: None,
+                                message: format!("Invalid message: {}", err),
+                            };
+                            if send(&mut socket, &error).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(text)).await
+}
@@ -1,19 +1,191 @@
-use crate::{models::ApiResponse, AppState};
-use axum::{extract::State, response::Json, Form};
+use crate::{
+    handlers::recalc::load_sheet,
+    models::{ApiResponse, SharePermission},
+    socialcalc::{
+        self,
+        delimited::{self, DelimitedOptions, Quoting},
+        ods, xlsx, CellRange, SpreadsheetSave,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Extension, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Form,
+};
 use serde::Deserialize;
+use std::convert::Infallible;
+use uuid::Uuid;
 
+/// CSV and TSV exports of larger ranges are refused.
+const MAX_EXPORT_CELLS: u64 = 5_000_000;
+
+pub const XLSX_CONTENT_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+/// Converts a sheet to another format. The sheet is either a stored file
+/// (`fname`, `owner`) or SocialCalc save data posted in `content`.
 #[derive(Debug, Deserialize)]
 pub struct DownloadForm {
     pub r#type: String,
-    pub content: String,
+    pub content: Option<String>,
+    pub fname: Option<String>,
+    pub owner: Option<String>,
+    /// `A1:C10`; the used area of the sheet when absent. CSV and TSV only.
+    pub range: Option<String>,
+    /// Export formulas instead of computed values.
+    pub formulas: Option<bool>,
+    /// A single character or `tab`. Defaults to `,` for CSV and tab for TSV.
+    pub delimiter: Option<String>,
+    #[serde(default)]
+    pub quote: Quoting,
 }
 
 pub async fn download_file(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
     Form(form): Form<DownloadForm>,
-) -> Json<ApiResponse<serde_json::Value>> {
-    Json(ApiResponse::success(serde_json::json!({
-        "type": form.r#type,
-        "message": "Download processed"
-    })))
+) -> Result<Response, StatusCode> {
+    let (content_type, extension, default_delimiter) = match form.r#type.as_str() {
+        "csv" => ("text/csv; charset=utf-8", "csv", ','),
+        "tsv" => ("text/tab-separated-values; charset=utf-8", "tsv", '\t'),
+        "xlsx" => (XLSX_CONTENT_TYPE, "xlsx", ','),
+        "ods" => (ods::MIME_TYPE, "ods", ','),
+        _ => return Ok(download_error("Unsupported type")),
+    };
+
+    let delimiter = match form.delimiter.as_deref() {
+        None | Some("") => default_delimiter,
+        Some("tab") => '\t',
+        Some(delimiter) => {
+            let mut chars = delimiter.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if !matches!(c, '"' | '\r' | '\n') => c,
+                _ => return Ok(download_error("Invalid delimiter")),
+            }
+        }
+    };
+
+    let range = match form.range.as_deref().filter(|range| !range.is_empty()) {
+        Some(range) => match CellRange::parse(range) {
+            Some(range) => Some(range),
+            None => return Ok(download_error("Invalid range")),
+        },
+        None => None,
+    };
+
+    let (name, mut save) = match (&form.fname, &form.content) {
+        (Some(fname), _) => {
+            let file_path = match VfsPath::home(fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(download_error(&err.to_string())),
+            };
+            match load_sheet(
+                &state,
+                user_id,
+                form.owner.as_deref(),
+                &file_path,
+                SharePermission::Viewer,
+            )
+            .await?
+            {
+                Ok((_, save)) => (file_path.file_name().to_string(), save),
+                Err(message) => return Ok(download_error(&message)),
+            }
+        }
+        (None, Some(content)) => match SpreadsheetSave::parse(content) {
+            Ok(save) => ("sheet".to_string(), save),
+            Err(err) => {
+                return Ok(download_error(&format!(
+                    "Content is not a SocialCalc sheet: {}",
+                    err
+                )))
+            }
+        },
+        (None, None) => return Ok(download_error("Missing fname or content")),
+    };
+
+    socialcalc::recalc(&mut save.sheet);
+
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+        _ => name,
+    };
+
+    let body = if extension == "xlsx" {
+        let sheet_name = stem.clone();
+        let workbook = tokio::task::spawn_blocking(move || xlsx::to_xlsx(&save.sheet, &sheet_name))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match workbook {
+            Ok(bytes) => Body::from(bytes),
+            Err(err) => {
+                return Ok(download_error(&format!(
+                    "Sheet cannot be written as XLSX: {}",
+                    err
+                )))
+            }
+        }
+    } else if extension == "ods" {
+        let sheet_name = stem.clone();
+        let document =
+            tokio::task::spawn_blocking(move || ods::to_ods(&[(&sheet_name, &save.sheet)]))
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        match document {
+            Ok(bytes) => Body::from(bytes),
+            Err(err) => {
+                return Ok(download_error(&format!(
+                    "Sheet cannot be written as ODS: {}",
+                    err
+                )))
+            }
+        }
+    } else {
+        let range = range.or_else(|| save.sheet.used_range());
+        let options = DelimitedOptions {
+            delimiter,
+            quoting: form.quote,
+            formulas: form.formulas.unwrap_or(false),
+        };
+        match range {
+            Some(range) if u64::from(range.rows()) * u64::from(range.cols()) > MAX_EXPORT_CELLS => {
+                return Ok(download_error(&format!(
+                    "Exports are limited to {} cells",
+                    MAX_EXPORT_CELLS
+                )))
+            }
+            Some(range) => {
+                let rows = delimited::rows(save.sheet, range, options);
+                Body::from_stream(futures_util::stream::iter(rows.map(Ok::<_, Infallible>)))
+            }
+            None => Body::empty(),
+        }
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    stem.replace('"', ""),
+                    extension
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn download_error(message: &str) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(ApiResponse::<()>::error(message.to_string())),
+    )
+        .into_response()
 }
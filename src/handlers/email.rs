@@ -1,4 +1,11 @@
-use crate::{models::ApiResponse, services::email::EmailService, AppState};
+use crate::{
+    handlers::recalc::load_sheet,
+    models::{ApiResponse, SharePermission},
+    services::email::EmailService,
+    socialcalc::{self, html, CellRange},
+    vfs::VfsPath,
+    AppState,
+};
 use axum::{
     extract::{Extension, State},
     http::StatusCode,
@@ -8,23 +15,62 @@ use axum::{
 use serde::Deserialize;
 use uuid::Uuid;
 
+/// The body is `text` followed by `data`, HTML from the client, or by the
+/// stored sheet `fname` (`owner` for a shared file) rendered as a table,
+/// limited to `range` when given.
 #[derive(Debug, Deserialize)]
 pub struct EmailForm {
     pub to: String,
     pub subject: String,
     pub text: String,
+    #[serde(default)]
     pub data: String,
     pub appname: String,
+    pub fname: Option<String>,
+    pub owner: Option<String>,
+    pub range: Option<String>,
 }
 
 pub async fn send_email(
     State(state): State<AppState>,
-    Extension(_user_id): Extension<Uuid>,
+    Extension(user_id): Extension<Uuid>,
     Form(form): Form<EmailForm>,
 ) -> Result<Json<ApiResponse<String>>, StatusCode> {
+    let data = match form.fname.as_deref().filter(|fname| !fname.is_empty()) {
+        Some(fname) => {
+            let file_path = match VfsPath::home(fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
+            let range = match form.range.as_deref().filter(|range| !range.is_empty()) {
+                Some(range) => match CellRange::parse(range) {
+                    Some(range) => Some(range),
+                    None => return Ok(Json(ApiResponse::error("Invalid range".to_string()))),
+                },
+                None => None,
+            };
+            match load_sheet(
+                &state,
+                user_id,
+                form.owner.as_deref(),
+                &file_path,
+                SharePermission::Viewer,
+            )
+            .await?
+            {
+                Ok((_, mut save)) => {
+                    socialcalc::recalc(&mut save.sheet);
+                    html::table_html(&save.sheet, range)
+                }
+                Err(message) => return Ok(Json(ApiResponse::error(message))),
+            }
+        }
+        None => form.data,
+    };
+
     let email_service = EmailService::new(&state.config);
 
-    let html_content = format!("<div><p>{}</p></div>{}", form.text, form.data);
+    let html_content = format!("<div><p>{}</p></div>{}", form.text, data);
 
     let subject = if form.subject.is_empty() {
         format!("Shared {}", form.appname)
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Extension, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Form,
+};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    auth::generate_random_string,
+    handlers::save::modify_sheet,
+    models::{ApiResponse, FormDefinition, FormField, FormFieldKind, SheetForm},
+    services::{email::EmailService, rate_limit::RateLimiter},
+    socialcalc::{
+        self, column_number, commands, encode_for_save, CellCoord, DataType, Sheet, SpreadsheetSave,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+
+const MAX_FIELDS: usize = 50;
+/// Text fields without a `max_length` are limited to this many characters,
+/// and none may allow more than `MAX_TEXT_LENGTH`.
+const DEFAULT_MAX_LENGTH: usize = 1000;
+const MAX_TEXT_LENGTH: usize = 10_000;
+/// Submissions allowed per window from one client to one form, and to one
+/// form in all.
+pub const SUBMISSION_WINDOW: Duration = Duration::from_secs(60);
+const SUBMISSIONS_PER_CLIENT: u32 = 5;
+const SUBMISSIONS_PER_FORM: u32 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct FormRequest {
+    pub fname: String,
+    pub definition: FormDefinition,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteFormForm {
+    pub fname: String,
+}
+
+/// Creates or replaces the form for one of the user's sheets. Its public
+/// URL stays the same when the definition is replaced.
+pub async fn save_form(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<FormRequest>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&request.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let mut definition = request.definition;
+    if let Err(message) = check_definition(&mut definition) {
+        return Ok(Json(ApiResponse::error(message)));
+    }
+
+    let file = match state.db.get_file(user_id, &file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    if let Err(err) = SpreadsheetSave::parse(&file.content) {
+        return Ok(Json(ApiResponse::error(format!(
+            "File is not a SocialCalc sheet: {}",
+            err
+        ))));
+    }
+
+    let token = generate_random_string(32);
+    let form = match state
+        .db
+        .upsert_sheet_form(file.id, user_id, &token, &definition)
+        .await
+    {
+        Ok(form) => form,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let mut entry = serde_json::to_value(&form).unwrap_or_default();
+    entry["url"] = json!(format!("/f/{}", form.token));
+    Ok(Json(ApiResponse::success(entry)))
+}
+
+pub async fn list_forms(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<ApiResponse<Vec<SheetForm>>>, StatusCode> {
+    match state.db.list_sheet_forms(user_id).await {
+        Ok(forms) => Ok(Json(ApiResponse::success(forms))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn delete_form(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<DeleteFormForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    match state.db.delete_sheet_form(user_id, &file_path).await {
+        Ok(true) => Ok(Json(ApiResponse::success(json!({"deleted": true})))),
+        Ok(false) => Ok(Json(ApiResponse::error("Form not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// The fields of a public form, for rendering it.
+pub async fn form_definition(State(state): State<AppState>, Path(token): Path<String>) -> Response {
+    match state.db.get_sheet_form(&token).await {
+        Ok(Some(form)) => Json(ApiResponse::success(form.definition.0)).into_response(),
+        Ok(None) => form_error(StatusCode::NOT_FOUND, "Not found"),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Appends a submission to the form's sheet as a new row, below the last
+/// one with anything in the form's columns.
+pub async fn submit_form(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(submitted): Form<HashMap<String, String>>,
+) -> Response {
+    let form = match state.db.get_sheet_form(&token).await {
+        Ok(Some(form)) => form,
+        Ok(None) => return form_error(StatusCode::NOT_FOUND, "Not found"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let client = client_ip(&headers, peer, &state.config.trusted_proxies);
+    if !admit(&state.form_limiter, &token, client) {
+        return form_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many submissions; try again in a minute",
+        );
+    }
+
+    let definition = &form.definition.0;
+    let mut cells = match row_values(definition, &submitted) {
+        Ok(cells) => cells,
+        Err(message) => return form_error(StatusCode::UNPROCESSABLE_ENTITY, &message),
+    };
+    if let Some(column) = definition
+        .timestamp_column
+        .as_deref()
+        .and_then(column_number)
+    {
+        let now = serial(Utc::now().naive_utc());
+        cells.push((column, format!("value ndt {}", now)));
+    }
+
+    // Through the room if the sheet is open, so live edits aren't lost
+    let file_path = VfsPath::from_stored(form.file_path.clone());
+    let mut row = 0;
+    let appended = modify_sheet(&state, form.owner_id, form.owner_id, &file_path, |save| {
+        let (next, cmds) = append_commands(&save.sheet, &cells);
+        row = next;
+        let parsed = commands::parse(&cmds).map_err(|err| err.to_string())?;
+        commands::apply(&mut save.sheet, &parsed);
+        socialcalc::recalc(&mut save.sheet);
+        Ok(true)
+    })
+    .await;
+    let appended = match appended {
+        Ok(Some(appended)) => appended.map(|_| ()),
+        Ok(None) => return form_error(StatusCode::NOT_FOUND, "Not found"),
+        Err(status) => return status.into_response(),
+    };
+    if let Err(message) = appended {
+        return form_error(StatusCode::UNPROCESSABLE_ENTITY, &message);
+    }
+
+    if let Err(err) = state.db.record_form_submission(form.id).await {
+        warn!("Failed to count submission to form {}: {}", form.id, err);
+    }
+    if definition.notify {
+        notify_owner(&state, &form, &submitted, row).await;
+    }
+
+    Json(ApiResponse::success(json!({ "row": row }))).into_response()
+}
+
+/// Counts a submission from `client` against its own limit for the form,
+/// and only if that admits it against the form's, so that one client can't
+/// use up the form's limit for everyone.
+fn admit(limiter: &RateLimiter, token: &str, client: IpAddr) -> bool {
+    limiter.check(&format!("{}:{}", client, token), SUBMISSIONS_PER_CLIENT)
+        && limiter.check(token, SUBMISSIONS_PER_FORM)
+}
+
+fn form_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ApiResponse::<()>::error(message.to_string()))).into_response()
+}
+
+/// The peer, unless it is a trusted proxy: then the nearest address in its
+/// `X-Forwarded-For` that isn't one too. Entries further left are the
+/// client's own claims.
+fn client_ip(headers: &HeaderMap, peer: SocketAddr, trusted: &[IpAddr]) -> IpAddr {
+    let mut client = peer.ip();
+    if !trusted.contains(&client) {
+        return client;
+    }
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded.into_iter().rev() {
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            Err(_) => break,
+        }
+        if !trusted.contains(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Checks a definition before it is stored, normalising its columns.
+fn check_definition(definition: &mut FormDefinition) -> Result<(), String> {
+    if definition.fields.is_empty() {
+        return Err("A form needs at least one field".to_string());
+    }
+    if definition.fields.len() > MAX_FIELDS {
+        return Err(format!("Forms are limited to {} fields", MAX_FIELDS));
+    }
+
+    let mut names = HashSet::new();
+    let mut columns = HashSet::new();
+    if let Some(column) = &mut definition.timestamp_column {
+        *column = column.trim().to_ascii_uppercase();
+        if column_number(column).is_none() {
+            return Err(format!("'{}' is not a column", column));
+        }
+        columns.insert(column.clone());
+    }
+    for field in &mut definition.fields {
+        field.name = field.name.trim().to_string();
+        if field.name.is_empty() {
+            return Err("Every field needs a name".to_string());
+        }
+        if !names.insert(field.name.clone()) {
+            return Err(format!("There are two fields named '{}'", field.name));
+        }
+        field.column = field.column.trim().to_ascii_uppercase();
+        if column_number(&field.column).is_none() {
+            return Err(format!(
+                "{}: '{}' is not a column",
+                field.name, field.column
+            ));
+        }
+        if !columns.insert(field.column.clone()) {
+            return Err(format!("Column {} is used twice", field.column));
+        }
+
+        if field.kind == FormFieldKind::Choice && field.options.is_empty() {
+            return Err(format!("{}: a choice needs options", field.name));
+        }
+        if field.max_length.is_some_and(|max| max > MAX_TEXT_LENGTH) {
+            return Err(format!(
+                "{}: text is limited to {} characters",
+                field.name, MAX_TEXT_LENGTH
+            ));
+        }
+        if matches!((field.min_length, field.max_length), (Some(min), Some(max)) if min > max)
+            || matches!((field.min, field.max), (Some(min), Some(max)) if min > max)
+        {
+            return Err(format!("{}: the minimum is over the maximum", field.name));
+        }
+    }
+    Ok(())
+}
+
+/// Validates a submission, returning each field's column and the end of
+/// the `set` command that writes it. Blank optional fields are left out.
+fn row_values(
+    definition: &FormDefinition,
+    submitted: &HashMap<String, String>,
+) -> Result<Vec<(u32, String)>, String> {
+    let mut cells = Vec::new();
+    for field in &definition.fields {
+        let label = field_label(field);
+        let input = submitted
+            .get(&field.name)
+            .map(|value| value.trim())
+            .unwrap_or_default();
+        let Some(column) = column_number(&field.column) else {
+            continue;
+        };
+
+        if field.kind == FormFieldKind::Checkbox {
+            let checked = matches!(
+                input.to_ascii_lowercase().as_str(),
+                "on" | "true" | "yes" | "1"
+            );
+            if field.required && !checked {
+                return Err(format!("{} must be checked", label));
+            }
+            cells.push((column, format!("value nl {}", u8::from(checked))));
+            continue;
+        }
+        if input.is_empty() {
+            if field.required {
+                return Err(format!("{} is required", label));
+            }
+            continue;
+        }
+
+        let contents = match field.kind {
+            FormFieldKind::Number => {
+                let n: f64 = input
+                    .parse()
+                    .ok()
+                    .filter(|n: &f64| n.is_finite())
+                    .ok_or_else(|| format!("{} must be a number", label))?;
+                if field.min.is_some_and(|min| n < min) || field.max.is_some_and(|max| n > max) {
+                    return Err(format!("{} is out of range", label));
+                }
+                format!("value n {}", n)
+            }
+            FormFieldKind::Date => {
+                let date = NaiveDate::parse_from_str(input, "%Y-%m-%d")
+                    .map_err(|_| format!("{} must be a date (YYYY-MM-DD)", label))?;
+                format!("value nd {}", serial(date.and_hms_opt(0, 0, 0).unwrap()))
+            }
+            FormFieldKind::Choice => {
+                if !field.options.iter().any(|option| option == input) {
+                    return Err(format!("{} is not one of the choices", label));
+                }
+                format!("text t {}", encode_for_save(input))
+            }
+            FormFieldKind::Email | FormFieldKind::Text => {
+                let length = input.chars().count();
+                let max = field.max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+                if length > max {
+                    return Err(format!("{} is limited to {} characters", label, max));
+                }
+                if field.min_length.is_some_and(|min| length < min) {
+                    return Err(format!("{} is too short", label));
+                }
+                if field.kind == FormFieldKind::Email && !is_email(input) {
+                    return Err(format!("{} must be an email address", label));
+                }
+                format!("text t {}", encode_for_save(&input.replace("\r\n", "\n")))
+            }
+            FormFieldKind::Checkbox => unreachable!(),
+        };
+        cells.push((column, contents));
+    }
+    Ok(cells)
+}
+
+fn field_label(field: &FormField) -> &str {
+    match field.label.trim() {
+        "" => &field.name,
+        label => label,
+    }
+}
+
+fn is_email(input: &str) -> bool {
+    let Some((local, domain)) = input.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains('@')
+        && !input.chars().any(char::is_whitespace)
+}
+
+/// The row after the last one with anything in the form's columns, and the
+/// commands that fill it.
+fn append_commands(sheet: &Sheet, cells: &[(u32, String)]) -> (u32, String) {
+    let row = sheet
+        .cells
+        .iter()
+        .filter(|(coord, cell)| {
+            cell.datatype != DataType::Empty && cells.iter().any(|(col, _)| *col == coord.col)
+        })
+        .map(|(coord, _)| coord.row)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let cmds = cells
+        .iter()
+        .map(|(col, contents)| format!("set {} {}", CellCoord::new(*col, row), contents))
+        .collect::<Vec<_>>()
+        .join("\n");
+    (row, cmds)
+}
+
+/// A date serial number, as sheets store dates and times.
+fn serial(at: NaiveDateTime) -> f64 {
+    let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    (at - epoch).num_seconds() as f64 / 86_400.0
+}
+
+async fn notify_owner(
+    state: &AppState,
+    form: &SheetForm,
+    submitted: &HashMap<String, String>,
+    row: u32,
+) {
+    let owner = match state.db.get_user_by_id(form.owner_id).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return,
+        Err(err) => {
+            warn!("Failed to look up owner of form {}: {}", form.id, err);
+            return;
+        }
+    };
+
+    let definition = &form.definition.0;
+    let mut message = format!(
+        "A new response to {} was added to {} as row {}.\n\n",
+        match definition.title.trim() {
+            "" => "your form",
+            title => title,
+        },
+        form.file_path,
+        row
+    );
+    for field in &definition.fields {
+        let value = submitted
+            .get(&field.name)
+            .map(|value| value.trim())
+            .unwrap_or_default();
+        message.push_str(&format!("{}: {}\n", field_label(field), value));
+    }
+
+    let email_service = EmailService::new(&state.config).await;
+    if let Err(err) = email_service
+        .send_email(&owner.email, "New form response", &message)
+        .await
+    {
+        warn!(
+            "Failed to notify {} of a form response: {}",
+            owner.email, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_client_cannot_use_up_a_forms_limit() {
+        let limiter = RateLimiter::new(SUBMISSION_WINDOW);
+        let flooder: IpAddr = "6.6.6.6".parse().unwrap();
+        for _ in 0..SUBMISSIONS_PER_FORM {
+            admit(&limiter, "form", flooder);
+        }
+        assert!(!admit(&limiter, "form", flooder));
+
+        // Everyone else still has all but the flooder's share of the form
+        let admitted = (0..SUBMISSIONS_PER_FORM)
+            .filter(|n| admit(&limiter, "form", IpAddr::from([10, 0, 0, *n as u8])))
+            .count() as u32;
+        assert_eq!(admitted, SUBMISSIONS_PER_FORM - SUBMISSIONS_PER_CLIENT);
+        assert!(admit(&limiter, "other", flooder));
+    }
+
+    #[test]
+    fn forwarded_clients_are_believed_only_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer = SocketAddr::new(proxy, 443);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap(),
+        );
+
+        assert_eq!(client_ip(&headers, peer, &[]), proxy);
+        assert_eq!(
+            client_ip(&headers, peer, &[proxy]),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        let chain = [proxy, "10.0.0.2".parse().unwrap()];
+        assert_eq!(
+            client_ip(&headers, peer, &chain),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+
+        headers.insert("x-forwarded-for", "garbage".parse().unwrap());
+        assert_eq!(client_ip(&headers, peer, &[proxy]), proxy);
+        assert_eq!(client_ip(&HeaderMap::new(), peer, &[proxy]), proxy);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{
+    handlers::share::resolve_owner,
+    models::{ApiResponse, FileEdit, SharePermission},
+    socialcalc::{self, commands, SpreadsheetSave},
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const DEFAULT_EDITS: i64 = 500;
+const MAX_EDITS: i64 = 5000;
+
+#[derive(Debug, Deserialize)]
+pub struct EditsQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+    /// The last revision the client has; edits after it are returned.
+    #[serde(default)]
+    pub since: i64,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+    /// Replay up to and including this edit...
+    pub seq: Option<i64>,
+    /// ...or the last one made by this time. The latest edit if neither.
+    pub at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EditLog {
+    /// The file's last edit; more follow if it is past the last one listed.
+    pub seq: i64,
+    pub edits: Vec<FileEdit>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Replayed {
+    pub seq: i64,
+    pub sheetstr: String,
+}
+
+/// Lists the edits made to a file after revision `since`, for a live
+/// editing client catching up after a dropped connection.
+pub async fn list_edits(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<EditsQuery>,
+) -> Result<Json<ApiResponse<EditLog>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let (first, last) = match state.db.get_file_log_range(owner_id, &file_path).await {
+        Ok(Some(range)) => range,
+        Ok(None) => {
+            return Ok(Json(ApiResponse::success(EditLog {
+                seq: 0,
+                edits: Vec::new(),
+            })))
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    if query.since < first {
+        return Ok(Json(ApiResponse::error(format!(
+            "Edits before {} are no longer kept; reload the file",
+            first
+        ))));
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_EDITS).clamp(1, MAX_EDITS);
+    match state
+        .db
+        .list_file_edits(owner_id, &file_path, query.since, limit)
+        .await
+    {
+        Ok(edits) => Ok(Json(ApiResponse::success(EditLog { seq: last, edits }))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Rebuilds a sheet as it was after an earlier edit, from the nearest
+/// snapshot before it and the commands logged since.
+pub async fn replay(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<ReplayQuery>,
+) -> Result<Json<ApiResponse<Replayed>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let (first, last) = match state.db.get_file_log_range(owner_id, &file_path).await {
+        Ok(Some(range)) => range,
+        Ok(None) => {
+            return Ok(Json(ApiResponse::error(
+                "This file has no edit history".to_string(),
+            )))
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let seq = match (query.seq, query.at) {
+        (Some(seq), _) => Some(seq),
+        (None, Some(at)) => match state
+            .db
+            .get_file_edit_seq_at(owner_id, &file_path, at)
+            .await
+        {
+            Ok(seq) => seq,
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        (None, None) => Some(last),
+    };
+    let seq = match seq {
+        Some(seq) if (first..=last).contains(&seq) => seq,
+        _ => {
+            return Ok(Json(ApiResponse::error(format!(
+                "History is kept from edit {} to {}",
+                first, last
+            ))))
+        }
+    };
+
+    let (base, content) = match state.db.get_file_snapshot(owner_id, &file_path, seq).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            return Ok(Json(ApiResponse::error(
+                "No snapshot before that edit".to_string(),
+            )))
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let edits = match state
+        .db
+        .list_file_edits(owner_id, &file_path, base, seq - base)
+        .await
+    {
+        Ok(edits) => edits,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let mut save = match SpreadsheetSave::parse(&content) {
+        Ok(save) => save,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    if let Err(message) = replay_edits(&mut save, &edits, seq) {
+        return Ok(Json(ApiResponse::error(message)));
+    }
+    socialcalc::recalc(&mut save.sheet);
+
+    Ok(Json(ApiResponse::success(Replayed {
+        seq,
+        sheetstr: save.serialize(),
+    })))
+}
+
+/// Applies the logged commands after a snapshot, up to and including edit
+/// `seq`. Whole-file saves are always snapshotted, so only commands follow
+/// one.
+fn replay_edits(save: &mut SpreadsheetSave, edits: &[FileEdit], seq: i64) -> Result<(), String> {
+    for edit in edits.iter().take_while(|edit| edit.seq <= seq) {
+        let Some(cmds) = &edit.commands else {
+            return Err(format!("Edit {} replaced the file", edit.seq));
+        };
+        let parsed = commands::parse(cmds).map_err(|err| format!("Edit {}: {}", edit.seq, err))?;
+        commands::apply(&mut save.sheet, &parsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socialcalc::CellCoord;
+
+    fn edit(seq: i64, commands: Option<&str>) -> FileEdit {
+        FileEdit {
+            seq,
+            user_id: None,
+            commands: commands.map(str::to_string),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn value(save: &SpreadsheetSave, coord: &str) -> Option<String> {
+        let cell = save.sheet.cells.get(&CellCoord::parse(coord).unwrap())?;
+        Some(cell.datavalue.clone())
+    }
+
+    #[test]
+    fn replays_edits_up_to_seq() {
+        let snapshot = SpreadsheetSave::parse("version:1.5\ncell:A1:v:1\nsheet:c:1:r:1\n").unwrap();
+        let edits = vec![
+            edit(101, Some("set A1 value n 2")),
+            edit(102, Some("set A2 value n 3")),
+            edit(103, Some("set A1 value n 4")),
+        ];
+
+        let mut save = snapshot.clone();
+        replay_edits(&mut save, &edits, 100).unwrap();
+        assert_eq!(value(&save, "A1").as_deref(), Some("1"));
+
+        let mut save = snapshot.clone();
+        replay_edits(&mut save, &edits, 102).unwrap();
+        assert_eq!(value(&save, "A1").as_deref(), Some("2"));
+        assert_eq!(value(&save, "A2").as_deref(), Some("3"));
+
+        let mut save = snapshot.clone();
+        replay_edits(&mut save, &edits, 103).unwrap();
+        assert_eq!(value(&save, "A1").as_deref(), Some("4"));
+
+        let broken = vec![
+            edit(101, Some("set A1 value n 2")),
+            edit(102, Some("frobnicate")),
+        ];
+        let mut save = snapshot.clone();
+        assert!(replay_edits(&mut save, &broken, 101).is_ok());
+        assert!(replay_edits(&mut save, &broken, 102)
+            .unwrap_err()
+            .starts_with("Edit 102"));
+        let replaced = vec![edit(101, None)];
+        assert!(replay_edits(&mut snapshot.clone(), &replaced, 101).is_err());
+    }
+}
@@ -1,9 +1,70 @@
-use crate::{models::ApiResponse, AppState};
+use crate::{
+    handlers::save::overwrite_file,
+    models::{ApiResponse, FileListOptions},
+    socialcalc::{ods, xlsx, ImportedSheet},
+    vfs::VfsPath,
+    AppState,
+};
 use axum::{
-    extract::{Extension, State},
-    response::Json,
+    body::Body,
+    extract::{Extension, Multipart, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+pub const MAX_ARCHIVE_BYTES: usize = 50 * 1024 * 1024;
+const MAX_ENTRY_BYTES: u64 = 10 * 1024 * 1024;
+const MAX_TOTAL_BYTES: u64 = 200 * 1024 * 1024;
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Skip,
+    Rename,
+    Replace,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStatus {
+    Created,
+    Replaced,
+    Renamed,
+    Skipped,
+    Rejected,
+    /// Could not be saved; the rest of the archive still is.
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportEntryReport {
+    pub entry: String,
+    pub fname: Option<String>,
+    pub status: ImportStatus,
+    pub reason: Option<String>,
+    /// Features of an imported workbook that were not carried over.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub folder: Option<String>,
+}
+
+struct ArchiveEntry {
+    name: String,
+    content: Result<String, &'static str>,
+    warnings: Vec<String>,
+}
 
 pub async fn import_page(
     State(_state): State<AppState>,
@@ -11,15 +72,595 @@ pub async fn import_page(
 ) -> Json<ApiResponse<serde_json::Value>> {
     Json(ApiResponse::success(serde_json::json!({
         "page": "import",
-        "message": "Import functionality"
+        "message": "Upload a zip or tar.gz archive of SocialCalc files, or an xlsx or ods workbook, as `file`",
+        "formats": ["zip", "tar.gz", "xlsx", "ods"],
+        "policies": ["skip", "rename", "replace"]
     })))
 }
 
 pub async fn handle_import(
-    State(_state): State<AppState>,
-    Extension(_user_id): Extension<Uuid>,
-) -> Json<ApiResponse<serde_json::Value>> {
-    Json(ApiResponse::success(serde_json::json!({
-        "message": "Import handled"
-    })))
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    mut multipart: Multipart,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let mut archive = None;
+    let mut upload_name = String::new();
+    let mut policy = ConflictPolicy::default();
+    let mut folder = String::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?
+    {
+        match field.name() {
+            Some("file") => {
+                upload_name = field.file_name().unwrap_or_default().to_string();
+                archive = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?);
+            }
+            Some("policy") => {
+                let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+                policy = match value.as_str() {
+                    "skip" => ConflictPolicy::Skip,
+                    "rename" => ConflictPolicy::Rename,
+                    "replace" => ConflictPolicy::Replace,
+                    _ => return Ok(Json(ApiResponse::error("Unknown policy".to_string()))),
+                };
+            }
+            Some("folder") => {
+                folder = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+            }
+            _ => {}
+        }
+    }
+
+    let archive = match archive {
+        Some(archive) => archive,
+        None => return Ok(Json(ApiResponse::error("No archive uploaded".to_string()))),
+    };
+
+    let folder = match VfsPath::home_dir(&folder) {
+        Ok(folder) => folder,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let entries =
+        match tokio::task::spawn_blocking(move || read_archive(&archive, &upload_name)).await {
+            Ok(Ok(entries)) => entries,
+            Ok(Err(reason)) => return Ok(Json(ApiResponse::error(reason.to_string()))),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+
+    let mut reports = Vec::with_capacity(entries.len());
+    for entry in entries {
+        reports.push(import_entry(&state, user_id, &folder, policy, entry).await);
+    }
+
+    let count =
+        |status: fn(&ImportStatus) -> bool| reports.iter().filter(|r| status(&r.status)).count();
+    let summary = json!({
+        "created": count(|s| matches!(s, ImportStatus::Created)),
+        "replaced": count(|s| matches!(s, ImportStatus::Replaced)),
+        "renamed": count(|s| matches!(s, ImportStatus::Renamed)),
+        "skipped": count(|s| matches!(s, ImportStatus::Skipped)),
+        "rejected": count(|s| matches!(s, ImportStatus::Rejected)),
+        "failed": count(|s| matches!(s, ImportStatus::Failed)),
+    });
+
+    Ok(Json(ApiResponse::success(json!({
+        "summary": summary,
+        "entries": reports
+    }))))
+}
+
+async fn import_entry(
+    state: &AppState,
+    user_id: Uuid,
+    folder: &VfsPath,
+    policy: ConflictPolicy,
+    entry: ArchiveEntry,
+) -> ImportEntryReport {
+    let rejected = |reason: &str| ImportEntryReport {
+        entry: entry.name.clone(),
+        fname: None,
+        status: ImportStatus::Rejected,
+        reason: Some(reason.to_string()),
+        warnings: Vec::new(),
+    };
+
+    let name = entry.name.replace('\\', "/");
+    if name.starts_with('/') {
+        return rejected("Absolute paths are not allowed");
+    }
+    let file_path = match folder.join(&name) {
+        Ok(file_path) => file_path,
+        Err(err) => return rejected(&err.to_string()),
+    };
+    let content = match &entry.content {
+        Ok(content) => content,
+        Err(reason) => return rejected(reason),
+    };
+
+    let (status, file_path) = match store_entry(state, user_id, file_path, policy, content).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return rejected("No free name available"),
+        Err(_) => {
+            return ImportEntryReport {
+                entry: entry.name,
+                fname: None,
+                status: ImportStatus::Failed,
+                reason: Some("The file could not be saved".to_string()),
+                warnings: Vec::new(),
+            }
+        }
+    };
+    let (reason, warnings) = match status {
+        ImportStatus::Skipped => (Some("File already exists".to_string()), Vec::new()),
+        _ => (None, entry.warnings),
+    };
+
+    ImportEntryReport {
+        entry: entry.name,
+        fname: Some(file_path.fname().to_string()),
+        status,
+        reason,
+        warnings,
+    }
+}
+
+/// Creates an entry's file, or if the path is taken as it is created,
+/// does what `policy` says. Returns what became of it and where; `None` if
+/// renaming found no free name.
+async fn store_entry(
+    state: &AppState,
+    user_id: Uuid,
+    file_path: VfsPath,
+    policy: ConflictPolicy,
+    content: &str,
+) -> Result<Option<(ImportStatus, VfsPath)>, StatusCode> {
+    let created = state
+        .db
+        .create_file_if_absent(user_id, &file_path, content)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if created.is_some() {
+        return Ok(Some((ImportStatus::Created, file_path)));
+    }
+
+    let status = import_status(true, policy);
+    match status {
+        ImportStatus::Replaced => {
+            overwrite_file(state, user_id, user_id, &file_path, content).await?;
+        }
+        ImportStatus::Renamed => {
+            // Find `name (n).ext` for the smallest `n` that is not taken
+            for n in 1..=100 {
+                let Some(candidate) = numbered_name(&file_path, n) else {
+                    return Ok(None);
+                };
+                let created = state
+                    .db
+                    .create_file_if_absent(user_id, &candidate, content)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                if created.is_some() {
+                    return Ok(Some((status, candidate)));
+                }
+            }
+            return Ok(None);
+        }
+        _ => {}
+    }
+    Ok(Some((status, file_path)))
+}
+
+/// What becomes of an entry, depending on whether its file `exists`.
+fn import_status(exists: bool, policy: ConflictPolicy) -> ImportStatus {
+    match (exists, policy) {
+        (false, _) => ImportStatus::Created,
+        (true, ConflictPolicy::Skip) => ImportStatus::Skipped,
+        (true, ConflictPolicy::Replace) => ImportStatus::Replaced,
+        (true, ConflictPolicy::Rename) => ImportStatus::Renamed,
+    }
+}
+
+/// `name (n).ext` beside `file_path`, if that is a valid name.
+fn numbered_name(file_path: &VfsPath, n: u32) -> Option<VfsPath> {
+    let (dir, _) = file_path.as_str().rsplit_once('/')?;
+    let file = file_path.file_name();
+    let (stem, ext) = match file.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (file, String::new()),
+    };
+    VfsPath::from_stored(dir.to_string())
+        .join(&format!("{} ({}){}", stem, n, ext))
+        .ok()
+}
+
+fn is_system_file(name: &str) -> bool {
+    name.starts_with("__MACOSX/")
+        || name
+            .rsplit('/')
+            .next()
+            .is_some_and(|file| file == ".DS_Store" || file == "Thumbs.db")
+}
+
+fn read_archive(data: &[u8], upload_name: &str) -> Result<Vec<ArchiveEntry>, &'static str> {
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        if is_xlsx(data) {
+            let sheets = xlsx::from_xlsx(data).map_err(|_| "Invalid xlsx workbook")?;
+            return workbook_entries(sheets, upload_name);
+        }
+        if is_ods(data) {
+            let sheets = ods::from_ods(data).map_err(|_| "Invalid ods spreadsheet")?;
+            return workbook_entries(sheets, upload_name);
+        }
+        read_zip(data)
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        read_tar_gz(data)
+    } else {
+        Err("Unsupported archive format")
+    }
+}
+
+fn read_entry(
+    reader: impl Read,
+    declared_size: u64,
+    total: &mut u64,
+) -> Result<String, &'static str> {
+    if declared_size > MAX_ENTRY_BYTES {
+        return Err("File is too large");
+    }
+
+    // Declared sizes can lie, so cap what is actually read as well
+    let mut bytes = Vec::new();
+    reader
+        .take(MAX_ENTRY_BYTES + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|_| "File could not be read")?;
+    if bytes.len() as u64 > MAX_ENTRY_BYTES {
+        return Err("File is too large");
+    }
+
+    *total += bytes.len() as u64;
+    String::from_utf8(bytes).map_err(|_| "File is not UTF-8 text")
+}
+
+fn read_zip(data: &[u8]) -> Result<Vec<ArchiveEntry>, &'static str> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|_| "Invalid zip archive")?;
+    if archive.len() > MAX_ENTRIES {
+        return Err("Archive has too many entries");
+    }
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(|_| "Invalid zip archive")?;
+        let name = file.name().to_string();
+        if file.is_dir() || is_system_file(&name) {
+            continue;
+        }
+
+        let size = file.size();
+        let content = read_entry(file, size, &mut total);
+        entries.push(ArchiveEntry {
+            name,
+            content,
+            warnings: Vec::new(),
+        });
+
+        if total > MAX_TOTAL_BYTES {
+            return Err("Archive is too large when extracted");
+        }
+    }
+
+    Ok(entries)
+}
+
+fn is_xlsx(data: &[u8]) -> bool {
+    ZipArchive::new(Cursor::new(data))
+        .is_ok_and(|archive| archive.index_for_name("xl/workbook.xml").is_some())
+}
+
+fn is_ods(data: &[u8]) -> bool {
+    let Ok(mut archive) = ZipArchive::new(Cursor::new(data)) else {
+        return false;
+    };
+    let Ok(file) = archive.by_name("mimetype") else {
+        return false;
+    };
+    let mut mimetype = String::new();
+    file.take(ods::MIME_TYPE.len() as u64 + 1)
+        .read_to_string(&mut mimetype)
+        .is_ok()
+        && mimetype == ods::MIME_TYPE
+}
+
+/// Each worksheet becomes a file named after the workbook, suffixed with
+/// the sheet name when there is more than one.
+fn workbook_entries(
+    sheets: Vec<ImportedSheet>,
+    upload_name: &str,
+) -> Result<Vec<ArchiveEntry>, &'static str> {
+    if sheets.len() > MAX_ENTRIES {
+        return Err("Workbook has too many sheets");
+    }
+
+    let file_name = upload_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let stem = match file_name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ if !file_name.is_empty() => file_name,
+        _ => "workbook",
+    };
+
+    let single = sheets.len() == 1;
+    Ok(sheets
+        .into_iter()
+        .map(|sheet| {
+            let name = if single {
+                stem.to_string()
+            } else {
+                format!("{}-{}", stem, sheet.name.replace(['/', '\\'], "-"))
+            };
+            ArchiveEntry {
+                name,
+                content: Ok(sheet.save.serialize()),
+                warnings: sheet.warnings,
+            }
+        })
+        .collect())
+}
+
+fn read_tar_gz(data: &[u8]) -> Result<Vec<ArchiveEntry>, &'static str> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(data));
+
+    let mut entries = Vec::new();
+    let mut total = 0;
+    for entry in archive.entries().map_err(|_| "Invalid tar.gz archive")? {
+        let entry = entry.map_err(|_| "Invalid tar.gz archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let name = match entry.path() {
+            Ok(path) => path.to_string_lossy().into_owned(),
+            Err(_) => continue,
+        };
+        if is_system_file(&name) {
+            continue;
+        }
+
+        let size = entry.size();
+        let content = read_entry(entry, size, &mut total);
+        entries.push(ArchiveEntry {
+            name,
+            content,
+            warnings: Vec::new(),
+        });
+
+        if entries.len() > MAX_ENTRIES {
+            return Err("Archive has too many entries");
+        }
+        if total > MAX_TOTAL_BYTES {
+            return Err("Archive is too large when extracted");
+        }
+    }
+
+    Ok(entries)
+}
+
+pub async fn export_folder(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    let folder = match VfsPath::home_dir(query.folder.as_deref().unwrap_or_default()) {
+        Ok(folder) => folder,
+        Err(err) => return Ok(Json(ApiResponse::<()>::error(err.to_string())).into_response()),
+    };
+    let prefix = folder.dir_prefix();
+
+    let page = state
+        .db
+        .list_file_meta(user_id, &folder, &FileListOptions::default())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Files are fetched one at a time and written to a temporary file, so
+    // neither the folder nor the archive is ever held in memory at once
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<(String, String)>(4);
+    let writer = tokio::task::spawn_blocking(move || -> anyhow::Result<std::fs::File> {
+        let mut zip = ZipWriter::new(tempfile::tempfile()?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        while let Some((name, content)) = rx.blocking_recv() {
+            zip.start_file(name, options)?;
+            zip.write_all(content.as_bytes())?;
+        }
+        let mut file = zip.finish()?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(file)
+    });
+
+    for meta in page.files {
+        let path = VfsPath::from_stored(meta.path);
+        if path.is_secure_store() {
+            continue;
+        }
+        let file = match state.db.get_file(user_id, &path).await {
+            Ok(Some(file)) => file,
+            Ok(None) => continue,
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        let name = path.as_str()[prefix.len()..].to_string();
+        if tx.send((name, file.content)).await.is_err() {
+            break;
+        }
+    }
+    drop(tx);
+
+    let file = match writer.await {
+        Ok(Ok(file)) => file,
+        _ => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let length = file
+        .metadata()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+
+    let archive_name = folder.file_name().to_string();
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}.zip\"",
+                    archive_name.replace('"', "")
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn tar_gz(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn names(entries: &[ArchiveEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_text_entries_and_leaves_out_system_files() {
+        let archive = [
+            ("budget.msc", &b"version:1.5\n"[..]),
+            ("__MACOSX/._budget.msc", b"junk"),
+            ("reports/.DS_Store", b"junk"),
+            ("reports/q1.msc", b"cell:A1:v:1\n"),
+        ];
+        for entries in [
+            read_archive(&zip(&archive), "backup.zip").unwrap(),
+            read_archive(&tar_gz(&archive), "backup.tar.gz").unwrap(),
+        ] {
+            assert_eq!(names(&entries), ["budget.msc", "reports/q1.msc"]);
+            assert_eq!(entries[1].content, Ok("cell:A1:v:1\n".to_string()));
+        }
+
+        let entries = read_archive(&zip(&[("binary.bin", &[0xff, 0xfe][..])]), "a.zip").unwrap();
+        assert_eq!(entries[0].content, Err("File is not UTF-8 text"));
+        assert_eq!(
+            read_archive(b"plain text", "a.txt").err(),
+            Some("Unsupported archive format")
+        );
+        assert_eq!(
+            read_archive(b"PK\x03\x04 truncated", "a.zip").err(),
+            Some("Invalid zip archive")
+        );
+    }
+
+    #[test]
+    fn archive_limits_are_enforced() {
+        let names: Vec<String> = (0..=MAX_ENTRIES).map(|i| format!("{}.msc", i)).collect();
+        let many: Vec<(&str, &[u8])> = names
+            .iter()
+            .map(|name| (name.as_str(), &b"x"[..]))
+            .collect();
+        assert_eq!(
+            read_archive(&zip(&many), "a.zip").err(),
+            Some("Archive has too many entries")
+        );
+        assert_eq!(
+            read_archive(&tar_gz(&many), "a.tar.gz").err(),
+            Some("Archive has too many entries")
+        );
+
+        // An oversized entry is rejected on its own, whatever size it claims
+        let big = vec![b'a'; MAX_ENTRY_BYTES as usize + 1];
+        let entries = read_archive(&zip(&[("big.msc", &big), ("ok.msc", b"x")]), "a.zip").unwrap();
+        assert_eq!(entries[0].content, Err("File is too large"));
+        assert_eq!(entries[1].content, Ok("x".to_string()));
+
+        let mut total = 0;
+        let lying = std::io::repeat(b'a').take(MAX_ENTRY_BYTES + 1);
+        assert_eq!(read_entry(lying, 10, &mut total), Err("File is too large"));
+        assert_eq!(
+            read_entry(&b"abc"[..], MAX_ENTRY_BYTES + 1, &mut total),
+            Err("File is too large")
+        );
+        assert_eq!(
+            read_entry(&b"abc"[..], 3, &mut total),
+            Ok("abc".to_string())
+        );
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn conflict_policies() {
+        for policy in [
+            ConflictPolicy::Skip,
+            ConflictPolicy::Rename,
+            ConflictPolicy::Replace,
+        ] {
+            assert!(matches!(
+                import_status(false, policy),
+                ImportStatus::Created
+            ));
+        }
+        assert!(matches!(
+            import_status(true, ConflictPolicy::Skip),
+            ImportStatus::Skipped
+        ));
+        assert!(matches!(
+            import_status(true, ConflictPolicy::Rename),
+            ImportStatus::Renamed
+        ));
+        assert!(matches!(
+            import_status(true, ConflictPolicy::Replace),
+            ImportStatus::Replaced
+        ));
+        assert_eq!(
+            serde_json::from_str::<ConflictPolicy>("\"rename\"").unwrap(),
+            ConflictPolicy::Rename
+        );
+        assert_eq!(ConflictPolicy::default(), ConflictPolicy::Skip);
+
+        let renamed = |fname: &str, n| {
+            numbered_name(&VfsPath::home(fname).unwrap(), n).map(|path| path.fname().to_string())
+        };
+        assert_eq!(renamed("budget.msc", 1).as_deref(), Some("budget (1).msc"));
+        assert_eq!(
+            renamed("reports/q1.v2.msc", 3).as_deref(),
+            Some("reports/q1.v2 (3).msc")
+        );
+        assert_eq!(renamed("README", 2).as_deref(), Some("README (2)"));
+        assert_eq!(renamed(".hidden", 1).as_deref(), Some(".hidden (1)"));
+    }
 }
@@ -1,4 +1,9 @@
-use crate::{models::ApiResponse, AppState};
+use crate::{
+    handlers::share::resolve_owner,
+    models::{ApiResponse, SharePermission},
+    vfs::VfsPath,
+    AppState,
+};
 use axum::{
     extract::{Extension, State},
     http::StatusCode,
@@ -11,6 +16,7 @@ use uuid::Uuid;
 #[derive(Debug, Deserialize)]
 pub struct InsertForm {
     pub filename: String,
+    pub owner: Option<String>,
 }
 
 pub async fn get_file(
@@ -18,9 +24,25 @@ pub async fn get_file(
     Extension(user_id): Extension<Uuid>,
     Form(form): Form<InsertForm>,
 ) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
-    let file_path = format!("home/{}", form.filename);
+    let file_path = match VfsPath::home(&form.filename) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
 
-    match state.db.get_file(user_id, &file_path).await {
+    match state.db.get_file(owner_id, &file_path).await {
         Ok(Some(file)) => Ok(Json(ApiResponse::success(serde_json::json!({
             "data": file.content,
             "result": "ok"
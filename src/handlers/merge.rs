@@ -0,0 +1,205 @@
+use crate::{
+    handlers::share::resolve_owner,
+    models::{ApiResponse, FileData, SharePermission},
+    socialcalc::{
+        self,
+        diff::{self, Change, Conflict},
+        SpreadsheetSave,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+    Form,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct MergeForm {
+    pub fname: String,
+    pub owner: Option<String>,
+    /// The revision `data` was edited from.
+    pub revision: i64,
+    pub data: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+    pub from: i64,
+    /// The current revision when absent.
+    pub to: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergeResult {
+    /// The revision merged into; save the result against it.
+    pub revision: i64,
+    pub sheetstr: String,
+    /// Changes in `data` that were left out because the file changed them
+    /// too.
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Merges a sheet edited from an older revision into the file as it is
+/// now, without saving.
+pub async fn merge_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<MergeForm>,
+) -> Result<Json<ApiResponse<MergeResult>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let current = match state.db.get_file(owner_id, &file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match merge_into(&state, &current, form.revision, &form.data).await? {
+        Ok(merged) => Ok(Json(ApiResponse::success(merged))),
+        Err(message) => Ok(Json(ApiResponse::error(message))),
+    }
+}
+
+/// Merges `data`, edited from `revision`, into `current`: the changes it
+/// made since then are applied unless the file changed the same things.
+pub async fn merge_into(
+    state: &AppState,
+    current: &FileData,
+    revision: i64,
+    data: &str,
+) -> Result<Result<MergeResult, String>, StatusCode> {
+    if revision != current.revision {
+        return merge_changes(state, current, revision, data).await;
+    }
+    if let Err(err) = SpreadsheetSave::parse(data) {
+        return Ok(Err(err.to_string()));
+    }
+    Ok(Ok(MergeResult {
+        revision,
+        sheetstr: data.to_string(),
+        conflicts: Vec::new(),
+    }))
+}
+
+/// `merge_into`, even if `current` is at `revision`: for a file open in a
+/// room, whose `content` has moved on from the revision it is stored at.
+pub async fn merge_changes(
+    state: &AppState,
+    current: &FileData,
+    revision: i64,
+    data: &str,
+) -> Result<Result<MergeResult, String>, StatusCode> {
+    let theirs = match SpreadsheetSave::parse(data) {
+        Ok(save) => save,
+        Err(err) => return Ok(Err(err.to_string())),
+    };
+
+    let path = VfsPath::from_stored(current.path.clone());
+    let base = match state
+        .db
+        .get_file_revision(current.user_id, &path, revision)
+        .await
+    {
+        Ok(Some(content)) => content,
+        Ok(None) => {
+            return Ok(Err(format!(
+                "Revision {} is no longer kept; reload the file",
+                revision
+            )))
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let (base, mut ours) = match (
+        SpreadsheetSave::parse(&base),
+        SpreadsheetSave::parse(&current.content),
+    ) {
+        (Ok(base), Ok(ours)) => (base, ours),
+        (Err(err), _) | (_, Err(err)) => return Ok(Err(err.to_string())),
+    };
+
+    let merged = diff::merge(&base.sheet, &ours.sheet, &theirs.sheet);
+    ours.sheet = merged.sheet;
+    socialcalc::recalc(&mut ours.sheet);
+
+    Ok(Ok(MergeResult {
+        revision: current.revision,
+        sheetstr: ours.serialize(),
+        conflicts: merged.conflicts,
+    }))
+}
+
+/// Lists what changed in a sheet between two revisions.
+pub async fn diff_revisions(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<ApiResponse<Vec<Change>>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let mut sheets = Vec::new();
+    for revision in [Some(query.from), query.to] {
+        let content = match revision {
+            Some(revision) => state
+                .db
+                .get_file_revision(owner_id, &file_path, revision)
+                .await
+                .map(|content| {
+                    content.ok_or_else(|| format!("Revision {} is no longer kept", revision))
+                }),
+            None => state.db.get_file(owner_id, &file_path).await.map(|file| {
+                file.map(|file| file.content)
+                    .ok_or_else(|| "File not found".to_string())
+            }),
+        };
+        let content = match content {
+            Ok(Ok(content)) => content,
+            Ok(Err(message)) => return Ok(Json(ApiResponse::error(message))),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        match SpreadsheetSave::parse(&content) {
+            Ok(save) => sheets.push(save.sheet),
+            Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+        }
+    }
+
+    Ok(Json(ApiResponse::success(diff::diff(
+        &sheets[0], &sheets[1],
+    ))))
+}
@@ -1,18 +1,32 @@
 pub mod amazon;
+pub mod attributes;
 pub mod auth;
 pub mod business;
+pub mod cells;
+pub mod collab;
 pub mod download;
 pub mod dropbox;
 pub mod email;
 pub mod finance;
+pub mod forms;
+pub mod history;
 pub mod image;
 pub mod import;
 pub mod inapp;
 pub mod insert;
+pub mod merge;
 pub mod pdf;
+pub mod publish;
+pub mod recalc;
 pub mod restore;
 pub mod run_as;
 pub mod save;
+pub mod search;
+pub mod share;
+pub mod share_link;
+pub mod stats;
+pub mod templates;
+pub mod transfer;
 pub mod user_sheet;
 pub mod webapp;
 
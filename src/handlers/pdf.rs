@@ -1,37 +1,293 @@
-use crate::{models::ApiResponse, AppState};
+use crate::{
+    handlers::{recalc::load_sheet, share::resolve_owner},
+    models::{ApiResponse, PdfDocument, SharePermission},
+    socialcalc::{
+        self,
+        pdf::{self, PageSize, PdfOptions},
+        CellRange, SpreadsheetSave,
+    },
+    utils::format_file_path,
+    vfs::VfsPath,
+    AppState,
+};
 use axum::{
-    extract::{Query, State},
-    response::Json,
+    body::Body,
+    extract::{Extension, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
     Form,
 };
 use serde::Deserialize;
+use serde_json::json;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+const POINTS_PER_MM: f32 = 72.0 / 25.4;
 
 #[derive(Debug, Deserialize)]
 pub struct PdfQuery {
     pub fname: String,
+    pub owner: Option<String>,
 }
 
+/// Prints a stored sheet (`fname`, `owner`) or SocialCalc save data posted
+/// in `content`, and saves the PDF as `output`.
 #[derive(Debug, Deserialize)]
 pub struct PdfForm {
-    pub content: String,
+    pub content: Option<String>,
+    pub fname: Option<String>,
+    pub owner: Option<String>,
+    /// `A1:C10`; the used area of the sheet when absent.
+    pub range: Option<String>,
+    /// Where to save the PDF; the sheet's name with a `.pdf` extension
+    /// when absent.
+    pub output: Option<String>,
+    /// `a4`, `a3`, `letter` or `legal`.
+    pub page_size: Option<String>,
+    /// `portrait` or `landscape`.
+    pub orientation: Option<String>,
+    /// In millimetres.
+    pub margin: Option<f32>,
+    pub fit_width: Option<bool>,
+    /// Rows at the top of the range repeated on every page.
+    pub header_rows: Option<u32>,
+    pub page_numbers: Option<bool>,
 }
 
+/// Serves a PDF saved by `convert_html_to_pdf`.
 pub async fn get_pdf(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
     Query(query): Query<PdfQuery>,
-) -> Json<ApiResponse<serde_json::Value>> {
-    Json(ApiResponse::success(serde_json::json!({
-        "fname": query.fname,
-        "message": "PDF retrieved"
-    })))
+) -> Result<Response, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(pdf_error(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    let Some(pdf) = find_pdf(&state, user_id, query.owner.as_deref(), &file_path).await? else {
+        return Ok(pdf_error(StatusCode::NOT_FOUND, "File not found"));
+    };
+
+    let stream = match state
+        .db
+        .storage()
+        .get_object_stream(&pdf.storage_key, None)
+        .await
+    {
+        Ok(Some(stream)) => stream,
+        Ok(None) => return Ok(pdf_error(StatusCode::NOT_FOUND, "File not found")),
+        Err(_) => return Err(StatusCode::BAD_GATEWAY),
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, pdf::MIME_TYPE.to_string()),
+            (header::CONTENT_LENGTH, pdf.size.to_string()),
+            (header::ETAG, format!("\"{}\"", pdf.revision)),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "inline; filename=\"{}\"",
+                    file_path.file_name().replace('"', "")
+                ),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(stream.into_async_read())),
+    )
+        .into_response())
+}
+
+/// Finds the PDF at `path`, the caller's own or, with `owner`, one printed
+/// from a sheet the caller may view.
+async fn find_pdf(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    path: &VfsPath,
+) -> Result<Option<PdfDocument>, StatusCode> {
+    let owner_id = match owner.filter(|owner| !owner.is_empty()) {
+        Some(owner) => match state.db.get_user_by_email(owner).await {
+            Ok(Some(owner)) => owner.id,
+            Ok(None) => return Ok(None),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        None => user_id,
+    };
+    let pdf = match state.db.get_pdf(owner_id, path).await {
+        Ok(Some(pdf)) => pdf,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    if owner_id == user_id {
+        return Ok(Some(pdf));
+    }
+
+    let Some(source) = pdf.source_path.clone().map(VfsPath::from_stored) else {
+        return Ok(None);
+    };
+    let viewer = resolve_owner(state, user_id, owner, &source, SharePermission::Viewer).await?;
+    Ok(viewer.map(|_| pdf))
 }
 
+/// Renders a sheet to PDF and saves it apart from the caller's files, for
+/// `get_pdf` to serve. It never replaces a file.
 pub async fn convert_html_to_pdf(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
     Form(form): Form<PdfForm>,
-) -> Json<ApiResponse<serde_json::Value>> {
-    Json(ApiResponse::success(serde_json::json!({
-        "message": "PDF conversion processed",
-        "content_length": form.content.len()
-    })))
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let mut options = PdfOptions::default();
+    if let Some(page_size) = form.page_size.as_deref().filter(|size| !size.is_empty()) {
+        match PageSize::parse(page_size) {
+            Some(page_size) => options.page_size = page_size,
+            None => {
+                return Ok(Json(ApiResponse::error(
+                    "Unsupported page size".to_string(),
+                )))
+            }
+        }
+    }
+    options.landscape = match form.orientation.as_deref() {
+        None | Some("") | Some("portrait") => false,
+        Some("landscape") => true,
+        Some(_) => return Ok(Json(ApiResponse::error("Invalid orientation".to_string()))),
+    };
+    if let Some(margin) = form.margin {
+        if !(0.0..=100.0).contains(&margin) {
+            return Ok(Json(ApiResponse::error("Invalid margin".to_string())));
+        }
+        options.margin = margin * POINTS_PER_MM;
+    }
+    options.fit_width = form.fit_width.unwrap_or(false);
+    options.header_rows = form.header_rows.unwrap_or(0);
+    options.page_numbers = form.page_numbers.unwrap_or(true);
+
+    let range = match form.range.as_deref().filter(|range| !range.is_empty()) {
+        Some(range) => match CellRange::parse(range) {
+            Some(range) => Some(range),
+            None => return Ok(Json(ApiResponse::error("Invalid range".to_string()))),
+        },
+        None => None,
+    };
+
+    let (source, mut save) = match (&form.fname, &form.content) {
+        (Some(fname), _) => {
+            let file_path = match VfsPath::home(fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
+            match load_sheet(
+                &state,
+                user_id,
+                form.owner.as_deref(),
+                &file_path,
+                SharePermission::Viewer,
+            )
+            .await?
+            {
+                Ok((_, save)) => (Some(file_path), save),
+                Err(message) => return Ok(Json(ApiResponse::error(message))),
+            }
+        }
+        (None, Some(content)) => match SpreadsheetSave::parse(content) {
+            Ok(save) => (None, save),
+            Err(err) => {
+                return Ok(Json(ApiResponse::error(format!(
+                    "Content is not a SocialCalc sheet: {}",
+                    err
+                ))))
+            }
+        },
+        (None, None) => {
+            return Ok(Json(ApiResponse::error(
+                "Missing fname or content".to_string(),
+            )))
+        }
+    };
+
+    let output = match form.output.as_deref().filter(|output| !output.is_empty()) {
+        Some(output) => VfsPath::home(output),
+        None => match &source {
+            Some(path) => {
+                let fname = path.fname();
+                let stem = match path.file_name().rsplit_once('.') {
+                    Some((stem, extension)) if !stem.is_empty() => {
+                        &fname[..fname.len() - extension.len() - 1]
+                    }
+                    _ => fname,
+                };
+                VfsPath::home(&format!("{}.pdf", stem))
+            }
+            None => VfsPath::home("sheet.pdf"),
+        },
+    };
+    let output = match output {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    if source.as_ref() == Some(&output) && form.owner.as_deref().unwrap_or("").is_empty() {
+        return Ok(Json(ApiResponse::error(
+            "Output would replace the sheet".to_string(),
+        )));
+    }
+    options.title = Some(match &source {
+        Some(path) => path.file_name().to_string(),
+        None => output.file_name().to_string(),
+    });
+
+    socialcalc::recalc(&mut save.sheet);
+    let document = tokio::task::spawn_blocking(move || pdf::to_pdf(&save.sheet, range, &options))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let (bytes, pages) = match document {
+        Ok(document) => document,
+        Err(err) => {
+            return Ok(Json(ApiResponse::error(format!(
+                "Sheet cannot be written as PDF: {}",
+                err
+            ))))
+        }
+    };
+
+    // A fresh key for every PDF, so a reader of the previous one never sees
+    // a half-replaced object
+    let storage_key = format_file_path(&user_id.to_string(), &format!("pdf/{}", Uuid::new_v4()));
+    if state
+        .db
+        .storage()
+        .put_object(&storage_key, &bytes)
+        .await
+        .is_err()
+    {
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+    let stored = state
+        .db
+        .store_pdf(
+            user_id,
+            &output,
+            source.as_ref(),
+            &storage_key,
+            bytes.len() as i64,
+        )
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !stored {
+        state.db.storage().delete_object(&storage_key).await.ok();
+        return Ok(Json(ApiResponse::error(format!(
+            "{} is a file, not a PDF, and would be replaced",
+            output.fname()
+        ))));
+    }
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": output.fname(),
+        "size": bytes.len(),
+        "pages": pages
+    }))))
+}
+
+fn pdf_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ApiResponse::<()>::error(message.to_string()))).into_response()
 }
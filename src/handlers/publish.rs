@@ -0,0 +1,380 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Form,
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    auth::generate_random_string,
+    models::{ApiResponse, PublishedRange},
+    socialcalc::{
+        self, column_name, CellCoord, CellRange, NumberKind, Sheet, SpreadsheetSave, Value,
+    },
+    vfs::VfsPath,
+    AppState,
+};
+
+/// Published ranges are limited to this many cells.
+const MAX_PUBLISHED_CELLS: u64 = 100_000;
+
+#[derive(Debug, Deserialize)]
+pub struct PublishForm {
+    pub fname: String,
+    /// A named range, an A1 range, or empty for the sheet's used area. Its
+    /// first row holds the field names.
+    #[serde(default)]
+    pub range: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnpublishForm {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublishedQuery {
+    pub key: Option<String>,
+}
+
+/// Publishes a range of one of the user's sheets. The API key is only
+/// returned here.
+pub async fn publish_range(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<PublishForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let file = match state.db.get_file(user_id, &file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let save = match SpreadsheetSave::parse(&file.content) {
+        Ok(save) => save,
+        Err(err) => {
+            return Ok(Json(ApiResponse::error(format!(
+                "File is not a SocialCalc sheet: {}",
+                err
+            ))))
+        }
+    };
+    let range_name = form.range.trim();
+    if let Err(message) = resolve_range(&save.sheet, range_name) {
+        return Ok(Json(ApiResponse::error(message)));
+    }
+
+    let token = generate_random_string(32);
+    let api_key = generate_random_string(40);
+    let published = match state
+        .db
+        .create_published_range(file.id, user_id, &token, range_name, &hash_key(&api_key))
+        .await
+    {
+        Ok(published) => published,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let mut entry = serde_json::to_value(&published).unwrap_or_default();
+    entry["api_key"] = json!(api_key);
+    entry["url"] = json!(format!("/data/{}", published.token));
+    Ok(Json(ApiResponse::success(entry)))
+}
+
+pub async fn list_published(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<ApiResponse<Vec<PublishedRange>>>, StatusCode> {
+    match state.db.list_published_ranges(user_id).await {
+        Ok(published) => Ok(Json(ApiResponse::success(published))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn unpublish_range(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<UnpublishForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    match state.db.revoke_published_range(user_id, &form.token).await {
+        Ok(true) => Ok(Json(ApiResponse::success(json!({"revoked": true})))),
+        Ok(false) => Ok(Json(ApiResponse::error(
+            "Published range not found".to_string(),
+        ))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Serves a published range as an array of objects keyed by its header
+/// row. The key is given as `X-API-Key` or `?key=`. The records are cached
+/// until the file is next saved.
+pub async fn published_data(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(query): Query<PublishedQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let published = match state.db.get_published_range(&token).await {
+        Ok(Some(published)) => published,
+        Ok(None) => return data_error(StatusCode::NOT_FOUND, "Not found"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if published.revoked_at.is_some() {
+        return data_error(StatusCode::GONE, "No longer published");
+    }
+
+    let key = headers
+        .get("x-api-key")
+        .and_then(|key| key.to_str().ok())
+        .or(query.key.as_deref())
+        .unwrap_or_default();
+    if hash_key(key) != published.api_key_hash {
+        return data_error(StatusCode::UNAUTHORIZED, "Invalid API key");
+    }
+
+    let (etag, body) = match state.db.get_published_cache(published.id).await {
+        Ok(Some(cached)) => cached,
+        Ok(None) => match render(&state, &published).await {
+            Ok(Ok(rendered)) => rendered,
+            Ok(Err(message)) => return data_error(StatusCode::UNPROCESSABLE_ENTITY, &message),
+            Err(status) => return status.into_response(),
+        },
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let etag = format!("\"{}\"", etag);
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "private, no-cache".to_string()),
+    ];
+    if etag_matches(&headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, "application/json".to_string())],
+        body,
+    )
+        .into_response()
+}
+
+/// Whether `If-None-Match` names `etag`, compared weakly as it should be.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+fn data_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ApiResponse::<()>::error(message.to_string()))).into_response()
+}
+
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Renders the records from the file as it is now and caches them.
+async fn render(
+    state: &AppState,
+    published: &PublishedRange,
+) -> Result<Result<(String, String), String>, StatusCode> {
+    let file = match state
+        .db
+        .get_file(
+            published.owner_id,
+            &VfsPath::from_stored(published.file_path.clone()),
+        )
+        .await
+    {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Err("File not found".to_string())),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let mut save = match SpreadsheetSave::parse(&file.content) {
+        Ok(save) => save,
+        Err(err) => return Ok(Err(format!("File is not a SocialCalc sheet: {}", err))),
+    };
+    let range = match resolve_range(&save.sheet, &published.range_name) {
+        Ok(range) => range,
+        Err(message) => return Ok(Err(message)),
+    };
+    socialcalc::recalc(&mut save.sheet);
+
+    let body = serde_json::to_string(&records(&save.sheet, range)).unwrap_or_default();
+    let etag: String = hash_key(&body).chars().take(32).collect();
+    if state
+        .db
+        .set_published_cache(published.id, file.revision, &etag, &body)
+        .await
+        .is_err()
+    {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(Ok((etag, body)))
+}
+
+/// A named range, an A1 range, or the used area when empty.
+fn resolve_range(sheet: &Sheet, range_name: &str) -> Result<CellRange, String> {
+    let range = if range_name.is_empty() {
+        sheet
+            .used_range()
+            .ok_or_else(|| "The sheet is empty".to_string())?
+    } else if let Some(range) = CellRange::parse(range_name) {
+        range
+    } else {
+        sheet
+            .names
+            .iter()
+            .find(|name| name.name.eq_ignore_ascii_case(range_name))
+            .and_then(|name| CellRange::parse(name.definition.trim_start_matches('=')))
+            .ok_or_else(|| format!("'{}' is not a range of this sheet", range_name))?
+    };
+    if u64::from(range.rows()) * u64::from(range.cols()) > MAX_PUBLISHED_CELLS {
+        return Err(format!(
+            "Published ranges are limited to {} cells",
+            MAX_PUBLISHED_CELLS
+        ));
+    }
+    Ok(range)
+}
+
+/// One object per row after the first, keyed by the first row's text.
+/// Blank rows are left out.
+fn records(sheet: &Sheet, range: CellRange) -> Vec<serde_json::Map<String, serde_json::Value>> {
+    let empty = Default::default();
+    let cell = |col, row| sheet.cells.get(&CellCoord::new(col, row)).unwrap_or(&empty);
+
+    let mut fields: Vec<String> = Vec::new();
+    for col in range.start.col..=range.end.col {
+        let text = socialcalc::display_text(sheet, cell(col, range.start.row));
+        let mut field = match text.trim() {
+            "" => column_name(col),
+            text => text.to_string(),
+        };
+        if fields.contains(&field) {
+            field = format!("{}_{}", field, column_name(col));
+        }
+        fields.push(field);
+    }
+
+    (range.start.row + 1..=range.end.row)
+        .map(|row| {
+            fields
+                .iter()
+                .zip(range.start.col..)
+                .map(|(field, col)| {
+                    let cell = cell(col, row);
+                    let value = match Value::from_cell(cell) {
+                        Value::Blank => serde_json::Value::Null,
+                        Value::Number(n, NumberKind::Logical) => json!(n != 0.0),
+                        Value::Number(
+                            _,
+                            NumberKind::Date | NumberKind::Time | NumberKind::DateTime,
+                        ) => {
+                            json!(socialcalc::display_text(sheet, cell))
+                        }
+                        Value::Number(n, _) => json!(n),
+                        Value::Text(text) => json!(text),
+                        Value::Error(error) => json!(error.as_str()),
+                    };
+                    (field.clone(), value)
+                })
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .filter(|record| record.values().any(|value| !value.is_null()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(lines: &str) -> Sheet {
+        let mut sheet = Sheet::parse(lines).unwrap();
+        socialcalc::recalc(&mut sheet);
+        sheet
+    }
+
+    #[test]
+    fn records_are_keyed_by_the_header_row() {
+        let save =
+            SpreadsheetSave::parse(include_str!("../socialcalc/testdata/contacts.msc")).unwrap();
+        let mut contacts = save.sheet;
+        socialcalc::recalc(&mut contacts);
+        let range = resolve_range(&contacts, "").unwrap();
+        let rows = serde_json::to_value(records(&contacts, range)).unwrap();
+        assert_eq!(rows.as_array().unwrap().len(), 3);
+        assert_eq!(rows[0]["Name"], json!("<b>Ada</b>"));
+        assert_eq!(rows[0]["Joined"], json!("2024-01-01"));
+        assert_eq!(rows[1]["Notes"], json!("#DIV/0!"));
+        assert_eq!(rows[2]["Joined"], json!(true));
+        assert_eq!(rows[2]["Notes"], json!(-0.25));
+
+        // Blank and repeated headings are told apart by column; blank rows
+        // are left out
+        let sheet = sheet(
+            "cell:A1:t:id\ncell:B1:t:id\ncell:D1:v:7\ncell:A2:v:1\ncell:C2:t:x\n\
+             cell:A4:v:2\nsheet:c:4:r:4\nname:DATA::A1\\cD4\n",
+        );
+        let range = resolve_range(&sheet, "data").unwrap();
+        let rows = serde_json::to_value(records(&sheet, range)).unwrap();
+        assert_eq!(
+            rows,
+            json!([
+                {"id": 1.0, "id_B": null, "C": "x", "7": null},
+                {"id": 2.0, "id_B": null, "C": null, "7": null},
+            ])
+        );
+    }
+
+    #[test]
+    fn ranges_are_resolved_and_limited() {
+        let sheet = sheet("cell:B2:v:1\ncell:C3:v:2\nsheet:c:3:r:3\nname:SALES::A1\\cB2\n");
+        assert_eq!(
+            resolve_range(&sheet, "").unwrap(),
+            CellRange::parse("A1:C3").unwrap()
+        );
+        assert_eq!(
+            resolve_range(&sheet, "b2:c3").unwrap(),
+            CellRange::parse("B2:C3").unwrap()
+        );
+        assert_eq!(
+            resolve_range(&sheet, "Sales").unwrap(),
+            CellRange::parse("A1:B2").unwrap()
+        );
+        assert!(resolve_range(&sheet, "missing").is_err());
+        assert!(resolve_range(&sheet, "A1:ZZ1000").is_err());
+        assert!(resolve_range(&Sheet::parse("").unwrap(), "").is_err());
+    }
+
+    #[test]
+    fn etags_are_matched_weakly() {
+        let matches = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+            etag_matches(&headers, "\"abc\"")
+        };
+        assert!(matches("\"abc\""));
+        assert!(matches("\"old\", \"abc\""));
+        assert!(matches("W/\"abc\""));
+        assert!(matches("*"));
+        assert!(!matches("\"abcd\""));
+        assert!(!matches("abc"));
+        assert!(!etag_matches(&HeaderMap::new(), "\"abc\""));
+    }
+}
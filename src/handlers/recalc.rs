@@ -0,0 +1,192 @@
+use crate::{
+    handlers::{save::modify_sheet, share::resolve_owner},
+    models::{ApiResponse, SharePermission},
+    socialcalc::{self, CellRange, SpreadsheetSave, Value},
+    vfs::VfsPath,
+    AppState,
+};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+    Form,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct RecalcForm {
+    pub fname: String,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValuesQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+    /// `A1:C10` or a single cell; the whole sheet when absent.
+    pub range: Option<String>,
+    /// Recalculate before reading. Defaults to true; `false` returns the
+    /// values as last saved.
+    pub recalc: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecalcResult {
+    pub evaluated: usize,
+    pub changed: usize,
+    pub errors: Vec<String>,
+    pub circular: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CellValue {
+    pub coord: String,
+    pub valuetype: String,
+    pub value: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub formula: Option<String>,
+}
+
+/// Recalculates a stored sheet and saves the new values if any changed.
+pub async fn recalc_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<RecalcForm>,
+) -> Result<Json<ApiResponse<RecalcResult>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let Some(owner_id) = resolve_owner(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    else {
+        return Ok(Json(ApiResponse::error("File not found".to_string())));
+    };
+
+    // Through the room if the file is open, or else under the row lock, so
+    // an edit made while recalculating isn't lost
+    let mut report = None;
+    let stored = modify_sheet(&state, owner_id, user_id, &file_path, |save| {
+        let recalculated = report.insert(socialcalc::recalc(&mut save.sheet));
+        Ok(recalculated.changed > 0)
+    })
+    .await?;
+    let report = match stored {
+        Some(Ok(_)) => report.unwrap(),
+        Some(Err(message)) => return Ok(Json(ApiResponse::error(message))),
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    Ok(Json(ApiResponse::success(RecalcResult {
+        evaluated: report.evaluated,
+        changed: report.changed,
+        errors: report.errors.iter().map(ToString::to_string).collect(),
+        circular: report.circular.iter().map(ToString::to_string).collect(),
+    })))
+}
+
+/// Reads computed cell values, recalculating in memory without saving.
+pub async fn get_values(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<ValuesQuery>,
+) -> Result<Json<ApiResponse<Vec<CellValue>>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let range = match query.range.as_deref().filter(|range| !range.is_empty()) {
+        Some(range) => match CellRange::parse(range) {
+            Some(range) => Some(range),
+            None => return Ok(Json(ApiResponse::error("Invalid range".to_string()))),
+        },
+        None => None,
+    };
+
+    let (_, mut save) = match load_sheet(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Ok(loaded) => loaded,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+
+    if query.recalc.unwrap_or(true) {
+        socialcalc::recalc(&mut save.sheet);
+    }
+
+    let values = save
+        .sheet
+        .cells
+        .iter()
+        .filter(|(coord, _)| range.is_none_or(|range| range.contains(**coord)))
+        .filter_map(|(coord, cell)| {
+            let value = match Value::from_cell(cell) {
+                Value::Blank => return None,
+                Value::Number(n, _) => serde_json::json!(n),
+                Value::Text(text) => serde_json::Value::String(text),
+                Value::Error(error) => serde_json::Value::String(error.as_str().to_string()),
+            };
+            Some(CellValue {
+                coord: coord.to_string(),
+                valuetype: cell.valuetype.clone(),
+                value,
+                formula: (cell.datatype == socialcalc::DataType::Formula)
+                    .then(|| cell.formula.clone()),
+            })
+        })
+        .collect();
+
+    Ok(Json(ApiResponse::success(values)))
+}
+
+/// Resolves the file's owner and parses its content. The inner error is a
+/// message for the client.
+pub async fn load_sheet(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    file_path: &VfsPath,
+    required: SharePermission,
+) -> Result<Result<(Uuid, SpreadsheetSave), String>, StatusCode> {
+    let loaded = load_sheet_at(state, user_id, owner, file_path, required).await?;
+    Ok(loaded.map(|(owner_id, save, _)| (owner_id, save)))
+}
+
+/// `load_sheet`, with the revision of the file as loaded.
+pub async fn load_sheet_at(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    file_path: &VfsPath,
+    required: SharePermission,
+) -> Result<Result<(Uuid, SpreadsheetSave, i64), String>, StatusCode> {
+    let Some(owner_id) = resolve_owner(state, user_id, owner, file_path, required).await? else {
+        return Ok(Err("File not found".to_string()));
+    };
+
+    let file = match state.db.get_file(owner_id, file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Err("File not found".to_string())),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match SpreadsheetSave::parse(&file.content) {
+        Ok(save) => Ok(Ok((owner_id, save, file.revision))),
+        Err(err) => Ok(Err(format!("File is not a SocialCalc sheet: {}", err))),
+    }
+}
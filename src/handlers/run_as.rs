@@ -1,8 +1,19 @@
-use crate::{models::ApiResponse, AppState};
+use crate::{
+    handlers::{recalc::load_sheet, save::modify_sheet, share::resolve_owner},
+    models::{ApiResponse, SharePermission},
+    socialcalc::{
+        self,
+        workbook::{self, Workbook, WorkbookError},
+        Sheet, SpreadsheetSave,
+    },
+    vfs::VfsPath,
+    AppState,
+};
 use axum::{
     extract::{Extension, Query, State},
     http::StatusCode,
     response::Json,
+    Form,
 };
 use serde::Deserialize;
 use serde_json::json;
@@ -10,33 +21,532 @@ use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub struct RunAsQuery {
+    /// Comma-separated sheet names to return from a workbook; all of them
+    /// when absent.
     pub sheets: Option<String>,
     pub file: String,
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddSheetForm {
+    pub file: String,
+    pub owner: Option<String>,
+    pub name: String,
+    /// An existing sheet to add; a new `<name>.msc` next to the workbook
+    /// when absent.
+    pub fname: Option<String>,
+    /// Zero-based; after the last sheet when absent.
+    pub position: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveSheetForm {
+    pub file: String,
+    pub owner: Option<String>,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameSheetForm {
+    pub file: String,
+    pub owner: Option<String>,
+    pub name: String,
+    pub new_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveSheetForm {
+    pub file: String,
+    pub owner: Option<String>,
+    pub name: String,
+    pub position: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SharedNameForm {
+    pub file: String,
+    pub owner: Option<String>,
+    pub name: String,
+    pub description: Option<String>,
+    /// An empty definition removes the name.
+    pub definition: String,
 }
 
+/// Loads a sheet, or every sheet of a workbook recalculated together.
 pub async fn run_app(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
     Query(query): Query<RunAsQuery>,
 ) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
-    let file_path = format!("home/{}", query.file);
+    let file_path = match VfsPath::home(&query.file) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let file = match state.db.get_file(owner_id, &file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let requested: Vec<String> = query
+        .sheets
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    // Sheets are SocialCalc saves; only workbook manifests are JSON
+    if !file.content.trim_start().starts_with('{') {
+        return Ok(Json(ApiResponse::success(json!({
+            "fname": query.file,
+            "sheetstr": file.content,
+            "sheets": requested
+        }))));
+    }
+    let workbook = match Workbook::parse(&file.content) {
+        Ok(workbook) => workbook,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    if let Some(missing) = requested.iter().find(|name| workbook.sheet(name).is_none()) {
+        return Ok(Json(ApiResponse::error(format!(
+            "No sheet named '{}'",
+            missing
+        ))));
+    }
+
+    // Every sheet is loaded, even if not requested, for references to it
+    let mut saves = match load_sheets(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &workbook,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Ok(saves) => saves,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+    let mut sheets: Vec<(&str, &mut Sheet)> = workbook
+        .sheets
+        .iter()
+        .zip(saves.iter_mut())
+        .map(|(entry, (_, _, save))| (entry.name.as_str(), &mut save.sheet))
+        .collect();
+    socialcalc::recalc_workbook(&mut sheets, &workbook.named_ranges());
+
+    let sheets: Vec<serde_json::Value> = workbook
+        .sheets
+        .iter()
+        .zip(&saves)
+        .enumerate()
+        .filter(|(index, _)| {
+            requested.is_empty()
+                || requested
+                    .iter()
+                    .any(|name| workbook.position(name) == Some(*index))
+        })
+        .map(|(_, (entry, (_, _, save)))| {
+            json!({
+                "name": entry.name,
+                "fname": entry.fname,
+                "sheetstr": save.serialize()
+            })
+        })
+        .collect();
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": query.file,
+        "workbook": workbook,
+        "sheets": sheets
+    }))))
+}
+
+/// Adds a sheet to a workbook, creating the workbook and the sheet file
+/// when they do not exist yet.
+pub async fn add_sheet(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<AddSheetForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let (file_path, owner_id, existing) =
+        match open_workbook(&state, user_id, form.owner.as_deref(), &form.file).await? {
+            Ok(opened) => opened,
+            Err(message) => return Ok(Json(ApiResponse::error(message))),
+        };
+    if existing.is_none() && owner_id != user_id {
+        return Ok(Json(ApiResponse::error("File not found".to_string())));
+    }
+    let mut workbook = existing.clone().unwrap_or_default();
 
-    match state.db.get_file(user_id, &file_path).await {
+    let sheet_path = match form.fname.as_deref().filter(|fname| !fname.is_empty()) {
+        Some(fname) => VfsPath::home(fname),
+        None => match file_path.fname().rsplit_once('/') {
+            Some((dir, _)) => VfsPath::home(&format!("{}/{}.msc", dir, form.name)),
+            None => VfsPath::home(&format!("{}.msc", form.name)),
+        },
+    };
+    let sheet_path = match sheet_path {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    if sheet_path == file_path {
+        return Ok(Json(ApiResponse::error(
+            "A workbook cannot contain itself".to_string(),
+        )));
+    }
+    if let Err(err) = workbook.add_sheet(&form.name, sheet_path.fname(), form.position) {
+        return Ok(Json(ApiResponse::error(err.to_string())));
+    }
+
+    match state.db.get_file(owner_id, &sheet_path).await {
         Ok(Some(file)) => {
-            let sheets = query
-                .sheets
-                .unwrap_or_default()
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .collect::<Vec<String>>();
-
-            Ok(Json(ApiResponse::success(json!({
-                "fname": query.file,
-                "sheetstr": file.content,
-                "sheets": sheets
-            }))))
+            if let Err(err) = SpreadsheetSave::parse(&file.content) {
+                return Ok(Json(ApiResponse::error(format!(
+                    "File is not a SocialCalc sheet: {}",
+                    err
+                ))));
+            }
+        }
+        Ok(None) if owner_id != user_id => {
+            return Ok(Json(ApiResponse::error("File not found".to_string())));
+        }
+        Ok(None) => {
+            let content = SpreadsheetSave::new(Sheet::default()).serialize();
+            if state
+                .db
+                .create_file(user_id, &sheet_path, &content)
+                .await
+                .is_err()
+            {
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+
+    // Added to the manifest as it is now, which may have changed since it
+    // was checked above
+    let workbook = if existing.is_some() {
+        match modify_workbook(&state, owner_id, &file_path, |workbook| {
+            workbook.add_sheet(&form.name, sheet_path.fname(), form.position)
+        })
+        .await?
+        {
+            Some(Ok(workbook)) => workbook,
+            Some(Err(message)) => return Ok(Json(ApiResponse::error(message))),
+            None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        }
+    } else {
+        state
+            .db
+            .create_file(owner_id, &file_path, &workbook.serialize())
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        workbook
+    };
+    Ok(Json(ApiResponse::success(json!({
+        "fname": file_path.fname(),
+        "workbook": workbook
+    }))))
+}
+
+/// Takes a sheet out of a workbook, keeping its file.
+pub async fn remove_sheet(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<RemoveSheetForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    edit_workbook(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &form.file,
+        |workbook| workbook.remove_sheet(&form.name).map(|_| ()),
+    )
+    .await
+}
+
+pub async fn move_sheet(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<MoveSheetForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    edit_workbook(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &form.file,
+        |workbook| workbook.move_sheet(&form.name, form.position),
+    )
+    .await
+}
+
+/// Adds, replaces or removes a named range shared by every sheet.
+pub async fn set_shared_name(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<SharedNameForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let definition = form.definition.trim();
+    if !definition.is_empty() {
+        let formula = definition.strip_prefix('=').unwrap_or(definition);
+        if let Err(reason) = socialcalc::check_workbook_formula(formula) {
+            return Ok(Json(ApiResponse::error(format!(
+                "Invalid definition: {}",
+                reason
+            ))));
         }
-        Ok(None) => Ok(Json(ApiResponse::error("File not found".to_string()))),
+    }
+    edit_workbook(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &form.file,
+        |workbook| {
+            workbook.set_name(
+                &form.name,
+                form.description.as_deref().unwrap_or(""),
+                definition,
+            )
+        },
+    )
+    .await
+}
+
+/// Renames a sheet, rewriting references to it in every sheet of the
+/// workbook and in its shared names. The manifest is renamed first and the
+/// sheets after it, one at a time; renaming again finishes a rename that
+/// failed partway.
+pub async fn rename_sheet(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<RenameSheetForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let (file_path, owner_id, workbook) =
+        match open_workbook(&state, user_id, form.owner.as_deref(), &form.file).await? {
+            Ok((path, owner_id, Some(workbook))) => (path, owner_id, workbook),
+            Ok(_) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+            Err(message) => return Ok(Json(ApiResponse::error(message))),
+        };
+
+    let mut renamed = workbook.clone();
+    let old_name = match rename_in_manifest(&mut renamed, &form.name, &form.new_name) {
+        Ok(old_name) => old_name,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    // Every sheet is loaded before anything is written, so a sheet that
+    // cannot be edited leaves the workbook as it was
+    let saves = match load_sheets(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &workbook,
+        SharePermission::Editor,
+    )
+    .await?
+    {
+        Ok(saves) => saves,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+
+    let written = match modify_workbook(&state, owner_id, &file_path, |workbook| {
+        rename_in_manifest(workbook, &form.name, &form.new_name).map(|_| ())
+    })
+    .await?
+    {
+        Some(Ok(written)) => written,
+        Some(Err(message)) => return Ok(Json(ApiResponse::error(message))),
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    // Each sheet is rewritten as it is now, through its room if it is open
+    let mut updated = Vec::new();
+    for (entry, (sheet_path, sheet_owner, _)) in renamed.sheets.iter().zip(&saves) {
+        let stored = modify_sheet(&state, *sheet_owner, user_id, sheet_path, |save| {
+            Ok(workbook::rename_in_sheet(
+                &mut save.sheet,
+                &old_name,
+                &form.new_name,
+            ))
+        })
+        .await?;
+        match stored {
+            Some(Ok(true)) => updated.push(entry.name.clone()),
+            Some(Err(message)) => {
+                return Ok(Json(ApiResponse::error(format!(
+                    "Sheet '{}': {}",
+                    entry.name, message
+                ))))
+            }
+            Some(Ok(false)) | None => {}
+        }
+    }
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": file_path.fname(),
+        "workbook": written,
+        "updated": updated
+    }))))
+}
+
+/// Renames a sheet in a manifest, returning the name references to it are
+/// rewritten from. A manifest the sheet has already been renamed in is left
+/// as it is, so that a rename can be tried again.
+fn rename_in_manifest(
+    workbook: &mut Workbook,
+    name: &str,
+    new_name: &str,
+) -> Result<String, WorkbookError> {
+    if workbook.position(name).is_none() && workbook.position(new_name).is_some() {
+        return Ok(name.to_string());
+    }
+    workbook.rename_sheet(name, new_name)
+}
+
+/// Resolves a workbook the caller may edit, with its manifest if the file
+/// exists.
+async fn open_workbook(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    file: &str,
+) -> Result<Result<(VfsPath, Uuid, Option<Workbook>), String>, StatusCode> {
+    let file_path = match VfsPath::home(file) {
+        Ok(path) => path,
+        Err(err) => return Ok(Err(err.to_string())),
+    };
+    let Some(owner_id) =
+        resolve_owner(state, user_id, owner, &file_path, SharePermission::Editor).await?
+    else {
+        return Ok(Err("File not found".to_string()));
+    };
+
+    match state.db.get_file(owner_id, &file_path).await {
+        Ok(Some(file)) => match Workbook::parse(&file.content) {
+            Ok(workbook) => Ok(Ok((file_path, owner_id, Some(workbook)))),
+            Err(err) => Ok(Err(err.to_string())),
+        },
+        Ok(None) => Ok(Ok((file_path, owner_id, None))),
         Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
+
+/// Applies a change that only touches the manifest of an existing workbook.
+async fn edit_workbook<E: std::fmt::Display>(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    file: &str,
+    edit: impl FnOnce(&mut Workbook) -> Result<(), E>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(file) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let Some(owner_id) =
+        resolve_owner(state, user_id, owner, &file_path, SharePermission::Editor).await?
+    else {
+        return Ok(Json(ApiResponse::error("File not found".to_string())));
+    };
+
+    match modify_workbook(state, owner_id, &file_path, edit).await? {
+        Some(Ok(workbook)) => Ok(Json(ApiResponse::success(json!({
+            "fname": file_path.fname(),
+            "workbook": workbook
+        })))),
+        Some(Err(message)) => Ok(Json(ApiResponse::error(message))),
+        None => Ok(Json(ApiResponse::error("File not found".to_string()))),
+    }
+}
+
+/// Rewrites a workbook's manifest with `edit`, keeping its row locked from
+/// reading to writing so that concurrent edits can't lose each other's
+/// changes. Returns the manifest as written, or `None` if there is no file.
+async fn modify_workbook<E: std::fmt::Display>(
+    state: &AppState,
+    owner_id: Uuid,
+    file_path: &VfsPath,
+    edit: impl FnOnce(&mut Workbook) -> Result<(), E>,
+) -> Result<Option<Result<Workbook, String>>, StatusCode> {
+    let mut written = None;
+    let stored = state
+        .db
+        .modify_file(owner_id, file_path, |content| {
+            let mut workbook = Workbook::parse(content).map_err(|err| err.to_string())?;
+            edit(&mut workbook).map_err(|err| err.to_string())?;
+            let content = workbook.serialize();
+            written = Some(workbook);
+            Ok(content)
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(stored.map(|stored| stored.map(|()| written.unwrap())))
+}
+
+/// Loads every sheet of a workbook, in order, with the path and owner of
+/// each.
+async fn load_sheets(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    workbook: &Workbook,
+    required: SharePermission,
+) -> Result<Result<Vec<(VfsPath, Uuid, SpreadsheetSave)>, String>, StatusCode> {
+    let mut saves = Vec::with_capacity(workbook.sheets.len());
+    for entry in &workbook.sheets {
+        let sheet_path = match VfsPath::home(&entry.fname) {
+            Ok(path) => path,
+            Err(err) => return Ok(Err(format!("Sheet '{}': {}", entry.name, err))),
+        };
+        match load_sheet(state, user_id, owner, &sheet_path, required).await? {
+            Ok((owner_id, save)) => saves.push((sheet_path, owner_id, save)),
+            Err(message) => return Ok(Err(format!("Sheet '{}': {}", entry.name, message))),
+        }
+    }
+    Ok(Ok(saves))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renaming_again_leaves_the_manifest_as_it_is() {
+        let mut workbook = Workbook::default();
+        workbook.add_sheet("Sales", "sales.msc", None).unwrap();
+        workbook.add_sheet("Costs", "costs.msc", None).unwrap();
+
+        assert_eq!(
+            rename_in_manifest(&mut workbook, "Sales", "Revenue").unwrap(),
+            "Sales"
+        );
+        let renamed = workbook.clone();
+        assert_eq!(
+            rename_in_manifest(&mut workbook, "Sales", "Revenue").unwrap(),
+            "Sales"
+        );
+        assert_eq!(workbook.serialize(), renamed.serialize());
+        assert_eq!(workbook.sheets[0].name, "Revenue");
+
+        assert!(rename_in_manifest(&mut workbook, "Missing", "Other").is_err());
+        assert!(rename_in_manifest(&mut workbook, "Revenue", "Costs").is_err());
+    }
+}
@@ -1,42 +1,115 @@
 use axum::{
-    extract::{Extension, State},
+    extract::{Extension, Query, State},
     http::StatusCode,
     response::Json,
     Form,
 };
+use chrono::{DateTime, Utc};
+use rand::Rng;
 use serde::Deserialize;
+use std::time::Duration;
 use uuid::Uuid;
 
 use crate::{
-    models::{ApiResponse, FileListEntry},
+    handlers::{
+        merge::{merge_changes, merge_into},
+        share::resolve_owner,
+    },
+    models::{
+        ApiResponse, FileCursor, FileListEntry, FileListOptions, FileListPage, FileSort,
+        SharePermission, SortOrder,
+    },
+    services::collab::RoomKey,
+    socialcalc::SpreadsheetSave,
+    vfs::VfsPath,
     AppState,
 };
 
+const MAX_PAGE_SIZE: i64 = 500;
+/// Times a save is made again after losing a race with another.
+const MAX_SAVE_ATTEMPTS: u64 = 10;
+
 #[derive(Debug, Deserialize)]
 pub struct SaveForm {
     pub fname: String,
     pub data: String,
+    pub owner: Option<String>,
+    /// The revision `data` was edited from. A save against an older one is
+    /// merged into the file as it is now.
+    pub revision: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListFilesQuery {
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+    pub modified_since: Option<DateTime<Utc>>,
+    pub pattern: Option<String>,
+    pub tag: Option<String>,
+    pub favorite: Option<bool>,
 }
 
 pub async fn list_files(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
-) -> Result<Json<ApiResponse<Vec<FileListEntry>>>, StatusCode> {
-    let files = match state.db.list_files(user_id, "home/").await {
-        Ok(files) => files,
+    Query(query): Query<ListFilesQuery>,
+) -> Result<Json<ApiResponse<FileListPage>>, StatusCode> {
+    let cursor = match query.cursor.as_deref() {
+        Some(cursor) => match FileCursor::decode(cursor) {
+            Some(cursor) if cursor.fits(query.sort, query.order) => Some(cursor),
+            Some(_) => {
+                return Ok(Json(ApiResponse::error(
+                    "Cursor is from a listing with another sort or order".to_string(),
+                )))
+            }
+            None => return Ok(Json(ApiResponse::error("Invalid cursor".to_string()))),
+        },
+        None => None,
+    };
+
+    let options = FileListOptions {
+        sort: query.sort,
+        order: query.order,
+        cursor,
+        limit: Some(query.limit.unwrap_or(100).clamp(1, MAX_PAGE_SIZE)),
+        modified_since: query.modified_since,
+        name_pattern: query.pattern.filter(|pattern| !pattern.is_empty()),
+        tag: query.tag.filter(|tag| !tag.is_empty()),
+        favorite: query.favorite,
+    };
+
+    let page = match state
+        .db
+        .list_file_meta(user_id, &VfsPath::root(), &options)
+        .await
+    {
+        Ok(page) => page,
         Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
     };
 
-    let entries: Vec<FileListEntry> = files
+    let files: Vec<FileListEntry> = page
+        .files
         .into_iter()
         .map(|file| FileListEntry {
-            fname: file.path.split('/').last().unwrap_or("").to_string(),
+            fname: file.path.rsplit('/').next().unwrap_or("").to_string(),
+            size: file.size,
+            revision: file.revision,
+            description: file.description,
+            tags: file.tags,
+            favorite: file.favorite,
             created_at: file.created_at,
             updated_at: file.updated_at,
         })
         .collect();
 
-    Ok(Json(ApiResponse::success(entries)))
+    Ok(Json(ApiResponse::success(FileListPage {
+        files,
+        next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+    })))
 }
 
 pub async fn save_file(
@@ -44,24 +117,243 @@ pub async fn save_file(
     Extension(user_id): Extension<Uuid>,
     Form(form): Form<SaveForm>,
 ) -> Result<Json<ApiResponse<String>>, StatusCode> {
-    let file_path = format!("home/{}", form.fname);
-
-    // Check if file exists
-    match state.db.get_file(user_id, &file_path).await {
-        Ok(Some(_)) => {
-            // Update existing file
-            if let Err(_) = state.db.update_file(user_id, &file_path, &form.data).await {
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+    };
+
+    let key = RoomKey {
+        owner_id,
+        path: file_path.clone(),
+    };
+
+    // A save that loses a race with another is merged again with that one,
+    // after a moment so that a burst of saves doesn't keep colliding
+    for attempt in 0..MAX_SAVE_ATTEMPTS {
+        if attempt > 0 {
+            let pause = rand::rng().random_range(0..10 * attempt);
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+        }
+        let room = state.collab.current(&key).await;
+        match state.db.get_file(owner_id, &file_path).await {
+            Ok(Some(mut file)) => {
+                let mut data = form.data.clone();
+                // An open room is ahead of the file by the edits it hasn't
+                // saved yet, so the save is merged with those too
+                let room_revision = room.map(|(save, room_revision)| {
+                    file.content = save.serialize();
+                    room_revision
+                });
+                if let Some(revision) = form.revision {
+                    let merged = if room_revision.is_some() {
+                        merge_changes(&state, &file, revision, &data).await?
+                    } else {
+                        merge_into(&state, &file, revision, &data).await?
+                    };
+                    match merged {
+                        Ok(merged) if merged.conflicts.is_empty() => data = merged.sheetstr,
+                        Ok(merged) => {
+                            return Ok(Json(ApiResponse::error(format!(
+                                "{} changes conflict with revision {}; resolve them with /merge",
+                                merged.conflicts.len(),
+                                merged.revision
+                            ))))
+                        }
+                        Err(message) => return Ok(Json(ApiResponse::error(message))),
+                    }
+                }
+
+                // Update existing file
+                let stored = store_file(
+                    &state,
+                    owner_id,
+                    user_id,
+                    &file_path,
+                    file.revision,
+                    room_revision,
+                    data,
+                )
+                .await?;
+                if stored {
+                    return Ok(Json(ApiResponse::success("Done".to_string())));
+                }
             }
+            Ok(None) if owner_id != user_id => {
+                return Ok(Json(ApiResponse::error("File not found".to_string())));
+            }
+            Ok(None) => {
+                // Create new file, or save over one made in the meantime
+                match state
+                    .db
+                    .create_file_if_absent(user_id, &file_path, &form.data)
+                    .await
+                {
+                    Ok(Some(_)) => return Ok(Json(ApiResponse::success("Done".to_string()))),
+                    Ok(None) => continue,
+                    Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+                }
+            }
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
         }
-        Ok(None) => {
-            // Create new file
-            if let Err(_) = state.db.create_file(user_id, &file_path, &form.data).await {
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Ok(Json(ApiResponse::error(
+        "File is being saved by someone else; try again".to_string(),
+    )))
+}
+
+/// Saves over an existing file, made from `revision` of it, or from
+/// `room_revision` of its room if it was open. Through the room, anyone
+/// editing it live carries on from the new version, which is saved behind
+/// the edits they've already made, if the room is still at `room_revision`.
+/// Otherwise it is saved only if the file is still at `revision`, closing
+/// any room opened on it meanwhile. False if the room or file has moved on
+/// and nothing was saved.
+pub async fn store_file(
+    state: &AppState,
+    owner_id: Uuid,
+    user_id: Uuid,
+    file_path: &VfsPath,
+    revision: i64,
+    room_revision: Option<u64>,
+    content: String,
+) -> Result<bool, StatusCode> {
+    let key = RoomKey {
+        owner_id,
+        path: file_path.clone(),
+    };
+    if let (Some(room_revision), Ok(save)) = (room_revision, SpreadsheetSave::parse(&content)) {
+        if let Some(saved) =
+            state
+                .collab
+                .replace_at(&key, user_id, save, content.clone(), room_revision)
+        {
+            return Ok(saved);
+        }
+    }
+    let stored = state
+        .db
+        .update_file_at(owner_id, file_path, &content, revision)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if stored {
+        state.collab.close(&key);
+    }
+    Ok(stored)
+}
+
+/// Saves over an existing file whatever revision it is at: through its room
+/// if it is open, so the save is ordered with the room's edits, or else
+/// straight to the file, closing any room opened on it meanwhile.
+pub async fn overwrite_file(
+    state: &AppState,
+    owner_id: Uuid,
+    user_id: Uuid,
+    file_path: &VfsPath,
+    content: &str,
+) -> Result<(), StatusCode> {
+    let key = RoomKey {
+        owner_id,
+        path: file_path.clone(),
+    };
+    if let Ok(save) = SpreadsheetSave::parse(content) {
+        if state
+            .collab
+            .replace(&key, user_id, save, content.to_string())
+        {
+            return Ok(());
+        }
+    }
+    state
+        .db
+        .update_file(owner_id, file_path, content)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.collab.close(&key);
+    Ok(())
+}
+
+/// Rewrites a sheet with `edit`, which returns whether it changed anything:
+/// in its room if it is open, so the rewrite is ordered with the room's
+/// edits, or else under the file's row lock. Returns whether the sheet was
+/// written: nothing is if `edit` changes nothing or fails. `None` if there
+/// is no such file.
+pub async fn modify_sheet(
+    state: &AppState,
+    owner_id: Uuid,
+    user_id: Uuid,
+    file_path: &VfsPath,
+    mut edit: impl FnMut(&mut SpreadsheetSave) -> Result<bool, String>,
+) -> Result<Option<Result<bool, String>>, StatusCode> {
+    let key = RoomKey {
+        owner_id,
+        path: file_path.clone(),
+    };
+
+    // The room's sheet may be edited again meanwhile, and then this edit is
+    // made again on the new one
+    for attempt in 1.. {
+        let Some((mut save, revision)) = state.collab.current(&key).await else {
+            break;
+        };
+        match edit(&mut save) {
+            Ok(true) => {}
+            Ok(false) => return Ok(Some(Ok(false))),
+            Err(message) => return Ok(Some(Err(message))),
+        }
+        let content = save.serialize();
+        match state
+            .collab
+            .replace_at(&key, user_id, save, content, revision)
+        {
+            Some(true) => return Ok(Some(Ok(true))),
+            Some(false) if attempt == MAX_SAVE_ATTEMPTS => {
+                return Ok(Some(Err(
+                    "File is being edited by someone else; try again".to_string()
+                )))
+            }
+            Some(false) => {
+                let pause = rand::rng().random_range(0..10 * attempt);
+                tokio::time::sleep(Duration::from_millis(pause)).await;
             }
+            None => break,
         }
-        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 
-    Ok(Json(ApiResponse::success("Done".to_string())))
+    // `Err(None)` leaves a file `edit` didn't change as it is
+    let stored = state
+        .db
+        .modify_file(owner_id, file_path, |content| {
+            let mut save = SpreadsheetSave::parse(content)
+                .map_err(|err| Some(format!("File is not a SocialCalc sheet: {}", err)))?;
+            match edit(&mut save) {
+                Ok(true) => Ok(save.serialize()),
+                Ok(false) => Err(None),
+                Err(message) => Err(Some(message)),
+            }
+        })
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    match stored {
+        Some(Ok(())) => {
+            state.collab.close(&key);
+            Ok(Some(Ok(true)))
+        }
+        Some(Err(None)) => Ok(Some(Ok(false))),
+        Some(Err(Some(message))) => Ok(Some(Err(message))),
+        None => Ok(None),
+    }
 }
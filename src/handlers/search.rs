@@ -0,0 +1,54 @@
+use crate::{models::ApiResponse, services::search, AppState};
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub fname: String,
+    pub snippet: String,
+    pub cells: Vec<String>,
+    pub rank: f32,
+}
+
+pub async fn search_files(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<ApiResponse<Vec<SearchResult>>>, StatusCode> {
+    if query.q.trim().is_empty() {
+        return Ok(Json(ApiResponse::error("Empty search query".to_string())));
+    }
+
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let hits = match state.db.search_files(user_id, &query.q, limit).await {
+        Ok(hits) => hits,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let results = hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            fname: hit
+                .path
+                .strip_prefix("home/")
+                .unwrap_or(&hit.path)
+                .to_string(),
+            snippet: search::highlight(&hit.snippet),
+            cells: hit.cells,
+            rank: hit.rank,
+        })
+        .collect();
+
+    Ok(Json(ApiResponse::success(results)))
+}
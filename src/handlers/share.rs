@@ -0,0 +1,228 @@
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+    Form,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    models::{ApiResponse, FileShare, SharePermission},
+    services::email::EmailService,
+    vfs::VfsPath,
+    AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ShareForm {
+    pub fname: String,
+    pub email: String,
+    pub permission: SharePermission,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnshareForm {
+    pub fname: String,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShareListQuery {
+    pub fname: String,
+}
+
+/// Resolves whose copy of `path` a request addresses. Without `owner` the
+/// caller's own file is used; otherwise the owner (by email) must have shared
+/// the file with the caller with at least `required` permission. Returns
+/// `None` when access is not granted.
+pub async fn resolve_owner(
+    state: &AppState,
+    user_id: Uuid,
+    owner: Option<&str>,
+    path: &VfsPath,
+    required: SharePermission,
+) -> Result<Option<Uuid>, StatusCode> {
+    let owner = match named_owner(owner) {
+        Some(owner) => owner,
+        None => return Ok(Some(user_id)),
+    };
+
+    let owner = match state.db.get_user_by_email(owner).await {
+        Ok(Some(owner)) => owner,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    if owner.id == user_id {
+        return Ok(Some(user_id));
+    }
+
+    let file_id = match state.db.get_file_id(owner.id, path).await {
+        Ok(Some(file_id)) => file_id,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match state.db.get_share_permission(file_id, user_id).await {
+        Ok(Some(permission)) if permission >= required => Ok(Some(owner.id)),
+        Ok(_) => Ok(None),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// The owner a request names, if any; an empty one names no one, so the
+/// caller's own file is meant.
+fn named_owner(owner: Option<&str>) -> Option<&str> {
+    owner.filter(|owner| !owner.is_empty())
+}
+
+pub async fn share_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<ShareForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let file_id = match state.db.get_file_id(user_id, &file_path).await {
+        Ok(Some(file_id)) => file_id,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let grantee = match state.db.get_user_by_email(&form.email).await {
+        Ok(Some(grantee)) => grantee,
+        Ok(None) => return Ok(Json(ApiResponse::error("User not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    if grantee.id == user_id {
+        return Ok(Json(ApiResponse::error(
+            "Cannot share a file with yourself".to_string(),
+        )));
+    }
+
+    if state
+        .db
+        .upsert_share(file_id, user_id, grantee.id, form.permission)
+        .await
+        .is_err()
+    {
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let owner_email = match state.db.get_user_by_id(user_id).await {
+        Ok(Some(owner)) => owner.email,
+        Ok(None) => String::new(),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    // The grant stands even if the invitation cannot be delivered
+    let message = format!(
+        "{} shared \"{}\" with you as {}.",
+        owner_email,
+        form.fname,
+        form.permission.as_str()
+    );
+    let email_service = EmailService::new(&state.config).await;
+    if let Err(err) = email_service
+        .send_email(&grantee.email, "A file was shared with you", &message)
+        .await
+    {
+        warn!(
+            "Failed to send share invitation to {}: {}",
+            grantee.email, err
+        );
+    }
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": form.fname,
+        "email": grantee.email,
+        "permission": form.permission
+    }))))
+}
+
+pub async fn unshare_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<UnshareForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let file_id = match state.db.get_file_id(user_id, &file_path).await {
+        Ok(Some(file_id)) => file_id,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let grantee = match state.db.get_user_by_email(&form.email).await {
+        Ok(Some(grantee)) => grantee,
+        Ok(None) => return Ok(Json(ApiResponse::error("User not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match state.db.delete_share(file_id, grantee.id).await {
+        Ok(true) => Ok(Json(ApiResponse::success(json!({"unshared": true})))),
+        Ok(false) => Ok(Json(ApiResponse::error("Share not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn list_file_shares(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<ShareListQuery>,
+) -> Result<Json<ApiResponse<Vec<FileShare>>>, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let file_id = match state.db.get_file_id(user_id, &file_path).await {
+        Ok(Some(file_id)) => file_id,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match state.db.list_shares_for_file(file_id).await {
+        Ok(shares) => Ok(Json(ApiResponse::success(shares))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn list_shared_with_me(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<ApiResponse<Vec<FileShare>>>, StatusCode> {
+    match state.db.list_shared_with(user_id).await {
+        Ok(shares) => Ok(Json(ApiResponse::success(shares))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editors_may_do_what_viewers_may() {
+        assert!(SharePermission::Editor >= SharePermission::Viewer);
+        assert!(SharePermission::Editor >= SharePermission::Editor);
+        assert!(SharePermission::Viewer < SharePermission::Editor);
+    }
+
+    #[test]
+    fn requests_without_an_owner_address_the_callers_own_file() {
+        assert_eq!(named_owner(None), None);
+        assert_eq!(named_owner(Some("")), None);
+        assert_eq!(named_owner(Some("a@example.com")), Some("a@example.com"));
+    }
+}
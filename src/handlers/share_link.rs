@@ -0,0 +1,313 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+    Form,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    auth::{generate_random_string, hash_password, verify_password},
+    models::{ApiResponse, ShareLink},
+    services::search,
+    socialcalc::{self, html, SpreadsheetSave},
+    utils::escape_html,
+    vfs::VfsPath,
+    AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareLinkForm {
+    pub fname: String,
+    pub password: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub expires_in_hours: Option<i64>,
+    pub max_views: Option<i32>,
+}
+
+/// Links expire at most this long after they are made.
+const MAX_EXPIRY_DAYS: i64 = 365;
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeShareLinkForm {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PublicLinkQuery {
+    pub format: Option<String>,
+}
+
+/// Passwords are only taken from a form body, never the URL, where they
+/// would end up in logs and browser history.
+#[derive(Debug, Deserialize)]
+pub struct PublicLinkForm {
+    pub format: Option<String>,
+    pub password: Option<String>,
+}
+
+pub async fn create_share_link(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<CreateShareLinkForm>,
+) -> Result<Json<ApiResponse<ShareLink>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let file = match state.db.get_file(user_id, &file_path).await {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    if form.max_views.is_some_and(|max_views| max_views < 1) {
+        return Ok(Json(ApiResponse::error(
+            "max_views must be at least 1".to_string(),
+        )));
+    }
+
+    let expires_at = match expiry(form.expires_at, form.expires_in_hours, Utc::now()) {
+        Ok(expires_at) => expires_at,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+
+    let password_hash = match form.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => match hash_password(password) {
+            Ok(hash) => Some(hash),
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+        None => None,
+    };
+
+    let token = generate_random_string(32);
+
+    match state
+        .db
+        .create_share_link(
+            file.id,
+            user_id,
+            &token,
+            password_hash.as_deref(),
+            expires_at,
+            form.max_views,
+        )
+        .await
+    {
+        Ok(link) => Ok(Json(ApiResponse::success(link))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// When a link made at `now` expires: at `expires_at`, or `expires_in_hours`
+/// from now, or never. Either must be in the future and within
+/// `MAX_EXPIRY_DAYS`.
+fn expiry(
+    expires_at: Option<DateTime<Utc>>,
+    expires_in_hours: Option<i64>,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let latest = now + Duration::days(MAX_EXPIRY_DAYS);
+    let too_late = || format!("Links can expire at most {} days ahead", MAX_EXPIRY_DAYS);
+
+    let expires_at = match (expires_at, expires_in_hours) {
+        (Some(expires_at), _) => expires_at,
+        (None, Some(hours)) if hours < 1 => {
+            return Err("expires_in_hours must be at least 1".to_string())
+        }
+        (None, Some(hours)) => Duration::try_hours(hours)
+            .and_then(|duration| now.checked_add_signed(duration))
+            .ok_or_else(too_late)?,
+        (None, None) => return Ok(None),
+    };
+    if expires_at <= now {
+        return Err("Expiry must be in the future".to_string());
+    }
+    if expires_at > latest {
+        return Err(too_late());
+    }
+    Ok(Some(expires_at))
+}
+
+pub async fn list_share_links(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<ApiResponse<Vec<serde_json::Value>>>, StatusCode> {
+    let links = match state.db.list_share_links(user_id).await {
+        Ok(links) => links,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let now = Utc::now();
+    let entries = links
+        .into_iter()
+        .filter_map(|link| {
+            let mut entry = serde_json::to_value(&link).ok()?;
+            entry["has_password"] = json!(link.has_password());
+            entry["active"] = json!(link.is_active(now));
+            Some(entry)
+        })
+        .collect();
+
+    Ok(Json(ApiResponse::success(entries)))
+}
+
+pub async fn revoke_share_link(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<RevokeShareLinkForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    match state.db.revoke_share_link(user_id, &form.token).await {
+        Ok(true) => Ok(Json(ApiResponse::success(json!({"revoked": true})))),
+        Ok(false) => Ok(Json(ApiResponse::error("Link not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn view_share_link(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Query(query): Query<PublicLinkQuery>,
+) -> Response {
+    render_share_link(&state, &token, query.format.as_deref(), None).await
+}
+
+pub async fn view_share_link_post(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Form(form): Form<PublicLinkForm>,
+) -> Response {
+    render_share_link(
+        &state,
+        &token,
+        form.format.as_deref(),
+        form.password.as_deref(),
+    )
+    .await
+}
+
+fn link_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ApiResponse::<()>::error(message.to_string()))).into_response()
+}
+
+async fn render_share_link(
+    state: &AppState,
+    token: &str,
+    format: Option<&str>,
+    password: Option<&str>,
+) -> Response {
+    let format = format.unwrap_or("html");
+    if !matches!(format, "raw" | "json" | "html") {
+        return link_error(StatusCode::BAD_REQUEST, "Unknown format");
+    }
+
+    let link = match state.db.get_share_link(token).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return link_error(StatusCode::NOT_FOUND, "Link not found"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    if !link.is_active(Utc::now()) {
+        return link_error(StatusCode::GONE, "Link is no longer available");
+    }
+
+    if let Some(password_hash) = &link.password_hash {
+        if !verify_password(password.unwrap_or_default(), password_hash) {
+            return link_error(StatusCode::UNAUTHORIZED, "Password required");
+        }
+    }
+
+    let file = match state
+        .db
+        .get_file(link.owner_id, &VfsPath::from_stored(link.file_path.clone()))
+        .await
+    {
+        Ok(Some(file)) => file,
+        Ok(None) => return link_error(StatusCode::NOT_FOUND, "Link not found"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    match state.db.record_share_link_view(link.id).await {
+        Ok(true) => {}
+        Ok(false) => return link_error(StatusCode::GONE, "Link is no longer available"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+
+    let fname = file
+        .path
+        .strip_prefix("home/")
+        .unwrap_or(&file.path)
+        .to_string();
+
+    match format {
+        "raw" => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            file.content,
+        )
+            .into_response(),
+        "json" => {
+            let document = search::build_document(&file.path, &file.content);
+            let cells: serde_json::Map<String, serde_json::Value> = document
+                .cells
+                .into_iter()
+                .map(|cell| (cell.coord, json!(cell.value)))
+                .collect();
+
+            Json(ApiResponse::success(json!({
+                "fname": fname,
+                "updated_at": file.updated_at,
+                "cells": cells,
+                "sheetstr": file.content
+            })))
+            .into_response()
+        }
+        _ => (
+            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+            render_html(&fname, &file.content),
+        )
+            .into_response(),
+    }
+}
+
+/// Sheets are rendered with their formatting; anything else is shown as
+/// plain text.
+fn render_html(title: &str, content: &str) -> String {
+    match SpreadsheetSave::parse(content) {
+        Ok(mut save) => {
+            socialcalc::recalc(&mut save.sheet);
+            html::to_html(&save.sheet, title)
+        }
+        Err(_) => format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body><pre>{}</pre></body></html>",
+            escape_html(title),
+            escape_html(content)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_is_in_the_future_and_within_reason() {
+        let now = Utc::now();
+        assert_eq!(expiry(None, None, now), Ok(None));
+        assert_eq!(
+            expiry(None, Some(2), now),
+            Ok(Some(now + Duration::hours(2)))
+        );
+        let at = now + Duration::days(30);
+        assert_eq!(expiry(Some(at), Some(2), now), Ok(Some(at)));
+
+        for hours in [0, -5, i64::MIN, MAX_EXPIRY_DAYS * 24 + 1, i64::MAX] {
+            assert!(expiry(None, Some(hours), now).is_err(), "{}", hours);
+        }
+        assert!(expiry(Some(now - Duration::hours(1)), None, now).is_err());
+        assert!(expiry(Some(now + Duration::days(MAX_EXPIRY_DAYS + 1)), None, now).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use crate::{models::ApiResponse, AppState};
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+pub async fn storage_stats(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let stats = match state.db.storage_stats(user_id).await {
+        Ok(stats) => stats,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let ratio = if stats.size > 0 {
+        stats.stored_size as f64 / stats.size as f64
+    } else {
+        1.0
+    };
+
+    Ok(Json(ApiResponse::success(json!({
+        "files": stats.files,
+        "size": stats.size,
+        "stored_size": stats.stored_size,
+        "compression_ratio": ratio,
+        "uncompressed_files": stats.uncompressed_files
+    }))))
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    response::Json,
+    Form,
+};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    db::Database,
+    handlers::recalc::load_sheet,
+    models::{ApiResponse, SharePermission, Template, TemplateMeta},
+    socialcalc::{self, html, template, CellCoord, CellRange, SpreadsheetSave},
+    vfs::VfsPath,
+    AppState,
+};
+
+/// Previews show at most this much of a template's top-left corner.
+const PREVIEW_ROWS: u32 = 12;
+const PREVIEW_COLS: u32 = 6;
+const MAX_TITLE_LENGTH: usize = 255;
+const MAX_CATEGORY_LENGTH: usize = 100;
+const MAX_VALUES: usize = 100;
+const MAX_VALUE_LENGTH: usize = 1000;
+
+/// Templates every user can start from, bundled with the server.
+struct SystemTemplate {
+    name: &'static str,
+    title: &'static str,
+    category: &'static str,
+    description: &'static str,
+    content: &'static str,
+}
+
+const SYSTEM_TEMPLATES: &[SystemTemplate] = &[
+    SystemTemplate {
+        name: "budget",
+        title: "Budget",
+        category: "Finance",
+        description: "Budgeted and actual spending by category for a fiscal year",
+        content: include_str!("../../assets/templates/budget.msc"),
+    },
+    SystemTemplate {
+        name: "invoice",
+        title: "Invoice",
+        category: "Business",
+        description: "An itemised invoice with subtotal, tax and total",
+        content: include_str!("../../assets/templates/invoice.msc"),
+    },
+];
+
+#[derive(Debug, Deserialize)]
+pub struct TemplatesQuery {
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveTemplateForm {
+    /// The sheet to save as a template.
+    pub fname: String,
+    pub owner: Option<String>,
+    /// Saving again under the same name replaces the template.
+    pub name: String,
+    pub title: Option<String>,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteTemplateForm {
+    pub id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateFromTemplate {
+    pub template: Uuid,
+    pub fname: String,
+    /// Values for the template's placeholders, such as `company`.
+    #[serde(default)]
+    pub values: HashMap<String, String>,
+}
+
+/// Installs the bundled templates, or updates them to this version.
+pub async fn install_system_templates(db: &Database) -> anyhow::Result<()> {
+    for system in SYSTEM_TEMPLATES {
+        let (content, preview, placeholders) = prepare(system.content)?;
+        let meta = TemplateMeta {
+            name: system.name.to_string(),
+            title: system.title.to_string(),
+            category: system.category.to_string(),
+            description: system.description.to_string(),
+        };
+        db.upsert_template(None, &meta, &preview, &placeholders, &content)
+            .await?;
+    }
+    Ok(())
+}
+
+/// The system-wide templates and the user's own.
+pub async fn list_templates(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<TemplatesQuery>,
+) -> Result<Json<ApiResponse<Vec<Template>>>, StatusCode> {
+    let category = query
+        .category
+        .as_deref()
+        .filter(|category| !category.is_empty());
+    match state.db.list_templates(user_id, category).await {
+        Ok(templates) => Ok(Json(ApiResponse::success(templates))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Saves a copy of a sheet as one of the user's templates.
+pub async fn save_template(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<SaveTemplateForm>,
+) -> Result<Json<ApiResponse<Template>>, StatusCode> {
+    let file_path = match VfsPath::home(&form.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    let name = form.name.trim();
+    let title = form
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(name);
+    let category = form.category.trim();
+    if name.is_empty() || name.len() > MAX_TITLE_LENGTH || title.len() > MAX_TITLE_LENGTH {
+        return Ok(Json(ApiResponse::error(format!(
+            "Names and titles must be 1 to {} characters",
+            MAX_TITLE_LENGTH
+        ))));
+    }
+    if category.len() > MAX_CATEGORY_LENGTH {
+        return Ok(Json(ApiResponse::error(format!(
+            "Categories are limited to {} characters",
+            MAX_CATEGORY_LENGTH
+        ))));
+    }
+
+    let (_, save) = match load_sheet(
+        &state,
+        user_id,
+        form.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Ok(loaded) => loaded,
+        Err(message) => return Ok(Json(ApiResponse::error(message))),
+    };
+    let (content, preview, placeholders) = match prepare(&save.serialize()) {
+        Ok(prepared) => prepared,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    let meta = TemplateMeta {
+        name: name.to_string(),
+        title: title.to_string(),
+        category: category.to_string(),
+        description: form.description.trim().to_string(),
+    };
+    match state
+        .db
+        .upsert_template(Some(user_id), &meta, &preview, &placeholders, &content)
+        .await
+    {
+        Ok(template) => Ok(Json(ApiResponse::success(template))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn delete_template(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Form(form): Form<DeleteTemplateForm>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    match state.db.delete_template(user_id, form.id).await {
+        Ok(true) => Ok(Json(ApiResponse::success(json!({"deleted": true})))),
+        Ok(false) => Ok(Json(ApiResponse::error("Template not found".to_string()))),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Makes a new file in `home/` from a template, filling in its
+/// placeholders. Placeholders without a value are left in the sheet.
+pub async fn create_from_template(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(request): Json<CreateFromTemplate>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    let file_path = match VfsPath::home(&request.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+    if request.values.len() > MAX_VALUES
+        || request
+            .values
+            .values()
+            .any(|value| value.len() > MAX_VALUE_LENGTH)
+    {
+        return Ok(Json(ApiResponse::error(format!(
+            "Up to {} values of up to {} characters",
+            MAX_VALUES, MAX_VALUE_LENGTH
+        ))));
+    }
+
+    let (_, content) = match state.db.get_template(user_id, request.template).await {
+        Ok(Some(template)) => template,
+        Ok(None) => return Ok(Json(ApiResponse::error("Template not found".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let mut save = match SpreadsheetSave::parse(&content) {
+        Ok(save) => save,
+        Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+    };
+
+    template::fill(&mut save.sheet, &request.values);
+    socialcalc::recalc(&mut save.sheet);
+    let unfilled = template::placeholders(&save.sheet);
+    match state
+        .db
+        .create_file_if_absent(user_id, &file_path, &save.serialize())
+        .await
+    {
+        Ok(Some(_)) => {}
+        Ok(None) => return Ok(Json(ApiResponse::error("File already exists".to_string()))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": file_path.fname(),
+        "unfilled": unfilled
+    }))))
+}
+
+/// A template's content recalculated, its preview and its placeholders.
+fn prepare(content: &str) -> Result<(String, String, Vec<String>), socialcalc::ParseError> {
+    let mut save = SpreadsheetSave::parse(content)?;
+    socialcalc::recalc(&mut save.sheet);
+
+    let preview = match save.sheet.used_range() {
+        Some(used) => {
+            let corner = CellCoord::new(
+                used.end.col.min(PREVIEW_COLS),
+                used.end.row.min(PREVIEW_ROWS),
+            );
+            html::table_html(&save.sheet, Some(CellRange::new(used.start, corner)))
+        }
+        None => String::new(),
+    };
+    let placeholders = template::placeholders(&save.sheet);
+    Ok((save.serialize(), preview, placeholders))
+}
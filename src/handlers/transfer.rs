@@ -0,0 +1,524 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, FromRequest, Multipart, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::{
+    handlers::{save::overwrite_file, share::resolve_owner},
+    models::{ApiResponse, SharePermission},
+    services::collab::RoomKey,
+    utils::format_file_path,
+    vfs::VfsPath,
+    AppState,
+};
+
+pub const MAX_UPLOAD_BYTES: usize = 512 * 1024 * 1024;
+/// Uploads up to this size are stored in the database like any other save.
+const INLINE_UPLOAD_BYTES: u64 = 1024 * 1024;
+/// Larger uploads are still indexed for search up to this size.
+const MAX_INDEXED_BYTES: u64 = 8 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct TransferQuery {
+    pub fname: String,
+    pub owner: Option<String>,
+}
+
+enum UploadError {
+    TooLarge,
+    NotText,
+    Body,
+    Io,
+}
+
+/// An upload written to a temporary file as it arrives.
+struct SpooledUpload {
+    file: tempfile::NamedTempFile,
+    size: u64,
+    /// The upload as text, kept while it is small enough to index.
+    text: Option<Vec<u8>>,
+}
+
+/// Checks UTF-8 across chunk boundaries without holding the whole body.
+#[derive(Default)]
+struct Utf8Check {
+    pending: Vec<u8>,
+}
+
+impl Utf8Check {
+    fn feed(&mut self, chunk: &[u8]) -> bool {
+        self.pending.extend_from_slice(chunk);
+        match std::str::from_utf8(&self.pending) {
+            Ok(_) => {
+                self.pending.clear();
+                true
+            }
+            // A character split across chunks completes with the next one
+            Err(err) if err.error_len().is_none() => {
+                self.pending.drain(..err.valid_up_to());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn finish(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+async fn spool<S, E>(stream: S) -> Result<SpooledUpload, UploadError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let named = tempfile::NamedTempFile::new().map_err(|_| UploadError::Io)?;
+    let mut file = tokio::fs::File::from_std(named.reopen().map_err(|_| UploadError::Io)?);
+    let mut stream = std::pin::pin!(stream);
+    let mut check = Utf8Check::default();
+    let mut text = Some(Vec::new());
+    let mut size = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|_| UploadError::Body)?;
+        size += chunk.len() as u64;
+        if size > MAX_UPLOAD_BYTES as u64 {
+            return Err(UploadError::TooLarge);
+        }
+        if !check.feed(&chunk) {
+            return Err(UploadError::NotText);
+        }
+
+        if size > MAX_INDEXED_BYTES {
+            text = None;
+        } else if let Some(text) = &mut text {
+            text.extend_from_slice(&chunk);
+        }
+
+        file.write_all(&chunk).await.map_err(|_| UploadError::Io)?;
+    }
+
+    if !check.finish() {
+        return Err(UploadError::NotText);
+    }
+    file.flush().await.map_err(|_| UploadError::Io)?;
+
+    Ok(SpooledUpload {
+        file: named,
+        size,
+        text,
+    })
+}
+
+fn transfer_error(status: StatusCode, message: &str) -> Response {
+    (status, Json(ApiResponse::<()>::error(message.to_string()))).into_response()
+}
+
+/// Streams a file upload to storage. The body is either the raw file content
+/// or `multipart/form-data` with the content in a `file` field.
+pub async fn upload_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<TransferQuery>,
+    request: Request,
+) -> Result<Response, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(transfer_error(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Editor,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(transfer_error(StatusCode::NOT_FOUND, "File not found")),
+    };
+
+    let exists = state
+        .db
+        .file_exists(owner_id, &file_path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !exists && owner_id != user_id {
+        return Ok(transfer_error(StatusCode::NOT_FOUND, "File not found"));
+    }
+
+    let is_multipart = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+
+    let spooled = if is_multipart {
+        let mut multipart = Multipart::from_request(request, &state)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let mut spooled = None;
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?
+        {
+            if field.name() == Some("file") {
+                spooled = Some(spool(field).await);
+                break;
+            }
+        }
+        match spooled {
+            Some(spooled) => spooled,
+            None => {
+                return Ok(transfer_error(
+                    StatusCode::BAD_REQUEST,
+                    "No file field uploaded",
+                ))
+            }
+        }
+    } else {
+        spool(request.into_body().into_data_stream()).await
+    };
+
+    let spooled = match spooled {
+        Ok(spooled) => spooled,
+        Err(UploadError::TooLarge) => {
+            return Ok(transfer_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "File is too large",
+            ))
+        }
+        Err(UploadError::NotText) => {
+            return Ok(transfer_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "File is not UTF-8 text",
+            ))
+        }
+        Err(UploadError::Body) => return Err(StatusCode::BAD_REQUEST),
+        Err(UploadError::Io) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let text = spooled
+        .text
+        .map(String::from_utf8)
+        .transpose()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match text {
+        Some(content) if spooled.size <= INLINE_UPLOAD_BYTES => {
+            // A file made since `exists` was read is replaced like any other
+            let created = !exists
+                && state
+                    .db
+                    .create_file_if_absent(owner_id, &file_path, &content)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                    .is_some();
+            if !created {
+                overwrite_file(&state, owner_id, user_id, &file_path, &content).await?;
+            }
+        }
+        text => {
+            // Each upload gets a fresh key so a concurrent reader of the
+            // previous version never sees a half-replaced object
+            let storage_key = format_file_path(
+                &owner_id.to_string(),
+                &format!("uploads/{}", Uuid::new_v4()),
+            );
+            if state
+                .db
+                .storage()
+                .put_object_from_path(&storage_key, spooled.file.path())
+                .await
+                .is_err()
+            {
+                return Err(StatusCode::BAD_GATEWAY);
+            }
+            state
+                .db
+                .store_external_file(
+                    owner_id,
+                    &file_path,
+                    &storage_key,
+                    spooled.size as i64,
+                    text.as_deref(),
+                )
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            // Too large for a room to take, so anyone editing the file live
+            // opens it again as uploaded
+            state.collab.close(&RoomKey {
+                owner_id,
+                path: file_path.clone(),
+            });
+        }
+    }
+
+    Ok(Json(ApiResponse::success(json!({
+        "fname": file_path.fname(),
+        "size": spooled.size
+    })))
+    .into_response())
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` header against a body of `total` bytes. Only single
+/// byte ranges are honoured; anything else is answered with the full body.
+fn parse_range(value: Option<&HeaderValue>, total: u64) -> ByteRange {
+    let Some(spec) = value
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(suffix) => (total.saturating_sub(suffix), total.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => (start, total.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(total.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if total == 0 || start >= total {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Streams a file with `Content-Length` and single `Range` support.
+pub async fn download_file(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(query): Query<TransferQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let file_path = match VfsPath::home(&query.fname) {
+        Ok(path) => path,
+        Err(err) => return Ok(transfer_error(StatusCode::BAD_REQUEST, &err.to_string())),
+    };
+
+    let owner_id = match resolve_owner(
+        &state,
+        user_id,
+        query.owner.as_deref(),
+        &file_path,
+        SharePermission::Viewer,
+    )
+    .await?
+    {
+        Some(owner_id) => owner_id,
+        None => return Ok(transfer_error(StatusCode::NOT_FOUND, "File not found")),
+    };
+
+    // Saving over a stored file deletes its old object, so a download that
+    // loses that race starts again from the file as it is now, once
+    for _ in 0..2 {
+        if let Some(response) = serve_file(&state, owner_id, &file_path, &headers).await? {
+            return Ok(response);
+        }
+    }
+    Ok(transfer_error(
+        StatusCode::CONFLICT,
+        "File changed while downloading; try again",
+    ))
+}
+
+/// The response for a download of `file_path`, or `None` if its stored
+/// object is gone.
+async fn serve_file(
+    state: &AppState,
+    owner_id: Uuid,
+    file_path: &VfsPath,
+    headers: &HeaderMap,
+) -> Result<Option<Response>, StatusCode> {
+    let info = match state.db.get_file_storage(owner_id, file_path).await {
+        Ok(Some(info)) => info,
+        Ok(None) => {
+            return Ok(Some(transfer_error(
+                StatusCode::NOT_FOUND,
+                "File not found",
+            )))
+        }
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    // Inline content is read up front so the range is checked against the
+    // bytes actually served
+    let inline = match info.storage_key {
+        Some(_) => None,
+        None => match state.db.get_file(owner_id, file_path).await {
+            Ok(Some(file)) => Some(file.content.into_bytes()),
+            Ok(None) => {
+                return Ok(Some(transfer_error(
+                    StatusCode::NOT_FOUND,
+                    "File not found",
+                )))
+            }
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        },
+    };
+
+    let total = match &inline {
+        Some(bytes) => bytes.len() as u64,
+        None => info.size as u64,
+    };
+    let range = parse_range(headers.get(header::RANGE), total);
+    let (status, start, end) = match range {
+        ByteRange::Full => (StatusCode::OK, 0, total.saturating_sub(1)),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        ByteRange::Unsatisfiable => {
+            return Ok(Some(
+                (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", total))],
+                )
+                    .into_response(),
+            ))
+        }
+    };
+    let length = if total == 0 { 0 } else { end - start + 1 };
+
+    let body = match (inline, &info.storage_key) {
+        (Some(bytes), _) => {
+            if length == 0 {
+                Body::empty()
+            } else {
+                Body::from(bytes[start as usize..=end as usize].to_vec())
+            }
+        }
+        (None, Some(key)) => {
+            let object_range = match range {
+                ByteRange::Partial(start, end) => Some((start, end)),
+                _ => None,
+            };
+            let stream = match state
+                .db
+                .storage()
+                .get_object_stream(key, object_range)
+                .await
+            {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return Ok(None),
+                Err(_) => return Err(StatusCode::BAD_GATEWAY),
+            };
+            Body::from_stream(ReaderStream::new(stream.into_async_read()))
+        }
+        (None, None) => Body::empty(),
+    };
+
+    let fname = file_path.file_name();
+    let mut response = (
+        status,
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (header::CONTENT_LENGTH, length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::ETAG, format!("\"{}\"", info.revision)),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", fname.replace('"', "")),
+            ),
+        ],
+        body,
+    )
+        .into_response();
+
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, total)) {
+            response.headers_mut().insert(header::CONTENT_RANGE, value);
+        }
+    }
+
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str, total: u64) -> ByteRange {
+        parse_range(Some(&HeaderValue::from_str(value).unwrap()), total)
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(range("bytes=0-9", 100), ByteRange::Partial(0, 9));
+        assert_eq!(range(" bytes=10-10 ", 100), ByteRange::Partial(10, 10));
+        // Ends past the body are cut short
+        assert_eq!(range("bytes=90-500", 100), ByteRange::Partial(90, 99));
+
+        // Open-ended, and the last N bytes
+        assert_eq!(range("bytes=95-", 100), ByteRange::Partial(95, 99));
+        assert_eq!(range("bytes=-10", 100), ByteRange::Partial(90, 99));
+        assert_eq!(range("bytes=-500", 100), ByteRange::Partial(0, 99));
+
+        // Out of range
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=100-200", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-5", 0), ByteRange::Unsatisfiable);
+
+        // Multiple ranges and anything malformed get the whole body
+        assert_eq!(range("bytes=0-1,5-6", 100), ByteRange::Full);
+        assert_eq!(range("bytes=5-1", 100), ByteRange::Full);
+        assert_eq!(range("bytes=a-b", 100), ByteRange::Full);
+        assert_eq!(range("bytes=5", 100), ByteRange::Full);
+        assert_eq!(range("items=0-1", 100), ByteRange::Full);
+        assert_eq!(range("bytes=-18446744073709551616", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn utf8_is_checked_across_chunks() {
+        let text = "Grüße €".as_bytes();
+        for split in 0..=text.len() {
+            let mut check = Utf8Check::default();
+            assert!(check.feed(&text[..split]), "{}", split);
+            assert!(check.feed(&text[split..]), "{}", split);
+            assert!(check.finish(), "{}", split);
+        }
+
+        let mut check = Utf8Check::default();
+        assert!(check.feed(&text[..3]));
+        assert!(!check.finish(), "ends inside a character");
+
+        let mut check = Utf8Check::default();
+        assert!(!check.feed(&[b'a', 0xff, b'b']));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{models::ApiResponse, AppState};
+use crate::{models::ApiResponse, vfs::VfsPath, AppState};
 use axum::{
     extract::{Extension, State},
     http::StatusCode,
@@ -20,7 +20,10 @@ pub async fn handle_user_sheet(
     Form(form): Form<UserSheetForm>,
 ) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
     if form.delete == Some("yes".to_string()) {
-        let file_path = format!("home/{}", form.pagename);
+        let file_path = match VfsPath::home(&form.pagename) {
+            Ok(path) => path,
+            Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+        };
         if let Err(_) = state.db.delete_file(user_id, &file_path).await {
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
@@ -8,7 +8,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
 
-use crate::{models::ApiResponse, AppState};
+use crate::{
+    models::{ApiResponse, FileListOptions},
+    services::secure_store::{self, SecureStoreError},
+    vfs::VfsPath,
+    AppState,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct WebAppQuery {
@@ -24,6 +29,8 @@ pub struct WebAppForm {
     pub data: Option<String>,
     pub uuid: Option<String>,
     pub password: Option<String>,
+    #[serde(rename = "newPassword")]
+    pub new_password: Option<String>,
     #[serde(rename = "deviceId")]
     pub device_id: Option<String>,
 }
@@ -34,6 +41,13 @@ pub struct InAppStatus {
     pub consumed: i32,
 }
 
+fn store_error(err: SecureStoreError) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    match err {
+        SecureStoreError::Internal(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        err => Ok(Json(ApiResponse::error(err.to_string()))),
+    }
+}
+
 pub async fn handle_webapp(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
@@ -80,7 +94,20 @@ pub async fn handle_webapp_post(
                 return Ok(Json(ApiResponse::success(json!({"result": "buy"}))));
             }
 
-            let file_path = format!("home/securestore/{}/{}", app_name, fname);
+            let file_path = match VfsPath::secure_store(&app_name, &fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
+
+            let key = match secure_store::unlock(&state.db, user_id, form.password.as_deref()).await
+            {
+                Ok(key) => key,
+                Err(err) => return store_error(err),
+            };
+            let data = match key.encrypt(file_path.as_str(), &data) {
+                Ok(data) => data,
+                Err(err) => return store_error(err),
+            };
 
             // Save file
             match state.db.get_file(user_id, &file_path).await {
@@ -103,7 +130,20 @@ pub async fn handle_webapp_post(
             let app_name = form.appname.unwrap_or_default();
             let fname = form.fname.unwrap_or_default();
             let data = form.data.unwrap_or_default();
-            let file_path = format!("home/securestore/{}/{}", app_name, fname);
+            let file_path = match VfsPath::secure_store(&app_name, &fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
+
+            let key = match secure_store::unlock(&state.db, user_id, form.password.as_deref()).await
+            {
+                Ok(key) => key,
+                Err(err) => return store_error(err),
+            };
+            let data = match key.encrypt(file_path.as_str(), &data) {
+                Ok(data) => data,
+                Err(err) => return store_error(err),
+            };
 
             // Check if file exists before updating
             match state.db.get_file(user_id, &file_path).await {
@@ -120,21 +160,70 @@ pub async fn handle_webapp_post(
         "getfile" => {
             let app_name = form.appname.unwrap_or_default();
             let fname = form.fname.unwrap_or_default();
-            let file_path = format!("home/securestore/{}/{}", app_name, fname);
+            let file_path = match VfsPath::secure_store(&app_name, &fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
 
-            match state.db.get_file(user_id, &file_path).await {
-                Ok(Some(file)) => Ok(Json(ApiResponse::success(json!({
-                    "data": file.content,
-                    "result": "ok"
-                })))),
-                Ok(None) => Ok(Json(ApiResponse::error("File not found".to_string()))),
-                Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+            let key = match secure_store::unlock(&state.db, user_id, form.password.as_deref()).await
+            {
+                Ok(key) => key,
+                Err(err) => return store_error(err),
+            };
+
+            let file = match state.db.get_file(user_id, &file_path).await {
+                Ok(Some(file)) => file,
+                Ok(None) => return Ok(Json(ApiResponse::error("File not found".to_string()))),
+                Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+            };
+
+            let data = if secure_store::is_encrypted(&file.content) {
+                match key.decrypt(file_path.as_str(), &file.content) {
+                    Ok(data) => data,
+                    Err(err) => return store_error(err),
+                }
+            } else {
+                // Saved before encryption was enabled; encrypt it now
+                let encrypted = match key.encrypt(file_path.as_str(), &file.content) {
+                    Ok(encrypted) => encrypted,
+                    Err(err) => return store_error(err),
+                };
+                if state
+                    .db
+                    .update_file(user_id, &file_path, &encrypted)
+                    .await
+                    .is_err()
+                {
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+                file.content
+            };
+
+            Ok(Json(ApiResponse::success(json!({
+                "data": data,
+                "result": "ok"
+            }))))
+        }
+        "changepassword" => {
+            match secure_store::change_password(
+                &state.db,
+                user_id,
+                form.password.as_deref(),
+                form.new_password.as_deref(),
+            )
+            .await
+            {
+                Ok(()) => Ok(Json(ApiResponse::success(json!({"result": "ok"})))),
+                Err(err) => store_error(err),
             }
         }
         "deletefile" => {
             let app_name = form.appname.unwrap_or_default();
             let fname = form.fname.unwrap_or_default();
-            let file_path = format!("home/securestore/{}/{}", app_name, fname);
+            let file_path = match VfsPath::secure_store(&app_name, &fname) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
 
             if let Err(_) = state.db.delete_file(user_id, &file_path).await {
                 return Err(StatusCode::INTERNAL_SERVER_ERROR);
@@ -144,16 +233,24 @@ pub async fn handle_webapp_post(
         }
         "listdir" => {
             let app_name = form.appname.unwrap_or_default();
-            let dir_path = format!("home/securestore/{}/", app_name);
+            let dir_path = match VfsPath::secure_store_dir(&app_name) {
+                Ok(path) => path,
+                Err(err) => return Ok(Json(ApiResponse::error(err.to_string()))),
+            };
 
-            let files = match state.db.list_files(user_id, &dir_path).await {
-                Ok(files) => files,
+            let page = match state
+                .db
+                .list_file_meta(user_id, &dir_path, &FileListOptions::default())
+                .await
+            {
+                Ok(page) => page,
                 Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
             };
 
-            let entries: Vec<String> = files
+            let entries: Vec<String> = page
+                .files
                 .into_iter()
-                .map(|file| file.path.split('/').last().unwrap_or("").to_string())
+                .map(|file| file.path.rsplit('/').next().unwrap_or("").to_string())
                 .collect();
 
             Ok(Json(ApiResponse::success(json!({
@@ -1,4 +1,5 @@
 use axum::{
+    extract::DefaultBodyLimit,
     //extract::{Path, Query, State},
     //http::StatusCode,
     //response::Json,
@@ -7,8 +8,9 @@ use axum::{
 };
 // use serde::{Deserialize, Serialize};
 // use std::collections::HashMap;
+use std::net::SocketAddr;
 use tower_http::cors::CorsLayer;
-use tracing::{info /*warn*/};
+use tracing::{info, warn};
 use tracing_subscriber::fmt::init;
 
 mod auth;
@@ -17,15 +19,22 @@ mod db;
 mod handlers;
 mod models;
 mod services;
+mod socialcalc;
 mod utils;
+mod vfs;
 
 use config::AppConfig;
 use db::Database;
+use services::collab::Hub;
+use services::rate_limit::RateLimiter;
+use services::storage::StorageService;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Database,
     pub config: AppConfig,
+    pub collab: Hub,
+    pub form_limiter: RateLimiter,
 }
 
 #[tokio::main]
@@ -33,9 +42,26 @@ async fn main() -> anyhow::Result<()> {
     init();
 
     let config = AppConfig::from_env()?;
-    let db = Database::new(&config.database_url).await?;
+    let storage = StorageService::new(&config).await;
+    let db = Database::new(&config.database_url, storage).await?;
+    handlers::templates::install_system_templates(&db).await?;
+    let indexer = db.clone();
+    tokio::spawn(async move {
+        match indexer.index_unindexed_files().await {
+            Ok(0) => {}
+            Ok(count) => info!("Indexed {} files for search", count),
+            Err(err) => warn!("Failed to index files for search: {}", err),
+        }
+    });
 
-    let state = AppState { db, config };
+    let collab = handlers::collab::hub(db.clone());
+    let form_limiter = RateLimiter::new(handlers::forms::SUBMISSION_WINDOW);
+    let state = AppState {
+        db,
+        config,
+        collab,
+        form_limiter,
+    };
 
     let app = Router::new()
         .route("/", get(handlers::home))
@@ -44,14 +70,92 @@ async fn main() -> anyhow::Result<()> {
             "/save",
             get(handlers::save::list_files).post(handlers::save::save_file),
         )
+        .route(
+            "/fileattrs",
+            get(handlers::attributes::get_attributes).post(handlers::attributes::update_attributes),
+        )
+        .route("/search", get(handlers::search::search_files))
+        .route("/recalc", post(handlers::recalc::recalc_file))
+        .route("/collab", get(handlers::collab::collab_socket))
+        .route("/edits", get(handlers::history::list_edits))
+        .route("/replay", get(handlers::history::replay))
+        .route("/diff", get(handlers::merge::diff_revisions))
+        .route("/merge", post(handlers::merge::merge_file))
+        .route("/values", get(handlers::recalc::get_values))
+        .route(
+            "/cells",
+            get(handlers::cells::get_cells).post(handlers::cells::update_cells),
+        )
+        .route("/storagestats", get(handlers::stats::storage_stats))
+        .route(
+            "/share",
+            get(handlers::share::list_file_shares).post(handlers::share::share_file),
+        )
+        .route("/unshare", post(handlers::share::unshare_file))
+        .route("/shared", get(handlers::share::list_shared_with_me))
+        .route(
+            "/sharelink",
+            get(handlers::share_link::list_share_links)
+                .post(handlers::share_link::create_share_link),
+        )
+        .route(
+            "/sharelink/revoke",
+            post(handlers::share_link::revoke_share_link),
+        )
+        .route(
+            "/s/:token",
+            get(handlers::share_link::view_share_link)
+                .post(handlers::share_link::view_share_link_post),
+        )
+        .route(
+            "/publish",
+            get(handlers::publish::list_published).post(handlers::publish::publish_range),
+        )
+        .route("/publish/revoke", post(handlers::publish::unpublish_range))
+        .route("/data/:token", get(handlers::publish::published_data))
+        .route(
+            "/form",
+            get(handlers::forms::list_forms).post(handlers::forms::save_form),
+        )
+        .route("/form/delete", post(handlers::forms::delete_form))
+        .route(
+            "/f/:token",
+            get(handlers::forms::form_definition).post(handlers::forms::submit_form),
+        )
+        .route(
+            "/templates",
+            get(handlers::templates::list_templates).post(handlers::templates::save_template),
+        )
+        .route(
+            "/templates/delete",
+            post(handlers::templates::delete_template),
+        )
+        .route(
+            "/templates/create",
+            post(handlers::templates::create_from_template),
+        )
         .route("/runas", get(handlers::run_as::run_app))
+        .route("/workbook/add", post(handlers::run_as::add_sheet))
+        .route("/workbook/remove", post(handlers::run_as::remove_sheet))
+        .route("/workbook/rename", post(handlers::run_as::rename_sheet))
+        .route("/workbook/move", post(handlers::run_as::move_sheet))
+        .route("/workbook/name", post(handlers::run_as::set_shared_name))
         .route("/runasemailer", post(handlers::email::send_email))
         .route("/usersheet", post(handlers::user_sheet::handle_user_sheet))
         .route("/insert", post(handlers::insert::get_file))
         .route(
             "/import",
-            get(handlers::import::import_page).post(handlers::import::handle_import),
+            get(handlers::import::import_page)
+                .post(handlers::import::handle_import)
+                .layer(DefaultBodyLimit::max(handlers::import::MAX_ARCHIVE_BYTES)),
+        )
+        .route("/export", get(handlers::import::export_folder))
+        .route(
+            "/upload",
+            post(handlers::transfer::upload_file)
+                .layer(DefaultBodyLimit::max(handlers::transfer::MAX_UPLOAD_BYTES)),
         )
+        .route("/download", get(handlers::transfer::download_file))
         .route("/downloadfile", post(handlers::download::download_file))
         .route(
             "/htmltopdf",
@@ -113,6 +217,10 @@ async fn main() -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
     info!("Server starting on http://0.0.0.0:8080");
 
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
@@ -18,6 +18,20 @@ pub struct FileData {
     pub user_id: Uuid,
     pub path: String,
     pub content: String,
+    pub size: i64,
+    pub revision: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct FileMeta {
+    pub path: String,
+    pub size: i64,
+    pub revision: i64,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub favorite: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -33,6 +47,201 @@ pub struct InAppPurchase {
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SharePermission {
+    Viewer,
+    Editor,
+}
+
+impl SharePermission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SharePermission::Viewer => "viewer",
+            SharePermission::Editor => "editor",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "viewer" => Some(SharePermission::Viewer),
+            "editor" => Some(SharePermission::Editor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileShare {
+    pub file_path: String,
+    pub owner_email: String,
+    pub grantee_email: String,
+    pub permission: SharePermission,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A range of a sheet published as read-only JSON records.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublishedRange {
+    #[serde(skip)]
+    pub id: Uuid,
+    pub token: String,
+    #[serde(skip)]
+    pub owner_id: Uuid,
+    pub file_path: String,
+    /// A named range, an A1 range, or empty for the sheet's used area.
+    pub range_name: String,
+    #[serde(skip)]
+    pub api_key_hash: String,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A public form that appends a row to a sheet for each submission.
+#[derive(Debug, Clone, Serialize)]
+pub struct SheetForm {
+    #[serde(skip)]
+    pub id: Uuid,
+    pub token: String,
+    #[serde(skip)]
+    pub owner_id: Uuid,
+    pub file_path: String,
+    pub definition: sqlx::types::Json<FormDefinition>,
+    pub submissions: i64,
+    pub last_submitted_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FormDefinition {
+    #[serde(default)]
+    pub title: String,
+    pub fields: Vec<FormField>,
+    /// The column stamped with the time of each submission, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_column: Option<String>,
+    /// Email the owner about each submission.
+    #[serde(default)]
+    pub notify: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FormField {
+    /// The name the field is submitted as.
+    pub name: String,
+    #[serde(default)]
+    pub label: String,
+    /// The column its value is written to, such as `B`.
+    pub column: String,
+    #[serde(rename = "type", default)]
+    pub kind: FormFieldKind,
+    #[serde(default)]
+    pub required: bool,
+    /// Length limits for text, in characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    /// Limits for numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    /// The values a `choice` field accepts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormFieldKind {
+    #[default]
+    Text,
+    Number,
+    Email,
+    /// `YYYY-MM-DD`, as a date input sends it.
+    Date,
+    Choice,
+    /// Checked when submitted as `on`, `true`, `yes` or `1`.
+    Checkbox,
+}
+
+/// A sheet to start new files from. System-wide templates have no owner.
+#[derive(Debug, Clone, Serialize)]
+pub struct Template {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub owner_id: Option<Uuid>,
+    pub system: bool,
+    pub name: String,
+    pub title: String,
+    pub category: String,
+    pub description: String,
+    /// An HTML table of the template's top-left corner.
+    pub preview: String,
+    /// The `{{name}}` placeholders filled in when a file is made from it.
+    pub placeholders: Vec<String>,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// What describes a template, as saved.
+#[derive(Debug, Default)]
+pub struct TemplateMeta {
+    pub name: String,
+    pub title: String,
+    pub category: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareLink {
+    #[serde(skip)]
+    pub id: Uuid,
+    pub token: String,
+    #[serde(skip)]
+    pub file_id: Uuid,
+    #[serde(skip)]
+    pub owner_id: Uuid,
+    pub file_path: String,
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_views: Option<i32>,
+    pub view_count: i32,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ShareLink {
+    pub fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none()
+            && self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && self
+                .max_views
+                .is_none_or(|max_views| self.view_count < max_views)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SecureStoreKey {
+    pub user_id: Uuid,
+    pub kdf_salt: Vec<u8>,
+    pub wrap_nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
     pub email: String,
@@ -107,10 +316,150 @@ impl<T> ApiResponse<T> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileListEntry {
     pub fname: String,
+    pub size: i64,
+    pub revision: i64,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub favorite: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileListPage {
+    pub files: Vec<FileListEntry>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    #[default]
+    Name,
+    Created,
+    Updated,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Position after the last entry of a listing page, in the sort and order
+/// of the listing it came from. The sort key is only set for date sorts;
+/// name sorts resume from the path alone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileCursor {
+    pub sort: FileSort,
+    pub order: SortOrder,
+    pub path: String,
+    pub at: Option<DateTime<Utc>>,
+}
+
+impl FileCursor {
+    /// Whether the cursor can resume a listing in `sort` and `order`.
+    pub fn fits(&self, sort: FileSort, order: SortOrder) -> bool {
+        self.sort == sort && self.order == order && (sort == FileSort::Name) == self.at.is_none()
+    }
+
+    pub fn encode(&self) -> String {
+        use base64::Engine;
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        use base64::Engine;
+        let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileListOptions {
+    pub sort: FileSort,
+    pub order: SortOrder,
+    pub cursor: Option<FileCursor>,
+    pub limit: Option<i64>,
+    pub modified_since: Option<DateTime<Utc>>,
+    pub name_pattern: Option<String>,
+    pub tag: Option<String>,
+    pub favorite: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAttributes {
+    pub description: String,
+    pub tags: Vec<String>,
+    pub favorite: bool,
+    pub metadata: serde_json::Value,
+}
+
+/// Partial update of [`FileAttributes`]; `None` leaves a field unchanged.
+#[derive(Debug, Default)]
+pub struct FileAttributesUpdate {
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub favorite: Option<bool>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug)]
+pub struct FileMetaPage {
+    pub files: Vec<FileMeta>,
+    pub next_cursor: Option<FileCursor>,
+}
+
+/// Where a file's content lives; `storage_key` is set for uploads kept in
+/// object storage.
+#[derive(Debug)]
+pub struct FileStorageInfo {
+    pub size: i64,
+    pub revision: i64,
+    pub updated_at: DateTime<Utc>,
+    pub storage_key: Option<String>,
+}
+
+/// A PDF generated from a sheet, kept in object storage apart from files.
+/// `source_path` is the sheet printed, if it was a stored one.
+#[derive(Debug)]
+pub struct PdfDocument {
+    pub source_path: Option<String>,
+    pub storage_key: String,
+    pub size: i64,
+    pub revision: i64,
+}
+
+/// An entry in a file's edit log; `commands` is `None` for a whole-file save.
+#[derive(Debug, Serialize)]
+pub struct FileEdit {
+    pub seq: i64,
+    pub user_id: Option<Uuid>,
+    pub commands: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub files: i64,
+    pub size: i64,
+    pub stored_size: i64,
+    pub uncompressed_files: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub path: String,
+    pub rank: f32,
+    pub snippet: String,
+    pub cells: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // User ID
@@ -122,3 +471,39 @@ pub struct SessionData {
     pub user_id: Uuid,
     pub email: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    #[test]
+    fn file_cursors_round_trip_and_fit_only_their_listing() {
+        let cursor = FileCursor {
+            sort: FileSort::Updated,
+            order: SortOrder::Desc,
+            path: "home/a b.msc".to_string(),
+            at: Some(Utc::now()),
+        };
+        let decoded = FileCursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded, cursor);
+        assert!(decoded.fits(FileSort::Updated, SortOrder::Desc));
+        assert!(!decoded.fits(FileSort::Updated, SortOrder::Asc));
+        assert!(!decoded.fits(FileSort::Name, SortOrder::Desc));
+
+        let by_name = FileCursor {
+            sort: FileSort::Name,
+            order: SortOrder::Asc,
+            path: "home/a b.msc".to_string(),
+            at: None,
+        };
+        assert!(by_name.fits(FileSort::Name, SortOrder::Asc));
+        assert!(!by_name.fits(FileSort::Created, SortOrder::Asc));
+
+        // Garbage, and cursors that don't say which listing they're from
+        assert!(FileCursor::decode("not a cursor!").is_none());
+        let unsorted = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(r#"{"path":"home/a.msc","at":null}"#);
+        assert!(FileCursor::decode(&unsorted).is_none());
+    }
+}
@@ -0,0 +1,1209 @@
+//! Live editing sessions. Everyone with a file open shares one copy of the
+//! sheet: edit commands are applied in the order they arrive, broadcast to
+//! the other participants and saved behind them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::socialcalc::commands::{self, Command};
+use crate::socialcalc::{self, CellCoord, Sheet, SpreadsheetSave};
+use crate::vfs::VfsPath;
+
+/// Command batches longer than this are rejected.
+pub const MAX_COMMAND_BYTES: usize = 256 * 1024;
+/// How long a room's saver waits for more edits before saving.
+const SAVE_DELAY: Duration = Duration::from_millis(500);
+/// Times in a row a room's saver tries again after failing to save, each
+/// time waiting twice as long, before it closes the room.
+const MAX_SAVE_RETRIES: u32 = 5;
+/// Messages a participant may fall behind by before it is disconnected.
+const OUTBOX_CAPACITY: usize = 64;
+
+/// Saves a room's edits and the sheet after them, one batch at a time and
+/// in order. Edits made while a batch waits or saves join the next one.
+/// A batch is saved only if the file is still at the revision given, the
+/// one the last batch left it at. Returns the revision after saving, or
+/// `None`, saving nothing, if the file was written around the room. An
+/// error saves nothing either, and the batch is tried again.
+pub type Persist = Arc<
+    dyn Fn(RoomKey, Vec<Edit>, String, i64) -> BoxFuture<'static, anyhow::Result<Option<i64>>>
+        + Send
+        + Sync,
+>;
+
+/// Sent to everyone in a room closed because its file was written around it.
+const CLOSED_MESSAGE: &str = "The file was changed elsewhere; open it again";
+/// Sent to everyone in a room closed because its sheet failed to recalculate.
+const RECALC_FAILED_MESSAGE: &str = "The sheet could not be recalculated; open it again";
+/// Sent to everyone in a room closed because its edits could not be saved.
+const SAVE_FAILED_MESSAGE: &str = "Your latest edits could not be saved; open the file again";
+
+/// A command batch as applied, for the file's edit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub user_id: Uuid,
+    /// `None` for a whole-file save made while the room was open.
+    pub commands: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A file as last saved, to open a room on.
+#[derive(Debug, Clone)]
+pub struct StoredSheet {
+    pub save: SpreadsheetSave,
+    /// The file's revision, which the room's first save must find it at.
+    pub file_revision: i64,
+    /// The last edit logged for the file; the room's revisions count on
+    /// from it.
+    pub logged: u64,
+}
+
+/// A file being edited, by its owner and path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomKey {
+    pub owner_id: Uuid,
+    pub path: VfsPath,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Presence {
+    pub client: u64,
+    pub user: Uuid,
+    pub name: String,
+    pub editor: bool,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    /// SocialCalc commands, one per line. `id` is echoed in the ack.
+    Command {
+        id: Option<String>,
+        cmds: String,
+    },
+    Cursor {
+        cell: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    /// The whole sheet, sent on joining and when the file is replaced by a
+    /// whole-file save.
+    Snapshot {
+        revision: u64,
+        client: u64,
+        sheetstr: String,
+        participants: Vec<Presence>,
+    },
+    Ack {
+        id: Option<String>,
+        revision: u64,
+    },
+    Command {
+        revision: u64,
+        client: u64,
+        cmds: String,
+    },
+    Join {
+        participant: Presence,
+    },
+    Leave {
+        client: u64,
+    },
+    Cursor {
+        client: u64,
+        cell: Option<String>,
+    },
+    Error {
+        id: Option<String>,
+        message: String,
+    },
+}
+
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<HubInner>,
+}
+
+struct HubInner {
+    /// Only held to find a room; each room has a lock of its own.
+    rooms: Mutex<HashMap<RoomKey, Arc<Mutex<Room>>>>,
+    persist: Persist,
+    next_client: AtomicU64,
+}
+
+struct Room {
+    /// The sheet with every edit applied, but not recalculated: snapshots
+    /// and saves recalculate a copy, off the room's lock.
+    save: SpreadsheetSave,
+    revision: u64,
+    participants: BTreeMap<u64, Participant>,
+    /// Edits waiting for the saver. A whole-file save carries its content.
+    unsaved: Vec<(Edit, Option<String>)>,
+    /// Whether anyone is in the room; false once everyone has left, while
+    /// the saver finishes.
+    open: bool,
+    /// Whether the room's saver is running.
+    saving: bool,
+    /// Whether the room was closed, its unsaved edits dropped.
+    closed: bool,
+    wake: Arc<Notify>,
+}
+
+struct Participant {
+    user_id: Uuid,
+    name: String,
+    can_edit: bool,
+    cursor: Option<CellCoord>,
+    outbox: mpsc::Sender<ServerMessage>,
+}
+
+impl Participant {
+    fn presence(&self, client: u64) -> Presence {
+        Presence {
+            client,
+            user: self.user_id,
+            name: self.name.clone(),
+            editor: self.can_edit,
+            cursor: self.cursor.map(|coord| coord.to_string()),
+        }
+    }
+}
+
+impl Room {
+    fn broadcast(&mut self, except: u64, message: ServerMessage) {
+        let lagging: Vec<u64> = self
+            .participants
+            .iter()
+            .filter(|(client, participant)| {
+                **client != except && participant.outbox.try_send(message.clone()).is_err()
+            })
+            .map(|(client, _)| *client)
+            .collect();
+        for client in lagging {
+            self.remove(client);
+        }
+    }
+
+    fn send(&mut self, client: u64, message: ServerMessage) {
+        let lagging = self
+            .participants
+            .get(&client)
+            .is_some_and(|participant| participant.outbox.try_send(message).is_err());
+        if lagging {
+            self.remove(client);
+        }
+    }
+
+    /// Takes a participant out of the room. One whose outbox is full is
+    /// disconnected this way too, its session ending once it has read what
+    /// was sent before.
+    fn remove(&mut self, client: u64) {
+        if self.participants.remove(&client).is_none() {
+            return;
+        }
+        self.broadcast(client, ServerMessage::Leave { client });
+        if self.participants.is_empty() {
+            // The saver removes the room once its last save is done, so a
+            // rejoin or a write in the meantime never reads a stale file
+            self.open = false;
+            self.wake.notify_one();
+        }
+    }
+
+    fn queue_save(&mut self, edit: Edit, content: Option<String>) {
+        self.unsaved.push((edit, content));
+        self.wake.notify_one();
+    }
+
+    /// Applies a parsed batch, queues it for saving and sends it to the
+    /// participants other than `client`. Returns the new revision.
+    fn apply(&mut self, client: u64, user_id: Uuid, parsed: &[Command], cmds: String) -> u64 {
+        commands::apply(&mut self.save.sheet, parsed);
+        self.revision += 1;
+        let edit = Edit {
+            user_id,
+            commands: Some(cmds.clone()),
+            created_at: Utc::now(),
+        };
+        self.queue_save(edit, None);
+
+        let revision = self.revision;
+        self.broadcast(
+            client,
+            ServerMessage::Command {
+                revision,
+                client,
+                cmds,
+            },
+        );
+        revision
+    }
+
+    /// Whether writes can go through the room: until its saver has saved
+    /// the last of them, even after everyone has left, unless it was closed.
+    fn writable(&self) -> bool {
+        self.saving && !self.closed
+    }
+
+    fn participants(&self) -> Vec<Presence> {
+        self.participants
+            .iter()
+            .map(|(client, participant)| participant.presence(*client))
+            .collect()
+    }
+}
+
+/// Removes a room, unless it was closed and its file has been opened again
+/// in a new one.
+fn forget(rooms: &mut HashMap<RoomKey, Arc<Mutex<Room>>>, key: &RoomKey, room: &Arc<Mutex<Room>>) {
+    if rooms.get(key).is_some_and(|entry| Arc::ptr_eq(entry, room)) {
+        rooms.remove(key);
+    }
+}
+
+/// Closes a room with `message` for its participants, removing it unless
+/// its file has been opened again in a new one.
+fn abandon(hub: &Weak<HubInner>, key: &RoomKey, room: &Arc<Mutex<Room>>, message: &str) {
+    if let Some(hub) = hub.upgrade() {
+        forget(&mut hub.rooms.lock().unwrap(), key, room);
+    }
+    Hub::shut(room, message);
+}
+
+/// Recalculates a copy of a room's sheet, off its lock and the async
+/// workers. `None` if recalculating it panicked.
+async fn recalculated(mut save: SpreadsheetSave) -> Option<SpreadsheetSave> {
+    let recalculated = tokio::task::spawn_blocking(move || {
+        socialcalc::recalc(&mut save.sheet);
+        save
+    })
+    .await;
+    match recalculated {
+        Ok(save) => Some(save),
+        Err(err) => {
+            warn!("Failed to recalculate a sheet: {}", err);
+            None
+        }
+    }
+}
+
+/// Recalculates and serializes a copy of a room's sheet, off its lock.
+async fn serialize(save: SpreadsheetSave) -> Option<String> {
+    Some(recalculated(save).await?.serialize())
+}
+
+impl Hub {
+    pub fn new(
+        persist: impl Fn(RoomKey, Vec<Edit>, String, i64) -> BoxFuture<'static, anyhow::Result<Option<i64>>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Hub {
+            inner: Arc::new(HubInner {
+                rooms: Mutex::new(HashMap::new()),
+                persist: Arc::new(persist),
+                next_client: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    fn room(&self, key: &RoomKey) -> Option<Arc<Mutex<Room>>> {
+        self.inner.rooms.lock().unwrap().get(key).cloned()
+    }
+
+    /// Joins the room for a file. `stored` is the file as last saved, used
+    /// only if nobody has it open: an open room's copy is newer.
+    pub fn join(
+        &self,
+        key: RoomKey,
+        user_id: Uuid,
+        name: String,
+        can_edit: bool,
+        stored: StoredSheet,
+    ) -> Session {
+        let client = self.inner.next_client.fetch_add(1, Ordering::Relaxed);
+        let (outbox, inbox) = mpsc::channel(OUTBOX_CAPACITY);
+        let participant = Participant {
+            user_id,
+            name,
+            can_edit,
+            cursor: None,
+            outbox,
+        };
+
+        let mut rooms = self.inner.rooms.lock().unwrap();
+        let entry = rooms.entry(key.clone()).or_insert_with(|| {
+            Arc::new(Mutex::new(Room {
+                save: stored.save,
+                revision: stored.logged,
+                participants: BTreeMap::new(),
+                unsaved: Vec::new(),
+                open: false,
+                saving: false,
+                closed: false,
+                wake: Arc::new(Notify::new()),
+            }))
+        });
+        let entry = entry.clone();
+        let mut room = entry.lock().unwrap();
+        drop(rooms);
+        room.open = true;
+        if !room.saving {
+            room.saving = true;
+            let wake = room.wake.clone();
+            self.spawn_saver(key.clone(), entry.clone(), wake, stored.file_revision);
+        }
+        room.broadcast(
+            client,
+            ServerMessage::Join {
+                participant: participant.presence(client),
+            },
+        );
+        room.participants.insert(client, participant);
+
+        // Everything after this revision reaches the inbox, behind it
+        let save = room.save.clone();
+        let revision = room.revision;
+        let participants = room.participants();
+        drop(room);
+        let hub = Arc::downgrade(&self.inner);
+        let room_key = key.clone();
+        let snapshot = tokio::spawn(async move {
+            let Some(sheetstr) = serialize(save).await else {
+                abandon(&hub, &room_key, &entry, RECALC_FAILED_MESSAGE);
+                return None;
+            };
+            Some(ServerMessage::Snapshot {
+                revision,
+                client,
+                sheetstr,
+                participants,
+            })
+        });
+
+        Session {
+            hub: self.clone(),
+            key,
+            client,
+            snapshot: Some(snapshot),
+            inbox,
+        }
+    }
+
+    /// Saves a whole file through its room if it is open, so the save is
+    /// ordered with the room's edits and its participants carry on from
+    /// it. A room everyone has left is open until its edits are saved.
+    /// Returns false if the room is not open and the caller must save.
+    pub fn replace(
+        &self,
+        key: &RoomKey,
+        user_id: Uuid,
+        save: SpreadsheetSave,
+        content: String,
+    ) -> bool {
+        self.replace_if(key, user_id, save, content, None).is_some()
+    }
+
+    /// The sheet in a file's room, if it is open, recalculated, with the
+    /// room's revision to pass back to `replace_at` once it is edited. A
+    /// room whose sheet fails to recalculate is closed.
+    pub async fn current(&self, key: &RoomKey) -> Option<(SpreadsheetSave, u64)> {
+        let entry = self.room(key)?;
+        let (save, revision) = {
+            let room = entry.lock().unwrap();
+            if !room.writable() {
+                return None;
+            }
+            (room.save.clone(), room.revision)
+        };
+        match recalculated(save).await {
+            Some(save) => Some((save, revision)),
+            None => {
+                abandon(
+                    &Arc::downgrade(&self.inner),
+                    key,
+                    &entry,
+                    RECALC_FAILED_MESSAGE,
+                );
+                None
+            }
+        }
+    }
+
+    /// `replace`, only if the room is still at `revision`. Returns `None` if
+    /// the room is not open, or else whether the file was saved.
+    pub fn replace_at(
+        &self,
+        key: &RoomKey,
+        user_id: Uuid,
+        save: SpreadsheetSave,
+        content: String,
+        revision: u64,
+    ) -> Option<bool> {
+        self.replace_if(key, user_id, save, content, Some(revision))
+    }
+
+    fn replace_if(
+        &self,
+        key: &RoomKey,
+        user_id: Uuid,
+        save: SpreadsheetSave,
+        content: String,
+        revision: Option<u64>,
+    ) -> Option<bool> {
+        let room = self.room(key)?;
+        let mut room = room.lock().unwrap();
+        if !room.writable() {
+            return None;
+        }
+        if revision.is_some_and(|revision| revision != room.revision) {
+            return Some(false);
+        }
+        let edit = Edit {
+            user_id,
+            commands: None,
+            created_at: Utc::now(),
+        };
+        room.queue_save(edit, Some(content.clone()));
+        room.save = save;
+        room.revision += 1;
+        let participants = room.participants();
+        let clients: Vec<u64> = room.participants.keys().copied().collect();
+        for client in clients {
+            let snapshot = ServerMessage::Snapshot {
+                revision: room.revision,
+                client,
+                sheetstr: content.clone(),
+                participants: participants.clone(),
+            };
+            room.send(client, snapshot);
+        }
+        Some(true)
+    }
+
+    /// Closes a file's room, if it has one, after the file has been written
+    /// around it. Its participants are told and disconnected, to open the
+    /// file again as written, and its unsaved edits are dropped.
+    pub fn close(&self, key: &RoomKey) {
+        let room = self.inner.rooms.lock().unwrap().remove(key);
+        if let Some(room) = room {
+            Self::shut(&room, CLOSED_MESSAGE);
+        }
+    }
+
+    fn shut(room: &Mutex<Room>, message: &str) {
+        let mut room = room.lock().unwrap();
+        room.open = false;
+        room.closed = true;
+        room.unsaved.clear();
+        room.broadcast(
+            0,
+            ServerMessage::Error {
+                id: None,
+                message: message.to_string(),
+            },
+        );
+        // Their sessions end once they have read what was sent before
+        room.participants.clear();
+        room.wake.notify_one();
+    }
+
+    /// Applies commands to a file through its room if it is open, as if an
+    /// editor had sent them; `build` writes them against the room's copy of
+    /// the sheet, which has not been recalculated. Returns `None` if the
+    /// room is not open and the caller must save, or else the new revision.
+    pub fn apply(
+        &self,
+        key: &RoomKey,
+        user_id: Uuid,
+        build: impl FnOnce(&Sheet) -> Result<String, String>,
+    ) -> Option<Result<u64, String>> {
+        let room = self.room(key)?;
+        let mut room = room.lock().unwrap();
+        if !room.writable() {
+            return None;
+        }
+
+        let cmds = match build(&room.save.sheet) {
+            Ok(cmds) => cmds,
+            Err(message) => return Some(Err(message)),
+        };
+        let parsed = match commands::parse(&cmds) {
+            Ok(parsed) => parsed,
+            Err(err) => return Some(Err(err.to_string())),
+        };
+        // Client ids start at 1, so every participant is sent the batch
+        Some(Ok(room.apply(0, user_id, &parsed, cmds)))
+    }
+
+    fn handle(&self, key: &RoomKey, client: u64, message: ClientMessage) {
+        let Some(room) = self.room(key) else {
+            return;
+        };
+
+        match message {
+            ClientMessage::Cursor { cell } => {
+                let cursor = cell.as_deref().and_then(CellCoord::parse);
+                let mut room = room.lock().unwrap();
+                let Some(participant) = room.participants.get_mut(&client) else {
+                    return;
+                };
+                participant.cursor = cursor;
+                room.broadcast(
+                    client,
+                    ServerMessage::Cursor {
+                        client,
+                        cell: cursor.map(|coord| coord.to_string()),
+                    },
+                );
+            }
+            ClientMessage::Command { id, cmds } => {
+                let error = |message: String| ServerMessage::Error {
+                    id: id.clone(),
+                    message,
+                };
+                let parsed = if cmds.len() > MAX_COMMAND_BYTES {
+                    Err("Commands are too long".to_string())
+                } else {
+                    commands::parse(&cmds).map_err(|err| err.to_string())
+                };
+
+                let mut room = room.lock().unwrap();
+                let Some(participant) = room.participants.get(&client) else {
+                    return;
+                };
+                if !participant.can_edit {
+                    room.send(client, error("You can only view this file".to_string()));
+                    return;
+                }
+                let parsed = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(message) => {
+                        room.send(client, error(message));
+                        return;
+                    }
+                };
+
+                let user_id = participant.user_id;
+                let revision = room.apply(client, user_id, &parsed, cmds);
+                room.send(client, ServerMessage::Ack { id, revision });
+            }
+        }
+    }
+
+    fn leave(&self, key: &RoomKey, client: u64) {
+        let Some(room) = self.room(key) else {
+            return;
+        };
+        room.lock().unwrap().remove(client);
+    }
+
+    /// Takes a room's unsaved edits for its saver, with a copy of the sheet
+    /// after them unless they end at a whole-file save. Returns `None`, and
+    /// removes the room, once it has closed and everything is saved.
+    #[allow(clippy::type_complexity)]
+    fn take_unsaved(
+        hub: &Weak<HubInner>,
+        key: &RoomKey,
+        room: &Arc<Mutex<Room>>,
+    ) -> Option<(Vec<(Edit, Option<String>)>, Option<SpreadsheetSave>)> {
+        let hub = hub.upgrade()?;
+        let mut rooms = hub.rooms.lock().unwrap();
+        let mut locked = room.lock().unwrap();
+        let unsaved = std::mem::take(&mut locked.unsaved);
+        if unsaved.is_empty() && !locked.open {
+            locked.saving = false;
+            drop(locked);
+            forget(&mut rooms, key, room);
+            return None;
+        }
+        // Once these are saved, look again for more, or for the room closing
+        if !unsaved.is_empty() {
+            locked.wake.notify_one();
+        }
+        let latest = match unsaved.last() {
+            Some((_, None)) => Some(locked.save.clone()),
+            _ => None,
+        };
+        Some((unsaved, latest))
+    }
+
+    /// Puts batches that failed to save back in front of a room's unsaved
+    /// edits, for its saver to try again, unless the room has been closed.
+    fn requeue(room: &Mutex<Room>, batches: impl Iterator<Item = (Vec<Edit>, String)>) {
+        let mut room = room.lock().unwrap();
+        if room.closed {
+            return;
+        }
+        // Only whole-file saves carry content; the rest is recalculated
+        let mut unsaved: Vec<(Edit, Option<String>)> = batches
+            .flat_map(|(batch, content)| {
+                batch.into_iter().map(move |edit| {
+                    let content = edit.commands.is_none().then(|| content.clone());
+                    (edit, content)
+                })
+            })
+            .collect();
+        unsaved.append(&mut room.unsaved);
+        room.unsaved = unsaved;
+        room.wake.notify_one();
+    }
+
+    /// Saves a room's edits a moment after they're made, so that a burst
+    /// of them is saved as one batch, until the room closes. A batch ends
+    /// at a whole-file save, so the log keeps the content after every one.
+    /// The room is closed if a save finds its file written around it: at a
+    /// revision other than `file_revision`, or the one the last save left.
+    /// A batch that fails to save is tried again with the edits made since,
+    /// and the room is closed if it keeps failing.
+    fn spawn_saver(
+        &self,
+        key: RoomKey,
+        room: Arc<Mutex<Room>>,
+        wake: Arc<Notify>,
+        mut file_revision: i64,
+    ) {
+        let persist = self.inner.persist.clone();
+        let hub: Weak<HubInner> = Arc::downgrade(&self.inner);
+
+        tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                wake.notified().await;
+                tokio::time::sleep(SAVE_DELAY * 2u32.pow(failures)).await;
+
+                let Some((unsaved, latest)) = Self::take_unsaved(&hub, &key, &room) else {
+                    return;
+                };
+
+                let mut batches = Vec::new();
+                let mut batch = Vec::new();
+                for (edit, content) in unsaved {
+                    batch.push(edit);
+                    if let Some(content) = content {
+                        batches.push((std::mem::take(&mut batch), content));
+                    }
+                }
+                if let Some(save) = latest {
+                    let Some(content) = serialize(save).await else {
+                        abandon(&hub, &key, &room, RECALC_FAILED_MESSAGE);
+                        continue;
+                    };
+                    batches.push((batch, content));
+                }
+
+                let mut batches = batches.into_iter();
+                while let Some((batch, content)) = batches.next() {
+                    match persist(key.clone(), batch.clone(), content.clone(), file_revision).await
+                    {
+                        Ok(Some(saved)) => {
+                            file_revision = saved;
+                            failures = 0;
+                        }
+                        Ok(None) => {
+                            abandon(&hub, &key, &room, CLOSED_MESSAGE);
+                            break;
+                        }
+                        Err(err) if failures == MAX_SAVE_RETRIES => {
+                            warn!("Failed to save {}, giving up: {}", key.path.as_str(), err);
+                            abandon(&hub, &key, &room, SAVE_FAILED_MESSAGE);
+                            break;
+                        }
+                        Err(err) => {
+                            warn!(
+                                "Failed to save {}, trying again: {}",
+                                key.path.as_str(),
+                                err
+                            );
+                            failures += 1;
+                            Self::requeue(&room, std::iter::once((batch, content)).chain(batches));
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// One participant's connection to a room. Dropping it leaves the room.
+pub struct Session {
+    hub: Hub,
+    key: RoomKey,
+    client: u64,
+    /// The room as this participant joined it, sent before anything else.
+    snapshot: Option<JoinHandle<Option<ServerMessage>>>,
+    inbox: mpsc::Receiver<ServerMessage>,
+}
+
+impl Session {
+    pub fn client(&self) -> u64 {
+        self.client
+    }
+
+    pub fn handle(&self, message: ClientMessage) {
+        self.hub.handle(&self.key, self.client, message);
+    }
+
+    /// The next message for this participant.
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        if let Some(snapshot) = &mut self.snapshot {
+            let snapshot = snapshot.await;
+            self.snapshot = None;
+            if let Ok(Some(snapshot)) = snapshot {
+                return Some(snapshot);
+            }
+        }
+        self.inbox.recv().await
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.hub.leave(&self.key, self.client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicI64;
+
+    type Saves = mpsc::UnboundedReceiver<(Vec<Edit>, String)>;
+
+    /// A hub saving to a file that is only at the revision given.
+    fn hub() -> (Hub, Saves, Arc<AtomicI64>) {
+        let (saved, saves) = mpsc::unbounded_channel();
+        let file = Arc::new(AtomicI64::new(0));
+        let stored = file.clone();
+        let hub = Hub::new(
+            move |_, edits, content, revision| -> BoxFuture<'static, anyhow::Result<Option<i64>>> {
+                let saved = stored
+                    .compare_exchange(revision, revision + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                    .then(|| {
+                        let _ = saved.send((edits, content));
+                        revision + 1
+                    });
+                Box::pin(async move { Ok(saved) })
+            },
+        );
+        (hub, saves, file)
+    }
+
+    fn key() -> RoomKey {
+        RoomKey {
+            owner_id: Uuid::nil(),
+            path: VfsPath::home("budget.msc").unwrap(),
+        }
+    }
+
+    fn stored(lines: &str) -> SpreadsheetSave {
+        SpreadsheetSave::new(Sheet::parse(lines).unwrap())
+    }
+
+    fn join(hub: &Hub, name: &str, can_edit: bool) -> Session {
+        hub.join(
+            key(),
+            Uuid::new_v4(),
+            name.to_string(),
+            can_edit,
+            StoredSheet {
+                save: stored("cell:A1:v:1"),
+                file_revision: 0,
+                logged: 0,
+            },
+        )
+    }
+
+    async fn next(session: &mut Session) -> ServerMessage {
+        session.recv().await.unwrap()
+    }
+
+    fn command(cmds: &str) -> ClientMessage {
+        ClientMessage::Command {
+            id: Some("1".to_string()),
+            cmds: cmds.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_are_ordered_broadcast_and_saved() {
+        let (hub, mut saves, _) = hub();
+        let mut editor = join(&hub, "editor", true);
+        assert!(matches!(
+            next(&mut editor).await,
+            ServerMessage::Snapshot { revision: 0, .. }
+        ));
+
+        let mut viewer = join(&hub, "viewer", false);
+        match next(&mut viewer).await {
+            ServerMessage::Snapshot { participants, .. } => assert_eq!(participants.len(), 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        match next(&mut editor).await {
+            ServerMessage::Join { participant } => assert_eq!(participant.name, "viewer"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        editor.handle(command("set A2 formula A1*10"));
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Ack {
+                id: Some("1".to_string()),
+                revision: 1
+            }
+        );
+        assert_eq!(
+            next(&mut viewer).await,
+            ServerMessage::Command {
+                revision: 1,
+                client: editor.client(),
+                cmds: "set A2 formula A1*10".to_string()
+            }
+        );
+        let (edits, content) = saves.recv().await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].commands.as_deref(), Some("set A2 formula A1*10"));
+        let save = SpreadsheetSave::parse(&content).unwrap();
+        let a2 = &save.sheet.cells[&CellCoord::parse("A2").unwrap()];
+        assert_eq!(a2.datavalue, "10");
+
+        viewer.handle(command("set A3 value n 1"));
+        assert!(matches!(
+            next(&mut viewer).await,
+            ServerMessage::Error { .. }
+        ));
+
+        viewer.handle(ClientMessage::Cursor {
+            cell: Some("b4".to_string()),
+        });
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Cursor {
+                client: viewer.client(),
+                cell: Some("B4".to_string())
+            }
+        );
+
+        let viewer_client = viewer.client();
+        drop(viewer);
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Leave {
+                client: viewer_client
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn bursts_of_edits_are_saved_together() {
+        let (hub, mut saves, _) = hub();
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+        for row in 1..=20 {
+            editor.handle(command(&format!("set B{} value n {}", row, row)));
+            next(&mut editor).await;
+        }
+
+        let (edits, content) = saves.recv().await.unwrap();
+        assert_eq!(edits.len(), 20);
+        let save = SpreadsheetSave::parse(&content).unwrap();
+        assert_eq!(save.sheet.cells.len(), 21);
+
+        // Leaving saves the room's last edits, then closes it
+        editor.handle(command("set C1 value n 3"));
+        next(&mut editor).await;
+        drop(editor);
+        let (edits, _) = saves.recv().await.unwrap();
+        assert_eq!(edits.len(), 1);
+        tokio::time::sleep(SAVE_DELAY * 2).await;
+        assert!(hub.inner.rooms.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn writes_after_everyone_leaves_are_saved_behind_the_room() {
+        let (hub, mut saves, _) = hub();
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+        editor.handle(command("set A2 value n 2"));
+        next(&mut editor).await;
+        drop(editor);
+
+        // The room is still saving the edit, so the write goes through it
+        let (_, revision) = hub.current(&key()).await.unwrap();
+        assert_eq!(revision, 1);
+        let replaced = stored("cell:A1:v:3");
+        assert!(hub.replace(&key(), Uuid::nil(), replaced.clone(), replaced.serialize()));
+
+        let mut logged = Vec::new();
+        while logged.len() < 2 {
+            let (edits, content) = saves.recv().await.unwrap();
+            if edits.last().unwrap().commands.is_none() {
+                assert_eq!(content, replaced.serialize());
+            }
+            logged.extend(edits.into_iter().map(|edit| edit.commands));
+        }
+        assert_eq!(logged, [Some("set A2 value n 2".to_string()), None]);
+
+        // Once it has saved everything, writes are the caller's
+        tokio::time::sleep(SAVE_DELAY * 2).await;
+        assert!(hub.inner.rooms.lock().unwrap().is_empty());
+        assert!(!hub.replace(&key(), Uuid::nil(), replaced.clone(), replaced.serialize()));
+    }
+
+    #[tokio::test]
+    async fn participants_that_fall_behind_are_disconnected() {
+        let (hub, _saves, _) = hub();
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+        let mut idle = join(&hub, "idle", false);
+        next(&mut editor).await;
+
+        for row in 1..=OUTBOX_CAPACITY {
+            editor.handle(command(&format!("set B{} value n {}", row, row)));
+            next(&mut editor).await;
+        }
+        // The next command doesn't fit in the idle participant's outbox
+        editor.handle(command("set C1 value n 1"));
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Leave {
+                client: idle.client()
+            }
+        );
+        assert!(matches!(next(&mut editor).await, ServerMessage::Ack { .. }));
+
+        // It still reads what fitted before its session ends
+        assert!(matches!(
+            next(&mut idle).await,
+            ServerMessage::Snapshot { .. }
+        ));
+        let mut commands = 0;
+        while let Some(message) = idle.recv().await {
+            assert!(matches!(message, ServerMessage::Command { .. }));
+            commands += 1;
+        }
+        assert_eq!(commands, OUTBOX_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn failed_saves_are_tried_again_with_later_edits() {
+        let (saved, mut saves) = mpsc::unbounded_channel();
+        let failing = Arc::new(AtomicI64::new(2));
+        let hub = Hub::new(
+            move |_,
+                  edits: Vec<Edit>,
+                  _,
+                  revision|
+                  -> BoxFuture<'static, anyhow::Result<Option<i64>>> {
+                let result = if failing.fetch_sub(1, Ordering::SeqCst) > 0 {
+                    Err(anyhow::anyhow!("database unavailable"))
+                } else {
+                    let _ = saved.send(edits);
+                    Ok(Some(revision + 1))
+                };
+                Box::pin(async move { result })
+            },
+        );
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+        editor.handle(command("set A2 value n 2"));
+        next(&mut editor).await;
+
+        // Made after the first save failed, and saved behind its edits
+        tokio::time::sleep(SAVE_DELAY * 2).await;
+        editor.handle(command("set A3 value n 3"));
+        next(&mut editor).await;
+
+        let edits = saves.recv().await.unwrap();
+        let logged: Vec<_> = edits.into_iter().map(|edit| edit.commands).collect();
+        assert_eq!(
+            logged,
+            [
+                Some("set A2 value n 2".to_string()),
+                Some("set A3 value n 3".to_string())
+            ]
+        );
+        assert!(hub.current(&key()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn later_participants_see_unsaved_edits() {
+        let (hub, mut saves, _) = hub();
+        let mut first = join(&hub, "first", true);
+        next(&mut first).await;
+        first.handle(command("set A1 value n 42"));
+        next(&mut first).await;
+
+        // The stored copy passed on joining is older than the room's
+        let mut second = join(&hub, "second", true);
+        match next(&mut second).await {
+            ServerMessage::Snapshot {
+                revision, sheetstr, ..
+            } => {
+                assert_eq!(revision, 1);
+                let save = SpreadsheetSave::parse(&sheetstr).unwrap();
+                let a1 = &save.sheet.cells[&CellCoord::parse("A1").unwrap()];
+                assert_eq!(a1.datavalue, "42");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        second.handle(command("set A1 value n 7"));
+        assert!(matches!(
+            next(&mut second).await,
+            ServerMessage::Ack { revision: 2, .. }
+        ));
+        let user_id = Uuid::new_v4();
+        let replaced = stored("cell:A1:v:3");
+        assert!(hub.replace(&key(), user_id, replaced.clone(), replaced.serialize()));
+        assert!(matches!(
+            next(&mut second).await,
+            ServerMessage::Snapshot { revision: 3, .. }
+        ));
+
+        // Logged in the order they happened, however they were batched
+        let mut logged = Vec::new();
+        while logged.len() < 3 {
+            let (edits, content) = saves.recv().await.unwrap();
+            if edits.last().unwrap().commands.is_none() {
+                assert_eq!(content, replaced.serialize());
+            }
+            logged.extend(edits.into_iter().map(|edit| edit.commands));
+        }
+        assert_eq!(
+            logged,
+            [
+                Some("set A1 value n 42".to_string()),
+                Some("set A1 value n 7".to_string()),
+                None
+            ]
+        );
+
+        let other = RoomKey {
+            owner_id: Uuid::nil(),
+            path: VfsPath::home("other.msc").unwrap(),
+        };
+        assert!(!hub.replace(&other, user_id, replaced.clone(), replaced.serialize()));
+    }
+
+    #[tokio::test]
+    async fn server_commands_reach_every_participant() {
+        let (hub, mut saves, _) = hub();
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+
+        let applied = hub.apply(&key(), Uuid::nil(), |sheet| {
+            let row = sheet.used_range().unwrap().end.row + 1;
+            Ok(format!("set A{} value n 2", row))
+        });
+        assert_eq!(applied, Some(Ok(1)));
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Command {
+                revision: 1,
+                client: 0,
+                cmds: "set A2 value n 2".to_string()
+            }
+        );
+        let (edits, _) = saves.recv().await.unwrap();
+        assert_eq!(edits[0].commands.as_deref(), Some("set A2 value n 2"));
+
+        assert_eq!(
+            hub.apply(&key(), Uuid::nil(), |_| Err("no".to_string())),
+            Some(Err("no".to_string()))
+        );
+        drop(editor);
+        let other = RoomKey {
+            owner_id: Uuid::nil(),
+            path: VfsPath::home("other.msc").unwrap(),
+        };
+        assert_eq!(hub.apply(&other, Uuid::nil(), |_| Ok(String::new())), None);
+    }
+
+    #[tokio::test]
+    async fn writes_around_a_room_close_it() {
+        let (hub, mut saves, file) = hub();
+        let mut editor = join(&hub, "editor", true);
+        next(&mut editor).await;
+        editor.handle(command("set A2 value n 2"));
+        next(&mut editor).await;
+        saves.recv().await.unwrap();
+
+        // Edited again on a copy, but the room moves on first
+        let (mut save, revision) = hub.current(&key()).await.unwrap();
+        editor.handle(command("set A3 value n 3"));
+        next(&mut editor).await;
+        save.sheet = Sheet::parse("cell:A1:v:5").unwrap();
+        let content = save.serialize();
+        assert_eq!(
+            hub.replace_at(&key(), Uuid::nil(), save, content, revision),
+            Some(false)
+        );
+        saves.recv().await.unwrap();
+
+        // The file is written around the room, which closes when it saves
+        file.fetch_add(1, Ordering::SeqCst);
+        editor.handle(command("set A4 value n 4"));
+        next(&mut editor).await;
+        assert_eq!(
+            next(&mut editor).await,
+            ServerMessage::Error {
+                id: None,
+                message: CLOSED_MESSAGE.to_string()
+            }
+        );
+        assert!(editor.recv().await.is_none());
+        assert!(saves.try_recv().is_err());
+        assert!(hub.current(&key()).await.is_none());
+
+        // Opened again from the file as written, in a new room
+        let mut reopened = hub.join(
+            key(),
+            Uuid::new_v4(),
+            "editor".to_string(),
+            true,
+            StoredSheet {
+                save: stored("cell:A1:v:1"),
+                file_revision: file.load(Ordering::SeqCst),
+                logged: 3,
+            },
+        );
+        next(&mut reopened).await;
+        reopened.handle(command("set A5 value n 5"));
+        assert!(matches!(
+            next(&mut reopened).await,
+            ServerMessage::Ack { revision: 4, .. }
+        ));
+        let (edits, _) = saves.recv().await.unwrap();
+        assert_eq!(edits[0].commands.as_deref(), Some("set A5 value n 5"));
+
+        // Or closed as soon as it is written around
+        hub.close(&key());
+        assert!(matches!(
+            next(&mut reopened).await,
+            ServerMessage::Error { .. }
+        ));
+        assert!(reopened.recv().await.is_none());
+        drop(editor);
+        drop(reopened);
+        tokio::time::sleep(SAVE_DELAY * 2).await;
+        assert!(hub.inner.rooms.lock().unwrap().is_empty());
+    }
+}
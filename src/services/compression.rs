@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a row's content is stored in `files`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    /// Plain text in `content`, written before compression was introduced.
+    Identity,
+    /// zstd frame in `content_compressed`.
+    Zstd,
+    /// Raw bytes in object storage under `storage_key`.
+    External,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::External => "external",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "identity" => Some(ContentEncoding::Identity),
+            "zstd" => Some(ContentEncoding::Zstd),
+            "external" => Some(ContentEncoding::External),
+            _ => None,
+        }
+    }
+}
+
+pub fn compress(content: &str) -> Result<Vec<u8>> {
+    Ok(zstd::encode_all(content.as_bytes(), ZSTD_LEVEL)?)
+}
+
+pub fn decode(encoding: &str, content: String, compressed: Option<Vec<u8>>) -> Result<String> {
+    match ContentEncoding::parse(encoding) {
+        Some(ContentEncoding::Identity) => Ok(content),
+        Some(ContentEncoding::Zstd) => {
+            let compressed = compressed.ok_or_else(|| anyhow!("zstd row without content"))?;
+            let bytes = zstd::decode_all(compressed.as_slice())?;
+            Ok(String::from_utf8(bytes)?)
+        }
+        Some(ContentEncoding::External) => Err(anyhow!("external content is not stored inline")),
+        None => Err(anyhow!("unknown content encoding: {}", encoding)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_content_round_trips() {
+        let content = "version:1.5\ncell:A1:t:Grüße\n".repeat(1000);
+        let compressed = compress(&content).unwrap();
+        assert!(compressed.len() < content.len() / 10);
+        assert_eq!(
+            decode("zstd", String::new(), Some(compressed)).unwrap(),
+            content
+        );
+        assert_eq!(
+            decode("zstd", String::new(), Some(compress("").unwrap())).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn legacy_rows_decode_as_stored() {
+        let content = "cell:A1:v:1\n".to_string();
+        assert_eq!(decode("identity", content.clone(), None).unwrap(), content);
+        // Any bytes left over from before are ignored
+        assert_eq!(
+            decode("identity", content.clone(), Some(vec![1, 2, 3])).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn bad_rows_are_errors() {
+        assert!(decode("zstd", String::new(), None).is_err());
+        assert!(decode("zstd", String::new(), Some(b"not zstd".to_vec())).is_err());
+        let invalid_utf8 = zstd::encode_all(&[0xff, 0xfe][..], ZSTD_LEVEL).unwrap();
+        assert!(decode("zstd", String::new(), Some(invalid_utf8)).is_err());
+        assert!(decode("gzip", "x".to_string(), None).is_err());
+        assert!(decode("external", String::new(), None).is_err());
+        for encoding in [
+            ContentEncoding::Identity,
+            ContentEncoding::Zstd,
+            ContentEncoding::External,
+        ] {
+            assert_eq!(ContentEncoding::parse(encoding.as_str()), Some(encoding));
+        }
+    }
+}
@@ -1,2 +1,7 @@
+pub mod collab;
+pub mod compression;
 pub mod email;
+pub mod rate_limit;
+pub mod search;
+pub mod secure_store;
 pub mod storage;
@@ -0,0 +1,66 @@
+//! Fixed-window request counters for public endpoints, which can't ask
+//! anonymous callers to log in or solve a captcha.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counters are swept of expired windows once there are this many.
+const SWEEP_AT: usize = 10_000;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    window: Duration,
+    hits: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    pub fn new(window: Duration) -> Self {
+        RateLimiter {
+            window,
+            hits: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Counts a request against `key`. Returns false, without counting it,
+    /// if `limit` requests have already been made in the current window.
+    pub fn check(&self, key: &str, limit: u32) -> bool {
+        self.check_at(key, limit, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, limit: u32, now: Instant) -> bool {
+        let mut hits = self.hits.lock().unwrap();
+        if hits.len() >= SWEEP_AT {
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+
+        let (start, count) = hits.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_key_per_window() {
+        let limiter = RateLimiter::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limiter.check_at("a", 2, start));
+        assert!(limiter.check_at("a", 2, start + Duration::from_secs(1)));
+        assert!(!limiter.check_at("a", 2, start + Duration::from_secs(2)));
+        assert!(limiter.check_at("b", 2, start + Duration::from_secs(2)));
+
+        assert!(limiter.check_at("a", 2, start + Duration::from_secs(60)));
+    }
+}
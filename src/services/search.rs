@@ -0,0 +1,85 @@
+use crate::socialcalc::SpreadsheetSave;
+use crate::utils::escape_html;
+
+/// What the search query has `ts_headline` put around matches, in place of
+/// markup: control characters, removed from the text it highlights.
+pub const MATCH_START: char = '\u{2}';
+pub const MATCH_END: char = '\u{3}';
+
+/// Text extracted from a saved sheet for the full-text index.
+#[derive(Debug, Default)]
+pub struct SearchDocument {
+    pub name: String,
+    pub body: String,
+    pub cells: Vec<IndexedCell>,
+}
+
+#[derive(Debug)]
+pub struct IndexedCell {
+    pub coord: String,
+    pub row: i32,
+    pub col: i32,
+    pub value: String,
+}
+
+pub fn build_document(path: &str, content: &str) -> SearchDocument {
+    let fname = path.rsplit('/').next().unwrap_or(path);
+    let name = fname
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>();
+
+    let cells = extract_cells(content);
+    let body = cells
+        .iter()
+        .map(|cell| cell.value.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    SearchDocument { name, body, cells }
+}
+
+/// Pulls the value of every cell out of a SocialCalc save, in sheet order.
+/// Content that does not parse as a save is indexed by name only.
+fn extract_cells(content: &str) -> Vec<IndexedCell> {
+    let Ok(save) = SpreadsheetSave::parse(content) else {
+        return Vec::new();
+    };
+
+    save.sheet
+        .cells
+        .into_iter()
+        .filter(|(_, cell)| !cell.datavalue.trim().is_empty())
+        .map(|(coord, cell)| IndexedCell {
+            coord: coord.to_string(),
+            row: coord.row as i32,
+            col: coord.col as i32,
+            value: cell.datavalue,
+        })
+        .collect()
+}
+
+/// Turns a headline marked with `MATCH_START` and `MATCH_END` into HTML,
+/// escaping the text and marking the matches with `<mark>`.
+pub fn highlight(headline: &str) -> String {
+    escape_html(headline)
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headlines_are_escaped_before_matches_are_marked() {
+        let headline = format!(
+            "<img src=x onerror=alert(1)> {}total{} & more",
+            MATCH_START, MATCH_END
+        );
+        assert_eq!(
+            highlight(&headline),
+            "&lt;img src=x onerror=alert(1)&gt; <mark>total</mark> &amp; more"
+        );
+    }
+}
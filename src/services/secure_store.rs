@@ -0,0 +1,262 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use argon2::Argon2;
+use base64::Engine;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{db::Database, models::SecureStoreKey};
+
+/// Marks content written through the secure store. Anything else under the
+/// namespace predates encryption and is still plaintext.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum SecureStoreError {
+    #[error("Store password required")]
+    PasswordRequired,
+    #[error("Incorrect store password")]
+    WrongPassword,
+    #[error("Stored data could not be decrypted")]
+    Corrupt,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+/// A user's unwrapped data key; every file in their secure store is
+/// encrypted with it.
+pub struct DataKey(Key<Aes256Gcm>);
+
+impl DataKey {
+    /// Encrypts `plaintext`, binding it to `path` so ciphertext cannot be
+    /// moved to another file.
+    pub fn encrypt(&self, path: &str, plaintext: &str) -> Result<String, SecureStoreError> {
+        let cipher = Aes256Gcm::new(&self.0);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: path.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}",
+            ENCRYPTED_PREFIX,
+            base64::engine::general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    pub fn decrypt(&self, path: &str, stored: &str) -> Result<String, SecureStoreError> {
+        let encoded = stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or(SecureStoreError::Corrupt)?;
+        let sealed = base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| SecureStoreError::Corrupt)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(SecureStoreError::Corrupt);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = Aes256Gcm::new(&self.0)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: path.as_bytes(),
+                },
+            )
+            .map_err(|_| SecureStoreError::Corrupt)?;
+
+        String::from_utf8(plaintext).map_err(|_| SecureStoreError::Corrupt)
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+/// Unlocks the user's data key with their store password, creating the key
+/// on first use.
+pub async fn unlock(
+    db: &Database,
+    user_id: Uuid,
+    password: Option<&str>,
+) -> Result<DataKey, SecureStoreError> {
+    let password = password
+        .filter(|password| !password.is_empty())
+        .ok_or(SecureStoreError::PasswordRequired)?;
+
+    if let Some(stored) = db.get_secure_store_key(user_id).await? {
+        return open_stored_key(password, &stored);
+    }
+
+    let data_key = Aes256Gcm::generate_key(OsRng);
+    let (salt, nonce, wrapped) = wrap_key(password, &data_key)?;
+
+    if db
+        .create_secure_store_key(user_id, &salt, &nonce, &wrapped)
+        .await?
+    {
+        return Ok(DataKey(data_key));
+    }
+
+    // Another request created the key first; use the one that was stored
+    match db.get_secure_store_key(user_id).await? {
+        Some(stored) => open_stored_key(password, &stored),
+        None => Err(anyhow::anyhow!("secure store key disappeared").into()),
+    }
+}
+
+/// Re-wraps the data key under a new password. File contents are untouched.
+pub async fn change_password(
+    db: &Database,
+    user_id: Uuid,
+    password: Option<&str>,
+    new_password: Option<&str>,
+) -> Result<(), SecureStoreError> {
+    let new_password = new_password
+        .filter(|password| !password.is_empty())
+        .ok_or(SecureStoreError::PasswordRequired)?;
+
+    let data_key = unlock(db, user_id, password).await?;
+    let (salt, nonce, wrapped) = wrap_key(new_password, &data_key.0)?;
+    db.update_secure_store_key(user_id, &salt, &nonce, &wrapped)
+        .await?;
+
+    Ok(())
+}
+
+fn open_stored_key(password: &str, stored: &SecureStoreKey) -> Result<DataKey, SecureStoreError> {
+    let kek = derive_kek(password, &stored.kdf_salt)?;
+    unwrap_key(&kek, &stored.wrap_nonce, &stored.wrapped_key)
+}
+
+fn derive_kek(password: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>, SecureStoreError> {
+    let mut kek = Key::<Aes256Gcm>::default();
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut kek)
+        .map_err(|err| anyhow::anyhow!("key derivation failed: {}", err))?;
+    Ok(kek)
+}
+
+type WrappedKey = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+fn wrap_key(password: &str, data_key: &Key<Aes256Gcm>) -> Result<WrappedKey, SecureStoreError> {
+    let mut salt = vec![0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+
+    let kek = derive_kek(password, &salt)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let wrapped = Aes256Gcm::new(&kek)
+        .encrypt(&nonce, data_key.as_slice())
+        .map_err(|_| anyhow::anyhow!("key wrapping failed"))?;
+
+    Ok((salt, nonce.to_vec(), wrapped))
+}
+
+fn unwrap_key(
+    kek: &Key<Aes256Gcm>,
+    nonce: &[u8],
+    wrapped: &[u8],
+) -> Result<DataKey, SecureStoreError> {
+    if nonce.len() != NONCE_LEN {
+        return Err(SecureStoreError::Corrupt);
+    }
+
+    // AEAD authentication fails when the password-derived key is wrong
+    let key = Aes256Gcm::new(kek)
+        .decrypt(Nonce::from_slice(nonce), wrapped)
+        .map_err(|_| SecureStoreError::WrongPassword)?;
+
+    Ok(DataKey(*Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(password: &str, data_key: &Key<Aes256Gcm>) -> SecureStoreKey {
+        let (kdf_salt, wrap_nonce, wrapped_key) = wrap_key(password, data_key).unwrap();
+        SecureStoreKey {
+            user_id: Uuid::nil(),
+            kdf_salt,
+            wrap_nonce,
+            wrapped_key,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn wrapped_key_opens_only_with_its_password() {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let stored = stored("correct horse", &data_key);
+
+        let opened = open_stored_key("correct horse", &stored).unwrap();
+        assert_eq!(opened.0, data_key);
+        assert!(matches!(
+            open_stored_key("battery staple", &stored),
+            Err(SecureStoreError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn ciphertext_is_bound_to_its_path() {
+        let key = DataKey(Aes256Gcm::generate_key(OsRng));
+        let sealed = key.encrypt("home/securestore/notes", "secret").unwrap();
+        assert!(is_encrypted(&sealed));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(
+            key.decrypt("home/securestore/notes", &sealed).unwrap(),
+            "secret"
+        );
+
+        assert!(matches!(
+            key.decrypt("home/securestore/other", &sealed),
+            Err(SecureStoreError::Corrupt)
+        ));
+        let other = DataKey(Aes256Gcm::generate_key(OsRng));
+        assert!(matches!(
+            other.decrypt("home/securestore/notes", &sealed),
+            Err(SecureStoreError::Corrupt)
+        ));
+        assert!(matches!(
+            key.decrypt("home/securestore/notes", "secret"),
+            Err(SecureStoreError::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn changing_the_password_keeps_the_data_key() {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let sealed = DataKey(data_key)
+            .encrypt("home/securestore/notes", "secret")
+            .unwrap();
+
+        // What change_password stores: the same key wrapped anew
+        let old = stored("old password", &data_key);
+        let unlocked = open_stored_key("old password", &old).unwrap();
+        let new = stored("new password", &unlocked.0);
+
+        assert!(matches!(
+            open_stored_key("old password", &new),
+            Err(SecureStoreError::WrongPassword)
+        ));
+        let reopened = open_stored_key("new password", &new).unwrap();
+        assert_eq!(
+            reopened.decrypt("home/securestore/notes", &sealed).unwrap(),
+            "secret"
+        );
+    }
+}
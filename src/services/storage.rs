@@ -7,7 +7,9 @@ use aws_sdk_s3::operation::put_object::PutObjectError;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::Client;
 use aws_smithy_types::error::metadata::ProvideErrorMetadata;
+use std::path::Path;
 
+#[derive(Clone)]
 pub struct StorageService {
     client: Client,
     bucket: String,
@@ -36,6 +38,41 @@ impl StorageService {
         Ok(())
     }
 
+    /// Uploads a file from disk without reading it into memory.
+    pub async fn put_object_from_path(&self, key: &str, path: &Path) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(ByteStream::from_path(path).await?)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// Streams an object, or the inclusive byte range `(start, end)` of it.
+    /// `None` if there is no such object.
+    pub async fn get_object_stream(
+        &self,
+        key: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<ByteStream>> {
+        match self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .set_range(range.map(|(start, end)| format!("bytes={}-{}", start, end)))
+            .send()
+            .await
+        {
+            Ok(resp) => Ok(Some(resp.body)),
+            Err(SdkError::ServiceError(inner)) if inner.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn get_object(&self, key: &str) -> Result<Vec<u8>> {
         let resp = self
             .client
@@ -0,0 +1,557 @@
+//! SocialCalc's sheet commands (`set A1 value n 5`, `erase A1:B2 all`, ...),
+//! as the spreadsheet control sends them while editing. Structural commands
+//! that move cells, such as `insertrow` or `sort`, are not supported.
+
+use thiserror::Error;
+
+use super::sheet::intern;
+use super::{
+    column_number, decode_from_save, Borders, Cell, CellCoord, CellRange, DataType, NamedRange,
+    Sheet,
+};
+
+/// Commands touching more cells than this are rejected.
+const MAX_COMMAND_CELLS: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("line {line}: {reason}")]
+pub struct CommandError {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    SetCells(CellRange, Contents),
+    SetCellAttribute(CellRange, CellAttribute, String),
+    /// Columns `first..=last`; `hide` when the flag is set, `width` otherwise.
+    SetColumns(u32, u32, bool, String),
+    SetRows(u32, u32, bool, String),
+    Erase(CellRange, Erase),
+    Merge(CellRange),
+    Unmerge(CellCoord),
+    DefineName(String, String),
+    DescribeName(String, String),
+    DeleteName(String),
+    /// Recalculation happens after every batch anyway.
+    Recalc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Contents {
+    Empty,
+    Value(String, String),
+    Text(String, String),
+    Formula(String),
+    Constant(String, String, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellAttribute {
+    Color,
+    BgColor,
+    Font,
+    Layout,
+    CellFormat,
+    TextValueFormat,
+    NonTextValueFormat,
+    BorderTop,
+    BorderRight,
+    BorderBottom,
+    BorderLeft,
+    ReadOnly,
+    Comment,
+}
+
+impl CellAttribute {
+    pub const ALL: [CellAttribute; 13] = [
+        CellAttribute::Color,
+        CellAttribute::BgColor,
+        CellAttribute::Font,
+        CellAttribute::Layout,
+        CellAttribute::CellFormat,
+        CellAttribute::TextValueFormat,
+        CellAttribute::NonTextValueFormat,
+        CellAttribute::BorderTop,
+        CellAttribute::BorderRight,
+        CellAttribute::BorderBottom,
+        CellAttribute::BorderLeft,
+        CellAttribute::ReadOnly,
+        CellAttribute::Comment,
+    ];
+
+    /// The name `set` commands use.
+    pub fn name(self) -> &'static str {
+        match self {
+            CellAttribute::Color => "color",
+            CellAttribute::BgColor => "bgcolor",
+            CellAttribute::Font => "font",
+            CellAttribute::Layout => "layout",
+            CellAttribute::CellFormat => "cellformat",
+            CellAttribute::TextValueFormat => "textvalueformat",
+            CellAttribute::NonTextValueFormat => "nontextvalueformat",
+            CellAttribute::BorderTop => "bt",
+            CellAttribute::BorderRight => "br",
+            CellAttribute::BorderBottom => "bb",
+            CellAttribute::BorderLeft => "bl",
+            CellAttribute::ReadOnly => "readonly",
+            CellAttribute::Comment => "comment",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|attribute| attribute.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    All,
+    Formulas,
+    Formats,
+}
+
+/// Parses newline-separated commands. Nothing is applied unless every
+/// line parses.
+pub fn parse(commands: &str) -> Result<Vec<Command>, CommandError> {
+    commands
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            parse_line(line.trim_end_matches('\r')).map_err(|reason| CommandError {
+                line: index + 1,
+                reason,
+            })
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Command, String> {
+    let (verb, rest) = split_word(line.trim_start());
+    match verb {
+        "set" => parse_set(rest),
+        "erase" => {
+            let (target, what) = split_word(rest);
+            let what = match what.trim() {
+                "all" | "" => Erase::All,
+                "formulas" => Erase::Formulas,
+                "formats" => Erase::Formats,
+                other => return Err(format!("unknown erase type '{}'", other)),
+            };
+            Ok(Command::Erase(range(target)?, what))
+        }
+        "merge" => Ok(Command::Merge(range(rest.trim())?)),
+        "unmerge" => match CellCoord::parse(rest.trim()) {
+            Some(coord) => Ok(Command::Unmerge(coord)),
+            None => Err(format!("invalid cell '{}'", rest.trim())),
+        },
+        "name" => {
+            let (action, rest) = split_word(rest);
+            let (name, value) = split_word(rest);
+            if name.is_empty() {
+                return Err("missing name".to_string());
+            }
+            let name = name.to_ascii_uppercase();
+            match action {
+                "define" => Ok(Command::DefineName(name, value.to_string())),
+                "desc" => Ok(Command::DescribeName(name, decode_from_save(value))),
+                "delete" => Ok(Command::DeleteName(name)),
+                other => Err(format!("unknown name action '{}'", other)),
+            }
+        }
+        "recalc" | "redisplay" => Ok(Command::Recalc),
+        other => Err(format!("unsupported command '{}'", other)),
+    }
+}
+
+fn parse_set(rest: &str) -> Result<Command, String> {
+    let (target, rest) = split_word(rest);
+    let (attribute, value) = split_word(rest);
+
+    // Whole columns (`A`, `A:C`) and rows (`3`, `3:5`)
+    if let Some((first, last)) = span(target, column_number) {
+        return match attribute {
+            "width" | "hide" => Ok(Command::SetColumns(
+                first,
+                last,
+                attribute == "hide",
+                value.to_string(),
+            )),
+            other => Err(format!("unknown column attribute '{}'", other)),
+        };
+    }
+    if let Some((first, last)) = span(target, |row| row.parse().ok().filter(|row| *row > 0)) {
+        return match attribute {
+            "height" | "hide" => Ok(Command::SetRows(
+                first,
+                last,
+                attribute == "hide",
+                value.to_string(),
+            )),
+            other => Err(format!("unknown row attribute '{}'", other)),
+        };
+    }
+
+    let range = range(target)?;
+    let contents = match attribute {
+        "empty" => Contents::Empty,
+        "value" => {
+            let (valuetype, value) = split_word(value);
+            let value = value.trim();
+            if value.parse::<f64>().is_err() {
+                return Err(format!("invalid number '{}'", value));
+            }
+            Contents::Value(valuetype.to_string(), value.to_string())
+        }
+        "text" => {
+            let (valuetype, text) = split_word(value);
+            if !valuetype.starts_with('t') {
+                return Err(format!("invalid text type '{}'", valuetype));
+            }
+            Contents::Text(valuetype.to_string(), decode_from_save(text))
+        }
+        "formula" => Contents::Formula(value.trim().to_string()),
+        "constant" => {
+            let (valuetype, rest) = split_word(value);
+            let (value, text) = split_word(rest);
+            Contents::Constant(
+                valuetype.to_string(),
+                value.to_string(),
+                decode_from_save(text),
+            )
+        }
+        other => {
+            let Some(attribute) = CellAttribute::parse(other) else {
+                return Err(format!("unknown cell attribute '{}'", other));
+            };
+            let value = match attribute {
+                CellAttribute::Comment => decode_from_save(value),
+                _ => value.trim().to_string(),
+            };
+            return Ok(Command::SetCellAttribute(range, attribute, value));
+        }
+    };
+    Ok(Command::SetCells(range, contents))
+}
+
+/// The first space-separated word and the rest of the line after it.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(' ') {
+        Some((word, rest)) => (word, rest),
+        None => (text, ""),
+    }
+}
+
+fn range(target: &str) -> Result<CellRange, String> {
+    let range = CellRange::parse(target).ok_or_else(|| format!("invalid range '{}'", target))?;
+    if range.rows() as u64 * range.cols() as u64 > MAX_COMMAND_CELLS {
+        return Err(format!("range '{}' is too large", target));
+    }
+    Ok(range)
+}
+
+/// Columns or rows `first:last`, capped at the cell limit so a command
+/// cannot loop over billions of them.
+fn span(target: &str, parse: impl Fn(&str) -> Option<u32>) -> Option<(u32, u32)> {
+    let (first, last) = target.split_once(':').unwrap_or((target, target));
+    let (first, last) = (parse(first)?, parse(last)?);
+    let (first, last) = (first.min(last), first.max(last));
+    Some((
+        first,
+        last.min(first.saturating_add(MAX_COMMAND_CELLS as u32 - 1)),
+    ))
+}
+
+/// Applies parsed commands in order. Formula values are left for the
+/// caller to recalculate.
+pub fn apply(sheet: &mut Sheet, commands: &[Command]) {
+    for command in commands {
+        match command {
+            Command::SetCells(range, contents) => {
+                for coord in range.coords() {
+                    set_contents(sheet, coord, contents);
+                }
+            }
+            Command::SetCellAttribute(range, attribute, value) => {
+                for coord in range.coords() {
+                    set_attribute(sheet, coord, *attribute, value);
+                }
+            }
+            Command::SetColumns(first, last, hide, value) => {
+                for col in *first..=*last {
+                    let attributes = sheet.cols.entry(col).or_default();
+                    let field = if *hide {
+                        &mut attributes.hide
+                    } else {
+                        &mut attributes.width
+                    };
+                    *field = Some(value.clone()).filter(|value| !value.is_empty());
+                    if attributes.width.is_none() && attributes.hide.is_none() {
+                        sheet.cols.remove(&col);
+                    }
+                }
+            }
+            Command::SetRows(first, last, hide, value) => {
+                for row in *first..=*last {
+                    let attributes = sheet.rows.entry(row).or_default();
+                    let field = if *hide {
+                        &mut attributes.hide
+                    } else {
+                        &mut attributes.height
+                    };
+                    *field = Some(value.clone()).filter(|value| !value.is_empty());
+                    if attributes.height.is_none() && attributes.hide.is_none() {
+                        sheet.rows.remove(&row);
+                    }
+                }
+            }
+            Command::Erase(range, what) => erase(sheet, *range, *what),
+            Command::Merge(range) => {
+                let cell = sheet.cells.entry(range.start).or_default();
+                cell.colspan = Some(range.cols()).filter(|cols| *cols > 1);
+                cell.rowspan = Some(range.rows()).filter(|rows| *rows > 1);
+            }
+            Command::Unmerge(coord) => {
+                if let Some(cell) = sheet.cells.get_mut(coord) {
+                    cell.colspan = None;
+                    cell.rowspan = None;
+                }
+            }
+            Command::DefineName(name, definition) => match named(sheet, name) {
+                Some(existing) => existing.definition = definition.clone(),
+                None => sheet.names.push(NamedRange {
+                    name: name.clone(),
+                    description: String::new(),
+                    definition: definition.clone(),
+                }),
+            },
+            Command::DescribeName(name, description) => {
+                if let Some(existing) = named(sheet, name) {
+                    existing.description = description.clone();
+                }
+            }
+            Command::DeleteName(name) => sheet.names.retain(|existing| existing.name != *name),
+            Command::Recalc => {}
+        }
+    }
+}
+
+fn named<'a>(sheet: &'a mut Sheet, name: &str) -> Option<&'a mut NamedRange> {
+    sheet
+        .names
+        .iter_mut()
+        .find(|existing| existing.name == name)
+}
+
+fn set_contents(sheet: &mut Sheet, coord: CellCoord, contents: &Contents) {
+    let cell = sheet.cells.entry(coord).or_default();
+    if cell.readonly {
+        return;
+    }
+    let (datatype, valuetype, datavalue, formula) = match contents {
+        Contents::Empty => (DataType::Empty, String::new(), String::new(), String::new()),
+        Contents::Value(valuetype, value) => (
+            DataType::Value,
+            valuetype.clone(),
+            value.clone(),
+            String::new(),
+        ),
+        Contents::Text(valuetype, text) => (
+            DataType::Text,
+            valuetype.clone(),
+            text.clone(),
+            String::new(),
+        ),
+        Contents::Formula(formula) => (
+            DataType::Formula,
+            "n".to_string(),
+            "0".to_string(),
+            formula.clone(),
+        ),
+        Contents::Constant(valuetype, value, text) => (
+            DataType::Constant,
+            valuetype.clone(),
+            value.clone(),
+            text.clone(),
+        ),
+    };
+    cell.datatype = datatype;
+    cell.valuetype = valuetype;
+    cell.datavalue = datavalue;
+    cell.formula = formula;
+    cell.errors = None;
+}
+
+/// A cell attribute as a `set` command would give it, with styles looked up
+/// in the sheet's tables. `None` when unset.
+pub(super) fn get_attribute(
+    sheet: &Sheet,
+    cell: &Cell,
+    attribute: CellAttribute,
+) -> Option<String> {
+    let borders = cell.borders.clone().unwrap_or_default();
+    let (table, index) = match attribute {
+        CellAttribute::Color => (&sheet.colors, cell.color),
+        CellAttribute::BgColor => (&sheet.colors, cell.bgcolor),
+        CellAttribute::Font => (&sheet.fonts, cell.font),
+        CellAttribute::Layout => (&sheet.layouts, cell.layout),
+        CellAttribute::CellFormat => (&sheet.cellformats, cell.cellformat),
+        CellAttribute::TextValueFormat => (&sheet.valueformats, cell.textvalueformat),
+        CellAttribute::NonTextValueFormat => (&sheet.valueformats, cell.nontextvalueformat),
+        CellAttribute::BorderTop => (&sheet.borders, borders.top),
+        CellAttribute::BorderRight => (&sheet.borders, borders.right),
+        CellAttribute::BorderBottom => (&sheet.borders, borders.bottom),
+        CellAttribute::BorderLeft => (&sheet.borders, borders.left),
+        CellAttribute::ReadOnly => return cell.readonly.then(|| "yes".to_string()),
+        CellAttribute::Comment => return cell.comment.clone(),
+    };
+    index.and_then(|index| table.get(&index)).cloned()
+}
+
+pub(super) fn set_attribute(
+    sheet: &mut Sheet,
+    coord: CellCoord,
+    attribute: CellAttribute,
+    value: &str,
+) {
+    let value = Some(value.to_string()).filter(|value| !value.is_empty());
+    let table = match attribute {
+        CellAttribute::Color | CellAttribute::BgColor => Some(&mut sheet.colors),
+        CellAttribute::Font => Some(&mut sheet.fonts),
+        CellAttribute::Layout => Some(&mut sheet.layouts),
+        CellAttribute::CellFormat => Some(&mut sheet.cellformats),
+        CellAttribute::TextValueFormat | CellAttribute::NonTextValueFormat => {
+            Some(&mut sheet.valueformats)
+        }
+        CellAttribute::BorderTop
+        | CellAttribute::BorderRight
+        | CellAttribute::BorderBottom
+        | CellAttribute::BorderLeft => Some(&mut sheet.borders),
+        CellAttribute::ReadOnly | CellAttribute::Comment => None,
+    };
+    let index = match (table, &value) {
+        (Some(table), Some(value)) => Some(intern(table, value.clone())),
+        _ => None,
+    };
+
+    let cell = sheet.cells.entry(coord).or_default();
+    match attribute {
+        CellAttribute::Color => cell.color = index,
+        CellAttribute::BgColor => cell.bgcolor = index,
+        CellAttribute::Font => cell.font = index,
+        CellAttribute::Layout => cell.layout = index,
+        CellAttribute::CellFormat => cell.cellformat = index,
+        CellAttribute::TextValueFormat => cell.textvalueformat = index,
+        CellAttribute::NonTextValueFormat => cell.nontextvalueformat = index,
+        CellAttribute::BorderTop
+        | CellAttribute::BorderRight
+        | CellAttribute::BorderBottom
+        | CellAttribute::BorderLeft => {
+            let borders = cell.borders.get_or_insert_with(Borders::default);
+            let side = match attribute {
+                CellAttribute::BorderTop => &mut borders.top,
+                CellAttribute::BorderRight => &mut borders.right,
+                CellAttribute::BorderBottom => &mut borders.bottom,
+                _ => &mut borders.left,
+            };
+            *side = index;
+            if *borders == Borders::default() {
+                cell.borders = None;
+            }
+        }
+        CellAttribute::ReadOnly => cell.readonly = value.as_deref() == Some("yes"),
+        CellAttribute::Comment => cell.comment = value,
+    }
+}
+
+fn erase(sheet: &mut Sheet, range: CellRange, what: Erase) {
+    let coords: Vec<CellCoord> = sheet
+        .cells
+        .range(range.start..=range.end)
+        .map(|(coord, _)| *coord)
+        .filter(|coord| range.contains(*coord))
+        .collect();
+    for coord in coords {
+        match what {
+            Erase::All => {
+                sheet.cells.remove(&coord);
+            }
+            Erase::Formulas => set_contents(sheet, coord, &Contents::Empty),
+            Erase::Formats => {
+                if let Some(cell) = sheet.cells.get_mut(&coord) {
+                    *cell = Cell {
+                        datatype: cell.datatype,
+                        valuetype: std::mem::take(&mut cell.valuetype),
+                        datavalue: std::mem::take(&mut cell.datavalue),
+                        formula: std::mem::take(&mut cell.formula),
+                        errors: cell.errors.take(),
+                        comment: cell.comment.take(),
+                        ..Default::default()
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socialcalc::{recalc, Value};
+
+    fn run(sheet: &mut Sheet, commands: &str) {
+        apply(sheet, &parse(commands).unwrap());
+        recalc(sheet);
+    }
+
+    fn value(sheet: &Sheet, coord: &str) -> Value {
+        Value::from_cell(&sheet.cells[&CellCoord::parse(coord).unwrap()])
+    }
+
+    #[test]
+    fn sets_values_formulas_and_text() {
+        let mut sheet = Sheet::default();
+        run(
+            &mut sheet,
+            "set A1:A2 value n 4\nset A3 formula SUM(A1:A2)*2\nset B1 text t a\\cb\\nc",
+        );
+        assert_eq!(value(&sheet, "A3"), Value::number(16.0));
+        assert_eq!(value(&sheet, "B1"), Value::Text("a:b\nc".to_string()));
+
+        run(&mut sheet, "set A2 empty\nerase B1 all");
+        assert_eq!(value(&sheet, "A3"), Value::number(8.0));
+        assert!(!sheet.cells.contains_key(&CellCoord::parse("B1").unwrap()));
+    }
+
+    #[test]
+    fn sets_styles_and_layout() {
+        let mut sheet = Sheet::default();
+        run(
+            &mut sheet,
+            "set A1:B1 bgcolor rgb(255,0,0)\nset B width 120\nset 2:3 hide yes\nmerge A4:C5\nset A1 bt 1px solid rgb(0,0,0)",
+        );
+        let a1 = &sheet.cells[&CellCoord::parse("A1").unwrap()];
+        let b1 = &sheet.cells[&CellCoord::parse("B1").unwrap()];
+        assert_eq!(a1.bgcolor, b1.bgcolor);
+        assert_eq!(sheet.colors[&a1.bgcolor.unwrap()], "rgb(255,0,0)");
+        assert!(a1.borders.as_ref().unwrap().top.is_some());
+        assert_eq!(sheet.cols[&2].width.as_deref(), Some("120"));
+        assert_eq!(sheet.rows[&3].hide.as_deref(), Some("yes"));
+        let a4 = &sheet.cells[&CellCoord::parse("A4").unwrap()];
+        assert_eq!((a4.colspan, a4.rowspan), (Some(3), Some(2)));
+
+        run(&mut sheet, "set A1 bgcolor\nerase A1 formats");
+        assert_eq!(sheet.cells[&CellCoord::parse("A1").unwrap()].borders, None);
+    }
+
+    #[test]
+    fn rejects_the_whole_batch_on_any_bad_line() {
+        let err = parse("set A1 value n 1\ninsertrow A2\n").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(parse("set A1 value n abc").is_err());
+        assert!(parse("set A1:ZZ9999 value n 1").is_err());
+    }
+}
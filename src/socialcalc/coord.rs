@@ -0,0 +1,113 @@
+use std::fmt;
+
+/// A cell address. Ordered row-major, the order SocialCalc saves cells in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellCoord {
+    pub row: u32,
+    pub col: u32,
+}
+
+impl CellCoord {
+    pub fn new(col: u32, row: u32) -> Self {
+        CellCoord { row, col }
+    }
+
+    /// Parses `A1`, also accepting absolute markers such as `$A$1`.
+    pub fn parse(coord: &str) -> Option<Self> {
+        let coord = coord.trim();
+        let split = coord.find(|c: char| c.is_ascii_digit())?;
+        let (letters, digits) = coord.split_at(split);
+        let letters = letters.strip_prefix('$').unwrap_or(letters);
+        let letters = letters.strip_suffix('$').unwrap_or(letters);
+
+        let col = column_number(letters)?;
+        let row = digits.parse::<u32>().ok().filter(|row| *row > 0)?;
+        Some(CellCoord { row, col })
+    }
+}
+
+impl fmt::Display for CellCoord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", column_name(self.col), self.row)
+    }
+}
+
+/// `1` → `A`, `27` → `AA`.
+pub fn column_name(mut col: u32) -> String {
+    let mut name = Vec::new();
+    while col > 0 {
+        let rem = (col - 1) % 26;
+        name.push(b'A' + rem as u8);
+        col = (col - 1) / 26;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+pub fn column_number(name: &str) -> Option<u32> {
+    if name.is_empty() {
+        return None;
+    }
+    name.bytes().try_fold(0u32, |acc, b| {
+        let b = b.to_ascii_uppercase();
+        if !b.is_ascii_uppercase() {
+            return None;
+        }
+        acc.checked_mul(26)?.checked_add((b - b'A' + 1) as u32)
+    })
+}
+
+/// A rectangular block of cells, inclusive at both corners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellRange {
+    pub start: CellCoord,
+    pub end: CellCoord,
+}
+
+impl CellRange {
+    /// Orders the corners so `start` is the top-left cell.
+    pub fn new(a: CellCoord, b: CellCoord) -> Self {
+        CellRange {
+            start: CellCoord::new(a.col.min(b.col), a.row.min(b.row)),
+            end: CellCoord::new(a.col.max(b.col), a.row.max(b.row)),
+        }
+    }
+
+    /// Parses `A1:C10`; a single coordinate is a one-cell range.
+    pub fn parse(range: &str) -> Option<Self> {
+        match range.split_once(':') {
+            Some((a, b)) => Some(CellRange::new(CellCoord::parse(a)?, CellCoord::parse(b)?)),
+            None => CellCoord::parse(range).map(|coord| CellRange::new(coord, coord)),
+        }
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.end.row - self.start.row + 1
+    }
+
+    pub fn cols(&self) -> u32 {
+        self.end.col - self.start.col + 1
+    }
+
+    pub fn contains(&self, coord: CellCoord) -> bool {
+        (self.start.row..=self.end.row).contains(&coord.row)
+            && (self.start.col..=self.end.col).contains(&coord.col)
+    }
+
+    /// Every coordinate in the range, row by row.
+    pub fn coords(&self) -> impl Iterator<Item = CellCoord> {
+        let (start, end) = (self.start, self.end);
+        (start.row..=end.row)
+            .flat_map(move |row| (start.col..=end.col).map(move |col| CellCoord::new(col, row)))
+    }
+}
+
+impl fmt::Display for CellRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}:{}", self.start, self.end)
+        }
+    }
+}
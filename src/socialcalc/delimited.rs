@@ -0,0 +1,156 @@
+//! CSV and TSV output of a sheet.
+
+use serde::Deserialize;
+
+use super::{Cell, CellCoord, CellRange, DataType, Sheet, Value};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quoting {
+    /// Quote fields containing the delimiter, a quote, a line break or
+    /// surrounding spaces.
+    #[default]
+    Minimal,
+    All,
+    /// Never quote; delimiters and line breaks inside fields become spaces.
+    Never,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DelimitedOptions {
+    pub delimiter: char,
+    pub quoting: Quoting,
+    /// Write formulas (`=SUM(A1:A3)`) instead of their computed values.
+    pub formulas: bool,
+}
+
+/// The lines of the export, one per row of `range`, each ending in CRLF.
+pub fn rows(
+    sheet: Sheet,
+    range: CellRange,
+    options: DelimitedOptions,
+) -> impl Iterator<Item = String> + Send {
+    (range.start.row..=range.end.row).map(move |row| {
+        let mut line = String::new();
+        for col in range.start.col..=range.end.col {
+            if col > range.start.col {
+                line.push(options.delimiter);
+            }
+            let text = sheet
+                .cells
+                .get(&CellCoord::new(col, row))
+                .map(|cell| cell_text(cell, options.formulas))
+                .unwrap_or_default();
+            push_field(&mut line, &text, &options);
+        }
+        line.push_str("\r\n");
+        line
+    })
+}
+
+fn cell_text(cell: &Cell, formulas: bool) -> String {
+    match cell.datatype {
+        DataType::Formula if formulas => format!("={}", cell.formula),
+        DataType::Constant if formulas => cell.formula.clone(),
+        _ => Value::from_cell(cell).to_string(),
+    }
+}
+
+fn push_field(line: &mut String, text: &str, options: &DelimitedOptions) {
+    let special = |c: char| c == options.delimiter || matches!(c, '"' | '\r' | '\n');
+    let quote = match options.quoting {
+        Quoting::All => true,
+        Quoting::Never => false,
+        Quoting::Minimal => text.contains(special) || text.starts_with(' ') || text.ends_with(' '),
+    };
+
+    if quote {
+        line.push('"');
+        line.push_str(&text.replace('"', "\"\""));
+        line.push('"');
+    } else if options.quoting == Quoting::Never {
+        line.extend(
+            text.chars()
+                .map(|c| if special(c) && c != '"' { ' ' } else { c }),
+        );
+    } else {
+        line.push_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(
+        content: &str,
+        range: &str,
+        delimiter: char,
+        quoting: Quoting,
+        formulas: bool,
+    ) -> String {
+        let mut sheet = Sheet::parse(content).unwrap();
+        super::super::recalc(&mut sheet);
+        let options = DelimitedOptions {
+            delimiter,
+            quoting,
+            formulas,
+        };
+        rows(sheet, CellRange::parse(range).unwrap(), options).collect()
+    }
+
+    const SHEET: &str = "cell:A1:t:plain\ncell:B1:t:a,b\ncell:C1:t:say \"hi\"\n\
+                         cell:A2:t: padded \ncell:B2:t:two\\nlines\ncell:C2:t:cr\rhere\n\
+                         cell:A3:v:2\ncell:B3:vtf:n:0:A3*2\ncell:C3:vtc:n$:1.5:$1.50";
+
+    #[test]
+    fn quotes_only_what_needs_it() {
+        assert_eq!(
+            export(SHEET, "A1:C3", ',', Quoting::Minimal, false),
+            "plain,\"a,b\",\"say \"\"hi\"\"\"\r\n\
+             \" padded \",\"two\nlines\",\"cr\rhere\"\r\n\
+             2,4,1.5\r\n"
+        );
+    }
+
+    #[test]
+    fn quotes_everything_or_nothing() {
+        assert_eq!(
+            export(SHEET, "A1:C2", ',', Quoting::All, false),
+            "\"plain\",\"a,b\",\"say \"\"hi\"\"\"\r\n\
+             \" padded \",\"two\nlines\",\"cr\rhere\"\r\n"
+        );
+        assert_eq!(
+            export(SHEET, "A1:C2", ',', Quoting::Never, false),
+            "plain,a b,say \"hi\"\r\n padded ,two lines,cr here\r\n"
+        );
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        assert_eq!(
+            export(SHEET, "A1:B1", ';', Quoting::Minimal, false),
+            "plain;a,b\r\n"
+        );
+        assert_eq!(
+            export(SHEET, "A1:C1", '\t', Quoting::Never, false),
+            "plain\ta,b\tsay \"hi\"\r\n"
+        );
+        assert_eq!(
+            export("cell:A1:t:x|y", "A1:B1", '|', Quoting::Minimal, false),
+            "\"x|y\"|\r\n"
+        );
+    }
+
+    #[test]
+    fn formulas_or_values() {
+        assert_eq!(
+            export(SHEET, "A3:C3", ',', Quoting::Minimal, false),
+            "2,4,1.5\r\n"
+        );
+        assert_eq!(
+            export(SHEET, "A3:C3", ',', Quoting::Minimal, true),
+            "2,=A3*2,$1.50\r\n"
+        );
+    }
+}
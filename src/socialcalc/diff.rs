@@ -0,0 +1,384 @@
+//! Differences between two sheets, property by property, and three-way
+//! merges of sheets edited apart from a common ancestor.
+//!
+//! Values are given as `set` commands take them, with styles looked up in
+//! each sheet's tables, so sheets with differently numbered tables compare
+//! equal when they look the same.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use super::commands::{get_attribute, set_attribute, CellAttribute};
+use super::{column_name, encode_for_save, Cell, CellCoord, DataType, NamedRange, Sheet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// A cell's contents, comment or read-only flag.
+    Cell,
+    /// A cell's formatting, borders or merged span.
+    Style,
+    Column,
+    Row,
+    Name,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// A cell (`B2`), column (`B`), row (`2`) or range name.
+    pub target: String,
+    pub property: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A property both sides changed differently. The merge keeps `ours`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Conflict {
+    pub kind: ChangeKind,
+    pub target: String,
+    pub property: &'static str,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub sheet: Sheet,
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Cell(CellCoord),
+    Column(u32),
+    Row(u32),
+    Name(String),
+}
+
+impl Target {
+    fn describe(&self) -> String {
+        match self {
+            Target::Cell(coord) => coord.to_string(),
+            Target::Column(col) => column_name(*col),
+            Target::Row(row) => row.to_string(),
+            Target::Name(name) => name.clone(),
+        }
+    }
+}
+
+type Key = (Target, &'static str);
+
+fn kind(key: &Key) -> ChangeKind {
+    match key {
+        (Target::Cell(_), "contents" | "readonly" | "comment") => ChangeKind::Cell,
+        (Target::Cell(_), _) => ChangeKind::Style,
+        (Target::Column(_), _) => ChangeKind::Column,
+        (Target::Row(_), _) => ChangeKind::Row,
+        (Target::Name(_), _) => ChangeKind::Name,
+    }
+}
+
+/// Changes that turn `old` into `new`.
+pub fn diff(old: &Sheet, new: &Sheet) -> Vec<Change> {
+    let (old, new) = (properties(old), properties(new));
+    let keys: BTreeSet<&Key> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .map(|key| Change {
+            kind: kind(key),
+            target: key.0.describe(),
+            property: key.1,
+            old: old.get(key).cloned(),
+            new: new.get(key).cloned(),
+        })
+        .collect()
+}
+
+/// Merges the changes `theirs` made since `base` into `ours`. Formulas are
+/// not recalculated.
+pub fn merge(base: &Sheet, ours: &Sheet, theirs: &Sheet) -> Merge {
+    let (base_props, our_props, their_props) =
+        (properties(base), properties(ours), properties(theirs));
+    let keys: BTreeSet<&Key> = base_props
+        .keys()
+        .chain(our_props.keys())
+        .chain(their_props.keys())
+        .collect();
+
+    let mut sheet = ours.clone();
+    let mut conflicts = Vec::new();
+    let mut touched = BTreeSet::new();
+    for key in keys {
+        let (base_value, our_value, their_value) = (
+            base_props.get(key),
+            our_props.get(key),
+            their_props.get(key),
+        );
+        if their_value == base_value || their_value == our_value {
+            continue;
+        }
+        if our_value != base_value {
+            conflicts.push(Conflict {
+                kind: kind(key),
+                target: key.0.describe(),
+                property: key.1,
+                base: base_value.cloned(),
+                ours: our_value.cloned(),
+                theirs: their_value.cloned(),
+            });
+            continue;
+        }
+        apply(&mut sheet, key, their_value.map(String::as_str), theirs);
+        if let Target::Cell(coord) = key.0 {
+            touched.insert(coord);
+        }
+    }
+
+    // Cells they cleared entirely
+    for coord in touched {
+        if sheet
+            .cells
+            .get(&coord)
+            .is_some_and(|cell| *cell == empty_like(cell))
+        {
+            sheet.cells.remove(&coord);
+        }
+    }
+    Merge { sheet, conflicts }
+}
+
+/// Every property the sheet sets, by what it belongs to.
+fn properties(sheet: &Sheet) -> BTreeMap<Key, String> {
+    let mut properties = BTreeMap::new();
+    for (coord, cell) in &sheet.cells {
+        let target = || Target::Cell(*coord);
+        if let Some(contents) = contents(cell) {
+            properties.insert((target(), "contents"), contents);
+        }
+        for attribute in CellAttribute::ALL {
+            if let Some(value) = get_attribute(sheet, cell, attribute) {
+                properties.insert((target(), attribute.name()), value);
+            }
+        }
+        if let Some(colspan) = cell.colspan {
+            properties.insert((target(), "colspan"), colspan.to_string());
+        }
+        if let Some(rowspan) = cell.rowspan {
+            properties.insert((target(), "rowspan"), rowspan.to_string());
+        }
+    }
+    for (col, attributes) in &sheet.cols {
+        if let Some(width) = &attributes.width {
+            properties.insert((Target::Column(*col), "width"), width.clone());
+        }
+        if let Some(hide) = &attributes.hide {
+            properties.insert((Target::Column(*col), "hide"), hide.clone());
+        }
+    }
+    for (row, attributes) in &sheet.rows {
+        if let Some(height) = &attributes.height {
+            properties.insert((Target::Row(*row), "height"), height.clone());
+        }
+        if let Some(hide) = &attributes.hide {
+            properties.insert((Target::Row(*row), "hide"), hide.clone());
+        }
+    }
+    for name in &sheet.names {
+        let target = || Target::Name(name.name.to_uppercase());
+        properties.insert((target(), "definition"), name.definition.clone());
+        if !name.description.is_empty() {
+            properties.insert((target(), "description"), name.description.clone());
+        }
+    }
+    properties
+}
+
+fn contents(cell: &Cell) -> Option<String> {
+    match cell.datatype {
+        DataType::Empty => None,
+        DataType::Value => Some(format!("value {} {}", cell.valuetype, cell.datavalue)),
+        DataType::Text => Some(format!(
+            "text {} {}",
+            cell.valuetype,
+            encode_for_save(&cell.datavalue)
+        )),
+        DataType::Formula => Some(format!("formula {}", cell.formula)),
+        DataType::Constant => Some(format!(
+            "constant {} {} {}",
+            cell.valuetype,
+            cell.datavalue,
+            encode_for_save(&cell.formula)
+        )),
+    }
+}
+
+/// Sets one property of `sheet` to `value`; cell contents are copied from
+/// `source`, which the value was read from.
+fn apply(sheet: &mut Sheet, key: &Key, value: Option<&str>, source: &Sheet) {
+    match key {
+        (Target::Cell(coord), "contents") => {
+            let from = source.cells.get(coord).cloned().unwrap_or_default();
+            let cell = sheet.cells.entry(*coord).or_default();
+            cell.datatype = from.datatype;
+            cell.valuetype = from.valuetype;
+            cell.datavalue = from.datavalue;
+            cell.formula = from.formula;
+            cell.errors = from.errors;
+        }
+        (Target::Cell(coord), "colspan" | "rowspan") => {
+            let cell = sheet.cells.entry(*coord).or_default();
+            let span = value.and_then(|value| value.parse().ok());
+            if key.1 == "colspan" {
+                cell.colspan = span;
+            } else {
+                cell.rowspan = span;
+            }
+        }
+        (Target::Cell(coord), property) => {
+            if let Some(attribute) = CellAttribute::ALL
+                .into_iter()
+                .find(|attribute| attribute.name() == *property)
+            {
+                set_attribute(sheet, *coord, attribute, value.unwrap_or_default());
+            }
+        }
+        (Target::Column(col), property) => {
+            let attributes = sheet.cols.entry(*col).or_default();
+            let field = if *property == "hide" {
+                &mut attributes.hide
+            } else {
+                &mut attributes.width
+            };
+            *field = value.map(str::to_string);
+            if attributes.width.is_none() && attributes.hide.is_none() {
+                sheet.cols.remove(col);
+            }
+        }
+        (Target::Row(row), property) => {
+            let attributes = sheet.rows.entry(*row).or_default();
+            let field = if *property == "hide" {
+                &mut attributes.hide
+            } else {
+                &mut attributes.height
+            };
+            *field = value.map(str::to_string);
+            if attributes.height.is_none() && attributes.hide.is_none() {
+                sheet.rows.remove(row);
+            }
+        }
+        (Target::Name(name), property) => {
+            let index = sheet
+                .names
+                .iter()
+                .position(|existing| existing.name.to_uppercase() == *name);
+            match (index, *property, value) {
+                (Some(index), "definition", None) => {
+                    sheet.names.remove(index);
+                }
+                (Some(index), "definition", Some(definition)) => {
+                    sheet.names[index].definition = definition.to_string();
+                }
+                (Some(index), _, description) => {
+                    sheet.names[index].description = description.unwrap_or_default().to_string();
+                }
+                (None, "definition", Some(definition)) => {
+                    let description = source
+                        .names
+                        .iter()
+                        .find(|existing| existing.name.to_uppercase() == *name)
+                        .map(|existing| existing.description.clone())
+                        .unwrap_or_default();
+                    sheet.names.push(NamedRange {
+                        name: name.clone(),
+                        description,
+                        definition: definition.to_string(),
+                    });
+                }
+                (None, _, _) => {}
+            }
+        }
+    }
+}
+
+/// What is left of a cell once every property merges compare is cleared.
+fn empty_like(cell: &Cell) -> Cell {
+    Cell {
+        modified: cell.modified.clone(),
+        cssc: cell.cssc.clone(),
+        csss: cell.csss.clone(),
+        unknown: cell.unknown.clone(),
+        ..Cell::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(lines: &str) -> Sheet {
+        Sheet::parse(lines).unwrap()
+    }
+
+    #[test]
+    fn compares_styles_by_value() {
+        let old =
+            sheet("cell:A1:v:1:f:1\ncell:B1:t:x\nfont:1:normal bold * *\nfont:2:italic * * *");
+        let new = sheet(
+            "cell:A1:v:2:f:2\ncell:C1:t:y\ncol:B:w:120\nfont:1:italic * * *\nfont:2:normal bold * *",
+        );
+        let changes = diff(&old, &new);
+        let changes: Vec<(ChangeKind, &str, &str, Option<&str>)> = changes
+            .iter()
+            .map(|c| (c.kind, c.target.as_str(), c.property, c.new.as_deref()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (ChangeKind::Cell, "A1", "contents", Some("value n 2")),
+                (ChangeKind::Cell, "B1", "contents", None),
+                (ChangeKind::Cell, "C1", "contents", Some("text t y")),
+                (ChangeKind::Column, "B", "width", Some("120")),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_independent_changes_and_records_conflicts() {
+        let base = sheet("cell:A1:v:1\ncell:A2:v:2\ncell:A3:v:3");
+        let ours = sheet("cell:A1:v:10\ncell:A2:v:2\ncell:A3:v:30\nrow:4:h:40");
+        let theirs = sheet("cell:A1:v:1\ncell:A2:v:20:bg:1\ncell:A3:v:300\ncolor:1:rgb(255,0,0)");
+
+        let merged = merge(&base, &ours, &theirs);
+        let value = |coord: &str| {
+            merged.sheet.cells[&CellCoord::parse(coord).unwrap()]
+                .datavalue
+                .clone()
+        };
+        assert_eq!(value("A1"), "10");
+        assert_eq!(value("A2"), "20");
+        assert_eq!(value("A3"), "30");
+        assert_eq!(merged.sheet.rows[&4].height.as_deref(), Some("40"));
+        let a2 = &merged.sheet.cells[&CellCoord::parse("A2").unwrap()];
+        assert_eq!(
+            get_attribute(&merged.sheet, a2, CellAttribute::BgColor).as_deref(),
+            Some("rgb(255,0,0)")
+        );
+
+        assert_eq!(
+            merged.conflicts,
+            [Conflict {
+                kind: ChangeKind::Cell,
+                target: "A3".to_string(),
+                property: "contents",
+                base: Some("value n 3".to_string()),
+                ours: Some("value n 30".to_string()),
+                theirs: Some("value n 300".to_string()),
+            }]
+        );
+        assert!(diff(&ours, &merge(&base, &ours, &ours).sheet).is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+//! Number format strings such as `#,##0.00`, `0.0%` or `d-mmm-yyyy`:
+//! their pieces, which other formats are built from, and how a number
+//! displays with one.
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Timelike};
+
+use super::formula::format_number;
+
+/// The colors a format section can name.
+const COLORS: &[(&str, u32)] = &[
+    ("black", 0x000000),
+    ("blue", 0x0000ff),
+    ("cyan", 0x00ffff),
+    ("green", 0x00ff00),
+    ("magenta", 0xff00ff),
+    ("red", 0xff0000),
+    ("white", 0xffffff),
+    ("yellow", 0xffff00),
+];
+
+/// Day zero of spreadsheet date serial numbers.
+pub fn epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1899, 12, 30)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+}
+
+/// `m` means minutes right after hours or right before seconds.
+pub fn is_minutes(tokens: &[FormatToken], at: usize) -> bool {
+    let previous = tokens[..at]
+        .iter()
+        .rev()
+        .find(|token| matches!(token, FormatToken::Date(..)));
+    let next = tokens[at + 1..]
+        .iter()
+        .find(|token| matches!(token, FormatToken::Date(..)));
+    matches!(previous, Some(FormatToken::Date('h' | 'H', _)))
+        || matches!(next, Some(FormatToken::Date('s', _)))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatToken {
+    Literal(String),
+    /// A run of `0`, `#`, `,`, `.` and an optional exponent.
+    Number(String),
+    Currency(String),
+    /// A date or time letter and how often it repeats; `H` is `[h]`, `a`
+    /// is AM/PM.
+    Date(char, usize),
+    /// `[Red]` and the other named colors, as `0xrrggbb`.
+    Color(u32),
+}
+
+/// The sections of a format, split at unquoted `;`: positive, negative,
+/// zero and text. There is always at least one.
+pub fn sections(format: &str) -> Vec<&str> {
+    let mut sections = Vec::new();
+    let (mut quoted, mut start) = (false, 0);
+    for (i, c) in format.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                sections.push(&format[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    sections.push(&format[start..]);
+    sections
+}
+
+pub fn format_tokens(format: &str) -> Vec<FormatToken> {
+    let chars: Vec<char> = format.chars().collect();
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut i = 0;
+    let has_date = {
+        let mut quoted = false;
+        chars.iter().any(|c| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            !quoted && matches!(c.to_ascii_lowercase(), 'y' | 'd' | 'h' | 's')
+        })
+    };
+
+    let flush = |tokens: &mut Vec<FormatToken>, literal: &mut String| {
+        if !literal.is_empty() {
+            tokens.push(FormatToken::Literal(std::mem::take(literal)));
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '"' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    literal.push(chars[i]);
+                    i += 1;
+                }
+                i += 1;
+            }
+            '\\' => {
+                if let Some(next) = chars.get(i + 1) {
+                    literal.push(*next);
+                }
+                i += 2;
+            }
+            // Padding the width of the next character, and repeating it to
+            // fill the cell
+            '_' => {
+                literal.push(' ');
+                i += 2;
+            }
+            '*' => i += 2,
+            '[' => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|c| *c == ']')
+                    .map_or(chars.len(), |p| i + p);
+                let inner: String = chars[i + 1..end].iter().collect();
+                if let Some(symbol) = inner.strip_prefix('$') {
+                    flush(&mut tokens, &mut literal);
+                    let symbol = symbol.split('-').next().unwrap_or(symbol);
+                    tokens.push(FormatToken::Currency(symbol.to_string()));
+                } else if inner.eq_ignore_ascii_case("h") {
+                    flush(&mut tokens, &mut literal);
+                    tokens.push(FormatToken::Date('H', 1));
+                } else if let Some((_, rgb)) = COLORS
+                    .iter()
+                    .find(|(name, _)| inner.eq_ignore_ascii_case(name))
+                {
+                    tokens.push(FormatToken::Color(*rgb));
+                }
+                i = end + 1;
+            }
+            '$' => {
+                flush(&mut tokens, &mut literal);
+                tokens.push(FormatToken::Currency("$".to_string()));
+                i += 1;
+            }
+            '0' | '#' | '?' if !has_date => {
+                flush(&mut tokens, &mut literal);
+                let start = i;
+                while i < chars.len() && matches!(chars[i], '0' | '#' | '?' | ',' | '.') {
+                    i += 1;
+                }
+                if matches!(chars.get(i), Some('E' | 'e'))
+                    && matches!(chars.get(i + 1), Some('+' | '-'))
+                {
+                    i += 2;
+                    while i < chars.len() && chars[i] == '0' {
+                        i += 1;
+                    }
+                }
+                let number: String = chars[start..i].iter().collect();
+                tokens.push(FormatToken::Number(number.replace('?', "#")));
+            }
+            _ if has_date && matches!(c.to_ascii_lowercase(), 'y' | 'm' | 'd' | 'h' | 's') => {
+                flush(&mut tokens, &mut literal);
+                let letter = c.to_ascii_lowercase();
+                let start = i;
+                while i < chars.len() && chars[i].to_ascii_lowercase() == letter {
+                    i += 1;
+                }
+                tokens.push(FormatToken::Date(letter, i - start));
+                // Fractions of seconds are not carried over
+                if letter == 's' && chars.get(i) == Some(&'.') {
+                    i += 1;
+                    while i < chars.len() && chars[i] == '0' {
+                        i += 1;
+                    }
+                }
+            }
+            _ if has_date
+                && (chars[i..].starts_with(&['A', 'M', '/', 'P', 'M'])
+                    || chars[i..].starts_with(&['a', 'm', '/', 'p', 'm'])) =>
+            {
+                flush(&mut tokens, &mut literal);
+                tokens.push(FormatToken::Date('a', 1));
+                i += 5;
+            }
+            _ => {
+                literal.push(c);
+                i += 1;
+            }
+        }
+    }
+    flush(&mut tokens, &mut literal);
+    tokens
+}
+
+/// `n` as shown with `format`, and the color the format gives it, if any.
+pub fn format_value(n: f64, format: &str) -> (String, Option<u32>) {
+    let sections = sections(format);
+    let (section, sign) = if n < 0.0 && sections.len() >= 2 {
+        (sections[1], false)
+    } else if n == 0.0 && sections.len() >= 3 {
+        (sections[2], false)
+    } else {
+        (sections[0], n < 0.0)
+    };
+    let tokens = format_tokens(section);
+    let color = tokens.iter().find_map(|token| match token {
+        FormatToken::Color(rgb) => Some(*rgb),
+        _ => None,
+    });
+
+    if tokens
+        .iter()
+        .any(|token| matches!(token, FormatToken::Date(..)))
+    {
+        return (format_date(n, &tokens), color);
+    }
+
+    let percent: usize = tokens
+        .iter()
+        .map(|token| match token {
+            FormatToken::Literal(text) => text.matches('%').count(),
+            _ => 0,
+        })
+        .sum();
+    let n = n.abs() * 100f64.powi(percent as i32);
+    let mut text = String::new();
+    let mut number = false;
+    for token in &tokens {
+        match token {
+            FormatToken::Literal(literal) => text.push_str(literal),
+            FormatToken::Currency(symbol) => text.push_str(symbol),
+            // Only the first digit group is the number; fractions and
+            // other groups are not supported
+            FormatToken::Number(pattern) if !number => {
+                text.push_str(&format_digits(n, pattern));
+                number = true;
+            }
+            _ => {}
+        }
+    }
+    if sign {
+        text.insert(0, '-');
+    }
+    (text, color)
+}
+
+/// The General format: up to 15 significant digits, as spreadsheets show
+/// numbers that have no format.
+pub fn format_general(n: f64) -> String {
+    if !n.is_finite() {
+        return format_number(n);
+    }
+    format!("{:.14e}", n)
+        .parse::<f64>()
+        .map_or_else(|_| format_number(n), format_number)
+}
+
+/// A non-negative number with a digit pattern such as `#,##0.00` or
+/// `0.0E+00`.
+fn format_digits(n: f64, pattern: &str) -> String {
+    let (mantissa, exponent) = match pattern.split_once(['E', 'e']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (pattern, None),
+    };
+    // Each trailing comma scales by a thousand
+    let trimmed = mantissa.trim_end_matches(',');
+    let mut n = n / 1000f64.powi((mantissa.len() - trimmed.len()) as i32);
+    let (integer, fraction) = trimmed.split_once('.').unwrap_or((trimmed, ""));
+    let min_integer = integer.matches('0').count();
+    let decimals = fraction.matches(['0', '#']).count();
+    let min_decimals = fraction.matches('0').count();
+
+    let mut suffix = String::new();
+    if let Some(exponent) = exponent {
+        let mut power = if n == 0.0 {
+            0
+        } else {
+            n.log10().floor() as i32
+        };
+        n /= 10f64.powi(power);
+        if format!("{:.*}", decimals, n).starts_with("10") {
+            n /= 10.0;
+            power += 1;
+        }
+        let sign = match (power < 0, exponent.starts_with('+')) {
+            (true, _) => "-",
+            (false, true) => "+",
+            (false, false) => "",
+        };
+        let digits = exponent.trim_start_matches(['+', '-']).len().max(1);
+        suffix = format!(
+            "E{}{:0digits$}",
+            sign,
+            power.unsigned_abs(),
+            digits = digits
+        );
+    }
+
+    let fixed = format!("{:.*}", decimals, n);
+    let (whole, fraction) = fixed.split_once('.').unwrap_or((&fixed, ""));
+    let fraction = fraction.trim_end_matches('0');
+    let fraction = format!("{:0<width$}", fraction, width = min_decimals);
+    let whole = match whole.trim_start_matches('0') {
+        "" => "0".repeat(min_integer),
+        digits => format!("{:0>width$}", digits, width = min_integer),
+    };
+    let whole = if integer.contains(',') {
+        group_thousands(&whole)
+    } else {
+        whole
+    };
+
+    let mut text = whole;
+    if !fraction.is_empty() {
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    text.push_str(&suffix);
+    text
+}
+
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// A date serial number with date and time tokens, to the nearest second.
+fn format_date(n: f64, tokens: &[FormatToken]) -> String {
+    let seconds = (n * 86_400.0).round();
+    if !seconds.is_finite() || seconds.abs() > 1e12 {
+        return format_number(n);
+    }
+    let Some(datetime) = epoch().checked_add_signed(Duration::seconds(seconds as i64)) else {
+        return format_number(n);
+    };
+    let twelve_hour = tokens
+        .iter()
+        .any(|token| matches!(token, FormatToken::Date('a', _)));
+
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let part = match token {
+            FormatToken::Literal(literal) => literal.clone(),
+            FormatToken::Currency(symbol) => symbol.clone(),
+            FormatToken::Number(_) | FormatToken::Color(_) => continue,
+            FormatToken::Date('y', count) if *count <= 2 => {
+                format!("{:02}", datetime.year().rem_euclid(100))
+            }
+            FormatToken::Date('y', _) => format!("{:04}", datetime.year()),
+            FormatToken::Date('m', count) if is_minutes(tokens, i) => {
+                pad(datetime.minute(), *count)
+            }
+            FormatToken::Date('m', count) => match count {
+                1 | 2 => pad(datetime.month(), *count),
+                3 => datetime.format("%b").to_string(),
+                4 => datetime.format("%B").to_string(),
+                _ => datetime.format("%B").to_string()[..1].to_string(),
+            },
+            FormatToken::Date('d', count) => match count {
+                1 | 2 => pad(datetime.day(), *count),
+                3 => datetime.format("%a").to_string(),
+                _ => datetime.format("%A").to_string(),
+            },
+            FormatToken::Date('h', count) if twelve_hour => {
+                let hour = datetime.hour() % 12;
+                pad(if hour == 0 { 12 } else { hour }, *count)
+            }
+            FormatToken::Date('h', count) => pad(datetime.hour(), *count),
+            FormatToken::Date('H', _) => ((seconds / 3600.0).floor() as i64).to_string(),
+            FormatToken::Date('s', count) => pad(datetime.second(), *count),
+            FormatToken::Date('a', _) => if datetime.hour() < 12 { "AM" } else { "PM" }.to_string(),
+            FormatToken::Date(..) => continue,
+        };
+        text.push_str(&part);
+    }
+    text
+}
+
+fn pad(n: u32, count: usize) -> String {
+    if count >= 2 {
+        format!("{:02}", n)
+    } else {
+        n.to_string()
+    }
+}
@@ -0,0 +1,360 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::parser::{self, BinaryOp, Expr};
+use super::{format_number, functions, FormulaError, NumberKind, Value};
+use crate::socialcalc::{CellCoord, CellRange, NamedRange, Sheet};
+
+/// Ranges larger than this evaluate to `#REF!` rather than being read
+/// into memory.
+const MAX_RANGE_CELLS: u64 = 1_000_000;
+/// Bounds evaluation of names defined in terms of other names, which can
+/// nest deeper than any one formula.
+const MAX_EVAL_DEPTH: usize = parser::MAX_DEPTH + parser::MAX_DEPTH / 2;
+
+pub type Names = HashMap<String, Result<Expr, String>>;
+
+/// Parses named ranges. Definitions are either a reference such as `A1:B5`
+/// or, when they start with `=`, a formula.
+pub fn parse_names(names: &[NamedRange]) -> Names {
+    names
+        .iter()
+        .map(|name| {
+            let definition = name
+                .definition
+                .strip_prefix('=')
+                .unwrap_or(&name.definition);
+            (name.name.to_ascii_uppercase(), parser::parse(definition))
+        })
+        .collect()
+}
+
+/// A range argument, read row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    pub rows: usize,
+    pub cols: usize,
+    pub values: Vec<Value>,
+}
+
+impl Grid {
+    pub fn get(&self, row: usize, col: usize) -> Option<&Value> {
+        if row < self.rows && col < self.cols {
+            self.values.get(row * self.cols + col)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Value(Value),
+    Range(Grid),
+}
+
+/// The workbook a sheet is recalculated in: its own name and the other
+/// sheets, by upper-cased name.
+pub struct Workbook<'a> {
+    pub name: &'a str,
+    pub sheets: HashMap<String, &'a Sheet>,
+}
+
+pub struct Evaluator<'a> {
+    sheet: &'a Sheet,
+    names: &'a Names,
+    workbook: Option<&'a Workbook<'a>>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(sheet: &'a Sheet, names: &'a Names) -> Self {
+        Evaluator {
+            sheet,
+            names,
+            workbook: None,
+        }
+    }
+
+    pub fn in_workbook(mut self, workbook: &'a Workbook<'a>) -> Self {
+        self.workbook = Some(workbook);
+        self
+    }
+
+    fn is_own_sheet(&self, name: &str) -> bool {
+        self.workbook
+            .is_some_and(|workbook| workbook.name.to_uppercase() == name.to_uppercase())
+    }
+
+    /// Outside a workbook every sheet reference is `#REF!`.
+    fn other_sheet(&self, name: &str) -> Option<&'a Sheet> {
+        if self.is_own_sheet(name) {
+            return Some(self.sheet);
+        }
+        let workbook = self.workbook?;
+        workbook.sheets.get(&name.to_uppercase()).copied()
+    }
+
+    /// The formula cells `expr` reads, directly or through names.
+    pub fn dependencies(&self, expr: &Expr, formula_cells: &BTreeSet<CellCoord>) -> Vec<CellCoord> {
+        let mut deps = BTreeSet::new();
+        let mut seen_names = HashSet::new();
+        self.collect_dependencies(expr, formula_cells, &mut deps, &mut seen_names);
+        deps.into_iter().collect()
+    }
+
+    fn collect_dependencies(
+        &self,
+        expr: &Expr,
+        formula_cells: &BTreeSet<CellCoord>,
+        deps: &mut BTreeSet<CellCoord>,
+        seen_names: &mut HashSet<String>,
+    ) {
+        expr.walk(&mut |reference| match reference {
+            Expr::Ref(coord) if formula_cells.contains(coord) => {
+                deps.insert(*coord);
+            }
+            Expr::Range(range) => {
+                let cells = formula_cells
+                    .range(range.start..=range.end)
+                    .filter(|coord| range.contains(**coord));
+                deps.extend(cells);
+            }
+            Expr::Name(name) => {
+                if let Some(Ok(definition)) = self.names.get(name) {
+                    if seen_names.insert(name.clone()) {
+                        self.collect_dependencies(definition, formula_cells, deps, seen_names);
+                    }
+                }
+            }
+            // Other sheets are settled by recalculating the workbook again
+            Expr::Sheet(name, inner) if self.is_own_sheet(name) => {
+                self.collect_dependencies(inner, formula_cells, deps, seen_names);
+            }
+            _ => {}
+        });
+    }
+
+    /// Evaluates a cell's formula to the single value the cell holds.
+    pub fn eval_cell(&self, expr: &Expr) -> Value {
+        let value = scalar(self.eval(expr, 0));
+        match value {
+            Value::Number(n, _) if !n.is_finite() => Value::Error(FormulaError::Num),
+            value => value,
+        }
+    }
+
+    /// `depth` is how deep in formulas and the names they use `expr` is.
+    fn eval(&self, expr: &Expr, depth: usize) -> Operand {
+        let value = match expr {
+            Expr::Blank => Value::Blank,
+            Expr::Number(n) => Value::number(*n),
+            Expr::Text(text) => Value::Text(text.clone()),
+            Expr::Bool(b) => Value::bool(*b),
+            Expr::Error(error) => Value::Error(*error),
+            Expr::Ref(coord) => self.cell(*coord),
+            Expr::Range(range) => return self.range(range),
+            Expr::Name(name) => match self.names.get(name) {
+                Some(Ok(definition)) if depth < MAX_EVAL_DEPTH => {
+                    return self.eval(definition, depth + 1)
+                }
+                _ => Value::Error(FormulaError::Name),
+            },
+            Expr::Sheet(name, inner) => match self.other_sheet(name) {
+                Some(sheet) => {
+                    let evaluator = Evaluator {
+                        sheet,
+                        names: self.names,
+                        workbook: self.workbook,
+                    };
+                    return evaluator.eval(inner, depth + 1);
+                }
+                None => Value::Error(FormulaError::Ref),
+            },
+            Expr::Negate(inner) => match scalar(self.eval(inner, depth + 1)) {
+                Value::Number(n, kind) if kind != NumberKind::Logical => Value::Number(-n, kind),
+                value => match to_number(&value) {
+                    Ok(n) => Value::number(-n),
+                    Err(error) => Value::Error(error),
+                },
+            },
+            Expr::Percent(inner) => match to_number(&scalar(self.eval(inner, depth + 1))) {
+                Ok(n) => Value::Number(n / 100.0, NumberKind::Percent),
+                Err(error) => Value::Error(error),
+            },
+            Expr::Binary(op, left, right) => {
+                let left = scalar(self.eval(left, depth + 1));
+                let right = scalar(self.eval(right, depth + 1));
+                binary(*op, left, right)
+            }
+            Expr::Call(name, args) => {
+                let args: Vec<Operand> = args.iter().map(|arg| self.eval(arg, depth + 1)).collect();
+                functions::call(name, &args)
+            }
+        };
+        Operand::Value(value)
+    }
+
+    fn cell(&self, coord: CellCoord) -> Value {
+        self.sheet
+            .cells
+            .get(&coord)
+            .map(Value::from_cell)
+            .unwrap_or(Value::Blank)
+    }
+
+    fn range(&self, range: &CellRange) -> Operand {
+        if range.rows() as u64 * range.cols() as u64 > MAX_RANGE_CELLS {
+            return Operand::Value(Value::Error(FormulaError::Ref));
+        }
+        Operand::Range(Grid {
+            rows: range.rows() as usize,
+            cols: range.cols() as usize,
+            values: range.coords().map(|coord| self.cell(coord)).collect(),
+        })
+    }
+}
+
+/// A range used where a single value is expected is only valid if it is a
+/// single cell.
+pub fn scalar(operand: Operand) -> Value {
+    match operand {
+        Operand::Value(value) => value,
+        Operand::Range(grid) if grid.values.len() == 1 => grid.values.into_iter().next().unwrap(),
+        Operand::Range(_) => Value::Error(FormulaError::Value),
+    }
+}
+
+pub fn to_number(value: &Value) -> Result<f64, FormulaError> {
+    match value {
+        Value::Blank => Ok(0.0),
+        Value::Number(n, _) => Ok(*n),
+        Value::Text(text) => {
+            let text = text.trim();
+            if let Some(percent) = text.strip_suffix('%') {
+                return percent
+                    .trim()
+                    .parse::<f64>()
+                    .map(|n| n / 100.0)
+                    .map_err(|_| FormulaError::Value);
+            }
+            text.parse().map_err(|_| FormulaError::Value)
+        }
+        Value::Error(error) => Err(*error),
+    }
+}
+
+pub fn to_text(value: &Value) -> Result<String, FormulaError> {
+    match value {
+        Value::Blank => Ok(String::new()),
+        Value::Number(n, NumberKind::Logical) => {
+            Ok(if *n != 0.0 { "TRUE" } else { "FALSE" }.to_string())
+        }
+        Value::Number(n, _) => Ok(format_number(*n)),
+        Value::Text(text) => Ok(text.clone()),
+        Value::Error(error) => Err(*error),
+    }
+}
+
+pub fn to_bool(value: &Value) -> Result<bool, FormulaError> {
+    match value {
+        Value::Blank => Ok(false),
+        Value::Number(n, _) => Ok(*n != 0.0),
+        Value::Text(text) if text.eq_ignore_ascii_case("TRUE") => Ok(true),
+        Value::Text(text) if text.eq_ignore_ascii_case("FALSE") => Ok(false),
+        Value::Text(_) => Err(FormulaError::Value),
+        Value::Error(error) => Err(*error),
+    }
+}
+
+/// Orders values as comparisons do: numbers before text, text compared
+/// without case, blank equal to both zero and the empty string.
+pub fn compare(left: &Value, right: &Value) -> Ordering {
+    match (left, right) {
+        (Value::Blank, Value::Blank) => Ordering::Equal,
+        (Value::Blank, Value::Number(n, _)) => 0.0_f64.total_cmp(n),
+        (Value::Number(n, _), Value::Blank) => n.total_cmp(&0.0),
+        (Value::Blank, Value::Text(text)) => "".cmp(text.as_str()),
+        (Value::Text(text), Value::Blank) => text.as_str().cmp(""),
+        (Value::Number(a, _), Value::Number(b, _)) => a.total_cmp(b),
+        (Value::Text(a), Value::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        (Value::Number(..), Value::Text(_)) => Ordering::Less,
+        (Value::Text(_), Value::Number(..)) => Ordering::Greater,
+        (Value::Error(_), _) | (_, Value::Error(_)) => Ordering::Equal,
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Value {
+    for value in [&left, &right] {
+        if let Value::Error(error) = value {
+            return Value::Error(*error);
+        }
+    }
+
+    match op {
+        BinaryOp::Concat => match (to_text(&left), to_text(&right)) {
+            (Ok(a), Ok(b)) => Value::Text(a + &b),
+            (Err(error), _) | (_, Err(error)) => Value::Error(error),
+        },
+        BinaryOp::Equal
+        | BinaryOp::NotEqual
+        | BinaryOp::Less
+        | BinaryOp::LessEqual
+        | BinaryOp::Greater
+        | BinaryOp::GreaterEqual => {
+            let ordering = compare(&left, &right);
+            Value::bool(match op {
+                BinaryOp::Equal => ordering == Ordering::Equal,
+                BinaryOp::NotEqual => ordering != Ordering::Equal,
+                BinaryOp::Less => ordering == Ordering::Less,
+                BinaryOp::LessEqual => ordering != Ordering::Greater,
+                BinaryOp::Greater => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            })
+        }
+        _ => {
+            let (a, b) = match (to_number(&left), to_number(&right)) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(error), _) | (_, Err(error)) => return Value::Error(error),
+            };
+            let kind = result_kind(op, kind_of(&left), kind_of(&right));
+            let result = match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Subtract => a - b,
+                BinaryOp::Multiply => a * b,
+                BinaryOp::Divide if b == 0.0 => return Value::Error(FormulaError::Div0),
+                BinaryOp::Divide => a / b,
+                _ if a == 0.0 && b == 0.0 => return Value::Error(FormulaError::Num),
+                _ => a.powf(b),
+            };
+            if result.is_finite() {
+                Value::Number(result, kind)
+            } else {
+                Value::Error(FormulaError::Num)
+            }
+        }
+    }
+}
+
+fn kind_of(value: &Value) -> NumberKind {
+    match value {
+        Value::Number(_, kind) => *kind,
+        _ => NumberKind::Plain,
+    }
+}
+
+/// A reduced form of SocialCalc's operator type table: dates stay dates when
+/// offset by a number, currency stays currency when scaled.
+fn result_kind(op: BinaryOp, left: NumberKind, right: NumberKind) -> NumberKind {
+    use NumberKind::*;
+    match (op, left, right) {
+        (BinaryOp::Add, Date, Plain) | (BinaryOp::Add, Plain, Date) => Date,
+        (BinaryOp::Add, Date, Time) | (BinaryOp::Add, Time, Date) => DateTime,
+        (BinaryOp::Subtract, Date, Plain) => Date,
+        (BinaryOp::Subtract, Date, Date) | (BinaryOp::Subtract, DateTime, DateTime) => Plain,
+        (BinaryOp::Add | BinaryOp::Subtract, a, b) if a == b && a != Logical => a,
+        (BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply, Currency, Plain | Percent)
+        | (BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply, Plain | Percent, Currency)
+        | (BinaryOp::Divide, Currency, Plain | Percent) => Currency,
+        _ => Plain,
+    }
+}
@@ -0,0 +1,1043 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use chrono::{Datelike, Duration, NaiveDate, Timelike, Utc};
+
+use super::eval::{compare, scalar, to_bool, to_number, to_text, Grid, Operand};
+use super::{FormulaError, NumberKind, Value};
+
+type FnResult = Result<Value, FormulaError>;
+
+/// Longest string text functions will build.
+const MAX_TEXT_LENGTH: usize = 32767;
+
+/// Unknown names are the only calls that fail with `#NAME?` before their
+/// arguments are checked.
+pub fn is_known(name: &str) -> bool {
+    call(name, &[]) != Value::Error(FormulaError::Name)
+}
+
+/// Calls a built-in function. Unknown functions evaluate to `#NAME?` and
+/// wrong argument counts to `#VALUE!`, as in SocialCalc.
+pub fn call(name: &str, args: &[Operand]) -> Value {
+    let result = match name {
+        // Math
+        "ABS" => math(args, f64::abs),
+        "ACOS" => math(args, f64::acos),
+        "ASIN" => math(args, f64::asin),
+        "ATAN" => math(args, f64::atan),
+        "COS" => math(args, f64::cos),
+        "SIN" => math(args, f64::sin),
+        "TAN" => math(args, f64::tan),
+        "DEGREES" => math(args, f64::to_degrees),
+        "RADIANS" => math(args, f64::to_radians),
+        "EXP" => math(args, f64::exp),
+        "LN" => math(args, f64::ln),
+        "LOG10" => math(args, f64::log10),
+        "SQRT" => math(args, f64::sqrt),
+        "INT" => math(args, f64::floor),
+        "SIGN" => math(args, |n| if n == 0.0 { 0.0 } else { n.signum() }),
+        "EVEN" => math(args, |n| round_away(n / 2.0) * 2.0),
+        "ODD" => math(args, odd),
+        "FACT" => fact(args),
+        "ATAN2" => atan2(args),
+        "LOG" => log(args),
+        "MOD" => modulo(args),
+        "POWER" => power(args),
+        "PI" => arity(args, 0, 0).map(|_| Value::number(std::f64::consts::PI)),
+        "ROUND" => round(args, f64::round),
+        "ROUNDUP" => round(args, round_away),
+        "ROUNDDOWN" | "TRUNC" => round(args, f64::trunc),
+        "CEILING" => multiple(args, f64::ceil),
+        "FLOOR" => multiple(args, f64::floor),
+        "SUM" => numbers(args).map(|ns| Value::number(ns.iter().sum())),
+        "PRODUCT" => product(args),
+        "SUMIF" => sumif(args),
+        "SUMPRODUCT" => sumproduct(args),
+
+        // Statistics
+        "AVERAGE" => average(args),
+        "COUNT" => count(args),
+        "COUNTA" => counta(args),
+        "COUNTBLANK" => countblank(args),
+        "COUNTIF" => countif(args),
+        "MAX" => {
+            numbers(args).map(|ns| Value::number(ns.into_iter().reduce(f64::max).unwrap_or(0.0)))
+        }
+        "MIN" => {
+            numbers(args).map(|ns| Value::number(ns.into_iter().reduce(f64::min).unwrap_or(0.0)))
+        }
+        "MEDIAN" => median(args),
+        "LARGE" => kth(args, true),
+        "SMALL" => kth(args, false),
+        "STDEV" => variance(args, 1).map(|v| Value::number(v.sqrt())),
+        "STDEVP" => variance(args, 0).map(|v| Value::number(v.sqrt())),
+        "VAR" => variance(args, 1).map(Value::number),
+        "VARP" => variance(args, 0).map(Value::number),
+
+        // Logical and information
+        "IF" => if_(args),
+        "IFERROR" => iferror(args),
+        "AND" => logical(args, true),
+        "OR" => logical(args, false),
+        "NOT" => arity(args, 1, 1).and_then(|_| Ok(Value::bool(!to_bool(&arg(args, 0))?))),
+        "TRUE" => arity(args, 0, 0).map(|_| Value::bool(true)),
+        "FALSE" => arity(args, 0, 0).map(|_| Value::bool(false)),
+        "NA" => arity(args, 0, 0).and(Err(FormulaError::NA)),
+        "ISBLANK" => is(args, |v| matches!(v, Value::Blank)),
+        "ISERR" => is(
+            args,
+            |v| matches!(v, Value::Error(e) if *e != FormulaError::NA),
+        ),
+        "ISERROR" => is(args, |v| matches!(v, Value::Error(_))),
+        "ISNA" => is(args, |v| matches!(v, Value::Error(FormulaError::NA))),
+        "ISNUMBER" => is(args, |v| matches!(v, Value::Number(..))),
+        "ISLOGICAL" => is(args, |v| matches!(v, Value::Number(_, NumberKind::Logical))),
+        "ISTEXT" => is(args, |v| matches!(v, Value::Text(_))),
+        "ISNONTEXT" => is(args, |v| !matches!(v, Value::Text(_))),
+
+        // Text
+        "LEN" => text_arg(args, 1, 1).map(|t| Value::number(t.chars().count() as f64)),
+        "LEFT" => left_right(args, true),
+        "RIGHT" => left_right(args, false),
+        "MID" => mid(args),
+        "UPPER" => text_arg(args, 1, 1).map(|t| Value::Text(t.to_uppercase())),
+        "LOWER" => text_arg(args, 1, 1).map(|t| Value::Text(t.to_lowercase())),
+        "PROPER" => text_arg(args, 1, 1).map(|t| Value::Text(proper(&t))),
+        "TRIM" => text_arg(args, 1, 1).map(|t| {
+            Value::Text(
+                t.split(' ')
+                    .filter(|w| !w.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        }),
+        "REPT" => rept(args),
+        "EXACT" => exact(args),
+        "FIND" => find(args, false),
+        "SEARCH" => find(args, true),
+        "SUBSTITUTE" => substitute(args),
+        "REPLACE" => replace(args),
+        "CONCATENATE" => concatenate(args),
+        "VALUE" => text_arg(args, 1, 1).and_then(|t| to_number(&Value::Text(t)).map(Value::number)),
+        "CHAR" => char_(args),
+        "CODE" => code(args),
+        "T" => arity(args, 1, 1).and_then(|_| match arg(args, 0) {
+            Value::Error(error) => Err(error),
+            Value::Text(text) => Ok(Value::Text(text)),
+            _ => Ok(Value::Text(String::new())),
+        }),
+        "N" => arity(args, 1, 1).and_then(|_| match arg(args, 0) {
+            Value::Error(error) => Err(error),
+            Value::Number(n, _) => Ok(Value::number(n)),
+            _ => Ok(Value::number(0.0)),
+        }),
+
+        // Dates and times
+        "DATE" => date(args),
+        "TIME" => time(args),
+        "NOW" => arity(args, 0, 0).map(|_| now(NumberKind::DateTime)),
+        "TODAY" => arity(args, 0, 0).map(|_| now(NumberKind::Date)),
+        "YEAR" => date_part(args, |d| d.year() as f64),
+        "MONTH" => date_part(args, |d| d.month() as f64),
+        "DAY" => date_part(args, |d| d.day() as f64),
+        "WEEKDAY" => weekday(args),
+        "HOUR" => time_part(args, |secs| secs / 3600),
+        "MINUTE" => time_part(args, |secs| secs / 60 % 60),
+        "SECOND" => time_part(args, |secs| secs % 60),
+
+        // Lookup
+        "VLOOKUP" => table_lookup(args, false),
+        "HLOOKUP" => table_lookup(args, true),
+        "LOOKUP" => lookup(args),
+        "MATCH" => match_(args),
+        "INDEX" => index(args),
+        "CHOOSE" => choose(args),
+        "ROWS" => arity(args, 1, 1).map(|_| Value::number(grid(&args[0]).rows as f64)),
+        "COLUMNS" => arity(args, 1, 1).map(|_| Value::number(grid(&args[0]).cols as f64)),
+
+        _ => Err(FormulaError::Name),
+    };
+
+    match result {
+        Ok(Value::Number(n, _)) if !n.is_finite() => Value::Error(FormulaError::Num),
+        Ok(value) => value,
+        Err(error) => Value::Error(error),
+    }
+}
+
+fn arity(args: &[Operand], min: usize, max: usize) -> Result<(), FormulaError> {
+    if (min..=max).contains(&args.len()) {
+        Ok(())
+    } else {
+        Err(FormulaError::Value)
+    }
+}
+
+fn arg(args: &[Operand], i: usize) -> Value {
+    args.get(i).cloned().map(scalar).unwrap_or(Value::Blank)
+}
+
+fn number(args: &[Operand], i: usize) -> Result<f64, FormulaError> {
+    to_number(&arg(args, i))
+}
+
+fn optional_number(args: &[Operand], i: usize, default: f64) -> Result<f64, FormulaError> {
+    match args.get(i) {
+        Some(_) if !matches!(arg(args, i), Value::Blank) => number(args, i),
+        _ => Ok(default),
+    }
+}
+
+fn text(args: &[Operand], i: usize) -> Result<String, FormulaError> {
+    to_text(&arg(args, i))
+}
+
+fn text_arg(args: &[Operand], min: usize, max: usize) -> Result<String, FormulaError> {
+    arity(args, min, max)?;
+    text(args, 0)
+}
+
+fn grid(operand: &Operand) -> Cow<'_, Grid> {
+    match operand {
+        Operand::Range(grid) => Cow::Borrowed(grid),
+        Operand::Value(value) => Cow::Owned(Grid {
+            rows: 1,
+            cols: 1,
+            values: vec![value.clone()],
+        }),
+    }
+}
+
+/// Numbers for aggregate functions. In ranges only numeric cells count;
+/// values passed directly must convert to numbers.
+fn numbers(args: &[Operand]) -> Result<Vec<f64>, FormulaError> {
+    let mut numbers = Vec::new();
+    for operand in args {
+        match operand {
+            Operand::Range(grid) => {
+                for value in &grid.values {
+                    match value {
+                        Value::Number(n, _) => numbers.push(*n),
+                        Value::Error(error) => return Err(*error),
+                        _ => {}
+                    }
+                }
+            }
+            Operand::Value(Value::Blank) => {}
+            Operand::Value(value) => numbers.push(to_number(value)?),
+        }
+    }
+    Ok(numbers)
+}
+
+fn math(args: &[Operand], f: fn(f64) -> f64) -> FnResult {
+    arity(args, 1, 1)?;
+    Ok(Value::number(f(number(args, 0)?)))
+}
+
+fn round_away(n: f64) -> f64 {
+    if n < 0.0 {
+        n.floor()
+    } else {
+        n.ceil()
+    }
+}
+
+fn odd(n: f64) -> f64 {
+    let rounded = round_away(n);
+    if rounded % 2.0 == 0.0 {
+        rounded + if n < 0.0 { -1.0 } else { 1.0 }
+    } else {
+        rounded
+    }
+}
+
+fn fact(args: &[Operand]) -> FnResult {
+    arity(args, 1, 1)?;
+    let n = number(args, 0)?.trunc();
+    if n < 0.0 {
+        return Err(FormulaError::Num);
+    }
+    Ok(Value::number(
+        (1..=n.min(171.0) as u32).map(f64::from).product(),
+    ))
+}
+
+fn atan2(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    let (x, y) = (number(args, 0)?, number(args, 1)?);
+    if x == 0.0 && y == 0.0 {
+        return Err(FormulaError::Div0);
+    }
+    Ok(Value::number(y.atan2(x)))
+}
+
+fn log(args: &[Operand]) -> FnResult {
+    arity(args, 1, 2)?;
+    let n = number(args, 0)?;
+    let base = optional_number(args, 1, 10.0)?;
+    if n <= 0.0 || base <= 0.0 || base == 1.0 {
+        return Err(FormulaError::Num);
+    }
+    Ok(Value::number(n.log(base)))
+}
+
+fn modulo(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    let (n, d) = (number(args, 0)?, number(args, 1)?);
+    if d == 0.0 {
+        return Err(FormulaError::Div0);
+    }
+    Ok(Value::number(n - d * (n / d).floor()))
+}
+
+fn power(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    let (base, exponent) = (number(args, 0)?, number(args, 1)?);
+    if base == 0.0 && exponent <= 0.0 {
+        return Err(if exponent == 0.0 {
+            FormulaError::Num
+        } else {
+            FormulaError::Div0
+        });
+    }
+    Ok(Value::number(base.powf(exponent)))
+}
+
+fn round(args: &[Operand], f: fn(f64) -> f64) -> FnResult {
+    arity(args, 1, 2)?;
+    let n = number(args, 0)?;
+    let digits = optional_number(args, 1, 0.0)?.trunc();
+    let scale = 10f64.powf(digits);
+    Ok(Value::number(f(n * scale) / scale))
+}
+
+fn multiple(args: &[Operand], f: fn(f64) -> f64) -> FnResult {
+    arity(args, 1, 2)?;
+    let n = number(args, 0)?;
+    let significance = optional_number(args, 1, if n < 0.0 { -1.0 } else { 1.0 })?;
+    if significance == 0.0 {
+        return Ok(Value::number(0.0));
+    }
+    if n * significance < 0.0 {
+        return Err(FormulaError::Num);
+    }
+    Ok(Value::number(f(n / significance) * significance))
+}
+
+fn product(args: &[Operand]) -> FnResult {
+    let numbers = numbers(args)?;
+    if numbers.is_empty() {
+        return Ok(Value::number(0.0));
+    }
+    Ok(Value::number(numbers.iter().product()))
+}
+
+fn sumproduct(args: &[Operand]) -> FnResult {
+    if args.is_empty() {
+        return Err(FormulaError::Value);
+    }
+    let grids: Vec<Cow<'_, Grid>> = args.iter().map(grid).collect();
+    let (rows, cols) = (grids[0].rows, grids[0].cols);
+    if grids.iter().any(|g| g.rows != rows || g.cols != cols) {
+        return Err(FormulaError::Value);
+    }
+
+    let mut total = 0.0;
+    for i in 0..rows * cols {
+        let mut product = 1.0;
+        for grid in &grids {
+            product *= match &grid.values[i] {
+                Value::Number(n, _) => *n,
+                Value::Error(error) => return Err(*error),
+                _ => 0.0,
+            };
+        }
+        total += product;
+    }
+    Ok(Value::number(total))
+}
+
+/// A COUNTIF/SUMIF criterion such as `">=10"`, `"apple"` or `"a*"`.
+struct Criteria {
+    op: fn(Ordering) -> bool,
+    target: Value,
+}
+
+impl Criteria {
+    fn parse(value: Value) -> Self {
+        let Value::Text(text) = value else {
+            return Criteria {
+                op: Ordering::is_eq,
+                target: value,
+            };
+        };
+
+        let (op, rest): (fn(Ordering) -> bool, &str) = [
+            ("<=", Ordering::is_le as fn(Ordering) -> bool),
+            (">=", Ordering::is_ge),
+            ("<>", Ordering::is_ne),
+            ("<", Ordering::is_lt),
+            (">", Ordering::is_gt),
+            ("=", Ordering::is_eq),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op)| text.strip_prefix(prefix).map(|rest| (op, rest)))
+        .unwrap_or((Ordering::is_eq, text.as_str()));
+
+        let target = match rest.trim().parse::<f64>() {
+            Ok(n) => Value::number(n),
+            Err(_) => Value::Text(rest.to_string()),
+        };
+        Criteria { op, target }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match (&self.target, value) {
+            (Value::Number(a, _), Value::Number(b, _)) => (self.op)(b.total_cmp(a)),
+            (Value::Text(pattern), Value::Text(text)) if is_equality(self.op) => {
+                wildcard_match(&pattern.to_lowercase(), &text.to_lowercase())
+                    == (self.op)(Ordering::Equal)
+            }
+            (Value::Text(pattern), Value::Blank) if pattern.is_empty() => {
+                (self.op)(Ordering::Equal)
+            }
+            (Value::Text(_), Value::Text(_)) => (self.op)(compare(value, &self.target)),
+            (Value::Blank, Value::Blank) => (self.op)(Ordering::Equal),
+            // Values of different types never compare equal
+            _ => {
+                !(self.op)(Ordering::Equal)
+                    && (self.op)(Ordering::Less)
+                    && (self.op)(Ordering::Greater)
+            }
+        }
+    }
+}
+
+fn is_equality(op: fn(Ordering) -> bool) -> bool {
+    op(Ordering::Equal) != op(Ordering::Less) && op(Ordering::Less) == op(Ordering::Greater)
+}
+
+/// Matches `*` and `?` wildcards against the whole of `text`.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn sumif(args: &[Operand]) -> FnResult {
+    arity(args, 2, 3)?;
+    let range = grid(&args[0]);
+    let criteria = Criteria::parse(arg(args, 1));
+    let sum_range = args.get(2).map(grid).unwrap_or_else(|| range.clone());
+
+    let mut total = 0.0;
+    for row in 0..range.rows {
+        for col in 0..range.cols {
+            if !criteria.matches(range.get(row, col).unwrap_or(&Value::Blank)) {
+                continue;
+            }
+            match sum_range.get(row, col) {
+                Some(Value::Number(n, _)) => total += n,
+                Some(Value::Error(error)) => return Err(*error),
+                _ => {}
+            }
+        }
+    }
+    Ok(Value::number(total))
+}
+
+fn countif(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    let criteria = Criteria::parse(arg(args, 1));
+    let count = grid(&args[0])
+        .values
+        .iter()
+        .filter(|value| criteria.matches(value))
+        .count();
+    Ok(Value::number(count as f64))
+}
+
+fn average(args: &[Operand]) -> FnResult {
+    let numbers = numbers(args)?;
+    if numbers.is_empty() {
+        return Err(FormulaError::Div0);
+    }
+    Ok(Value::number(
+        numbers.iter().sum::<f64>() / numbers.len() as f64,
+    ))
+}
+
+fn count(args: &[Operand]) -> FnResult {
+    let count: usize = args
+        .iter()
+        .map(|operand| match operand {
+            Operand::Range(grid) => grid
+                .values
+                .iter()
+                .filter(|value| matches!(value, Value::Number(..)))
+                .count(),
+            Operand::Value(value) => to_number(value).is_ok() as usize,
+        })
+        .sum();
+    Ok(Value::number(count as f64))
+}
+
+fn counta(args: &[Operand]) -> FnResult {
+    let count: usize = args
+        .iter()
+        .map(|operand| {
+            grid(operand)
+                .values
+                .iter()
+                .filter(|value| !matches!(value, Value::Blank))
+                .count()
+        })
+        .sum();
+    Ok(Value::number(count as f64))
+}
+
+fn countblank(args: &[Operand]) -> FnResult {
+    arity(args, 1, 1)?;
+    let count = grid(&args[0])
+        .values
+        .iter()
+        .filter(|value| match value {
+            Value::Blank => true,
+            Value::Text(text) => text.is_empty(),
+            _ => false,
+        })
+        .count();
+    Ok(Value::number(count as f64))
+}
+
+fn median(args: &[Operand]) -> FnResult {
+    let mut numbers = numbers(args)?;
+    if numbers.is_empty() {
+        return Err(FormulaError::Num);
+    }
+    numbers.sort_by(f64::total_cmp);
+    let mid = numbers.len() / 2;
+    Ok(Value::number(if numbers.len() % 2 == 0 {
+        (numbers[mid - 1] + numbers[mid]) / 2.0
+    } else {
+        numbers[mid]
+    }))
+}
+
+fn kth(args: &[Operand], largest: bool) -> FnResult {
+    arity(args, 2, 2)?;
+    let mut numbers = numbers(&args[..1])?;
+    let k = number(args, 1)?.ceil();
+    if k < 1.0 || k as usize > numbers.len() {
+        return Err(FormulaError::Num);
+    }
+    numbers.sort_by(f64::total_cmp);
+    if largest {
+        numbers.reverse();
+    }
+    Ok(Value::number(numbers[k as usize - 1]))
+}
+
+/// Variance with `ddof` = 1 for a sample, 0 for a population.
+fn variance(args: &[Operand], ddof: usize) -> Result<f64, FormulaError> {
+    let numbers = numbers(args)?;
+    if numbers.len() <= ddof {
+        return Err(FormulaError::Div0);
+    }
+    let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+    let squares: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+    Ok(squares / (numbers.len() - ddof) as f64)
+}
+
+fn if_(args: &[Operand]) -> FnResult {
+    arity(args, 2, 3)?;
+    if to_bool(&arg(args, 0))? {
+        Ok(arg(args, 1))
+    } else if args.len() == 3 {
+        Ok(arg(args, 2))
+    } else {
+        Ok(Value::bool(false))
+    }
+}
+
+fn iferror(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    match arg(args, 0) {
+        Value::Error(_) => Ok(arg(args, 1)),
+        value => Ok(value),
+    }
+}
+
+fn logical(args: &[Operand], all: bool) -> FnResult {
+    let mut seen = false;
+    let mut result = all;
+    for operand in args {
+        let values: Vec<bool> = match operand {
+            Operand::Range(grid) => grid
+                .values
+                .iter()
+                .filter(|value| matches!(value, Value::Number(..) | Value::Error(_)))
+                .map(to_bool)
+                .collect::<Result<_, _>>()?,
+            Operand::Value(value) => vec![to_bool(value)?],
+        };
+        for value in values {
+            seen = true;
+            result = if all {
+                result && value
+            } else {
+                result || value
+            };
+        }
+    }
+    if !seen {
+        return Err(FormulaError::Value);
+    }
+    Ok(Value::bool(result))
+}
+
+fn is(args: &[Operand], test: fn(&Value) -> bool) -> FnResult {
+    arity(args, 1, 1)?;
+    Ok(Value::bool(test(&arg(args, 0))))
+}
+
+fn count_arg(args: &[Operand], i: usize, default: f64) -> Result<usize, FormulaError> {
+    let n = optional_number(args, i, default)?.trunc();
+    if n < 0.0 {
+        return Err(FormulaError::Value);
+    }
+    Ok(n as usize)
+}
+
+fn left_right(args: &[Operand], left: bool) -> FnResult {
+    let text = text_arg(args, 1, 2)?;
+    let n = count_arg(args, 1, 1.0)?;
+    let chars: Vec<char> = text.chars().collect();
+    let n = n.min(chars.len());
+    let slice = if left {
+        &chars[..n]
+    } else {
+        &chars[chars.len() - n..]
+    };
+    Ok(Value::Text(slice.iter().collect()))
+}
+
+fn mid(args: &[Operand]) -> FnResult {
+    let text = text_arg(args, 3, 3)?;
+    let start = number(args, 1)?.trunc();
+    if start < 1.0 {
+        return Err(FormulaError::Value);
+    }
+    let n = count_arg(args, 2, 0.0)?;
+    Ok(Value::Text(
+        text.chars().skip(start as usize - 1).take(n).collect(),
+    ))
+}
+
+fn proper(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut after_letter = false;
+    for c in text.chars() {
+        if after_letter {
+            out.extend(c.to_lowercase());
+        } else {
+            out.extend(c.to_uppercase());
+        }
+        after_letter = c.is_alphabetic();
+    }
+    out
+}
+
+fn rept(args: &[Operand]) -> FnResult {
+    let text = text_arg(args, 2, 2)?;
+    let n = count_arg(args, 1, 0.0)?;
+    if text.len().saturating_mul(n) > MAX_TEXT_LENGTH {
+        return Err(FormulaError::Value);
+    }
+    Ok(Value::Text(text.repeat(n)))
+}
+
+fn exact(args: &[Operand]) -> FnResult {
+    arity(args, 2, 2)?;
+    Ok(Value::bool(text(args, 0)? == text(args, 1)?))
+}
+
+fn find(args: &[Operand], ignore_case: bool) -> FnResult {
+    arity(args, 2, 3)?;
+    let (mut needle, mut haystack) = (text(args, 0)?, text(args, 1)?);
+    if ignore_case {
+        needle = needle.to_lowercase();
+        haystack = haystack.to_lowercase();
+    }
+    let start = optional_number(args, 2, 1.0)?.trunc();
+    let chars: Vec<char> = haystack.chars().collect();
+    if start < 1.0 || start as usize > chars.len() + 1 {
+        return Err(FormulaError::Value);
+    }
+
+    let needle: Vec<char> = needle.chars().collect();
+    (start as usize - 1..=chars.len())
+        .find(|&i| chars[i..].starts_with(&needle))
+        .map(|i| Value::number((i + 1) as f64))
+        .ok_or(FormulaError::Value)
+}
+
+fn substitute(args: &[Operand]) -> FnResult {
+    arity(args, 3, 4)?;
+    let (text, old, new) = (text(args, 0)?, text(args, 1)?, text(args, 2)?);
+    if old.is_empty() {
+        return Ok(Value::Text(text));
+    }
+    if args.len() < 4 {
+        return Ok(Value::Text(text.replace(&old, &new)));
+    }
+
+    let instance = number(args, 3)?.trunc();
+    if instance < 1.0 {
+        return Err(FormulaError::Value);
+    }
+    match text.match_indices(&old).nth(instance as usize - 1) {
+        Some((at, _)) => Ok(Value::Text(format!(
+            "{}{}{}",
+            &text[..at],
+            new,
+            &text[at + old.len()..]
+        ))),
+        None => Ok(Value::Text(text)),
+    }
+}
+
+fn replace(args: &[Operand]) -> FnResult {
+    arity(args, 4, 4)?;
+    let text = text(args, 0)?;
+    let start = number(args, 1)?.trunc();
+    if start < 1.0 {
+        return Err(FormulaError::Value);
+    }
+    let n = count_arg(args, 2, 0.0)?;
+    let new = self::text(args, 3)?;
+
+    let chars: Vec<char> = text.chars().collect();
+    let start = (start as usize - 1).min(chars.len());
+    let end = start.saturating_add(n).min(chars.len());
+    let mut out: String = chars[..start].iter().collect();
+    out.push_str(&new);
+    out.extend(&chars[end..]);
+    Ok(Value::Text(out))
+}
+
+fn concatenate(args: &[Operand]) -> FnResult {
+    let mut out = String::new();
+    for i in 0..args.len() {
+        out.push_str(&text(args, i)?);
+        if out.len() > MAX_TEXT_LENGTH {
+            return Err(FormulaError::Value);
+        }
+    }
+    Ok(Value::Text(out))
+}
+
+fn char_(args: &[Operand]) -> FnResult {
+    arity(args, 1, 1)?;
+    let code = number(args, 0)?.trunc();
+    if !(1.0..=255.0).contains(&code) {
+        return Err(FormulaError::Value);
+    }
+    char::from_u32(code as u32)
+        .map(|c| Value::Text(c.to_string()))
+        .ok_or(FormulaError::Value)
+}
+
+fn code(args: &[Operand]) -> FnResult {
+    let text = text_arg(args, 1, 1)?;
+    text.chars()
+        .next()
+        .map(|c| Value::number(c as u32 as f64))
+        .ok_or(FormulaError::Value)
+}
+
+/// Day zero of spreadsheet date serial numbers.
+fn epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1899, 12, 30).unwrap()
+}
+
+fn to_serial(date: NaiveDate) -> f64 {
+    (date - epoch()).num_days() as f64
+}
+
+fn from_serial(serial: f64) -> Result<NaiveDate, FormulaError> {
+    if serial < 0.0 {
+        return Err(FormulaError::Num);
+    }
+    Duration::try_days(serial.floor() as i64)
+        .and_then(|days| epoch().checked_add_signed(days))
+        .ok_or(FormulaError::Num)
+}
+
+fn date(args: &[Operand]) -> FnResult {
+    arity(args, 3, 3)?;
+    let mut year = number(args, 0)?.trunc() as i64;
+    let month = number(args, 1)?.trunc() as i64;
+    let day = number(args, 2)?.trunc() as i64;
+    if (0..1900).contains(&year) {
+        year += 1900;
+    }
+
+    // Months and days outside their usual range roll over
+    let months = year
+        .checked_mul(12)
+        .and_then(|months| months.checked_add(month.saturating_sub(1)))
+        .ok_or(FormulaError::Num)?;
+    let first = i32::try_from(months.div_euclid(12))
+        .ok()
+        .and_then(|year| NaiveDate::from_ymd_opt(year, months.rem_euclid(12) as u32 + 1, 1))
+        .ok_or(FormulaError::Num)?;
+    let date = Duration::try_days(day.saturating_sub(1))
+        .and_then(|days| first.checked_add_signed(days))
+        .ok_or(FormulaError::Num)?;
+    if date < epoch() {
+        return Err(FormulaError::Num);
+    }
+    Ok(Value::Number(to_serial(date), NumberKind::Date))
+}
+
+fn time(args: &[Operand]) -> FnResult {
+    arity(args, 3, 3)?;
+    let seconds = number(args, 0)?.trunc() * 3600.0
+        + number(args, 1)?.trunc() * 60.0
+        + number(args, 2)?.trunc();
+    if seconds < 0.0 {
+        return Err(FormulaError::Num);
+    }
+    Ok(Value::Number(
+        (seconds % 86400.0) / 86400.0,
+        NumberKind::Time,
+    ))
+}
+
+fn now(kind: NumberKind) -> Value {
+    let now = Utc::now().naive_utc();
+    let days = to_serial(now.date());
+    if kind == NumberKind::Date {
+        return Value::Number(days, kind);
+    }
+    Value::Number(
+        days + now.num_seconds_from_midnight() as f64 / 86400.0,
+        kind,
+    )
+}
+
+fn date_part(args: &[Operand], part: fn(NaiveDate) -> f64) -> FnResult {
+    arity(args, 1, 1)?;
+    Ok(Value::number(part(from_serial(number(args, 0)?)?)))
+}
+
+fn weekday(args: &[Operand]) -> FnResult {
+    arity(args, 1, 2)?;
+    let date = from_serial(number(args, 0)?)?;
+    let from_sunday = date.weekday().num_days_from_sunday() as f64;
+    let from_monday = date.weekday().num_days_from_monday() as f64;
+    match optional_number(args, 1, 1.0)?.trunc() as i64 {
+        1 => Ok(Value::number(from_sunday + 1.0)),
+        2 => Ok(Value::number(from_monday + 1.0)),
+        3 => Ok(Value::number(from_monday)),
+        _ => Err(FormulaError::Num),
+    }
+}
+
+fn time_part(args: &[Operand], part: fn(i64) -> i64) -> FnResult {
+    arity(args, 1, 1)?;
+    let serial = number(args, 0)?;
+    if serial < 0.0 {
+        return Err(FormulaError::Num);
+    }
+    let seconds = (serial.fract() * 86400.0).round() as i64 % 86400;
+    Ok(Value::number(part(seconds) as f64))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MatchMode {
+    Exact,
+    /// The last position not greater than the value, in ascending data.
+    LessOrEqual,
+    /// The last position not less than the value, in descending data.
+    GreaterOrEqual,
+}
+
+fn find_position<'a>(
+    candidates: impl Iterator<Item = &'a Value>,
+    value: &Value,
+    mode: MatchMode,
+) -> Option<usize> {
+    let same_type = |candidate: &Value| {
+        matches!(
+            (candidate, value),
+            (Value::Number(..), Value::Number(..)) | (Value::Text(_), Value::Text(_))
+        )
+    };
+
+    let mut found = None;
+    for (i, candidate) in candidates.enumerate() {
+        if !same_type(candidate) {
+            continue;
+        }
+        let ordering = compare(candidate, value);
+        match mode {
+            MatchMode::Exact if ordering == Ordering::Equal => return Some(i),
+            MatchMode::Exact => {}
+            MatchMode::LessOrEqual if ordering == Ordering::Greater => break,
+            MatchMode::GreaterOrEqual if ordering == Ordering::Less => break,
+            _ => found = Some(i),
+        }
+    }
+    found
+}
+
+fn table_lookup(args: &[Operand], horizontal: bool) -> FnResult {
+    arity(args, 3, 4)?;
+    let value = arg(args, 0);
+    if let Value::Error(error) = value {
+        return Err(error);
+    }
+    let table = grid(&args[1]);
+    let offset = number(args, 2)?.trunc();
+    let sorted = args.len() < 4 || to_bool(&arg(args, 3))?;
+    let (keys, width) = if horizontal {
+        (table.rows, table.cols)
+    } else {
+        (table.cols, table.rows)
+    };
+    if offset < 1.0 {
+        return Err(FormulaError::Value);
+    }
+    if offset as usize > keys {
+        return Err(FormulaError::Ref);
+    }
+
+    let key = |i: usize| {
+        if horizontal {
+            table.get(0, i)
+        } else {
+            table.get(i, 0)
+        }
+    };
+    let mode = if sorted {
+        MatchMode::LessOrEqual
+    } else {
+        MatchMode::Exact
+    };
+    let position =
+        find_position((0..width).filter_map(key), &value, mode).ok_or(FormulaError::NA)?;
+
+    let result = if horizontal {
+        table.get(offset as usize - 1, position)
+    } else {
+        table.get(position, offset as usize - 1)
+    };
+    Ok(result.cloned().unwrap_or(Value::Blank))
+}
+
+/// A one-dimensional view of a row or column range.
+fn vector(grid: &Grid) -> Option<Vec<&Value>> {
+    if grid.rows == 1 || grid.cols == 1 {
+        Some(grid.values.iter().collect())
+    } else {
+        None
+    }
+}
+
+fn lookup(args: &[Operand]) -> FnResult {
+    arity(args, 2, 3)?;
+    let value = arg(args, 0);
+    if let Value::Error(error) = value {
+        return Err(error);
+    }
+    let keys = grid(&args[1]);
+    let keys = vector(&keys).ok_or(FormulaError::NA)?;
+    let position = find_position(keys.iter().copied(), &value, MatchMode::LessOrEqual)
+        .ok_or(FormulaError::NA)?;
+
+    match args.get(2) {
+        Some(results) => {
+            let results = grid(results);
+            let results = vector(&results).ok_or(FormulaError::NA)?;
+            Ok(results
+                .get(position)
+                .map(|v| (*v).clone())
+                .unwrap_or(Value::Blank))
+        }
+        None => Ok(keys[position].clone()),
+    }
+}
+
+fn match_(args: &[Operand]) -> FnResult {
+    arity(args, 2, 3)?;
+    let value = arg(args, 0);
+    if let Value::Error(error) = value {
+        return Err(error);
+    }
+    let range = grid(&args[1]);
+    let candidates = vector(&range).ok_or(FormulaError::NA)?;
+    let mode = match optional_number(args, 2, 1.0)? {
+        n if n > 0.0 => MatchMode::LessOrEqual,
+        n if n < 0.0 => MatchMode::GreaterOrEqual,
+        _ => MatchMode::Exact,
+    };
+    find_position(candidates.into_iter(), &value, mode)
+        .map(|i| Value::number((i + 1) as f64))
+        .ok_or(FormulaError::NA)
+}
+
+fn index(args: &[Operand]) -> FnResult {
+    arity(args, 2, 3)?;
+    let range = grid(&args[0]);
+    let mut row = number(args, 1)?.trunc();
+    let mut col = optional_number(args, 2, 1.0)?.trunc();
+    // A single row is indexed by column
+    if range.rows == 1 && args.len() == 2 {
+        (row, col) = (1.0, row);
+    }
+    if row < 1.0 || col < 1.0 {
+        return Err(FormulaError::Value);
+    }
+    range
+        .get(row as usize - 1, col as usize - 1)
+        .cloned()
+        .ok_or(FormulaError::Ref)
+}
+
+fn choose(args: &[Operand]) -> FnResult {
+    if args.len() < 2 {
+        return Err(FormulaError::Value);
+    }
+    let i = number(args, 0)?.trunc();
+    if i < 1.0 || i as usize >= args.len() {
+        return Err(FormulaError::Value);
+    }
+    Ok(arg(args, i as usize))
+}
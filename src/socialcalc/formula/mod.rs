@@ -0,0 +1,785 @@
+//! Server-side recalculation of SocialCalc formulas.
+
+mod eval;
+mod functions;
+mod parser;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use super::{Cell, CellCoord, DataType, NamedRange, Sheet};
+use eval::{Evaluator, Names, Workbook};
+use parser::Expr;
+
+/// SocialCalc's error values, stored in a cell's value type as `e#DIV/0!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormulaError {
+    Null,
+    Div0,
+    Value,
+    Ref,
+    Name,
+    Num,
+    NA,
+}
+
+impl FormulaError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FormulaError::Null => "#NULL!",
+            FormulaError::Div0 => "#DIV/0!",
+            FormulaError::Value => "#VALUE!",
+            FormulaError::Ref => "#REF!",
+            FormulaError::Name => "#NAME?",
+            FormulaError::Num => "#NUM!",
+            FormulaError::NA => "#N/A",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            FormulaError::Null,
+            FormulaError::Div0,
+            FormulaError::Value,
+            FormulaError::Ref,
+            FormulaError::Name,
+            FormulaError::Num,
+            FormulaError::NA,
+        ]
+        .into_iter()
+        .find(|error| error.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// The sub-type SocialCalc keeps on numbers, which decides how a value is
+/// displayed when the cell has no explicit format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberKind {
+    Plain,
+    Logical,
+    Date,
+    Time,
+    DateTime,
+    Percent,
+    Currency,
+}
+
+impl NumberKind {
+    pub fn valuetype(&self) -> &'static str {
+        match self {
+            NumberKind::Plain => "n",
+            NumberKind::Logical => "nl",
+            NumberKind::Date => "nd",
+            NumberKind::Time => "nt",
+            NumberKind::DateTime => "ndt",
+            NumberKind::Percent => "n%",
+            NumberKind::Currency => "n$",
+        }
+    }
+
+    fn from_valuetype(valuetype: &str) -> Self {
+        match valuetype {
+            "nl" => NumberKind::Logical,
+            "nd" => NumberKind::Date,
+            "nt" => NumberKind::Time,
+            "ndt" => NumberKind::DateTime,
+            "n%" => NumberKind::Percent,
+            "n$" => NumberKind::Currency,
+            _ => NumberKind::Plain,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Blank,
+    Number(f64, NumberKind),
+    Text(String),
+    Error(FormulaError),
+}
+
+impl Value {
+    pub fn number(n: f64) -> Self {
+        Value::Number(n, NumberKind::Plain)
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Value::Number(if b { 1.0 } else { 0.0 }, NumberKind::Logical)
+    }
+
+    /// The value a cell currently holds, as last saved or recalculated.
+    pub fn from_cell(cell: &Cell) -> Self {
+        match cell.datatype {
+            DataType::Empty => Value::Blank,
+            DataType::Text => Value::Text(cell.datavalue.clone()),
+            DataType::Value | DataType::Formula | DataType::Constant => {
+                let valuetype = cell.valuetype.as_str();
+                if let Some(error) = valuetype.strip_prefix('e') {
+                    Value::Error(FormulaError::from_name(error).unwrap_or(FormulaError::Value))
+                } else if valuetype.starts_with('t') {
+                    Value::Text(cell.datavalue.clone())
+                } else if valuetype == "b" {
+                    Value::Blank
+                } else {
+                    match cell.datavalue.trim().parse::<f64>() {
+                        Ok(n) => Value::Number(n, NumberKind::from_valuetype(valuetype)),
+                        Err(_) if cell.datavalue.is_empty() => Value::Blank,
+                        Err(_) => Value::Text(cell.datavalue.clone()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// The `(valuetype, datavalue)` pair stored for this value.
+    pub fn to_cell_value(&self) -> (String, String) {
+        match self {
+            // A formula that evaluates to a blank cell shows as zero
+            Value::Blank => ("n".to_string(), "0".to_string()),
+            Value::Number(n, kind) => (kind.valuetype().to_string(), format_number(*n)),
+            Value::Text(text) => ("t".to_string(), text.clone()),
+            Value::Error(error) => (format!("e{}", error.as_str()), error.as_str().to_string()),
+        }
+    }
+}
+
+/// The value as spreadsheet text: logicals as `TRUE`/`FALSE`, errors by
+/// name.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Blank => Ok(()),
+            Value::Number(n, NumberKind::Logical) => {
+                f.write_str(if *n != 0.0 { "TRUE" } else { "FALSE" })
+            }
+            Value::Number(n, _) => f.write_str(&format_number(*n)),
+            Value::Text(text) => f.write_str(text),
+            Value::Error(error) => f.write_str(error.as_str()),
+        }
+    }
+}
+
+/// Formats like JavaScript's `Number.toString`, which is what SocialCalc
+/// writes: integers without a fractional part.
+pub fn format_number(n: f64) -> String {
+    if n == 0.0 {
+        "0".to_string()
+    } else {
+        n.to_string()
+    }
+}
+
+/// Whether a formula can be evaluated here, which is also what decides
+/// whether it is carried over to and from other spreadsheet formats. Those
+/// hold one sheet, so references to other sheets are not carried over.
+pub fn check_formula(formula: &str) -> Result<(), String> {
+    check(&parser::parse(formula)?, false)
+}
+
+/// Like `check_formula`, for formulas that may refer to other sheets of a
+/// workbook.
+pub fn check_workbook_formula(formula: &str) -> Result<(), String> {
+    check(&parser::parse(formula)?, true)
+}
+
+fn check(expr: &Expr, other_sheets: bool) -> Result<(), String> {
+    match expr {
+        Expr::Call(name, args) => {
+            if !functions::is_known(name) {
+                return Err(format!("unsupported function {}", name));
+            }
+            args.iter().try_for_each(|arg| check(arg, other_sheets))
+        }
+        Expr::Sheet(..) if !other_sheets => {
+            Err("references to other sheets are not supported".to_string())
+        }
+        Expr::Negate(inner) | Expr::Percent(inner) => check(inner, other_sheets),
+        Expr::Binary(_, left, right) => {
+            check(left, other_sheets).and_then(|_| check(right, other_sheets))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A sheet name as written before `!` in a reference, quoted unless it is a
+/// plain identifier.
+pub fn quote_sheet_name(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.'));
+    if plain {
+        name.to_string()
+    } else {
+        format!("'{}'", name.replace('\'', "''"))
+    }
+}
+
+/// Rewrites references to sheet `old` in a formula to point at `new`,
+/// leaving string literals alone. `None` when nothing refers to `old`.
+pub fn rename_sheet_references(formula: &str, old: &str, new: &str) -> Option<String> {
+    let mut output = String::with_capacity(formula.len());
+    let mut renamed = false;
+    let mut chars = formula.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let name = match c {
+            '"' => {
+                chars.next();
+                output.push(c);
+                for c in chars.by_ref() {
+                    output.push(c);
+                    if c == '"' {
+                        break;
+                    }
+                }
+                continue;
+            }
+            '\'' => {
+                chars.next();
+                let mut name = String::new();
+                let mut written = String::from("'");
+                while let Some(c) = chars.next() {
+                    written.push(c);
+                    match c {
+                        '\'' if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            written.push('\'');
+                            name.push('\'');
+                        }
+                        '\'' => break,
+                        c => name.push(c),
+                    }
+                }
+                (name, written)
+            }
+            // Numbers such as 1E5 must not be read as identifiers
+            c if c.is_alphanumeric() || matches!(c, '$' | '_') => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || matches!(c, '$' | '_' | '.')) {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                (ident.clone(), ident)
+            }
+            _ => {
+                chars.next();
+                output.push(c);
+                continue;
+            }
+        };
+
+        let (name, written) = name;
+        if chars.peek() == Some(&'!') && name.to_uppercase() == old.to_uppercase() {
+            output.push_str(&quote_sheet_name(new));
+            renamed = true;
+        } else {
+            output.push_str(&written);
+        }
+    }
+
+    renamed.then_some(output)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecalcReport {
+    /// Formula cells evaluated.
+    pub evaluated: usize,
+    /// Formula cells whose value changed.
+    pub changed: usize,
+    /// Formula cells that evaluated to an error value.
+    pub errors: Vec<CellCoord>,
+    /// Cells that are part of a reference cycle.
+    pub circular: Vec<CellCoord>,
+}
+
+/// Recomputes every formula cell in dependency order, updating each cell's
+/// value type and value in place.
+pub fn recalc(sheet: &mut Sheet) -> RecalcReport {
+    let names = eval::parse_names(&sheet.names);
+    recalc_with(sheet, &names, None)
+}
+
+/// Recalculates the sheets of a workbook, given by name, so references
+/// between them see settled values. Names defined on a sheet take
+/// precedence over the workbook's `shared` names.
+pub fn recalc_workbook(
+    sheets: &mut [(&str, &mut Sheet)],
+    shared: &[NamedRange],
+) -> Vec<RecalcReport> {
+    let shared = eval::parse_names(shared);
+    let mut reports = vec![RecalcReport::default(); sheets.len()];
+
+    // Each pass reads the other sheets as the previous one left them, so a
+    // chain of references through every sheet settles within as many passes
+    for pass in 0..=sheets.len() {
+        let mut changed = false;
+        for i in 0..sheets.len() {
+            let mut sheet = std::mem::take(&mut *sheets[i].1);
+            let workbook = Workbook {
+                name: sheets[i].0,
+                sheets: sheets
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| *j != i)
+                    .map(|(_, (name, other))| (name.to_uppercase(), &**other))
+                    .collect(),
+            };
+            let mut names = eval::parse_names(&sheet.names);
+            for (name, definition) in &shared {
+                names
+                    .entry(name.clone())
+                    .or_insert_with(|| definition.clone());
+            }
+
+            let mut report = recalc_with(&mut sheet, &names, Some(&workbook));
+            *sheets[i].1 = sheet;
+            changed |= report.changed > 0;
+            if pass > 0 {
+                report.changed += reports[i].changed;
+            }
+            reports[i] = report;
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    reports
+}
+
+fn recalc_with(sheet: &mut Sheet, names: &Names, workbook: Option<&Workbook>) -> RecalcReport {
+    let formulas: BTreeMap<CellCoord, Result<Expr, String>> = sheet
+        .cells
+        .iter()
+        .filter(|(_, cell)| cell.datatype == DataType::Formula)
+        .map(|(coord, cell)| (*coord, parser::parse(&cell.formula)))
+        .collect();
+    let formula_cells: BTreeSet<CellCoord> = formulas.keys().copied().collect();
+
+    let mut evaluator = Evaluator::new(sheet, names);
+    if let Some(workbook) = workbook {
+        evaluator = evaluator.in_workbook(workbook);
+    }
+    let dependencies: HashMap<CellCoord, Vec<CellCoord>> = formulas
+        .iter()
+        .map(|(coord, expr)| {
+            let deps = match expr {
+                Ok(expr) => evaluator.dependencies(expr, &formula_cells),
+                Err(_) => Vec::new(),
+            };
+            (*coord, deps)
+        })
+        .collect();
+
+    let (order, circular) = evaluation_order(&formula_cells, &dependencies);
+
+    let mut report = RecalcReport {
+        circular: circular.iter().copied().collect(),
+        ..Default::default()
+    };
+    for coord in order {
+        let (value, errors) = if circular.contains(&coord) {
+            let errors = format!("Circular reference to {}", coord);
+            (Value::Error(FormulaError::Ref), Some(errors))
+        } else {
+            match &formulas[&coord] {
+                Ok(expr) => {
+                    let mut evaluator = Evaluator::new(sheet, names);
+                    if let Some(workbook) = workbook {
+                        evaluator = evaluator.in_workbook(workbook);
+                    }
+                    (evaluator.eval_cell(expr), None)
+                }
+                Err(reason) => {
+                    let errors = format!("Formula error: {}", reason);
+                    (Value::Error(FormulaError::Value), Some(errors))
+                }
+            }
+        };
+
+        let (valuetype, datavalue) = value.to_cell_value();
+        let Some(cell) = sheet.cells.get_mut(&coord) else {
+            continue;
+        };
+        if cell.valuetype != valuetype || cell.datavalue != datavalue || cell.errors != errors {
+            report.changed += 1;
+        }
+        if matches!(value, Value::Error(_)) {
+            report.errors.push(coord);
+        }
+        cell.valuetype = valuetype;
+        cell.datavalue = datavalue;
+        cell.errors = errors;
+        report.evaluated += 1;
+    }
+    report.errors.sort();
+
+    sheet.attributes.circularreferencecell = report.circular.first().map(|coord| coord.to_string());
+    if sheet.attributes.needsrecalc.is_some() {
+        sheet.attributes.needsrecalc = Some("no".to_string());
+    }
+
+    report
+}
+
+/// Orders formula cells so every cell comes after the cells it reads,
+/// collecting the members of any cycle. Iterative so that long chains of
+/// references cannot overflow the stack.
+fn evaluation_order(
+    cells: &BTreeSet<CellCoord>,
+    dependencies: &HashMap<CellCoord, Vec<CellCoord>>,
+) -> (Vec<CellCoord>, BTreeSet<CellCoord>) {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Mark {
+        Visiting,
+        Done,
+    }
+
+    let mut marks: HashMap<CellCoord, Mark> = HashMap::new();
+    let mut order = Vec::with_capacity(cells.len());
+    let mut circular = BTreeSet::new();
+
+    for &root in cells {
+        if marks.contains_key(&root) {
+            continue;
+        }
+        // Each frame is a cell and the index of its next dependency to visit
+        let mut stack: Vec<(CellCoord, usize)> = vec![(root, 0)];
+        marks.insert(root, Mark::Visiting);
+
+        while let Some((coord, next)) = stack.last_mut() {
+            let deps = &dependencies[coord];
+            if let Some(&dep) = deps.get(*next) {
+                *next += 1;
+                match marks.get(&dep) {
+                    None => {
+                        marks.insert(dep, Mark::Visiting);
+                        stack.push((dep, 0));
+                    }
+                    Some(Mark::Visiting) => {
+                        let start = stack.iter().position(|(c, _)| *c == dep).unwrap_or(0);
+                        circular.extend(stack[start..].iter().map(|(c, _)| *c));
+                    }
+                    Some(Mark::Done) => {}
+                }
+            } else {
+                let coord = *coord;
+                marks.insert(coord, Mark::Done);
+                order.push(coord);
+                stack.pop();
+            }
+        }
+    }
+
+    (order, circular)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(lines: &[&str]) -> Sheet {
+        Sheet::parse(&lines.join("\n")).unwrap()
+    }
+
+    fn value(sheet: &Sheet, coord: &str) -> Value {
+        Value::from_cell(&sheet.cells[&CellCoord::parse(coord).unwrap()])
+    }
+
+    fn evaluate(formula: &str) -> Value {
+        let mut sheet = sheet(&[
+            "cell:A1:v:10",
+            "cell:A2:v:20",
+            "cell:A3:t:apple",
+            "cell:A4:v:5",
+            "cell:B1:t:one",
+            "cell:B2:t:two",
+            "cell:B3:t:three",
+            "cell:B4:t:four",
+            &format!("cell:Z1:vtf:n:0:{}", super::super::encode_for_save(formula)),
+            "name:PRICES::A1\\cA4",
+        ]);
+        recalc(&mut sheet);
+        value(&sheet, "Z1")
+    }
+
+    #[test]
+    fn arithmetic_and_precedence() {
+        assert_eq!(evaluate("1+2*3"), Value::number(7.0));
+        assert_eq!(evaluate("(1+2)*3"), Value::number(9.0));
+        assert_eq!(evaluate("-2^2"), Value::number(4.0));
+        assert_eq!(evaluate("2^3^2"), Value::number(64.0));
+        assert_eq!(evaluate("A1/A4+1"), Value::number(3.0));
+        assert_eq!(evaluate("50%"), Value::Number(0.5, NumberKind::Percent));
+        assert_eq!(evaluate("\"a\"&1&TRUE"), Value::Text("a1TRUE".to_string()));
+        assert_eq!(evaluate("A1>=10"), Value::bool(true));
+        assert_eq!(evaluate("\"ABC\"=\"abc\""), Value::bool(true));
+        assert_eq!(evaluate("A9+1"), Value::number(1.0));
+    }
+
+    #[test]
+    fn error_values() {
+        assert_eq!(evaluate("1/0"), Value::Error(FormulaError::Div0));
+        assert_eq!(evaluate("A3+1"), Value::Error(FormulaError::Value));
+        assert_eq!(evaluate("NOSUCH(1)"), Value::Error(FormulaError::Name));
+        assert_eq!(evaluate("UNDEFINED+1"), Value::Error(FormulaError::Name));
+        assert_eq!(evaluate("SQRT(-1)"), Value::Error(FormulaError::Num));
+        assert_eq!(evaluate("#N/A"), Value::Error(FormulaError::NA));
+        assert_eq!(evaluate("Sheet2!A1"), Value::Error(FormulaError::Ref));
+        assert_eq!(
+            evaluate("IFERROR(1/0,\"none\")"),
+            Value::Text("none".into())
+        );
+        assert_eq!(evaluate("ISERROR(A3*2)"), Value::bool(true));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(evaluate("SUM(A1:A4,1)"), Value::number(36.0));
+        assert_eq!(evaluate("SUM(PRICES)"), Value::number(35.0));
+        assert_eq!(evaluate("AVERAGE(A1:A4)"), Value::number(35.0 / 3.0));
+        assert_eq!(evaluate("COUNT(A1:B4)"), Value::number(3.0));
+        assert_eq!(evaluate("COUNTA(A1:B4)"), Value::number(8.0));
+        assert_eq!(evaluate("COUNTIF(A1:A4,\">8\")"), Value::number(2.0));
+        assert_eq!(evaluate("SUMIF(A1:A4,\"<>20\")"), Value::number(15.0));
+        assert_eq!(evaluate("MAX(A1:A4)-MIN(A1:A4)"), Value::number(15.0));
+        assert_eq!(evaluate("MEDIAN(1,3,2,4)"), Value::number(2.5));
+        assert_eq!(
+            evaluate("ROUND(STDEV(2,4,4,4,5,5,7,9)^2,4)"),
+            Value::number(4.5714)
+        );
+        assert_eq!(
+            evaluate("IF(A1>5,\"big\",\"small\")"),
+            Value::Text("big".into())
+        );
+        assert_eq!(evaluate("AND(TRUE,A1)"), Value::bool(true));
+        assert_eq!(evaluate("OR(FALSE,0)"), Value::bool(false));
+        assert_eq!(
+            evaluate("MID(\"spreadsheet\",3,4)"),
+            Value::Text("read".into())
+        );
+        assert_eq!(
+            evaluate("PROPER(\"hello wORLD\")"),
+            Value::Text("Hello World".into())
+        );
+        assert_eq!(evaluate("TRIM(\"  a   b \")"), Value::Text("a b".into()));
+        assert_eq!(
+            evaluate("SUBSTITUTE(\"a-b-c\",\"-\",\"+\",2)"),
+            Value::Text("a-b+c".into())
+        );
+        assert_eq!(evaluate("FIND(\"p\",A3)"), Value::number(2.0));
+        assert_eq!(evaluate("LEN(A3)"), Value::number(5.0));
+        assert_eq!(
+            evaluate("VLOOKUP(20,A1:B4,2,FALSE)"),
+            Value::Text("two".into())
+        );
+        assert_eq!(
+            evaluate("VLOOKUP(99,A1:B2,2,FALSE)"),
+            Value::Error(FormulaError::NA)
+        );
+        assert_eq!(
+            evaluate("HLOOKUP(\"apple\",A3:B4,2,FALSE)"),
+            Value::number(5.0)
+        );
+        assert_eq!(evaluate("MATCH(\"three\",B1:B4,0)"), Value::number(3.0));
+        assert_eq!(evaluate("INDEX(A1:B4,2,2)"), Value::Text("two".into()));
+        assert_eq!(evaluate("CHOOSE(2,\"x\",\"y\")"), Value::Text("y".into()));
+        assert_eq!(evaluate("ROWS(A1:B4)*COLUMNS(A1:B4)"), Value::number(8.0));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            evaluate("DATE(2024,2,29)"),
+            Value::Number(45351.0, NumberKind::Date)
+        );
+        assert_eq!(evaluate("DATE(2023,14,1)"), evaluate("DATE(2024,2,1)"));
+        assert_eq!(
+            evaluate("YEAR(45351)*100+MONTH(45351)"),
+            Value::number(202402.0)
+        );
+        assert_eq!(evaluate("DAY(45351)"), Value::number(29.0));
+        assert_eq!(evaluate("WEEKDAY(45351)"), Value::number(5.0));
+        assert_eq!(evaluate("HOUR(TIME(13,45,0))"), Value::number(13.0));
+        assert_eq!(evaluate("MINUTE(0.5+TIME(0,30,0))"), Value::number(30.0));
+        for formula in [
+            "DATE(1E18,1,1)",
+            "DATE(2024,-1E18,1)",
+            "DATE(2024,1,1E18)",
+            "DATE(2024,1,-1E18)",
+            "YEAR(1E18)",
+        ] {
+            assert_eq!(
+                evaluate(formula),
+                Value::Error(FormulaError::Num),
+                "{}",
+                formula
+            );
+        }
+    }
+
+    #[test]
+    fn evaluates_in_dependency_order() {
+        let mut sheet = sheet(&[
+            "cell:A1:vtf:n:0:A2*2",
+            "cell:A2:vtf:n:0:A3+1",
+            "cell:A3:v:4",
+            "cell:B1:vtf:n:0:SUM(A1\\cA3)",
+        ]);
+        let report = recalc(&mut sheet);
+        assert_eq!(value(&sheet, "A1"), Value::number(10.0));
+        assert_eq!(value(&sheet, "B1"), Value::number(19.0));
+        assert_eq!(report.evaluated, 3);
+        assert_eq!(report.changed, 3);
+        assert!(report.circular.is_empty());
+
+        assert_eq!(recalc(&mut sheet).changed, 0);
+    }
+
+    #[test]
+    fn detects_circular_references() {
+        let mut sheet = sheet(&[
+            "cell:A1:vtf:n:0:B1+1",
+            "cell:B1:vtf:n:0:A1+1",
+            "cell:C1:vtf:n:0:A1*2",
+            "cell:D1:vtf:n:0:SUM(D1\\cD2)",
+            "cell:E1:vtf:n:0:1+1",
+        ]);
+        let report = recalc(&mut sheet);
+        let coords = |names: &[&str]| -> Vec<CellCoord> {
+            names.iter().map(|c| CellCoord::parse(c).unwrap()).collect()
+        };
+        assert_eq!(report.circular, coords(&["A1", "B1", "D1"]));
+        assert_eq!(value(&sheet, "C1"), Value::Error(FormulaError::Ref));
+        assert_eq!(value(&sheet, "E1"), Value::number(2.0));
+        assert_eq!(
+            sheet.attributes.circularreferencecell.as_deref(),
+            Some("A1")
+        );
+        let a1 = &sheet.cells[&CellCoord::parse("A1").unwrap()];
+        assert_eq!(a1.valuetype, "e#REF!");
+        assert!(a1
+            .errors
+            .as_deref()
+            .unwrap()
+            .starts_with("Circular reference"));
+    }
+
+    #[test]
+    fn long_reference_chains_do_not_overflow() {
+        let mut lines = vec!["cell:A1:v:1".to_string()];
+        for row in 2..=20_000 {
+            lines.push(format!("cell:A{}:vtf:n:0:A{}+1", row, row - 1));
+        }
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut sheet = sheet(&lines);
+        recalc(&mut sheet);
+        assert_eq!(value(&sheet, "A20000"), Value::number(20_000.0));
+    }
+
+    #[test]
+    fn checks_formulas_for_unsupported_functions() {
+        assert_eq!(check_formula("SUM(A1:A3)*2"), Ok(()));
+        assert_eq!(check_formula("IF(A1>0,LEFT(B1,2),\"\")"), Ok(()));
+        assert_eq!(
+            check_formula("1+XIRR(A1:A3)"),
+            Err("unsupported function XIRR".to_string())
+        );
+        assert!(check_formula("1+").is_err());
+        assert!(check_formula("Costs!A1*2").is_err());
+        assert_eq!(check_workbook_formula("Costs!A1*2"), Ok(()));
+    }
+
+    #[test]
+    fn references_between_sheets_settle() {
+        // Summary reads Costs, which reads Rates, listed in the worst order
+        let mut summary = sheet(&[
+            "cell:A1:vtf:n:0:SUM(Costs!A1\\cA2)*TAX",
+            "cell:A2:vtf:n:0:Summary!A1+'Costs'!A1",
+        ]);
+        let mut costs = sheet(&["cell:A1:vtf:n:0:Rates!A1*2", "cell:A2:v:5"]);
+        let mut rates = sheet(&["cell:A1:v:10"]);
+        let shared = [NamedRange {
+            name: "TAX".to_string(),
+            description: String::new(),
+            definition: "=Rates!A1/10".to_string(),
+        }];
+
+        let reports = recalc_workbook(
+            &mut [
+                ("Summary", &mut summary),
+                ("Costs", &mut costs),
+                ("Rates", &mut rates),
+            ],
+            &shared,
+        );
+        assert_eq!(value(&summary, "A1"), Value::number(25.0));
+        assert_eq!(value(&summary, "A2"), Value::number(45.0));
+        assert!(reports.iter().all(|report| report.errors.is_empty()));
+
+        let mut alone = sheet(&["cell:A1:vtf:n:0:Costs!A1"]);
+        recalc(&mut alone);
+        assert_eq!(value(&alone, "A1"), Value::Error(FormulaError::Ref));
+    }
+
+    #[test]
+    fn renames_sheet_references() {
+        assert_eq!(
+            rename_sheet_references("Data!A1+'data'!B2&\"Data!C3\"", "DATA", "New Data"),
+            Some("'New Data'!A1+'New Data'!B2&\"Data!C3\"".to_string())
+        );
+        assert_eq!(
+            rename_sheet_references("'It''s'!A1", "It's", "Plain"),
+            Some("Plain!A1".to_string())
+        );
+        assert_eq!(rename_sheet_references("Data2!A1+Data", "Data", "X"), None);
+        assert_eq!(quote_sheet_name("It's"), "'It''s'");
+    }
+
+    #[test]
+    fn rejects_deeply_nested_formulas() {
+        fn on_small_stack<T: Send + 'static>(run: impl FnOnce() -> T + Send + 'static) -> T {
+            std::thread::Builder::new()
+                .stack_size(2 << 20)
+                .spawn(run)
+                .unwrap()
+                .join()
+                .unwrap()
+        }
+        let depth = parser::MAX_DEPTH - 1;
+        let nested = format!("{}1{}", "ABS(".repeat(depth), ")".repeat(depth));
+        let chain = format!("1{}", "+1".repeat(depth));
+        let (nested, chain) = on_small_stack(move || (evaluate(&nested), evaluate(&chain)));
+        assert_eq!(nested, Value::number(1.0));
+        assert_eq!(chain, Value::number(parser::MAX_DEPTH as f64));
+
+        // A name used inside itself nests as deep as evaluation allows
+        let looped = format!("={}LOOP{}", "ABS(".repeat(depth), ")".repeat(depth));
+        let looped = on_small_stack(move || {
+            let mut sheet = sheet(&[
+                "cell:A1:vtf:n:0:LOOP",
+                &format!("name:LOOP::{}", super::super::encode_for_save(&looped)),
+            ]);
+            recalc(&mut sheet);
+            value(&sheet, "A1")
+        });
+        assert_eq!(looped, Value::Error(FormulaError::Name));
+
+        for formula in [
+            format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000)),
+            format!("{}1", "-".repeat(10_000)),
+            format!("1{}", "+1".repeat(10_000)),
+            format!("1{}", "%".repeat(10_000)),
+            format!("{}1{}", "SUM(".repeat(10_000), ")".repeat(10_000)),
+        ] {
+            let (checked, value) =
+                on_small_stack(move || (check_formula(&formula), evaluate(&formula)));
+            assert!(checked.unwrap_err().contains("nested"));
+            assert_eq!(value, Value::Error(FormulaError::Value));
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use super::FormulaError;
+use crate::socialcalc::{CellCoord, CellRange};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Concat,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Blank,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(FormulaError),
+    Ref(CellCoord),
+    Range(CellRange),
+    Name(String),
+    /// A reference or range on another sheet of the workbook, by the sheet
+    /// name as written.
+    Sheet(String, Box<Expr>),
+    Negate(Box<Expr>),
+    Percent(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Calls `visit` with every cell reference, range and name in the
+    /// expression, which is what recalculation orders cells by.
+    pub fn walk(&self, visit: &mut impl FnMut(&Expr)) {
+        match self {
+            Expr::Ref(_) | Expr::Range(_) | Expr::Name(_) | Expr::Sheet(..) => visit(self),
+            Expr::Negate(inner) | Expr::Percent(inner) => inner.walk(visit),
+            Expr::Binary(_, left, right) => {
+                left.walk(visit);
+                right.walk(visit);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.walk(visit)),
+            Expr::Blank | Expr::Number(_) | Expr::Text(_) | Expr::Bool(_) | Expr::Error(_) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    /// A sheet name and the `!` after it.
+    Sheet(String),
+    Error(FormulaError),
+    Op(BinaryOp),
+    Minus,
+    Plus,
+    Percent,
+    Colon,
+    Comma,
+    Open,
+    Close,
+}
+
+/// How deeply formulas may nest, so parsing and evaluating them can't
+/// exhaust the stack.
+pub const MAX_DEPTH: usize = 64;
+
+/// Parses a formula as stored in a cell, without the leading `=`.
+pub fn parse(formula: &str) -> Result<Expr, String> {
+    let tokens = tokenize(formula)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        nesting: 0,
+    };
+    let (expr, _) = parser.comparison()?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+fn tokenize(formula: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => {
+                chars.next();
+            }
+            '0'..='9' | '.' => tokens.push(Token::Number(number(&mut chars)?)),
+            '"' => tokens.push(Token::Text(text(&mut chars)?)),
+            '\'' => {
+                let name = quoted_sheet_name(&mut chars)?;
+                if chars.next() != Some('!') {
+                    return Err(format!("missing '!' after sheet name '{}'", name));
+                }
+                tokens.push(Token::Sheet(name));
+            }
+            '#' => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    name.push(c);
+                    chars.next();
+                    if matches!(c, '!' | '?') || name == "#N/A" {
+                        break;
+                    }
+                }
+                let error = FormulaError::from_name(&name)
+                    .ok_or_else(|| format!("unknown error value '{}'", name))?;
+                tokens.push(Token::Error(error));
+            }
+            c if c.is_alphabetic() || c == '$' || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || matches!(c, '$' | '_' | '.')) {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    tokens.push(Token::Sheet(ident));
+                } else {
+                    tokens.push(Token::Ident(ident));
+                }
+            }
+            _ => {
+                chars.next();
+                let token = match c {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' => Token::Op(BinaryOp::Multiply),
+                    '/' => Token::Op(BinaryOp::Divide),
+                    '^' => Token::Op(BinaryOp::Power),
+                    '&' => Token::Op(BinaryOp::Concat),
+                    '%' => Token::Percent,
+                    ':' => Token::Colon,
+                    ',' => Token::Comma,
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '=' => Token::Op(BinaryOp::Equal),
+                    '<' => match chars.peek() {
+                        Some('=') => {
+                            chars.next();
+                            Token::Op(BinaryOp::LessEqual)
+                        }
+                        Some('>') => {
+                            chars.next();
+                            Token::Op(BinaryOp::NotEqual)
+                        }
+                        _ => Token::Op(BinaryOp::Less),
+                    },
+                    '>' => match chars.peek() {
+                        Some('=') => {
+                            chars.next();
+                            Token::Op(BinaryOp::GreaterEqual)
+                        }
+                        _ => Token::Op(BinaryOp::Greater),
+                    },
+                    _ => return Err(format!("unexpected character '{}'", c)),
+                };
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars<'_>>) -> Result<f64, String> {
+    let mut literal = String::new();
+    while let Some(&c) = chars.peek() {
+        let exponent_sign = matches!(c, '+' | '-') && literal.ends_with(['e', 'E']);
+        if !(c.is_ascii_digit() || c == '.' || matches!(c, 'e' | 'E') || exponent_sign) {
+            break;
+        }
+        literal.push(c);
+        chars.next();
+    }
+    literal
+        .parse()
+        .map_err(|_| format!("invalid number '{}'", literal))
+}
+
+/// A double-quoted string, with `""` standing for a quote.
+fn text(chars: &mut Peekable<Chars<'_>>) -> Result<String, String> {
+    chars.next();
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                text.push('"');
+            }
+            Some('"') => return Ok(text),
+            Some(c) => text.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+/// A single-quoted sheet name, with `''` standing for a quote.
+fn quoted_sheet_name(chars: &mut Peekable<Chars<'_>>) -> Result<String, String> {
+    chars.next();
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some('\'') if chars.peek() == Some(&'\'') => {
+                chars.next();
+                name.push('\'');
+            }
+            Some('\'') => return Ok(name),
+            Some(c) => name.push(c),
+            None => return Err("unterminated sheet name".to_string()),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How many parentheses, arguments and signs the parser is inside.
+    nesting: usize,
+}
+
+/// An expression and how deep its tree is.
+type Parsed = Result<(Expr, usize), String>;
+
+fn too_deep() -> String {
+    format!("formula is nested more than {} deep", MAX_DEPTH)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// `expr` as a node over children `depth` deep.
+    fn node(expr: Expr, depth: usize) -> Parsed {
+        if depth >= MAX_DEPTH {
+            return Err(too_deep());
+        }
+        Ok((expr, depth + 1))
+    }
+
+    fn nested<T>(&mut self, parse: fn(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.nesting >= MAX_DEPTH {
+            return Err(too_deep());
+        }
+        self.nesting += 1;
+        let parsed = parse(self);
+        self.nesting -= 1;
+        parsed
+    }
+
+    fn binary(&mut self, ops: &[BinaryOp], operand: fn(&mut Self) -> Parsed) -> Parsed {
+        let (mut left, mut depth) = operand(self)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if ops.contains(op) => *op,
+                Some(Token::Plus) if ops.contains(&BinaryOp::Add) => BinaryOp::Add,
+                Some(Token::Minus) if ops.contains(&BinaryOp::Subtract) => BinaryOp::Subtract,
+                _ => return Ok((left, depth)),
+            };
+            self.pos += 1;
+            let (right, right_depth) = operand(self)?;
+            (left, depth) = Self::node(
+                Expr::Binary(op, Box::new(left), Box::new(right)),
+                depth.max(right_depth),
+            )?;
+        }
+    }
+
+    fn comparison(&mut self) -> Parsed {
+        use BinaryOp::*;
+        self.binary(
+            &[Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual],
+            Self::concat,
+        )
+    }
+
+    fn concat(&mut self) -> Parsed {
+        self.binary(&[BinaryOp::Concat], Self::additive)
+    }
+
+    fn additive(&mut self) -> Parsed {
+        self.binary(&[BinaryOp::Add, BinaryOp::Subtract], Self::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Parsed {
+        self.binary(&[BinaryOp::Multiply, BinaryOp::Divide], Self::power)
+    }
+
+    fn power(&mut self) -> Parsed {
+        self.binary(&[BinaryOp::Power], Self::unary)
+    }
+
+    /// Unary minus binds tighter than `^`, so `-2^2` is 4 as in SocialCalc.
+    fn unary(&mut self) -> Parsed {
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                let (inner, depth) = self.nested(Self::unary)?;
+                Self::node(Expr::Negate(Box::new(inner)), depth)
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.nested(Self::unary)
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Parsed {
+        let (mut expr, mut depth) = self.primary()?;
+        while self.peek() == Some(&Token::Percent) {
+            self.pos += 1;
+            (expr, depth) = Self::node(Expr::Percent(Box::new(expr)), depth)?;
+        }
+        Ok((expr, depth))
+    }
+
+    fn primary(&mut self) -> Parsed {
+        let expr = match self.next() {
+            Some(Token::Number(n)) => Expr::Number(n),
+            Some(Token::Text(text)) => Expr::Text(text),
+            Some(Token::Error(error)) => Expr::Error(error),
+            Some(Token::Open) => {
+                let parsed = self.nested(Self::comparison)?;
+                return match self.next() {
+                    Some(Token::Close) => Ok(parsed),
+                    _ => Err("missing ')'".to_string()),
+                };
+            }
+            Some(Token::Ident(ident)) => return self.identifier(ident),
+            Some(Token::Sheet(sheet)) => match self.next() {
+                Some(Token::Ident(ident)) => match CellCoord::parse(&ident) {
+                    Some(coord) => Expr::Sheet(sheet, Box::new(self.reference(coord)?)),
+                    None => return Err(format!("invalid reference '{}!{}'", sheet, ident)),
+                },
+                _ => return Err(format!("missing reference after '{}!'", sheet)),
+            },
+            Some(token) => return Err(format!("unexpected {:?}", token)),
+            None => return Err("unexpected end of formula".to_string()),
+        };
+        let depth = if matches!(expr, Expr::Sheet(..)) {
+            2
+        } else {
+            1
+        };
+        Ok((expr, depth))
+    }
+
+    fn identifier(&mut self, ident: String) -> Parsed {
+        if self.peek() == Some(&Token::Open) {
+            self.pos += 1;
+            let (args, depth) = self.nested(Self::arguments)?;
+            return Self::node(Expr::Call(ident.to_ascii_uppercase(), args), depth);
+        }
+
+        let Some(coord) = CellCoord::parse(&ident) else {
+            let expr = match ident.to_ascii_uppercase().as_str() {
+                "TRUE" => Expr::Bool(true),
+                "FALSE" => Expr::Bool(false),
+                _ => Expr::Name(ident.to_ascii_uppercase()),
+            };
+            return Ok((expr, 1));
+        };
+        Ok((self.reference(coord)?, 1))
+    }
+
+    /// A cell reference, or a range if a `:` follows.
+    fn reference(&mut self, coord: CellCoord) -> Result<Expr, String> {
+        if self.peek() != Some(&Token::Colon) {
+            return Ok(Expr::Ref(coord));
+        }
+        self.pos += 1;
+        match self.next() {
+            Some(Token::Ident(end)) => match CellCoord::parse(&end) {
+                Some(end) => Ok(Expr::Range(CellRange::new(coord, end))),
+                None => Err(format!("invalid range end '{}'", end)),
+            },
+            _ => Err("invalid range".to_string()),
+        }
+    }
+
+    /// The arguments of a call, and the depth of the deepest.
+    fn arguments(&mut self) -> Result<(Vec<Expr>, usize), String> {
+        let mut args = Vec::new();
+        let mut depth = 0;
+        if self.peek() == Some(&Token::Close) {
+            self.pos += 1;
+            return Ok((args, depth));
+        }
+        loop {
+            // An empty argument, as in IF(A1,,2), is blank
+            if matches!(self.peek(), Some(Token::Comma) | Some(Token::Close)) {
+                args.push(Expr::Blank);
+            } else {
+                let (arg, arg_depth) = self.comparison()?;
+                args.push(arg);
+                depth = depth.max(arg_depth);
+            }
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::Close) => return Ok((args, depth)),
+                _ => return Err("missing ')' after arguments".to_string()),
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Standalone HTML for a sheet. Styles are inline, so the markup works as
+//! an email body or a page without SocialCalc's scripts and stylesheets.
+
+use crate::utils::escape_html;
+
+use super::render::{layout, Align, CellStyle, Grid};
+use super::{CellRange, Sheet};
+
+const TABLE_STYLE: &str =
+    "border-collapse:collapse;table-layout:fixed;white-space:pre-wrap;word-wrap:break-word";
+const DEFAULT_FONT: CellStyle = CellStyle {
+    italic: false,
+    bold: false,
+    font_size: Some(10.0),
+    font_family: None,
+    color: None,
+    background: None,
+    borders: [None, None, None, None],
+    padding: None,
+    align: Align::Left,
+    vertical_align: None,
+};
+
+/// A complete HTML document showing the used area of the sheet.
+pub fn to_html(sheet: &Sheet, title: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body style=\"margin:16px\">{}</body></html>",
+        escape_html(title),
+        table_html(sheet, None)
+    )
+}
+
+/// The sheet as a `<table>`, for `range` or the used area.
+pub fn table_html(sheet: &Sheet, range: Option<CellRange>) -> String {
+    write_grid(&layout(sheet, range))
+}
+
+fn write_grid(grid: &Grid) -> String {
+    let width: f64 = grid.columns.iter().map(|column| column.width).sum();
+    // The sheet defaults go on the table, where cells inherit them
+    let base = &grid.default_style;
+    let mut table_style = format!("{};width:{}px", TABLE_STYLE, width.round());
+    table_style.push_str(&font_css(base, &DEFAULT_FONT));
+    if base.font_family.is_none() {
+        table_style.push_str(";font-family:Arial,Helvetica,sans-serif");
+    }
+    if let Some(background) = base.background {
+        table_style.push_str(&format!(";background-color:#{:06x}", background));
+    }
+    let mut html = format!(
+        "<table cellspacing=\"0\" cellpadding=\"0\" style=\"{}\"><colgroup>",
+        escape_html(&table_style)
+    );
+    for column in &grid.columns {
+        html.push_str(&format!("<col style=\"width:{}px\">", column.width.round()));
+    }
+    html.push_str("</colgroup>");
+
+    for row in &grid.rows {
+        match row.height {
+            Some(height) => html.push_str(&format!("<tr style=\"height:{}px\">", height.round())),
+            None => html.push_str("<tr>"),
+        }
+        for cell in &row.cells {
+            html.push_str("<td");
+            if cell.colspan > 1 {
+                html.push_str(&format!(" colspan=\"{}\"", cell.colspan));
+            }
+            if cell.rowspan > 1 {
+                html.push_str(&format!(" rowspan=\"{}\"", cell.rowspan));
+            }
+            let css = cell_css(&cell.style, base, !cell.text.is_empty());
+            if css.is_empty() {
+                html.push('>');
+            } else {
+                html.push_str(&format!(" style=\"{}\">", escape_html(&css)));
+            }
+            let text = escape_html(&cell.text).replace('\n', "<br>");
+            match &cell.link {
+                Some(link) => {
+                    html.push_str(&format!("<a href=\"{}\">{}</a>", escape_html(link), text))
+                }
+                None => html.push_str(&text),
+            }
+            html.push_str("</td>");
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    html
+}
+
+/// What the cell needs beyond the table's styles. Alignment and padding
+/// only matter for cells with content.
+fn cell_css(style: &CellStyle, base: &CellStyle, content: bool) -> String {
+    let mut css = String::new();
+    if content {
+        let padding = style.padding.as_deref().and_then(css_value);
+        css.push_str(&format!(";padding:{}", padding.unwrap_or("2px 3px")));
+        css.push_str(match style.align {
+            Align::Left => ";text-align:left",
+            Align::Center => ";text-align:center",
+            Align::Right => ";text-align:right",
+        });
+        css.push_str(match style.vertical_align.as_deref() {
+            Some("top") => ";vertical-align:top",
+            Some("middle") => ";vertical-align:middle",
+            _ => ";vertical-align:bottom",
+        });
+    }
+    css.push_str(&font_css(style, base));
+    if style.background != base.background {
+        css.push_str(&format!(
+            ";background-color:{}",
+            style
+                .background
+                .map_or("transparent".to_string(), |rgb| format!("#{:06x}", rgb))
+        ));
+    }
+    for (side, border) in ["top", "right", "bottom", "left"]
+        .iter()
+        .zip(&style.borders)
+    {
+        if let Some(border) = border.as_deref().and_then(css_value) {
+            css.push_str(&format!(";border-{}:{}", side, border));
+        }
+    }
+    css.trim_start_matches(';').to_string()
+}
+
+/// Font and text color declarations where `style` differs from `base`.
+fn font_css(style: &CellStyle, base: &CellStyle) -> String {
+    let mut css = String::new();
+    if style.bold != base.bold {
+        css.push_str(if style.bold {
+            ";font-weight:bold"
+        } else {
+            ";font-weight:normal"
+        });
+    }
+    if style.italic != base.italic {
+        css.push_str(if style.italic {
+            ";font-style:italic"
+        } else {
+            ";font-style:normal"
+        });
+    }
+    if style.font_size != base.font_size {
+        if let Some(size) = style.font_size {
+            css.push_str(&format!(";font-size:{}pt", size));
+        }
+    }
+    if style.font_family != base.font_family {
+        if let Some(family) = style.font_family.as_deref().and_then(css_value) {
+            css.push_str(&format!(";font-family:{}", family));
+        }
+    }
+    if style.color != base.color {
+        if let Some(color) = style.color {
+            css.push_str(&format!(";color:#{:06x}", color));
+        }
+    }
+    css
+}
+
+/// Style values come from the sheet, so only plain values are let through:
+/// nothing that could end the declaration or load a resource.
+fn css_value(value: &str) -> Option<&str> {
+    let value = value.trim();
+    let plain = value.chars().all(|c| {
+        c.is_alphanumeric()
+            || matches!(
+                c,
+                ' ' | ',' | '.' | '#' | '(' | ')' | '-' | '%' | '\'' | '"'
+            )
+    });
+    let lower = value.to_ascii_lowercase();
+    (plain && !value.is_empty() && !lower.contains("url(") && !lower.contains("expression("))
+        .then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socialcalc::{recalc, Cell, CellCoord, DataType, SpreadsheetSave};
+
+    fn sheet(content: &str) -> Sheet {
+        let mut save = SpreadsheetSave::parse(content).unwrap();
+        recalc(&mut save.sheet);
+        save.sheet
+    }
+
+    #[test]
+    fn values_follow_their_formats() {
+        let html = to_html(&sheet(include_str!("testdata/budget.msc")), "Budget <2024>");
+        assert!(html.contains("<title>Budget &lt;2024&gt;</title>"));
+        assert!(html.contains("<col style=\"width:160px\">"));
+        assert!(html.contains(">1,200.00</td>"));
+        assert!(html.contains(">83.9%</td>"));
+        assert!(html.contains(">1430</td>"));
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        let html = table_html(&sheet(include_str!("testdata/contacts.msc")), None);
+        assert!(html.contains(">&lt;b&gt;Ada&lt;/b&gt;</td>"));
+        assert!(html.contains(">Call at 10:30<br>Ask for C:\\Users</td>"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn hidden_columns_shrink_merges() {
+        let sheet = sheet(include_str!("testdata/styled.msc"));
+        let range = CellRange::new(CellCoord::new(1, 1), CellCoord::new(3, 3));
+        let html = table_html(&sheet, Some(range));
+        assert_eq!(html.matches("<col ").count(), 2);
+        assert!(html.contains("<td colspan=\"2\""));
+        assert!(html.contains("<td rowspan=\"2\""));
+        assert!(html.contains("font-family:Verdana"));
+        // B2 has the only border, and column B is hidden
+        assert!(!html.contains("dashed"));
+    }
+
+    #[test]
+    fn links_only_keep_safe_urls() {
+        let mut sheet = Sheet::default();
+        for (row, text) in ["Docs<https://example.com/?a=1&b=2>", "javascript:alert(1)"]
+            .into_iter()
+            .enumerate()
+        {
+            let cell = Cell {
+                datatype: DataType::Text,
+                valuetype: "tl".to_string(),
+                datavalue: text.to_string(),
+                ..Default::default()
+            };
+            sheet.cells.insert(CellCoord::new(1, row as u32 + 1), cell);
+        }
+        let html = table_html(&sheet, None);
+        assert!(html.contains("<a href=\"https://example.com/?a=1&amp;b=2\">Docs</a>"));
+        assert!(html.contains(">javascript:alert(1)</td>"));
+        assert!(!html.contains("href=\"javascript"));
+    }
+}
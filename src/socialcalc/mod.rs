@@ -0,0 +1,479 @@
+//! The SocialCalc save format: the multipart wrapper written by the
+//! spreadsheet control, and the sheet and editor parts inside it.
+
+pub mod commands;
+mod coord;
+pub mod delimited;
+pub mod diff;
+mod format;
+mod formula;
+pub mod html;
+pub mod ods;
+pub mod pdf;
+mod render;
+mod sheet;
+mod style;
+pub mod template;
+pub mod workbook;
+pub mod xlsx;
+
+pub use coord::{column_name, column_number, CellCoord, CellRange};
+pub use formula::{
+    check_workbook_formula, recalc, recalc_workbook, rename_sheet_references, FormulaError,
+    NumberKind, Value,
+};
+pub use render::display_text;
+pub use sheet::{Borders, Cell, ColAttributes, DataType, NamedRange, RowAttributes, Sheet};
+
+use thiserror::Error;
+
+/// Imported worksheets with more cells than this are left empty.
+const MAX_IMPORT_CELLS: usize = 1_000_000;
+
+pub const DEFAULT_BOUNDARY: &str = "SocialCalcSpreadsheetControlSave";
+const PART_CONTENT_TYPE: &str = "Content-type: text/plain; charset=UTF-8";
+const INDEX_COMMENT: &str = "# SocialCalc Spreadsheet Control Save";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseError {
+    #[error("line {line}: {reason}")]
+    InvalidLine { line: usize, reason: String },
+    #[error("multipart save has no boundary")]
+    MissingBoundary,
+    #[error("multipart save is not terminated")]
+    Unterminated,
+}
+
+impl ParseError {
+    fn line(line: usize, reason: String) -> Self {
+        ParseError::InvalidLine { line, reason }
+    }
+}
+
+/// One worksheet of an imported workbook converted to a SocialCalc save,
+/// with the features that could not be carried over.
+pub struct ImportedSheet {
+    pub name: String,
+    pub save: SpreadsheetSave,
+    pub warnings: Vec<String>,
+}
+
+/// Editor state saved alongside the sheet: panes, cursor and selection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EditSettings {
+    pub version: Option<String>,
+    pub rowpanes: Vec<(u32, u32)>,
+    pub colpanes: Vec<(u32, u32)>,
+    pub ecell: Option<String>,
+    pub range: Option<EditRange>,
+    pub unknown_lines: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EditRange {
+    pub anchor: String,
+    pub top: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub right: u32,
+}
+
+/// A complete save. Bare sheet saves, without the multipart wrapper, parse
+/// into a save with no `container`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpreadsheetSave {
+    pub container: Option<Container>,
+    pub sheet: Sheet,
+    pub edit: Option<EditSettings>,
+    /// The audit trail: one recorded command per line.
+    pub audit: Option<String>,
+    /// Parts other than sheet, edit and audit, kept verbatim.
+    pub other_parts: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    pub version: String,
+    pub boundary: String,
+    pub index_version: String,
+    /// Part names in the order they are listed and stored.
+    pub parts: Vec<String>,
+}
+
+impl SpreadsheetSave {
+    /// Wraps a sheet in the multipart container the spreadsheet control
+    /// writes, with empty edit and audit parts.
+    pub fn new(sheet: Sheet) -> Self {
+        SpreadsheetSave {
+            container: Some(Container {
+                version: "1.0".to_string(),
+                boundary: DEFAULT_BOUNDARY.to_string(),
+                index_version: "1.0".to_string(),
+                parts: vec!["sheet".to_string(), "edit".to_string(), "audit".to_string()],
+            }),
+            sheet,
+            edit: Some(EditSettings {
+                version: Some("1.0".to_string()),
+                ..Default::default()
+            }),
+            audit: Some(String::new()),
+            other_parts: Vec::new(),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ParseError> {
+        let Some(rest) = content.strip_prefix("socialcalc:version:") else {
+            return Ok(SpreadsheetSave {
+                sheet: Sheet::parse(content)?,
+                ..Default::default()
+            });
+        };
+
+        let (version, rest) = rest.split_once('\n').ok_or(ParseError::MissingBoundary)?;
+        let boundary = rest
+            .lines()
+            .take_while(|line| !line.starts_with("--"))
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                if !name.eq_ignore_ascii_case("content-type") {
+                    return None;
+                }
+                value
+                    .split(';')
+                    .find_map(|param| param.trim().strip_prefix("boundary="))
+                    .map(|boundary| boundary.trim_matches('"').to_string())
+            })
+            .ok_or(ParseError::MissingBoundary)?;
+
+        let mut bodies = split_parts(rest, &boundary)?.into_iter();
+        let index = bodies.next().ok_or(ParseError::Unterminated)?;
+
+        let mut container = Container {
+            version: version.to_string(),
+            boundary,
+            index_version: String::new(),
+            parts: Vec::new(),
+        };
+        for line in index.lines() {
+            if let Some(version) = line.strip_prefix("version:") {
+                container.index_version = version.to_string();
+            } else if let Some(part) = line.strip_prefix("part:") {
+                container.parts.push(part.to_string());
+            }
+        }
+
+        let mut save = SpreadsheetSave::default();
+        for (name, body) in container.parts.iter().zip(bodies) {
+            match name.as_str() {
+                "sheet" => save.sheet = Sheet::parse(body)?,
+                "edit" => save.edit = Some(parse_edit(body)?),
+                "audit" => save.audit = Some(body.to_string()),
+                _ => save.other_parts.push((name.clone(), body.to_string())),
+            }
+        }
+        save.container = Some(container);
+
+        Ok(save)
+    }
+
+    pub fn serialize(&self) -> String {
+        let Some(container) = &self.container else {
+            return self.sheet.serialize();
+        };
+
+        let boundary = &container.boundary;
+        let mut out = format!(
+            "socialcalc:version:{}\nMIME-Version: 1.0\nContent-Type: multipart/mixed; boundary={}\n",
+            container.version, boundary
+        );
+
+        out.push_str(&format!(
+            "--{}\n{}\n\n{}\nversion:{}\n",
+            boundary, PART_CONTENT_TYPE, INDEX_COMMENT, container.index_version
+        ));
+        for name in &container.parts {
+            out.push_str(&format!("part:{}\n", name));
+        }
+
+        for name in &container.parts {
+            let body = match name.as_str() {
+                "sheet" => self.sheet.serialize(),
+                "edit" => self.edit.as_ref().map(serialize_edit).unwrap_or_default(),
+                "audit" => self.audit.clone().unwrap_or_default(),
+                _ => self
+                    .other_parts
+                    .iter()
+                    .find(|(other, _)| other == name)
+                    .map(|(_, body)| body.clone())
+                    .unwrap_or_default(),
+            };
+            out.push_str(&format!(
+                "--{}\n{}\n\n{}",
+                boundary, PART_CONTENT_TYPE, body
+            ));
+        }
+
+        out.push_str(&format!("--{}--\n", boundary));
+        out
+    }
+}
+
+/// Returns the body of every part, from just after its headers up to the
+/// next delimiter line.
+fn split_parts<'a>(content: &'a str, boundary: &str) -> Result<Vec<&'a str>, ParseError> {
+    let delimiter = format!("--{}", boundary);
+    let closing = format!("--{}--", boundary);
+
+    let mut bodies = Vec::new();
+    let mut body_start: Option<usize> = None;
+    let mut offset = 0;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        if trimmed == delimiter || trimmed == closing {
+            if let Some(start) = body_start {
+                bodies.push(&content[start..offset]);
+            }
+            if trimmed == closing {
+                return Ok(bodies);
+            }
+
+            // Part headers end at the first blank line
+            let headers_start = offset + line.len();
+            let blank = content[headers_start..]
+                .find("\n\n")
+                .map(|at| headers_start + at + 2)
+                .ok_or(ParseError::Unterminated)?;
+            body_start = Some(blank);
+        }
+        offset += line.len();
+    }
+
+    Err(ParseError::Unterminated)
+}
+
+fn parse_edit(content: &str) -> Result<EditSettings, ParseError> {
+    let mut edit = EditSettings::default();
+
+    for (index, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || ParseError::line(index + 1, format!("invalid editor line '{}'", line));
+        let parts: Vec<&str> = line.split(':').collect();
+        let number = |i: usize| -> Result<u32, ParseError> {
+            parts
+                .get(i)
+                .and_then(|p| p.parse().ok())
+                .ok_or_else(invalid)
+        };
+
+        match parts[0] {
+            "version" => {
+                let version = line.strip_prefix("version:").ok_or_else(invalid)?;
+                edit.version = Some(version.to_string())
+            }
+            "rowpane" => edit.rowpanes.push((number(2)?, number(3)?)),
+            "colpane" => edit.colpanes.push((number(2)?, number(3)?)),
+            "ecell" => edit.ecell = Some(parts.get(1).ok_or_else(invalid)?.to_string()),
+            "range" => {
+                edit.range = Some(EditRange {
+                    anchor: parts.get(1).ok_or_else(invalid)?.to_string(),
+                    top: number(2)?,
+                    bottom: number(3)?,
+                    left: number(4)?,
+                    right: number(5)?,
+                })
+            }
+            _ => edit.unknown_lines.push(line.to_string()),
+        }
+    }
+
+    Ok(edit)
+}
+
+fn serialize_edit(edit: &EditSettings) -> String {
+    let mut out = String::new();
+    if let Some(version) = &edit.version {
+        out.push_str(&format!("version:{}\n", version));
+    }
+    for (i, (first, last)) in edit.rowpanes.iter().enumerate() {
+        out.push_str(&format!("rowpane:{}:{}:{}\n", i, first, last));
+    }
+    for (i, (first, last)) in edit.colpanes.iter().enumerate() {
+        out.push_str(&format!("colpane:{}:{}:{}\n", i, first, last));
+    }
+    if let Some(ecell) = &edit.ecell {
+        out.push_str(&format!("ecell:{}\n", ecell));
+    }
+    if let Some(range) = &edit.range {
+        out.push_str(&format!(
+            "range:{}:{}:{}:{}:{}\n",
+            range.anchor, range.top, range.bottom, range.left, range.right
+        ));
+    }
+    for line in &edit.unknown_lines {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Escapes `\`, `:` and newlines as SocialCalc does inside save lines.
+pub fn encode_for_save(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => encoded.push_str("\\b"),
+            ':' => encoded.push_str("\\c"),
+            '\n' => encoded.push_str("\\n"),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+pub fn decode_from_save(value: &str) -> String {
+    let mut decoded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('c') => decoded.push(':'),
+            Some('n') => decoded.push('\n'),
+            Some('b') => decoded.push('\\'),
+            Some(other) => {
+                decoded.push('\\');
+                decoded.push(other);
+            }
+            None => decoded.push('\\'),
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: &[(&str, &str)] = &[
+        ("budget", include_str!("testdata/budget.msc")),
+        ("contacts", include_str!("testdata/contacts.msc")),
+        ("styled", include_str!("testdata/styled.msc")),
+        ("bare_sheet", include_str!("testdata/bare_sheet.sc")),
+    ];
+
+    #[test]
+    fn samples_round_trip_exactly() {
+        for (name, content) in SAMPLES {
+            let save = SpreadsheetSave::parse(content)
+                .unwrap_or_else(|err| panic!("{} failed to parse: {}", name, err));
+            assert_eq!(&save.serialize(), content, "{} changed on round trip", name);
+        }
+    }
+
+    #[test]
+    fn reparsing_a_serialized_save_is_stable() {
+        for (_, content) in SAMPLES {
+            let save = SpreadsheetSave::parse(content).unwrap();
+            assert_eq!(SpreadsheetSave::parse(&save.serialize()).unwrap(), save);
+        }
+    }
+
+    #[test]
+    fn budget_parses_into_typed_cells() {
+        let save = SpreadsheetSave::parse(SAMPLES[0].1).unwrap();
+        let sheet = &save.sheet;
+
+        let header = &sheet.cells[&CellCoord::parse("A1").unwrap()];
+        assert_eq!(header.datatype, DataType::Text);
+        assert_eq!(header.datavalue, "Item");
+        assert_eq!(header.font, Some(1));
+
+        let total = &sheet.cells[&CellCoord::parse("B5").unwrap()];
+        assert_eq!(total.datatype, DataType::Formula);
+        assert_eq!(total.valuetype, "n");
+        assert_eq!(total.formula, "SUM(B2:B4)");
+
+        let price = &sheet.cells[&CellCoord::parse("B3").unwrap()];
+        assert_eq!(price.datatype, DataType::Constant);
+        assert_eq!(price.formula, "$12.50");
+
+        assert_eq!(sheet.attributes.lastcol, 3);
+        assert_eq!(sheet.attributes.lastrow, 5);
+        assert_eq!(sheet.cols[&1].width.as_deref(), Some("160"));
+        assert_eq!(sheet.names[0].name, "TOTAL");
+        assert_eq!(sheet.layouts[&1], "padding:* * * *;vertical-align:top;");
+
+        let container = save.container.as_ref().unwrap();
+        assert_eq!(container.parts, ["sheet", "edit", "audit"]);
+        assert_eq!(save.edit.as_ref().unwrap().ecell.as_deref(), Some("B5"));
+        assert!(save.audit.as_ref().unwrap().contains("set B5 formula"));
+    }
+
+    #[test]
+    fn declared_size_widens_the_used_range_within_reason() {
+        let sheet = Sheet::parse("cell:B2:v:1\nsheet:c:3:r:5").unwrap();
+        assert_eq!(sheet.used_range().unwrap().to_string(), "A1:C5");
+
+        let sheet = Sheet::parse("cell:B2:v:1\nsheet:c:1:r:4000000000").unwrap();
+        assert_eq!(sheet.used_range().unwrap().to_string(), "A1:B2");
+        assert_eq!(
+            Sheet::parse("sheet:c:3:r:5")
+                .unwrap()
+                .used_range()
+                .unwrap()
+                .to_string(),
+            "A1:C5"
+        );
+    }
+
+    #[test]
+    fn encoded_text_is_decoded() {
+        let save = SpreadsheetSave::parse(SAMPLES[1].1).unwrap();
+        let note = &save.sheet.cells[&CellCoord::parse("C2").unwrap()];
+        assert_eq!(note.datavalue, "Call at 10:30\nAsk for C:\\Users");
+        assert_eq!(note.comment.as_deref(), Some("follow up: friday"));
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        for value in [
+            "",
+            "plain",
+            "a:b",
+            "line\nbreak",
+            "back\\slash",
+            "\\c literal",
+        ] {
+            assert_eq!(decode_from_save(&encode_for_save(value)), value);
+        }
+    }
+
+    #[test]
+    fn coordinates() {
+        assert_eq!(column_name(1), "A");
+        assert_eq!(column_name(26), "Z");
+        assert_eq!(column_name(27), "AA");
+        assert_eq!(column_number("AZ"), Some(52));
+        assert_eq!(CellCoord::parse("$C$12"), Some(CellCoord::new(3, 12)));
+        assert_eq!(CellCoord::parse("A0"), None);
+        assert_eq!(CellCoord::new(28, 3).to_string(), "AB3");
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        assert!(matches!(
+            Sheet::parse("version:1.5\ncell:A1:vtf:n:1\n"),
+            Err(ParseError::InvalidLine { line: 2, .. })
+        ));
+        assert!(SpreadsheetSave::parse("socialcalc:version:1.0\nMIME-Version: 1.0\n").is_err());
+        assert!(matches!(
+            parse_edit("ecell:A1\nversion\n"),
+            Err(ParseError::InvalidLine { line: 2, .. })
+        ));
+    }
+}
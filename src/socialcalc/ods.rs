@@ -0,0 +1,2577 @@
+//! Conversion between SocialCalc sheets and OpenDocument `.ods` spreadsheets.
+//!
+//! Both directions work on the ODF XML directly: cell values, formulas
+//! (translated to and from OpenFormula), merged cells, comments, column
+//! widths, row heights, hidden rows and columns, named ranges, fonts,
+//! colors, borders, alignment and number formats.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Cursor, Read, Write};
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, Timelike};
+use quick_xml::escape::{escape, resolve_predefined_entity};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use thiserror::Error;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::format::{epoch, format_tokens, is_minutes, sections, FormatToken};
+use super::formula::{check_formula, format_number};
+use super::sheet::intern;
+use super::style::{font_size_pt, layout_property, parse_rgb, rgb_string, Font};
+use super::{
+    column_name, recalc, Borders, Cell, CellCoord, CellRange, ColAttributes, DataType,
+    FormulaError, ImportedSheet, NamedRange, NumberKind, RowAttributes, Sheet, SpreadsheetSave,
+    Value, MAX_IMPORT_CELLS,
+};
+
+pub const MIME_TYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+
+/// Cap on the decompressed size of `content.xml` and `styles.xml`.
+const MAX_XML_BYTES: u64 = 64 * 1024 * 1024;
+const PX_PER_INCH: f64 = 96.0;
+/// Bounds the parent chain followed when resolving a cell style.
+const MAX_STYLE_DEPTH: usize = 8;
+
+const NAMESPACES: &str = concat!(
+    r#"xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" "#,
+    r#"xmlns:style="urn:oasis:names:tc:opendocument:xmlns:style:1.0" "#,
+    r#"xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0" "#,
+    r#"xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0" "#,
+    r#"xmlns:fo="urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0" "#,
+    r#"xmlns:number="urn:oasis:names:tc:opendocument:xmlns:datastyle:1.0" "#,
+    r#"xmlns:of="urn:oasis:names:tc:opendocument:xmlns:of:1.2" "#,
+    r#"office:version="1.2""#,
+);
+
+const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.2">
+ <manifest:file-entry manifest:full-path="/" manifest:version="1.2" manifest:media-type="application/vnd.oasis.opendocument.spreadsheet"/>
+ <manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/>
+ <manifest:file-entry manifest:full-path="styles.xml" manifest:media-type="text/xml"/>
+</manifest:manifest>
+"#;
+
+#[derive(Debug, Error)]
+pub enum OdsError {
+    #[error("invalid archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("invalid XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Invalid(&'static str),
+}
+
+/// Writes each `(name, sheet)` pair as one table of a spreadsheet document.
+pub fn to_ods(sheets: &[(&str, &Sheet)]) -> Result<Vec<u8>, OdsError> {
+    // Names are document-wide unless several sheets define the same one,
+    // in which case each stays scoped to its table
+    let mut defined = HashSet::new();
+    let mut shared = HashSet::new();
+    for (_, sheet) in sheets {
+        for named in &sheet.names {
+            let name = named.name.to_uppercase();
+            if !defined.insert(name.clone()) {
+                shared.insert(name);
+            }
+        }
+    }
+
+    let mut styles = StyleWriter::default();
+    let mut tables = String::new();
+    let mut global_names = String::new();
+    let mut table_names = HashSet::new();
+    for (i, (name, sheet)) in sheets.iter().enumerate() {
+        let mut name = table_name(name);
+        if name.is_empty() || !table_names.insert(name.to_lowercase()) {
+            name = format!("Sheet{}", i + 1);
+            table_names.insert(name.to_lowercase());
+        }
+        write_table(&mut tables, &name, sheet, &shared, &mut styles);
+        for named in &sheet.names {
+            if !shared.contains(&named.name.to_uppercase()) {
+                write_name(&mut global_names, &name, named);
+            }
+        }
+    }
+    if !global_names.is_empty() {
+        tables.push_str(&format!(
+            "<table:named-expressions>{}</table:named-expressions>",
+            global_names
+        ));
+    }
+
+    let content = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><office:document-content {}><office:automatic-styles>{}</office:automatic-styles><office:body><office:spreadsheet>{}</office:spreadsheet></office:body></office:document-content>"#,
+        NAMESPACES, styles.xml, tables
+    );
+    let document_styles = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><office:document-styles {}><office:styles><style:style style:name="Default" style:family="table-cell"/></office:styles></office:document-styles>"#,
+        NAMESPACES
+    );
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    // The mimetype must come first and uncompressed so the type can be
+    // sniffed from a fixed offset
+    zip.start_file(
+        "mimetype",
+        SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(MIME_TYPE.as_bytes())?;
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, body) in [
+        ("META-INF/manifest.xml", MANIFEST),
+        ("content.xml", content.as_str()),
+        ("styles.xml", document_styles.as_str()),
+    ] {
+        zip.start_file(name, options)?;
+        zip.write_all(body.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// ODF forbids `[]*?:/\` in table names and a leading or trailing quote.
+fn table_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '[' | ']' | '*' | '?' | ':' | '/' | '\\'))
+        .collect();
+    name.trim_matches('\'').trim().to_string()
+}
+
+/// Automatic styles, shared between tables and written once each.
+#[derive(Default)]
+struct StyleWriter {
+    xml: String,
+    names: HashMap<String, String>,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl StyleWriter {
+    /// The name of the style with the given body, adding it if new.
+    /// `element` is written as `<element style:name="..." body>`.
+    fn intern(&mut self, prefix: &'static str, element: &str, body: String) -> String {
+        let key = format!("{} {}", element, body);
+        if let Some(name) = self.names.get(&key) {
+            return name.clone();
+        }
+        let count = self.counts.entry(prefix).or_default();
+        *count += 1;
+        let name = format!("{}{}", prefix, count);
+        let (attributes, children) = body.split_once('>').unwrap_or((&body, ""));
+        if children.is_empty() {
+            self.xml.push_str(&format!(
+                r#"<{} style:name="{}"{}/>"#,
+                element, name, attributes
+            ));
+        } else {
+            self.xml.push_str(&format!(
+                r#"<{} style:name="{}"{}>{}</{}>"#,
+                element, name, attributes, children, element
+            ));
+        }
+        self.names.insert(key, name.clone());
+        name
+    }
+
+    fn column(&mut self, width: f64) -> String {
+        self.intern(
+            "co",
+            "style:style",
+            format!(
+                r#" style:family="table-column"><style:table-column-properties style:column-width="{}"/>"#,
+                inches(width)
+            ),
+        )
+    }
+
+    fn row(&mut self, height: f64) -> String {
+        self.intern(
+            "ro",
+            "style:style",
+            format!(
+                r#" style:family="table-row"><style:table-row-properties style:row-height="{}" style:use-optimal-row-height="false"/>"#,
+                inches(height)
+            ),
+        )
+    }
+
+    fn data(&mut self, format: &str) -> Option<String> {
+        let (element, body) = data_style(format)?;
+        Some(self.intern("N", element, body))
+    }
+
+    fn cell(&mut self, sheet: &Sheet, cell: &Cell) -> Option<String> {
+        let defaults = &sheet.attributes;
+        let mut cell_properties = String::new();
+        let mut paragraph_properties = String::new();
+        let mut text_properties = String::new();
+
+        if let Some(font) = cell
+            .font
+            .or(defaults.defaultfont)
+            .and_then(|n| sheet.fonts.get(&n))
+        {
+            let font = Font::parse(font);
+            if let Some(style) = font.style {
+                push_attribute(&mut text_properties, "fo:font-style", style);
+            }
+            if let Some(weight) = font.weight {
+                push_attribute(&mut text_properties, "fo:font-weight", weight);
+            }
+            if let Some(size) = font.size.and_then(font_size_pt) {
+                push_attribute(
+                    &mut text_properties,
+                    "fo:font-size",
+                    &format!("{}pt", format_number(size)),
+                );
+            }
+            if let Some(family) = font.family {
+                push_attribute(&mut text_properties, "fo:font-family", family);
+            }
+        }
+        let color = |n: Option<u32>| {
+            n.and_then(|n| sheet.colors.get(&n))
+                .and_then(|c| parse_rgb(c))
+        };
+        if let Some(rgb) = color(cell.color.or(defaults.defaultcolor)) {
+            push_attribute(&mut text_properties, "fo:color", &hex(rgb));
+        }
+        if let Some(rgb) = color(cell.bgcolor.or(defaults.defaultbgcolor)) {
+            push_attribute(&mut cell_properties, "fo:background-color", &hex(rgb));
+        }
+
+        if let Some(borders) = &cell.borders {
+            let sides = [
+                ("fo:border-top", borders.top),
+                ("fo:border-right", borders.right),
+                ("fo:border-bottom", borders.bottom),
+                ("fo:border-left", borders.left),
+            ];
+            for (attribute, border) in sides {
+                if let Some(spec) = border.and_then(|n| sheet.borders.get(&n)) {
+                    push_attribute(&mut cell_properties, attribute, &border_to_odf(spec));
+                }
+            }
+        }
+
+        if let Some(layout) = cell
+            .layout
+            .or(defaults.defaultlayout)
+            .and_then(|n| sheet.layouts.get(&n))
+        {
+            if let Some(padding) = layout_property(layout, "padding") {
+                let sides = [
+                    "fo:padding-top",
+                    "fo:padding-right",
+                    "fo:padding-bottom",
+                    "fo:padding-left",
+                ];
+                for (attribute, value) in sides.iter().zip(padding.split_whitespace()) {
+                    if value != "*" {
+                        push_attribute(&mut cell_properties, attribute, value);
+                    }
+                }
+            }
+            if let Some(align) = layout_property(layout, "vertical-align") {
+                push_attribute(&mut cell_properties, "style:vertical-align", align);
+            }
+        }
+
+        let text = matches!(Value::from_cell(cell), Value::Text(_));
+        let alignment = cell.cellformat.or(if text {
+            defaults.defaulttextformat
+        } else {
+            defaults.defaultnontextformat
+        });
+        let align = match alignment
+            .and_then(|n| sheet.cellformats.get(&n))
+            .map(String::as_str)
+        {
+            Some("left") => Some("start"),
+            Some("center") => Some("center"),
+            Some("right") => Some("end"),
+            _ => None,
+        };
+        if let Some(align) = align {
+            push_attribute(&mut paragraph_properties, "fo:text-align", align);
+        }
+
+        let data_style = sheet
+            .number_format(cell)
+            .and_then(|format| self.data(&format));
+
+        if cell_properties.is_empty()
+            && paragraph_properties.is_empty()
+            && text_properties.is_empty()
+            && data_style.is_none()
+        {
+            return None;
+        }
+
+        let mut body =
+            r#" style:family="table-cell" style:parent-style-name="Default""#.to_string();
+        if let Some(data_style) = data_style {
+            push_attribute(&mut body, "style:data-style-name", &data_style);
+        }
+        body.push('>');
+        for (element, properties) in [
+            ("style:table-cell-properties", cell_properties),
+            ("style:paragraph-properties", paragraph_properties),
+            ("style:text-properties", text_properties),
+        ] {
+            if !properties.is_empty() {
+                body.push_str(&format!("<{}{}/>", element, properties));
+            }
+        }
+        Some(self.intern("ce", "style:style", body))
+    }
+}
+
+fn push_attribute(out: &mut String, name: &str, value: &str) {
+    out.push_str(&format!(r#" {}="{}""#, name, escape(value)));
+}
+
+fn inches(px: f64) -> String {
+    format!("{:.4}in", px / PX_PER_INCH)
+}
+
+fn hex(rgb: u32) -> String {
+    format!("#{:06x}", rgb)
+}
+
+/// `1px solid rgb(0,0,0)` becomes `0.75pt solid #000000`.
+fn border_to_odf(spec: &str) -> String {
+    let mut width = "0.75pt".to_string();
+    let mut style = "solid";
+    let mut color = 0;
+    for part in split_css(spec) {
+        if let Some(rgb) = parse_rgb(part) {
+            color = rgb;
+        } else if let Some(px) = part
+            .strip_suffix("px")
+            .and_then(|px| px.parse::<f64>().ok())
+        {
+            width = format!("{}pt", format_number(px * 0.75));
+        } else if part.ends_with("pt") {
+            width = part.to_string();
+        } else if matches!(part, "solid" | "dashed" | "dotted" | "double" | "none") {
+            style = part;
+        }
+    }
+    format!("{} {} {}", width, style, hex(color))
+}
+
+/// The reverse of [`border_to_odf`].
+fn border_from_odf(spec: &str) -> Option<String> {
+    let mut width = None;
+    let mut style = "solid";
+    let mut color = 0;
+    for part in split_css(spec) {
+        if let Some(rgb) = parse_rgb(part) {
+            color = rgb;
+        } else if let Some(px) = length_px(part) {
+            width = Some(px.round().max(1.0));
+        } else if part == "none" || part == "hidden" {
+            return None;
+        } else if part.chars().all(|c| c.is_ascii_alphabetic() || c == '-') {
+            style = part;
+        }
+    }
+    Some(format!(
+        "{}px {} {}",
+        format_number(width.unwrap_or(1.0)),
+        style,
+        rgb_string(color)
+    ))
+}
+
+/// Splits on spaces outside parentheses, keeping `rgb(0, 0, 0)` whole.
+fn split_css(spec: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in spec.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ' ' if depth == 0 => {
+                if i > start {
+                    parts.push(&spec[start..i]);
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < spec.len() {
+        parts.push(&spec[start..]);
+    }
+    parts
+}
+
+/// Converts an ODF length (`1.5in`, `2.54cm`, `12pt`, ...) to pixels.
+fn length_px(length: &str) -> Option<f64> {
+    let split = length.find(|c: char| c.is_ascii_alphabetic())?;
+    let (number, unit) = length.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let per_inch = match unit {
+        "in" => 1.0,
+        "cm" => 2.54,
+        "mm" => 25.4,
+        "pt" => 72.0,
+        "pc" => 6.0,
+        "px" => PX_PER_INCH,
+        _ => return None,
+    };
+    Some(number / per_inch * PX_PER_INCH)
+}
+
+fn write_table(
+    out: &mut String,
+    name: &str,
+    sheet: &Sheet,
+    shared_names: &HashSet<String>,
+    styles: &mut StyleWriter,
+) {
+    out.push_str(&format!(r#"<table:table table:name="{}">"#, escape(name)));
+
+    let used = sheet.used_range();
+    let last_col = sheet
+        .cols
+        .keys()
+        .copied()
+        .chain(used.map(|range| range.end.col))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let default_width = sheet
+        .attributes
+        .defaultcolwidth
+        .as_deref()
+        .and_then(|width| width.parse::<f64>().ok());
+    let columns: Vec<String> = (1..=last_col)
+        .map(|col| {
+            let attributes = sheet.cols.get(&col);
+            let mut element = String::new();
+            let width = attributes
+                .and_then(|a| a.width.as_deref())
+                .and_then(|width| width.parse::<f64>().ok())
+                .or(default_width);
+            if let Some(width) = width {
+                push_attribute(&mut element, "table:style-name", &styles.column(width));
+            }
+            if attributes.and_then(|a| a.hide.as_deref()) == Some("yes") {
+                push_attribute(&mut element, "table:visibility", "collapse");
+            }
+            element
+        })
+        .collect();
+    for (element, count) in run_lengths(columns) {
+        out.push_str("<table:table-column");
+        out.push_str(&element);
+        if count > 1 {
+            push_attribute(out, "table:number-columns-repeated", &count.to_string());
+        }
+        out.push_str("/>");
+    }
+
+    // Cells hidden under a merged cell are written as covered cells
+    let mut covered: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+    for (coord, cell) in &sheet.cells {
+        let colspan = cell.colspan.unwrap_or(1).max(1);
+        let rowspan = cell.rowspan.unwrap_or(1).max(1);
+        for row in coord.row..coord.row.saturating_add(rowspan) {
+            for col in coord.col..coord.col.saturating_add(colspan) {
+                if (row, col) != (coord.row, coord.col) {
+                    covered.entry(row).or_default().insert(col);
+                }
+            }
+        }
+    }
+
+    let last_row = sheet
+        .rows
+        .keys()
+        .copied()
+        .chain(used.map(|range| range.end.row))
+        .chain(covered.keys().copied())
+        .max()
+        .unwrap_or(0);
+    let mut empty_rows = 0;
+    for row in 1..=last_row {
+        let mut element = String::new();
+        if let Some(attributes) = sheet.rows.get(&row) {
+            if let Some(height) = attributes
+                .height
+                .as_deref()
+                .and_then(|h| h.parse::<f64>().ok())
+            {
+                push_attribute(&mut element, "table:style-name", &styles.row(height));
+            }
+            if attributes.hide.as_deref() == Some("yes") {
+                push_attribute(&mut element, "table:visibility", "collapse");
+            }
+        }
+
+        let cells = sheet
+            .cells
+            .range(CellCoord::new(1, row)..=CellCoord::new(u32::MAX, row));
+        let mut slots: BTreeMap<u32, (Option<&Cell>, bool)> = cells
+            .map(|(coord, cell)| (coord.col, (Some(cell), false)))
+            .collect();
+        for col in covered.get(&row).into_iter().flatten() {
+            slots.entry(*col).or_insert((None, true)).1 = true;
+        }
+
+        if element.is_empty() && slots.is_empty() {
+            empty_rows += 1;
+            continue;
+        }
+        write_empty_rows(out, &mut empty_rows);
+
+        out.push_str("<table:table-row");
+        out.push_str(&element);
+        out.push('>');
+        let mut next_col = 1;
+        for (col, (cell, is_covered)) in slots {
+            if col > next_col {
+                write_empty_cells(out, col - next_col);
+            }
+            write_cell(out, sheet, cell, is_covered, styles);
+            next_col = col + 1;
+        }
+        if next_col == 1 {
+            out.push_str("<table:table-cell/>");
+        }
+        out.push_str("</table:table-row>");
+    }
+    if last_row == 0 {
+        out.push_str("<table:table-row><table:table-cell/></table:table-row>");
+    }
+
+    let mut local_names = String::new();
+    for named in &sheet.names {
+        if shared_names.contains(&named.name.to_uppercase()) {
+            write_name(&mut local_names, name, named);
+        }
+    }
+    if !local_names.is_empty() {
+        out.push_str(&format!(
+            "<table:named-expressions>{}</table:named-expressions>",
+            local_names
+        ));
+    }
+
+    out.push_str("</table:table>");
+}
+
+fn write_empty_rows(out: &mut String, count: &mut u32) {
+    if *count == 0 {
+        return;
+    }
+    out.push_str("<table:table-row");
+    if *count > 1 {
+        push_attribute(out, "table:number-rows-repeated", &count.to_string());
+    }
+    out.push_str("><table:table-cell/></table:table-row>");
+    *count = 0;
+}
+
+fn write_empty_cells(out: &mut String, count: u32) {
+    out.push_str("<table:table-cell");
+    if count > 1 {
+        push_attribute(out, "table:number-columns-repeated", &count.to_string());
+    }
+    out.push_str("/>");
+}
+
+fn run_lengths(items: Vec<String>) -> Vec<(String, u32)> {
+    let mut runs: Vec<(String, u32)> = Vec::new();
+    for item in items {
+        match runs.last_mut() {
+            Some((last, count)) if *last == item => *count += 1,
+            _ => runs.push((item, 1)),
+        }
+    }
+    runs
+}
+
+fn write_cell(
+    out: &mut String,
+    sheet: &Sheet,
+    cell: Option<&Cell>,
+    covered: bool,
+    styles: &mut StyleWriter,
+) {
+    let element = if covered {
+        "table:covered-table-cell"
+    } else {
+        "table:table-cell"
+    };
+    let Some(cell) = cell else {
+        out.push_str(&format!("<{}/>", element));
+        return;
+    };
+
+    out.push('<');
+    out.push_str(element);
+    if let Some(style) = styles.cell(sheet, cell) {
+        push_attribute(out, "table:style-name", &style);
+    }
+    if !covered {
+        if let Some(span) = cell.colspan.filter(|span| *span > 1) {
+            push_attribute(out, "table:number-columns-spanned", &span.to_string());
+        }
+        if let Some(span) = cell.rowspan.filter(|span| *span > 1) {
+            push_attribute(out, "table:number-rows-spanned", &span.to_string());
+        }
+    }
+    if cell.datatype == DataType::Formula && check_formula(&cell.formula).is_ok() {
+        push_attribute(out, "table:formula", &to_openformula(&cell.formula));
+    }
+
+    let value = Value::from_cell(cell);
+    let text = match &value {
+        Value::Blank => None,
+        Value::Number(n, kind) => {
+            let (value_type, attribute, value) = match kind {
+                NumberKind::Logical => ("boolean", "office:boolean-value", (*n != 0.0).to_string()),
+                NumberKind::Date | NumberKind::DateTime => {
+                    ("date", "office:date-value", date_value(*n, *kind))
+                }
+                NumberKind::Time => ("time", "office:time-value", time_value(*n)),
+                NumberKind::Percent => ("percentage", "office:value", format_number(*n)),
+                NumberKind::Currency => ("currency", "office:value", format_number(*n)),
+                NumberKind::Plain => ("float", "office:value", format_number(*n)),
+            };
+            push_attribute(out, "office:value-type", value_type);
+            push_attribute(out, attribute, &value);
+            Some(value.to_string())
+        }
+        Value::Text(text) => {
+            push_attribute(out, "office:value-type", "string");
+            Some(text.clone())
+        }
+        Value::Error(error) => {
+            push_attribute(out, "office:value-type", "string");
+            Some(error.as_str().to_string())
+        }
+    };
+
+    if text.is_none() && cell.comment.is_none() {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    if let Some(comment) = &cell.comment {
+        out.push_str("<office:annotation>");
+        push_paragraphs(out, comment);
+        out.push_str("</office:annotation>");
+    }
+    if let Some(text) = text {
+        push_paragraphs(out, &text);
+    }
+    out.push_str(&format!("</{}>", element));
+}
+
+/// One `text:p` per line, with runs of spaces and tabs written as the
+/// elements ODF uses for them, since plain whitespace collapses.
+fn push_paragraphs(out: &mut String, text: &str) {
+    for line in text.split('\n') {
+        out.push_str("<text:p>");
+        let mut spaces = 0;
+        let mut at_start = true;
+        let flush = |out: &mut String, spaces: &mut usize, at_start: bool| {
+            if *spaces == 0 {
+                return;
+            }
+            let literal = if at_start { 0 } else { 1 };
+            if literal == 1 {
+                out.push(' ');
+            }
+            if *spaces > literal {
+                out.push_str(&format!(r#"<text:s text:c="{}"/>"#, *spaces - literal));
+            }
+            *spaces = 0;
+        };
+        for c in line.chars() {
+            match c {
+                ' ' => spaces += 1,
+                '\t' => {
+                    flush(out, &mut spaces, at_start);
+                    out.push_str("<text:tab/>");
+                    at_start = false;
+                }
+                '\r' => {}
+                c => {
+                    flush(out, &mut spaces, at_start);
+                    at_start = false;
+                    out.push_str(&escape(&*c.encode_utf8(&mut [0; 4])));
+                }
+            }
+        }
+        // Trailing spaces are dropped by readers unless written as elements
+        if spaces > 0 {
+            out.push_str(&format!(r#"<text:s text:c="{}"/>"#, spaces));
+        }
+        out.push_str("</text:p>");
+    }
+}
+
+fn write_name(out: &mut String, table: &str, named: &NamedRange) {
+    let definition = named.definition.trim();
+    let base = format!("{}.$A$1", quote_table(table));
+    if let Some(formula) = definition.strip_prefix('=') {
+        if check_formula(formula).is_ok() {
+            out.push_str(&format!(
+                r#"<table:named-expression table:name="{}" table:base-cell-address="{}" table:expression="{}"/>"#,
+                escape(&named.name),
+                escape(&base),
+                escape(to_openformula(formula))
+            ));
+        }
+        return;
+    }
+    let Some(range) = CellRange::parse(definition) else {
+        return;
+    };
+    out.push_str(&format!(
+        r#"<table:named-range table:name="{}" table:base-cell-address="{}" table:cell-range-address="{}.${}${}:.${}${}"/>"#,
+        escape(&named.name),
+        escape(&base),
+        escape(quote_table(table)),
+        column_name(range.start.col),
+        range.start.row,
+        column_name(range.end.col),
+        range.end.row
+    ));
+}
+
+fn quote_table(table: &str) -> String {
+    format!("$'{}'", table.replace('\'', "''"))
+}
+
+fn date_value(serial: f64, kind: NumberKind) -> String {
+    let millis = (serial * 86_400_000.0).round() as i64;
+    let datetime = epoch() + Duration::milliseconds(millis);
+    if kind == NumberKind::Date && datetime.num_seconds_from_midnight() == 0 {
+        datetime.format("%Y-%m-%d").to_string()
+    } else {
+        datetime.format("%Y-%m-%dT%H:%M:%S%.f").to_string()
+    }
+}
+
+fn time_value(serial: f64) -> String {
+    let millis = (serial * 86_400_000.0).round() as i64;
+    let sign = if millis < 0 { "-" } else { "" };
+    let millis = millis.unsigned_abs();
+    let (hours, minutes) = (millis / 3_600_000, millis / 60_000 % 60);
+    let (seconds, fraction) = (millis / 1000 % 60, millis % 1000);
+    if fraction == 0 {
+        format!("{}PT{}H{:02}M{:02}S", sign, hours, minutes, seconds)
+    } else {
+        format!(
+            "{}PT{}H{:02}M{:02}.{:03}S",
+            sign, hours, minutes, seconds, fraction
+        )
+    }
+}
+
+fn parse_date_value(value: &str) -> Option<(f64, bool)> {
+    let value = value.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let days = (date.and_hms_opt(0, 0, 0)? - epoch()).num_days();
+        return Some((days as f64, false));
+    }
+    // Time zone suffixes are ignored; sheets have no time zone
+    let value = value.trim_end_matches('Z');
+    let value = match value.get(19..).and_then(|rest| rest.find(['+', '-'])) {
+        Some(at) => &value[..19 + at],
+        None => value,
+    };
+    let datetime = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").ok()?;
+    let millis = (datetime - epoch()).num_milliseconds();
+    Some((millis as f64 / 86_400_000.0, true))
+}
+
+/// Parses ISO 8601 durations such as `PT10H30M00S` or `-P1DT2H`.
+fn parse_time_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(rest) => (-1.0, rest),
+        None => (1.0, value),
+    };
+    let value = value.strip_prefix('P')?;
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value.chars() {
+        match c {
+            'T' => in_time = true,
+            '0'..='9' | '.' => number.push(c),
+            unit => {
+                let n: f64 = number.parse().ok()?;
+                number.clear();
+                seconds += n * match (unit, in_time) {
+                    ('D', false) => 86_400.0,
+                    ('H', true) => 3_600.0,
+                    ('M', true) => 60.0,
+                    ('S', true) => 1.0,
+                    _ => return None,
+                };
+            }
+        }
+    }
+    number.is_empty().then_some(sign * seconds / 86_400.0)
+}
+
+/// `SUM(A1:B2,C3)` becomes `of:=SUM([.A1:.B2];[.C3])`.
+fn to_openformula(formula: &str) -> String {
+    let chars: Vec<char> = formula.chars().collect();
+    let mut out = String::from("of:=");
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '"' {
+            let start = i;
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == '"' {
+                    if chars.get(i + 1) == Some(&'"') {
+                        i += 2;
+                        continue;
+                    }
+                    break;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            out.extend(&chars[start..i]);
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let mut j = i + 1;
+                if matches!(chars.get(j), Some('+' | '-')) {
+                    j += 1;
+                }
+                if chars.get(j).is_some_and(char::is_ascii_digit) {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            out.extend(&chars[start..i]);
+        } else if c.is_alphabetic() || c == '$' || c == '_' {
+            let word = read_word(&chars, &mut i);
+            if chars.get(i) == Some(&'(') || !is_reference(&word) {
+                out.push_str(&word);
+                continue;
+            }
+            out.push_str("[.");
+            out.push_str(&word);
+            if chars.get(i) == Some(&':') {
+                let mut j = i + 1;
+                let end = read_word(&chars, &mut j);
+                if is_reference(&end) {
+                    out.push_str(":.");
+                    out.push_str(&end);
+                    i = j;
+                }
+            }
+            out.push(']');
+        } else {
+            out.push(if c == ',' { ';' } else { c });
+            i += 1;
+        }
+    }
+    out
+}
+
+fn read_word(chars: &[char], i: &mut usize) -> String {
+    let start = *i;
+    while *i < chars.len() && (chars[*i].is_alphanumeric() || matches!(chars[*i], '$' | '_' | '.'))
+    {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect()
+}
+
+fn is_reference(word: &str) -> bool {
+    word.contains(|c: char| c.is_ascii_digit()) && CellCoord::parse(word).is_some()
+}
+
+/// `of:=SUM([.A1:.B2];[.C3])` becomes `SUM(A1:B2,C3)`. References to other
+/// tables, and functions this engine does not have, are errors.
+fn from_openformula(formula: &str, table: &str) -> Result<String, String> {
+    let body = ["of:", "oooc:"]
+        .iter()
+        .find_map(|namespace| formula.strip_prefix(namespace))
+        .unwrap_or(formula);
+    let body = body.strip_prefix('=').unwrap_or(body);
+    if body.starts_with("msoxl:") {
+        return Err("Excel formula syntax".to_string());
+    }
+
+    let chars: Vec<char> = body.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"' => {
+                let start = i;
+                i += 1;
+                while i < chars.len() {
+                    if chars[i] == '"' {
+                        if chars.get(i + 1) == Some(&'"') {
+                            i += 2;
+                            continue;
+                        }
+                        break;
+                    }
+                    i += 1;
+                }
+                i = (i + 1).min(chars.len());
+                out.extend(&chars[start..i]);
+            }
+            '[' => {
+                let start = i + 1;
+                let mut quoted = false;
+                while i < chars.len() && (quoted || chars[i] != ']') {
+                    if chars[i] == '\'' {
+                        quoted = !quoted;
+                    }
+                    i += 1;
+                }
+                let reference: String = chars[start..i.min(chars.len())].iter().collect();
+                out.push_str(&translate_reference(&reference, table)?);
+                i += 1;
+            }
+            ';' => {
+                out.push(',');
+                i += 1;
+            }
+            c => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    check_formula(&out)?;
+    Ok(out)
+}
+
+fn translate_reference(reference: &str, table: &str) -> Result<String, String> {
+    let mut cells = Vec::new();
+    for part in split_outside_quotes(reference, ':') {
+        let (part_table, cell) = parse_address(part).ok_or("unsupported reference")?;
+        if part_table.is_some_and(|part_table| part_table != table) {
+            return Err("reference to another sheet".to_string());
+        }
+        cells.push(cell);
+    }
+    match cells.len() {
+        1 | 2 => Ok(cells.join(":")),
+        _ => Err("unsupported reference".to_string()),
+    }
+}
+
+fn split_outside_quotes(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut quoted, mut start) = (false, 0);
+    for (i, c) in text.char_indices() {
+        if c == '\'' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Splits `$'Sheet 1'.$A$1`, `Sheet1.A1` or `.A1` into the table name, if
+/// any, and the cell with its absolute markers.
+fn parse_address(address: &str) -> Option<(Option<String>, &str)> {
+    let address = address.trim();
+    let address = address.strip_prefix('$').unwrap_or(address);
+    let (table, cell) = if let Some(quoted) = address.strip_prefix('\'') {
+        let mut table = String::new();
+        let mut chars = quoted.char_indices().peekable();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            if c == '\'' {
+                if chars.peek().map(|(_, c)| *c) == Some('\'') {
+                    chars.next();
+                    table.push('\'');
+                    continue;
+                }
+                end = Some(i + 1);
+                break;
+            }
+            table.push(c);
+        }
+        let rest = quoted[end?..].strip_prefix('.')?;
+        (Some(table), rest)
+    } else {
+        match address.rsplit_once('.') {
+            Some(("", cell)) => (None, cell),
+            Some((table, cell)) => (Some(table.to_string()), cell),
+            None => (None, address),
+        }
+    };
+    CellCoord::parse(cell).map(|_| (table, cell))
+}
+
+/// The ODF data style for a SocialCalc number format, as the style element
+/// and its attributes and children. Formats with no ODF equivalent give `None`.
+fn data_style(format: &str) -> Option<(&'static str, String)> {
+    let tokens = format_tokens(sections(format)[0]);
+    if tokens
+        .iter()
+        .any(|token| matches!(token, FormatToken::Date(..)))
+    {
+        return date_style(&tokens);
+    }
+
+    let mut children = String::new();
+    let mut kind = "number:number-style";
+    let mut core = None;
+    for token in &tokens {
+        match token {
+            FormatToken::Number(number) => {
+                if core.is_none() {
+                    core = Some(number.clone());
+                    children.push_str(&number_element(number));
+                }
+            }
+            FormatToken::Currency(symbol) => {
+                kind = "number:currency-style";
+                children.push_str(&format!(
+                    "<number:currency-symbol>{}</number:currency-symbol>",
+                    escape(symbol)
+                ));
+            }
+            FormatToken::Literal(text) => {
+                if text.contains('%') {
+                    kind = "number:percentage-style";
+                }
+                children.push_str(&format!("<number:text>{}</number:text>", escape(text)));
+            }
+            FormatToken::Date(..) | FormatToken::Color(_) => {}
+        }
+    }
+    core?;
+    Some((kind, format!(">{}", children)))
+}
+
+fn number_element(number: &str) -> String {
+    if let Some((mantissa, exponent)) = number.split_once(['E', 'e']) {
+        let decimals = mantissa.split_once('.').map_or(0, |(_, d)| d.len());
+        return format!(
+            r#"<number:scientific-number number:decimal-places="{}" number:min-integer-digits="1" number:min-exponent-digits="{}"/>"#,
+            decimals,
+            exponent.trim_start_matches(['+', '-']).len().max(1)
+        );
+    }
+    let (integer, decimals) = number.split_once('.').unwrap_or((number, ""));
+    let mut element = format!(
+        r#"<number:number number:decimal-places="{}" number:min-integer-digits="{}""#,
+        decimals.len(),
+        integer.chars().filter(|c| *c == '0').count()
+    );
+    if integer.contains(',') {
+        element.push_str(r#" number:grouping="true""#);
+    }
+    element.push_str("/>");
+    element
+}
+
+fn date_style(tokens: &[FormatToken]) -> Option<(&'static str, String)> {
+    let mut children = String::new();
+    let mut has_date = false;
+    let parts: Vec<(usize, &FormatToken)> = tokens.iter().enumerate().collect();
+    for (i, token) in &parts {
+        match token {
+            FormatToken::Date(letter, count) => {
+                let long = if *count >= 2 {
+                    r#" number:style="long""#
+                } else {
+                    ""
+                };
+                let element = match letter {
+                    'y' => {
+                        has_date = true;
+                        let long = if *count > 2 {
+                            r#" number:style="long""#
+                        } else {
+                            ""
+                        };
+                        format!("<number:year{}/>", long)
+                    }
+                    'm' if is_minutes(tokens, *i) => format!("<number:minutes{}/>", long),
+                    'm' => {
+                        has_date = true;
+                        match count {
+                            1 | 2 => format!("<number:month{}/>", long),
+                            3 => r#"<number:month number:textual="true"/>"#.to_string(),
+                            _ => r#"<number:month number:textual="true" number:style="long"/>"#
+                                .to_string(),
+                        }
+                    }
+                    'd' => {
+                        has_date = true;
+                        match count {
+                            1 | 2 => format!("<number:day{}/>", long),
+                            3 => "<number:day-of-week/>".to_string(),
+                            _ => r#"<number:day-of-week number:style="long"/>"#.to_string(),
+                        }
+                    }
+                    'h' => format!("<number:hours{}/>", long),
+                    'H' => r#"<number:hours number:style="long"/>"#.to_string(),
+                    's' => format!("<number:seconds{}/>", long),
+                    'a' => "<number:am-pm/>".to_string(),
+                    _ => continue,
+                };
+                children.push_str(&element);
+            }
+            FormatToken::Literal(text) => {
+                children.push_str(&format!("<number:text>{}</number:text>", escape(text)));
+            }
+            _ => {}
+        }
+    }
+    if has_date {
+        return Some(("number:date-style", format!(">{}", children)));
+    }
+    // `[h]` keeps counting past 24 hours
+    let elapsed = tokens
+        .iter()
+        .any(|token| matches!(token, FormatToken::Date('H', _)));
+    let attributes = if elapsed {
+        r#" number:truncate-on-overflow="false""#
+    } else {
+        ""
+    };
+    Some(("number:time-style", format!("{}>{}", attributes, children)))
+}
+
+/// Converts every table of an `.ods` document. Everything listed in the
+/// module documentation is kept; charts, images, conditional formatting,
+/// validation rules and formulas this engine cannot evaluate are listed in
+/// each sheet's warnings.
+pub fn from_ods(data: &[u8]) -> Result<Vec<ImportedSheet>, OdsError> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    let mut importer = Importer::default();
+    if let Some(styles) = read_part(&mut archive, "styles.xml")? {
+        importer.read(&styles)?;
+    }
+    let content = read_part(&mut archive, "content.xml")?
+        .ok_or(OdsError::Invalid("document has no content.xml"))?;
+    importer.read(&content)?;
+    Ok(importer.finish())
+}
+
+fn read_part(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<Option<String>, OdsError> {
+    let file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut xml = String::new();
+    file.take(MAX_XML_BYTES + 1).read_to_string(&mut xml)?;
+    if xml.len() as u64 > MAX_XML_BYTES {
+        return Err(OdsError::Invalid("document is too large"));
+    }
+    Ok(Some(xml))
+}
+
+#[derive(Debug, Clone, Default)]
+struct CellStyle {
+    parent: Option<String>,
+    data_style: Option<String>,
+    font_style: Option<String>,
+    font_weight: Option<String>,
+    font_size: Option<String>,
+    font_family: Option<String>,
+    color: Option<String>,
+    background: Option<String>,
+    /// Top, right, bottom, left.
+    borders: [Option<String>; 4],
+    padding: [Option<String>; 4],
+    text_align: Option<String>,
+    vertical_align: Option<String>,
+}
+
+impl CellStyle {
+    fn inherit(&mut self, parent: &CellStyle) {
+        fn fill(value: &mut Option<String>, parent: &Option<String>) {
+            if value.is_none() {
+                value.clone_from(parent);
+            }
+        }
+        fill(&mut self.data_style, &parent.data_style);
+        fill(&mut self.font_style, &parent.font_style);
+        fill(&mut self.font_weight, &parent.font_weight);
+        fill(&mut self.font_size, &parent.font_size);
+        fill(&mut self.font_family, &parent.font_family);
+        fill(&mut self.color, &parent.color);
+        fill(&mut self.background, &parent.background);
+        for side in 0..4 {
+            fill(&mut self.borders[side], &parent.borders[side]);
+            fill(&mut self.padding[side], &parent.padding[side]);
+        }
+        fill(&mut self.text_align, &parent.text_align);
+        fill(&mut self.vertical_align, &parent.vertical_align);
+    }
+}
+
+#[derive(Default)]
+struct Styles {
+    cells: HashMap<String, CellStyle>,
+    column_widths: HashMap<String, f64>,
+    row_heights: HashMap<String, f64>,
+    /// Number formats by data style name; `None` for styles with no
+    /// SocialCalc equivalent.
+    data: HashMap<String, Option<String>>,
+}
+
+impl Styles {
+    /// The style with its parents' properties filled in. `Default` is the
+    /// document default and becomes the sheet defaults instead.
+    fn resolve(&self, name: &str) -> Option<CellStyle> {
+        let mut style = self.cells.get(name)?.clone();
+        let mut parent = style.parent.clone();
+        for _ in 0..MAX_STYLE_DEPTH {
+            let Some(name) = parent.filter(|name| name != "Default") else {
+                break;
+            };
+            let Some(next) = self.cells.get(&name) else {
+                break;
+            };
+            style.inherit(next);
+            parent = next.parent.clone();
+        }
+        Some(style)
+    }
+}
+
+enum PendingStyle {
+    Cell(String, Box<CellStyle>),
+    Column(String, Option<f64>),
+    Row(String, Option<f64>),
+    Data {
+        name: String,
+        format: String,
+        supported: bool,
+        elapsed: bool,
+    },
+}
+
+enum NameDefinition {
+    Range(String),
+    Expression { expression: String, base: String },
+}
+
+/// Style table indexes for one cell style, as a cell stores them.
+#[derive(Clone, Default)]
+struct Formatting {
+    font: Option<u32>,
+    color: Option<u32>,
+    bgcolor: Option<u32>,
+    borders: Option<Borders>,
+    layout: Option<u32>,
+    cellformat: Option<u32>,
+    valueformat: Option<u32>,
+}
+
+#[derive(Default)]
+struct PendingCell {
+    repeat: u32,
+    covered: bool,
+    style: Option<String>,
+    value_type: Option<String>,
+    value: Option<String>,
+    date_value: Option<String>,
+    time_value: Option<String>,
+    boolean_value: Option<String>,
+    string_value: Option<String>,
+    formula: Option<String>,
+    colspan: u32,
+    rowspan: u32,
+    text: String,
+    paragraphs: usize,
+    comment: Option<String>,
+    comment_paragraphs: usize,
+    in_annotation: bool,
+    in_paragraph: bool,
+    /// Whether the paragraph so far ends in collapsible whitespace.
+    after_space: bool,
+    /// Depth inside annotation metadata (author, date), which is skipped.
+    skip: usize,
+}
+
+struct Table {
+    name: String,
+    sheet: Sheet,
+    warnings: Vec<String>,
+    cells: usize,
+    truncated: bool,
+    formatting: HashMap<String, Formatting>,
+    column_runs: Vec<(u32, u32, ColAttributes)>,
+    row_runs: Vec<(u32, u32, RowAttributes)>,
+    next_column: u32,
+    row: u32,
+    row_repeat: u32,
+    row_cells: Vec<(u32, Cell)>,
+    col: u32,
+    cell: Option<PendingCell>,
+    names: Vec<(String, NameDefinition)>,
+}
+
+impl Table {
+    fn new(name: String) -> Self {
+        Table {
+            name,
+            sheet: Sheet {
+                version: Some("1.5".to_string()),
+                ..Default::default()
+            },
+            warnings: Vec::new(),
+            cells: 0,
+            truncated: false,
+            formatting: HashMap::new(),
+            column_runs: Vec::new(),
+            row_runs: Vec::new(),
+            next_column: 1,
+            row: 1,
+            row_repeat: 1,
+            row_cells: Vec::new(),
+            col: 1,
+            cell: None,
+            names: Vec::new(),
+        }
+    }
+
+    fn warn(&mut self, warning: &str) {
+        if !self.warnings.iter().any(|existing| existing == warning) {
+            self.warnings.push(warning.to_string());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Importer {
+    styles: Styles,
+    pending_style: Option<PendingStyle>,
+    /// Text of a `number:text` or `number:currency-symbol` being read.
+    data_text: Option<String>,
+    table: Option<Table>,
+    sheets: Vec<ImportedSheet>,
+    names: Vec<(String, NameDefinition)>,
+    warnings: Vec<String>,
+}
+
+type Attributes = HashMap<String, String>;
+
+fn attributes(element: &BytesStart) -> Result<Attributes, OdsError> {
+    let mut map = HashMap::new();
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(quick_xml::Error::from)?;
+        map.insert(
+            String::from_utf8_lossy(attribute.key.as_ref()).into_owned(),
+            attribute
+                .decoded_and_normalized_value(XmlVersion::default(), element.decoder())?
+                .into_owned(),
+        );
+    }
+    Ok(map)
+}
+
+fn count(attributes: &Attributes, name: &str) -> u32 {
+    attributes
+        .get(name)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1u32)
+        .max(1)
+}
+
+impl Importer {
+    fn read(&mut self, xml: &str) -> Result<(), OdsError> {
+        let mut reader = Reader::from_str(xml);
+        loop {
+            match reader.read_event()? {
+                Event::Start(element) => {
+                    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+                    self.start(&name, &attributes(&element)?);
+                }
+                Event::Empty(element) => {
+                    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+                    self.start(&name, &attributes(&element)?);
+                    self.end(&name);
+                }
+                Event::End(element) => {
+                    self.end(&String::from_utf8_lossy(element.name().as_ref()));
+                }
+                Event::Text(text) => {
+                    self.text(&text.decode().map_err(quick_xml::Error::from)?, false);
+                }
+                Event::CData(text) => self.text(&String::from_utf8_lossy(&text), false),
+                Event::GeneralRef(reference) => {
+                    if let Some(c) = reference.resolve_char_ref()? {
+                        self.text(c.encode_utf8(&mut [0; 4]), false);
+                    } else {
+                        let name = reference.decode().map_err(quick_xml::Error::from)?;
+                        if let Some(text) = resolve_predefined_entity(&name) {
+                            self.text(text, false);
+                        }
+                    }
+                }
+                Event::Eof => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds character data. Runs of whitespace in paragraphs collapse to a
+    /// single space unless they come from `text:s`, `text:tab` or
+    /// `text:line-break`, which are `literal`.
+    fn text(&mut self, text: &str, literal: bool) {
+        if let Some(data_text) = &mut self.data_text {
+            data_text.push_str(text);
+            return;
+        }
+        let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) else {
+            return;
+        };
+        if cell.skip > 0 || !cell.in_paragraph {
+            return;
+        }
+        let out = if cell.in_annotation {
+            cell.comment.get_or_insert_with(String::new)
+        } else {
+            &mut cell.text
+        };
+        if literal {
+            out.push_str(text);
+            cell.after_space = false;
+            return;
+        }
+        for c in text.chars() {
+            if c.is_ascii_whitespace() {
+                if !cell.after_space {
+                    out.push(' ');
+                    cell.after_space = true;
+                }
+            } else {
+                out.push(c);
+                cell.after_space = false;
+            }
+        }
+    }
+
+    fn start(&mut self, name: &str, attributes: &Attributes) {
+        let get = |key: &str| attributes.get(key).cloned();
+        match name {
+            "style:style" => {
+                let Some(style_name) = get("style:name") else {
+                    return;
+                };
+                self.pending_style = match attributes.get("style:family").map(String::as_str) {
+                    Some("table-cell") => Some(PendingStyle::Cell(
+                        style_name,
+                        Box::new(CellStyle {
+                            parent: get("style:parent-style-name"),
+                            data_style: get("style:data-style-name"),
+                            ..Default::default()
+                        }),
+                    )),
+                    Some("table-column") => Some(PendingStyle::Column(style_name, None)),
+                    Some("table-row") => Some(PendingStyle::Row(style_name, None)),
+                    _ => None,
+                };
+            }
+            "style:table-cell-properties" => {
+                if let Some(PendingStyle::Cell(_, style)) = &mut self.pending_style {
+                    style.background = get("fo:background-color").or(style.background.take());
+                    if let Some(border) = get("fo:border") {
+                        style.borders = [0, 1, 2, 3].map(|_| Some(border.clone()));
+                    }
+                    if let Some(padding) = get("fo:padding") {
+                        style.padding = [0, 1, 2, 3].map(|_| Some(padding.clone()));
+                    }
+                    for (side, name) in ["top", "right", "bottom", "left"].iter().enumerate() {
+                        if let Some(border) = get(&format!("fo:border-{}", name)) {
+                            style.borders[side] = Some(border);
+                        }
+                        if let Some(padding) = get(&format!("fo:padding-{}", name)) {
+                            style.padding[side] = Some(padding);
+                        }
+                    }
+                    style.vertical_align =
+                        get("style:vertical-align").or(style.vertical_align.take());
+                }
+            }
+            "style:paragraph-properties" => {
+                if let Some(PendingStyle::Cell(_, style)) = &mut self.pending_style {
+                    style.text_align = get("fo:text-align").or(style.text_align.take());
+                }
+            }
+            "style:text-properties" => {
+                if let Some(PendingStyle::Cell(_, style)) = &mut self.pending_style {
+                    style.font_style = get("fo:font-style").or(style.font_style.take());
+                    style.font_weight = get("fo:font-weight").or(style.font_weight.take());
+                    style.font_size = get("fo:font-size").or(style.font_size.take());
+                    style.font_family = get("fo:font-family")
+                        .or(get("style:font-name"))
+                        .or(style.font_family.take());
+                    style.color = get("fo:color").or(style.color.take());
+                }
+            }
+            "style:table-column-properties" => {
+                if let Some(PendingStyle::Column(_, width)) = &mut self.pending_style {
+                    *width = get("style:column-width").and_then(|w| length_px(&w));
+                }
+            }
+            "style:table-row-properties" => {
+                if let Some(PendingStyle::Row(_, height)) = &mut self.pending_style {
+                    if get("style:use-optimal-row-height").as_deref() != Some("true") {
+                        *height = get("style:row-height").and_then(|h| length_px(&h));
+                    }
+                }
+            }
+            "number:number-style"
+            | "number:percentage-style"
+            | "number:currency-style"
+            | "number:date-style"
+            | "number:time-style"
+            | "number:boolean-style"
+            | "number:text-style" => {
+                let Some(style_name) = get("style:name") else {
+                    return;
+                };
+                self.pending_style = Some(PendingStyle::Data {
+                    name: style_name,
+                    format: String::new(),
+                    supported: !matches!(name, "number:boolean-style" | "number:text-style"),
+                    elapsed: get("number:truncate-on-overflow").as_deref() == Some("false"),
+                });
+            }
+            "number:text" | "number:currency-symbol" => {
+                if matches!(self.pending_style, Some(PendingStyle::Data { .. })) {
+                    self.data_text = Some(String::new());
+                }
+            }
+            _ if name.starts_with("number:") => {
+                let Some(PendingStyle::Data {
+                    format,
+                    supported,
+                    elapsed,
+                    ..
+                }) = &mut self.pending_style
+                else {
+                    return;
+                };
+                let long = get("number:style").as_deref() == Some("long");
+                let textual = get("number:textual").as_deref() == Some("true");
+                let part = match name {
+                    "number:number" => {
+                        let decimals = count_or_zero(attributes, "number:decimal-places");
+                        let integers = count_or_zero(attributes, "number:min-integer-digits");
+                        let grouping = get("number:grouping").as_deref() == Some("true");
+                        number_pattern(integers, decimals, grouping)
+                    }
+                    "number:scientific-number" => {
+                        let decimals = count_or_zero(attributes, "number:decimal-places");
+                        let exponent = count_or_zero(attributes, "number:min-exponent-digits");
+                        format!(
+                            "{}E+{}",
+                            number_pattern(1, decimals, false),
+                            "0".repeat(exponent.max(1))
+                        )
+                    }
+                    "number:year" if long => "yyyy".to_string(),
+                    "number:year" => "yy".to_string(),
+                    "number:month" => match (textual, long) {
+                        (true, true) => "mmmm",
+                        (true, false) => "mmm",
+                        (false, true) => "mm",
+                        (false, false) => "m",
+                    }
+                    .to_string(),
+                    "number:day" => if long { "dd" } else { "d" }.to_string(),
+                    "number:day-of-week" => if long { "dddd" } else { "ddd" }.to_string(),
+                    "number:hours" if *elapsed => {
+                        *elapsed = false;
+                        "[h]".to_string()
+                    }
+                    "number:hours" => if long { "hh" } else { "h" }.to_string(),
+                    "number:minutes" => if long { "mm" } else { "m" }.to_string(),
+                    "number:seconds" => if long { "ss" } else { "s" }.to_string(),
+                    "number:am-pm" => "AM/PM".to_string(),
+                    "number:fraction" => {
+                        *supported = false;
+                        self.warnings
+                            .push("Fraction number formats are not imported".to_string());
+                        return;
+                    }
+                    _ => return,
+                };
+                format.push_str(&part);
+            }
+            "table:table" => {
+                let table_name = get("table:name").unwrap_or_default();
+                self.table = Some(Table::new(table_name));
+            }
+            "table:table-column" => {
+                let Some(table) = &mut self.table else {
+                    return;
+                };
+                let repeat = count(attributes, "table:number-columns-repeated");
+                let width = get("table:style-name")
+                    .and_then(|style| self.styles.column_widths.get(&style).copied());
+                let attributes = ColAttributes {
+                    width: width.map(|width| format_number(width.round())),
+                    hide: matches!(
+                        get("table:visibility").as_deref(),
+                        Some("collapse" | "filter")
+                    )
+                    .then(|| "yes".to_string()),
+                };
+                if attributes.width.is_some() || attributes.hide.is_some() {
+                    table
+                        .column_runs
+                        .push((table.next_column, repeat, attributes));
+                }
+                table.next_column = table.next_column.saturating_add(repeat);
+            }
+            "table:table-row" => {
+                let Some(table) = &mut self.table else {
+                    return;
+                };
+                table.row_repeat = count(attributes, "table:number-rows-repeated");
+                table.col = 1;
+                table.row_cells.clear();
+                let height = get("table:style-name")
+                    .and_then(|style| self.styles.row_heights.get(&style).copied());
+                let attributes = RowAttributes {
+                    height: height.map(|height| format_number(height.round())),
+                    hide: matches!(
+                        get("table:visibility").as_deref(),
+                        Some("collapse" | "filter")
+                    )
+                    .then(|| "yes".to_string()),
+                };
+                if attributes.height.is_some() || attributes.hide.is_some() {
+                    table
+                        .row_runs
+                        .push((table.row, table.row_repeat, attributes));
+                }
+            }
+            "table:table-cell" | "table:covered-table-cell" => {
+                let Some(table) = &mut self.table else {
+                    return;
+                };
+                table.cell = Some(PendingCell {
+                    repeat: count(attributes, "table:number-columns-repeated"),
+                    covered: name == "table:covered-table-cell",
+                    style: get("table:style-name"),
+                    value_type: get("office:value-type"),
+                    value: get("office:value"),
+                    date_value: get("office:date-value"),
+                    time_value: get("office:time-value"),
+                    boolean_value: get("office:boolean-value"),
+                    string_value: get("office:string-value"),
+                    formula: get("table:formula"),
+                    colspan: count(attributes, "table:number-columns-spanned"),
+                    rowspan: count(attributes, "table:number-rows-spanned"),
+                    ..Default::default()
+                });
+            }
+            "text:p" => {
+                let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) else {
+                    return;
+                };
+                if cell.skip > 0 {
+                    return;
+                }
+                cell.in_paragraph = true;
+                cell.after_space = true;
+                if cell.in_annotation {
+                    if cell.comment_paragraphs > 0 {
+                        cell.comment.get_or_insert_with(String::new).push('\n');
+                    }
+                    cell.comment_paragraphs += 1;
+                } else {
+                    if cell.paragraphs > 0 {
+                        cell.text.push('\n');
+                    }
+                    cell.paragraphs += 1;
+                }
+            }
+            "text:s" => {
+                let spaces = count(attributes, "text:c").min(1000) as usize;
+                self.text(&" ".repeat(spaces), true);
+            }
+            "text:tab" => self.text("\t", true),
+            "text:line-break" => self.text("\n", true),
+            "office:annotation" => {
+                if let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) {
+                    cell.in_annotation = true;
+                }
+            }
+            "dc:creator" | "dc:date" | "meta:date-string" => {
+                if let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) {
+                    cell.skip += 1;
+                }
+            }
+            "draw:frame" | "draw:object" | "draw:image" => {
+                if let Some(table) = &mut self.table {
+                    table.warn("Images and charts are not imported");
+                }
+            }
+            "calcext:conditional-formats" => {
+                if let Some(table) = &mut self.table {
+                    table.warn("Conditional formatting is not imported");
+                }
+            }
+            "table:content-validations" => {
+                self.warnings
+                    .push("Data validation rules are not imported".to_string());
+            }
+            "table:named-range" | "table:named-expression" => {
+                let (Some(range_name), definition) = (
+                    get("table:name"),
+                    if name == "table:named-range" {
+                        get("table:cell-range-address").map(NameDefinition::Range)
+                    } else {
+                        get("table:expression").map(|expression| NameDefinition::Expression {
+                            expression,
+                            base: get("table:base-cell-address").unwrap_or_default(),
+                        })
+                    },
+                ) else {
+                    return;
+                };
+                let Some(definition) = definition else {
+                    return;
+                };
+                match &mut self.table {
+                    Some(table) => table.names.push((range_name, definition)),
+                    None => self.names.push((range_name, definition)),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, name: &str) {
+        match name {
+            "style:style" => match self.pending_style.take() {
+                Some(PendingStyle::Cell(name, style)) => {
+                    self.styles.cells.insert(name, *style);
+                }
+                Some(PendingStyle::Column(name, Some(width))) => {
+                    self.styles.column_widths.insert(name, width);
+                }
+                Some(PendingStyle::Row(name, Some(height))) => {
+                    self.styles.row_heights.insert(name, height);
+                }
+                _ => {}
+            },
+            "number:number-style"
+            | "number:percentage-style"
+            | "number:currency-style"
+            | "number:date-style"
+            | "number:time-style"
+            | "number:boolean-style"
+            | "number:text-style" => {
+                if let Some(PendingStyle::Data {
+                    name,
+                    format,
+                    supported,
+                    ..
+                }) = self.pending_style.take()
+                {
+                    let format = (supported && !format.is_empty()).then_some(format);
+                    self.styles.data.insert(name, format);
+                }
+            }
+            "number:text" | "number:currency-symbol" => {
+                let Some(text) = self.data_text.take() else {
+                    return;
+                };
+                if let Some(PendingStyle::Data { format, .. }) = &mut self.pending_style {
+                    if name == "number:currency-symbol" {
+                        if text == "$" {
+                            format.push('$');
+                        } else {
+                            format.push_str(&format!("[${}]", text));
+                        }
+                    } else {
+                        format.push_str(&format_literal(&text));
+                    }
+                }
+            }
+            "text:p" => {
+                if let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) {
+                    if cell.skip == 0 {
+                        cell.in_paragraph = false;
+                    }
+                }
+            }
+            "office:annotation" => {
+                if let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) {
+                    cell.in_annotation = false;
+                }
+            }
+            "dc:creator" | "dc:date" | "meta:date-string" => {
+                if let Some(cell) = self.table.as_mut().and_then(|table| table.cell.as_mut()) {
+                    cell.skip = cell.skip.saturating_sub(1);
+                }
+            }
+            "table:table-cell" | "table:covered-table-cell" => self.finish_cell(),
+            "table:table-row" => self.finish_row(),
+            "table:table" => self.finish_table(),
+            _ => {}
+        }
+    }
+
+    fn finish_cell(&mut self) {
+        let Some(table) = &mut self.table else {
+            return;
+        };
+        let Some(pending) = table.cell.take() else {
+            return;
+        };
+        let start = table.col;
+        table.col = table.col.saturating_add(pending.repeat);
+        if pending.covered {
+            return;
+        }
+        let coord = CellCoord::new(start, table.row);
+        let Some(cell) = import_cell(&pending, coord, table, &self.styles) else {
+            return;
+        };
+        let repeat = pending.repeat.min(MAX_IMPORT_CELLS as u32);
+        for k in 0..repeat {
+            table.row_cells.push((start + k, cell.clone()));
+        }
+    }
+
+    fn finish_row(&mut self) {
+        let Some(table) = &mut self.table else {
+            return;
+        };
+        if !table.row_cells.is_empty() {
+            'rows: for k in 0..table.row_repeat {
+                for (col, cell) in &table.row_cells {
+                    if table.cells >= MAX_IMPORT_CELLS {
+                        table.truncated = true;
+                        break 'rows;
+                    }
+                    let coord = CellCoord::new(*col, table.row.saturating_add(k));
+                    table.sheet.cells.insert(coord, cell.clone());
+                    table.cells += 1;
+                }
+            }
+            table.row_cells.clear();
+        }
+        table.row = table.row.saturating_add(table.row_repeat);
+        table.row_repeat = 1;
+    }
+
+    fn finish_table(&mut self) {
+        let Some(mut table) = self.table.take() else {
+            return;
+        };
+        if table.truncated {
+            table.warn(&format!(
+                "Sheet has more than {} cells; the rest was not imported",
+                MAX_IMPORT_CELLS
+            ));
+        }
+
+        // Runs of styled rows and columns often extend to the sheet's
+        // limits, so they are only applied where there is content
+        if let Some(used) = table.sheet.used_range() {
+            for (start, repeat, attributes) in std::mem::take(&mut table.column_runs) {
+                let end = start.saturating_add(repeat - 1).min(used.end.col);
+                for col in start..=end {
+                    table.sheet.cols.insert(col, attributes.clone());
+                }
+            }
+            for (start, repeat, attributes) in std::mem::take(&mut table.row_runs) {
+                let end = start.saturating_add(repeat - 1).min(used.end.row);
+                for row in start..=end {
+                    table.sheet.rows.insert(row, attributes.clone());
+                }
+            }
+            table.sheet.attributes.lastcol = used.end.col;
+            table.sheet.attributes.lastrow = used.end.row;
+        }
+
+        if let Some(default) = self.styles.cells.get("Default").cloned() {
+            let formatting = formatting(&mut table.sheet, &default, &self.styles);
+            let attributes = &mut table.sheet.attributes;
+            attributes.defaultfont = formatting.font;
+            attributes.defaultcolor = formatting.color;
+            attributes.defaultbgcolor = formatting.bgcolor;
+            attributes.defaultlayout = formatting.layout;
+            attributes.defaulttextformat = formatting.cellformat;
+            attributes.defaultnontextformat = formatting.cellformat;
+            attributes.defaultnontextvalueformat = formatting.valueformat;
+        }
+
+        for (name, definition) in std::mem::take(&mut table.names) {
+            match resolve_name(&name, &definition, &table.name) {
+                Ok((_, named)) => table.sheet.names.push(named),
+                Err(reason) => table.warn(&format!("Named range {} {}", name, reason)),
+            }
+        }
+
+        recalc(&mut table.sheet);
+        self.sheets.push(ImportedSheet {
+            name: table.name,
+            save: SpreadsheetSave::new(table.sheet),
+            warnings: table.warnings,
+        });
+    }
+
+    fn finish(mut self) -> Vec<ImportedSheet> {
+        let first = self
+            .sheets
+            .first()
+            .map(|sheet| sheet.name.clone())
+            .unwrap_or_default();
+        for (name, definition) in std::mem::take(&mut self.names) {
+            let resolved = resolve_name(&name, &definition, &first);
+            let target = match &resolved {
+                Ok((table, _)) => table.as_deref().unwrap_or(&first),
+                Err(_) => &first,
+            };
+            let Some(sheet) = self.sheets.iter_mut().find(|sheet| sheet.name == target) else {
+                continue;
+            };
+            match resolved {
+                Ok((_, named)) => sheet.save.sheet.names.push(named),
+                Err(reason) => sheet
+                    .warnings
+                    .push(format!("Named range {} {}", name, reason)),
+            }
+        }
+        for sheet in &mut self.sheets {
+            for warning in &self.warnings {
+                if !sheet.warnings.contains(warning) {
+                    sheet.warnings.push(warning.clone());
+                }
+            }
+        }
+        self.sheets
+    }
+}
+
+fn count_or_zero(attributes: &Attributes, name: &str) -> usize {
+    attributes
+        .get(name)
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+fn number_pattern(integers: usize, decimals: usize, grouping: bool) -> String {
+    let mut pattern = match (grouping, integers) {
+        (true, 0) => "#,###".to_string(),
+        (true, n) => format!("#,##{}", "0".repeat(n)),
+        (false, 0) => "#".to_string(),
+        (false, n) => "0".repeat(n),
+    };
+    if decimals > 0 {
+        pattern.push('.');
+        pattern.push_str(&"0".repeat(decimals));
+    }
+    pattern
+}
+
+/// Literal text in a number format, quoted unless it is punctuation that
+/// formats accept bare.
+fn format_literal(text: &str) -> String {
+    if text.chars().all(|c| " -/:,.()%".contains(c)) {
+        text.to_string()
+    } else {
+        format!("\"{}\"", text.replace('"', ""))
+    }
+}
+
+/// The table a name belongs to, if its definition says, and the name as
+/// the sheet stores it.
+fn resolve_name(
+    name: &str,
+    definition: &NameDefinition,
+    table: &str,
+) -> Result<(Option<String>, NamedRange), String> {
+    let named = |definition: String| NamedRange {
+        name: name.to_ascii_uppercase(),
+        description: String::new(),
+        definition,
+    };
+    match definition {
+        NameDefinition::Range(address) => {
+            let parts = split_outside_quotes(address.trim(), ':');
+            if parts.len() > 2 || address.trim().contains(' ') {
+                return Err("is not a simple range".to_string());
+            }
+            let mut owner = None;
+            let mut coords = Vec::new();
+            for part in parts {
+                let (part_table, cell) =
+                    parse_address(part).ok_or_else(|| "is not a simple range".to_string())?;
+                if owner.is_none() {
+                    owner = part_table;
+                }
+                coords.extend(CellCoord::parse(cell));
+            }
+            let range = CellRange::new(coords[0], *coords.last().unwrap_or(&coords[0]));
+            Ok((owner, named(range.to_string())))
+        }
+        NameDefinition::Expression { expression, base } => {
+            let owner = parse_address(base).and_then(|(owner, _)| owner);
+            let formula = from_openformula(expression, owner.as_deref().unwrap_or(table))
+                .map_err(|reason| format!("is not imported ({})", reason))?;
+            Ok((owner, named(format!("={}", formula))))
+        }
+    }
+}
+
+fn import_cell(
+    pending: &PendingCell,
+    coord: CellCoord,
+    table: &mut Table,
+    styles: &Styles,
+) -> Option<Cell> {
+    let mut cell = Cell::default();
+    let text = pending
+        .string_value
+        .clone()
+        .unwrap_or_else(|| pending.text.clone());
+
+    let number = match pending.value_type.as_deref() {
+        Some("float") => pending
+            .value
+            .as_deref()
+            .and_then(parse_number)
+            .map(|n| (n, "n")),
+        Some("percentage") => pending
+            .value
+            .as_deref()
+            .and_then(parse_number)
+            .map(|n| (n, "n%")),
+        Some("currency") => pending
+            .value
+            .as_deref()
+            .and_then(parse_number)
+            .map(|n| (n, "n$")),
+        Some("date") => pending
+            .date_value
+            .as_deref()
+            .and_then(parse_date_value)
+            .map(|(n, time)| (n, if time { "ndt" } else { "nd" })),
+        Some("time") => pending
+            .time_value
+            .as_deref()
+            .and_then(parse_time_value)
+            .map(|n| (n, "nt")),
+        Some("boolean") => pending
+            .boolean_value
+            .as_deref()
+            .map(|b| (if b == "true" { 1.0 } else { 0.0 }, "nl")),
+        _ => None,
+    };
+    match number {
+        Some((n, valuetype)) => {
+            cell.datatype = DataType::Value;
+            cell.valuetype = valuetype.to_string();
+            cell.datavalue = format_number(n);
+        }
+        None if !text.is_empty() => {
+            cell.datatype = DataType::Text;
+            cell.valuetype = "t".to_string();
+            cell.datavalue = text.clone();
+        }
+        None => {}
+    }
+
+    if let Some(formula) = &pending.formula {
+        match from_openformula(formula, &table.name) {
+            Ok(formula) => {
+                if cell.datatype == DataType::Empty {
+                    cell.valuetype = "n".to_string();
+                    cell.datavalue = "0".to_string();
+                }
+                if let Some(error) = FormulaError::from_name(&text) {
+                    cell.valuetype = format!("e{}", error.as_str());
+                    cell.datavalue = error.as_str().to_string();
+                }
+                cell.datatype = DataType::Formula;
+                cell.formula = formula;
+            }
+            Err(reason) => table.warnings.push(format!(
+                "{}: formula {} kept as its value ({})",
+                coord,
+                formula.strip_prefix("of:").unwrap_or(formula),
+                reason
+            )),
+        }
+    }
+
+    cell.colspan = Some(pending.colspan).filter(|span| *span > 1);
+    cell.rowspan = Some(pending.rowspan).filter(|span| *span > 1);
+    cell.comment = pending
+        .comment
+        .clone()
+        .filter(|comment| !comment.is_empty());
+
+    if cell.datatype == DataType::Empty
+        && cell.colspan.is_none()
+        && cell.rowspan.is_none()
+        && cell.comment.is_none()
+    {
+        return None;
+    }
+
+    if let Some(style) = &pending.style {
+        if !table.formatting.contains_key(style) {
+            let resolved = styles.resolve(style).unwrap_or_default();
+            let formatting = formatting(&mut table.sheet, &resolved, styles);
+            table.formatting.insert(style.clone(), formatting);
+        }
+        let formatting = &table.formatting[style];
+        cell.font = formatting.font;
+        cell.color = formatting.color;
+        cell.bgcolor = formatting.bgcolor;
+        cell.borders = formatting.borders.clone();
+        cell.layout = formatting.layout;
+        cell.cellformat = formatting.cellformat;
+        if cell.valuetype.starts_with('n') {
+            cell.nontextvalueformat = formatting.valueformat;
+        }
+    }
+
+    Some(cell)
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    value.trim().parse().ok().filter(|n: &f64| n.is_finite())
+}
+
+/// Adds the style's entries to the sheet's style tables.
+fn formatting(sheet: &mut Sheet, style: &CellStyle, styles: &Styles) -> Formatting {
+    let mut formatting = Formatting::default();
+
+    if style.font_style.is_some()
+        || style.font_weight.is_some()
+        || style.font_size.is_some()
+        || style.font_family.is_some()
+    {
+        let size = style.font_size.as_deref().and_then(|size| {
+            if size.ends_with("pt") || size.ends_with("px") {
+                Some(size.to_string())
+            } else {
+                length_px(size).map(|px| format!("{}pt", format_number((px * 0.75).round())))
+            }
+        });
+        let font = format!(
+            "{} {} {} {}",
+            style.font_style.as_deref().unwrap_or("*"),
+            style.font_weight.as_deref().unwrap_or("*"),
+            size.as_deref().unwrap_or("*"),
+            style.font_family.as_deref().unwrap_or("*"),
+        );
+        formatting.font = Some(intern(&mut sheet.fonts, font));
+    }
+
+    let color = |color: &Option<String>| color.as_deref().and_then(parse_rgb).map(rgb_string);
+    formatting.color = color(&style.color).map(|color| intern(&mut sheet.colors, color));
+    formatting.bgcolor = color(&style.background).map(|color| intern(&mut sheet.colors, color));
+
+    let borders = style
+        .borders
+        .clone()
+        .map(|border| border.as_deref().and_then(border_from_odf));
+    if borders.iter().any(Option::is_some) {
+        let [top, right, bottom, left] =
+            borders.map(|border| border.map(|border| intern(&mut sheet.borders, border)));
+        formatting.borders = Some(Borders {
+            top,
+            right,
+            bottom,
+            left,
+        });
+    }
+
+    let padding = style.padding.clone().map(|padding| {
+        padding
+            .as_deref()
+            .and_then(length_px)
+            .map(|px| format!("{}px", format_number(px.round())))
+    });
+    if padding.iter().any(Option::is_some) || style.vertical_align.is_some() {
+        let padding: Vec<&str> = padding
+            .iter()
+            .map(|side| side.as_deref().unwrap_or("*"))
+            .collect();
+        let layout = format!(
+            "padding:{};vertical-align:{};",
+            padding.join(" "),
+            style.vertical_align.as_deref().unwrap_or("*")
+        );
+        formatting.layout = Some(intern(&mut sheet.layouts, layout));
+    }
+
+    let alignment = match style.text_align.as_deref() {
+        Some("start" | "left") => Some("left"),
+        Some("center") => Some("center"),
+        Some("end" | "right") => Some("right"),
+        _ => None,
+    };
+    formatting.cellformat =
+        alignment.map(|alignment| intern(&mut sheet.cellformats, alignment.to_string()));
+
+    formatting.valueformat = style
+        .data_style
+        .as_ref()
+        .and_then(|name| styles.data.get(name).cloned().flatten())
+        .map(|format| intern(&mut sheet.valueformats, format));
+
+    formatting
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: &[(&str, &str)] = &[
+        ("budget", include_str!("testdata/budget.msc")),
+        ("contacts", include_str!("testdata/contacts.msc")),
+        ("styled", include_str!("testdata/styled.msc")),
+    ];
+
+    fn round_trip(sheets: &[(&str, &Sheet)]) -> Vec<ImportedSheet> {
+        from_ods(&to_ods(sheets).unwrap()).unwrap()
+    }
+
+    fn document(content: &[u8]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("mimetype", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(MIME_TYPE.as_bytes()).unwrap();
+        zip.start_file("content.xml", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(content).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// The font, colors and alignment a cell is displayed with, after
+    /// sheet defaults.
+    fn appearance(sheet: &Sheet, cell: &Cell) -> [Option<String>; 4] {
+        let attributes = &sheet.attributes;
+        let lookup =
+            |table: &BTreeMap<u32, String>, n: Option<u32>| n.and_then(|n| table.get(&n).cloned());
+        let alignment = cell.cellformat.or(if cell.valuetype.starts_with('t') {
+            attributes.defaulttextformat
+        } else {
+            attributes.defaultnontextformat
+        });
+        [
+            lookup(&sheet.fonts, cell.font.or(attributes.defaultfont)),
+            lookup(&sheet.colors, cell.color.or(attributes.defaultcolor)),
+            lookup(&sheet.colors, cell.bgcolor.or(attributes.defaultbgcolor)),
+            lookup(&sheet.cellformats, alignment),
+        ]
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        for (name, content) in SAMPLES {
+            let original = SpreadsheetSave::parse(content).unwrap().sheet;
+            let imported = round_trip(&[(name, &original)]);
+            assert_eq!(imported.len(), 1);
+            assert_eq!(imported[0].name, *name);
+            let sheet = &imported[0].save.sheet;
+
+            for (coord, cell) in &original.cells {
+                let copy = &sheet.cells[coord];
+                let context = format!("{} {}", name, coord);
+                if cell.datatype == DataType::Formula {
+                    assert_eq!(copy.formula, cell.formula, "{}", context);
+                } else if cell.valuetype.starts_with('n') {
+                    assert_eq!(copy.valuetype, cell.valuetype, "{}", context);
+                    let value = |cell: &Cell| cell.datavalue.parse::<f64>().unwrap();
+                    assert_eq!(value(copy), value(cell), "{}", context);
+                } else {
+                    assert_eq!(copy.datavalue, cell.datavalue, "{}", context);
+                }
+                assert_eq!(copy.colspan, cell.colspan, "{}", context);
+                assert_eq!(copy.rowspan, cell.rowspan, "{}", context);
+                assert_eq!(copy.comment, cell.comment, "{}", context);
+                assert_eq!(
+                    sheet.number_format(copy),
+                    original.number_format(cell),
+                    "{}",
+                    context
+                );
+                assert_eq!(
+                    appearance(sheet, copy),
+                    appearance(&original, cell),
+                    "{}",
+                    context
+                );
+            }
+
+            for (col, attributes) in &original.cols {
+                if let Some(width) = attributes.width.as_deref().filter(|w| *w != "auto") {
+                    assert_eq!(sheet.cols[col].width.as_deref(), Some(width), "{}", name);
+                }
+                assert_eq!(sheet.cols[col].hide, attributes.hide, "{}", name);
+            }
+            for (row, attributes) in &original.rows {
+                assert_eq!(sheet.rows[row].height, attributes.height, "{}", name);
+                assert_eq!(sheet.rows[row].hide, attributes.hide, "{}", name);
+            }
+
+            let names: Vec<_> = original
+                .names
+                .iter()
+                .map(|named| (&named.name, &named.definition))
+                .collect();
+            let copied: Vec<_> = sheet
+                .names
+                .iter()
+                .map(|named| (&named.name, &named.definition))
+                .collect();
+            assert_eq!(copied, names, "{}", name);
+        }
+    }
+
+    #[test]
+    fn styles_survive_a_round_trip() {
+        let original = SpreadsheetSave::parse(SAMPLES[2].1).unwrap().sheet;
+        let sheet = &round_trip(&[("styled", &original)])[0].save.sheet;
+
+        let title = &sheet.cells[&CellCoord::parse("A1").unwrap()];
+        assert_eq!(sheet.fonts[&title.font.unwrap()], "normal bold 14pt *");
+        assert_eq!(sheet.colors[&title.bgcolor.unwrap()], "rgb(255,255,224)");
+
+        let bordered = &sheet.cells[&CellCoord::parse("B2").unwrap()];
+        let borders = bordered.borders.as_ref().unwrap();
+        assert_eq!(
+            sheet.borders[&borders.bottom.unwrap()],
+            "2px dashed rgb(255,0,0)"
+        );
+        assert_eq!(borders.top, None);
+        assert_eq!(
+            sheet.layouts[&bordered.layout.unwrap()],
+            "padding:2px 2px 1px 2px;vertical-align:middle;"
+        );
+    }
+
+    #[test]
+    fn number_formats_round_trip() {
+        let formats = [
+            "#,##0.00",
+            "0.0%",
+            "[$£]#,##0.00",
+            "0.00E+00",
+            "dd/mm/yyyy",
+            "d-mmm-yyyy h:mm:ss",
+            "[h]:mm:ss",
+            "h:mm AM/PM",
+        ];
+        let mut sheet = Sheet::default();
+        for (i, format) in formats.iter().enumerate() {
+            let n = i as u32 + 1;
+            sheet.valueformats.insert(n, format.to_string());
+            sheet.cells.insert(
+                CellCoord::new(1, n),
+                Cell {
+                    datatype: DataType::Value,
+                    valuetype: "n".to_string(),
+                    datavalue: "45292.25".to_string(),
+                    nontextvalueformat: Some(n),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let imported = &round_trip(&[("formats", &sheet)])[0].save.sheet;
+        for (i, format) in formats.iter().enumerate() {
+            let cell = &imported.cells[&CellCoord::new(1, i as u32 + 1)];
+            assert_eq!(imported.number_format(cell).as_deref(), Some(*format));
+        }
+    }
+
+    #[test]
+    fn sheets_become_tables_with_their_own_names() {
+        let mut first = Sheet::parse("cell:A1:v:1\nname:TOTAL::A1\nname:RATE::A1\n").unwrap();
+        let second = Sheet::parse("cell:B2:v:2\nname:TOTAL::B2\n").unwrap();
+        first.attributes.lastcol = 1;
+
+        let bytes = to_ods(&[("Data", &first), ("data", &second), ("a/b", &second)]).unwrap();
+        let content = {
+            let mut archive = ZipArchive::new(Cursor::new(&bytes[..])).unwrap();
+            read_part(&mut archive, "content.xml").unwrap().unwrap()
+        };
+        // TOTAL is defined twice so each sheet keeps its own; RATE is global
+        assert!(
+            content.contains(r#"<table:named-expressions><table:named-range table:name="TOTAL""#)
+        );
+        assert!(content.ends_with(
+            r#"<table:named-expressions><table:named-range table:name="RATE" table:base-cell-address="$&apos;Data&apos;.$A$1" table:cell-range-address="$&apos;Data&apos;.$A$1:.$A$1"/></table:named-expressions></office:spreadsheet></office:body></office:document-content>"#
+        ));
+
+        let sheets = from_ods(&bytes).unwrap();
+        let names: Vec<_> = sheets.iter().map(|sheet| sheet.name.as_str()).collect();
+        assert_eq!(names, ["Data", "Sheet2", "ab"]);
+        let defined = |sheet: &ImportedSheet| -> Vec<String> {
+            sheet
+                .save
+                .sheet
+                .names
+                .iter()
+                .map(|named| format!("{}={}", named.name, named.definition))
+                .collect()
+        };
+        assert_eq!(defined(&sheets[0]), ["TOTAL=A1", "RATE=A1"]);
+        assert_eq!(defined(&sheets[1]), ["TOTAL=B2"]);
+        assert_eq!(
+            sheets[1].save.sheet.cells[&CellCoord::new(2, 2)].datavalue,
+            "2"
+        );
+    }
+
+    #[test]
+    fn formulas_translate_to_openformula_and_back() {
+        assert_eq!(
+            to_openformula(r#"IF(SUM(A1:B2,$C$3)>0,"a;b",A1)"#),
+            r#"of:=IF(SUM([.A1:.B2];[.$C$3])>0;"a;b";[.A1])"#
+        );
+        assert_eq!(
+            from_openformula("of:=SUM([.A1:.B2];[.$C$3])", "T").unwrap(),
+            "SUM(A1:B2,$C$3)"
+        );
+        assert_eq!(
+            from_openformula("of:=[$T.A1]+['It''s'.B1]", "It's").unwrap_err(),
+            "reference to another sheet"
+        );
+        assert_eq!(from_openformula("of:=['It''s'.B1]", "It's").unwrap(), "B1");
+        assert!(from_openformula("msoxl:=A1", "T").is_err());
+        assert!(from_openformula("of:=WEBSERVICE(\"x\")", "T").is_err());
+    }
+
+    #[test]
+    fn libreoffice_document_imports() {
+        let sheets = from_ods(&document(include_bytes!("testdata/libreoffice.xml"))).unwrap();
+        assert_eq!(sheets.len(), 2);
+        let budget = &sheets[0];
+        let sheet = &budget.save.sheet;
+        let cell = |coord: &str| &sheet.cells[&CellCoord::parse(coord).unwrap()];
+
+        // Repeated rows and columns are expanded only where they have content
+        assert_eq!(sheet.attributes.lastcol, 4);
+        assert_eq!(sheet.attributes.lastrow, 6);
+        assert_eq!(cell("A4").datavalue, "Savings");
+        assert_eq!(cell("B4").datavalue, "250");
+        assert_eq!(sheet.cols[&1].width.as_deref(), Some("144"));
+        assert_eq!(sheet.cols[&4].width.as_deref(), Some("85"));
+        assert!(!sheet.cols.contains_key(&5));
+        assert_eq!(sheet.rows[&1].height.as_deref(), Some("29"));
+        assert!(!sheet.rows.contains_key(&2));
+
+        let title = cell("A1");
+        assert_eq!(title.datavalue, "Household   budget");
+        assert_eq!(title.colspan, Some(2));
+        assert!(!sheet.cells.contains_key(&CellCoord::parse("B1").unwrap()));
+        assert_eq!(
+            sheet.fonts[&title.font.unwrap()],
+            "* bold * Liberation Sans"
+        );
+        assert_eq!(sheet.colors[&title.color.unwrap()], "rgb(0,0,128)");
+        assert_eq!(sheet.colors[&title.bgcolor.unwrap()], "rgb(221,221,221)");
+        assert_eq!(sheet.cellformats[&title.cellformat.unwrap()], "center");
+        let borders = title.borders.as_ref().unwrap();
+        assert_eq!(borders.top, None);
+        assert_eq!(
+            sheet.borders[&borders.bottom.unwrap()],
+            "1px solid rgb(0,0,0)"
+        );
+
+        assert_eq!(
+            cell("B2").comment.as_deref(),
+            Some("Due on the 1st\nPaid by transfer")
+        );
+        assert_eq!(sheet.number_format(cell("B2")).as_deref(), Some("#,##0.00"));
+        // Inherited from the parent style
+        assert_eq!(sheet.number_format(cell("B3")).as_deref(), Some("#,##0.00"));
+        assert_eq!(sheet.fonts[&cell("B3").font.unwrap()], "italic * 12pt *");
+
+        assert_eq!(cell("B5").formula, "SUM(B2:B4)");
+        assert_eq!(cell("B5").datavalue, "1700");
+        assert_eq!(cell("C5").datatype, DataType::Value);
+        assert_eq!(cell("C5").datavalue, "340");
+
+        assert_eq!(cell("B6").valuetype, "nd");
+        assert_eq!(cell("B6").datavalue, "45352");
+        assert_eq!(
+            sheet.number_format(cell("B6")).as_deref(),
+            Some("dd/mm/yyyy")
+        );
+        assert_eq!(cell("C6").valuetype, "n$");
+        assert_eq!(
+            sheet.number_format(cell("C6")).as_deref(),
+            Some("[$£]#,##0.00")
+        );
+        assert_eq!(cell("D6").valuetype, "nl");
+
+        let names: Vec<_> = sheet
+            .names
+            .iter()
+            .map(|named| (named.name.as_str(), named.definition.as_str()))
+            .collect();
+        assert_eq!(names, [("SAVINGS", "B3:B4"), ("DOUBLE", "=B5*2")]);
+        assert_eq!(
+            budget.warnings,
+            [
+                "C5: formula =[.B5]*[$Rates.$B$1] kept as its value (reference to another sheet)",
+                "Data validation rules are not imported",
+            ]
+        );
+
+        let rates = &sheets[1].save.sheet;
+        assert_eq!(sheets[1].name, "Rates");
+        assert_eq!(rates.cells[&CellCoord::new(2, 1)].valuetype, "n%");
+        assert_eq!(rates.names[0].name, "TAXRATE");
+        assert_eq!(rates.names[0].definition, "B1");
+    }
+
+    #[test]
+    fn non_documents_are_rejected() {
+        assert!(from_ods(b"not a zip").is_err());
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("mimetype", SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(MIME_TYPE.as_bytes()).unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+        assert!(matches!(from_ods(&bytes), Err(OdsError::Invalid(_))));
+    }
+}
@@ -0,0 +1,937 @@
+//! Printable pages for a sheet, drawn from the same layout as the HTML
+//! output. Text is set in DejaVu Sans, subset and embedded so the file looks
+//! the same in every viewer.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::ops::Range;
+
+use flate2::{write::ZlibEncoder, Compression};
+use pdf_writer::types::{CidFontType, FontFlags, LineCapStyle, SystemInfo};
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+use thiserror::Error;
+use ttf_parser::{Face, GlyphId};
+
+use super::render::{layout, Align, CellStyle, Grid};
+use super::style::parse_rgb;
+use super::{CellRange, Sheet};
+
+pub const MIME_TYPE: &str = "application/pdf";
+
+const REGULAR: &[u8] = include_bytes!("../../assets/fonts/DejaVuSans.ttf");
+const BOLD: &[u8] = include_bytes!("../../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// CSS pixels are 1/96 inch, points 1/72.
+const PT_PER_PX: f32 = 0.75;
+const DEFAULT_FONT_SIZE: f32 = 10.0;
+const LINE_HEIGHT: f32 = 1.2;
+/// Italics are slanted from the upright faces.
+const ITALIC_SKEW: f32 = 0.2;
+/// `2px 3px`, as in the HTML output: top, right, bottom, left.
+const DEFAULT_PADDING: [f32; 4] = [1.5, 2.25, 1.5, 2.25];
+/// Rows without a height of their own are at least this tall.
+const MIN_ROW_HEIGHT: f32 = 15.0;
+const FOOTER_SIZE: f32 = 8.0;
+
+const SYSTEM_INFO: SystemInfo = SystemInfo {
+    registry: Str(b"Adobe"),
+    ordering: Str(b"Identity"),
+    supplement: 0,
+};
+
+#[derive(Debug, Error)]
+pub enum PdfError {
+    #[error("margins leave no room on the page")]
+    NoRoom,
+    #[error("font cannot be embedded: {0}")]
+    Font(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageSize {
+    #[default]
+    A4,
+    A3,
+    Letter,
+    Legal,
+}
+
+impl PageSize {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "a4" => Some(PageSize::A4),
+            "a3" => Some(PageSize::A3),
+            "letter" => Some(PageSize::Letter),
+            "legal" => Some(PageSize::Legal),
+            _ => None,
+        }
+    }
+
+    /// Portrait width and height in points.
+    fn dimensions(self) -> (f32, f32) {
+        match self {
+            PageSize::A4 => (595.28, 841.89),
+            PageSize::A3 => (841.89, 1190.55),
+            PageSize::Letter => (612.0, 792.0),
+            PageSize::Legal => (612.0, 1008.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdfOptions {
+    pub page_size: PageSize,
+    pub landscape: bool,
+    /// In points, on every side.
+    pub margin: f32,
+    /// Scales the sheet down so all of its columns fit across one page.
+    pub fit_width: bool,
+    /// Rows at the top of the area that are repeated on every page.
+    pub header_rows: u32,
+    pub page_numbers: bool,
+    pub title: Option<String>,
+}
+
+impl Default for PdfOptions {
+    fn default() -> Self {
+        PdfOptions {
+            page_size: PageSize::A4,
+            landscape: false,
+            margin: 36.0,
+            fit_width: false,
+            header_rows: 0,
+            page_numbers: true,
+            title: None,
+        }
+    }
+}
+
+/// Writes `range`, or the used area of the sheet, as a PDF document. Returns
+/// the document and its number of pages.
+pub fn to_pdf(
+    sheet: &Sheet,
+    range: Option<CellRange>,
+    options: &PdfOptions,
+) -> Result<(Vec<u8>, usize), PdfError> {
+    let (width, height) = options.page_size.dimensions();
+    let (page_width, page_height) = if options.landscape {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let margin = options.margin.max(0.0);
+    let area_width = page_width - 2.0 * margin;
+    let area_height = page_height - 2.0 * margin;
+    if area_width < 72.0 || area_height < 72.0 {
+        return Err(PdfError::NoRoom);
+    }
+
+    let mut fonts = [FontFace::new(REGULAR)?, FontFace::new(BOLD)?];
+    let grid = layout(sheet, range);
+    let table = Table::new(&grid, &fonts);
+
+    let total_width: f32 = table.widths.iter().sum();
+    let scale = if options.fit_width && total_width > area_width {
+        area_width / total_width
+    } else {
+        1.0
+    };
+    // A header too tall to leave room for the body is printed only once
+    let header = (options.header_rows as usize).min(table.heights.len());
+    let header_height: f32 = table.heights[..header].iter().sum();
+    let header = if header_height * scale <= area_height / 2.0 {
+        header
+    } else {
+        0
+    };
+    // Dividing by the scale again could round just below the total width
+    let band_width = if scale < 1.0 { total_width } else { area_width };
+    let pages = paginate(
+        &table.heights,
+        &table.widths,
+        header,
+        band_width,
+        area_height / scale,
+    );
+
+    let mut contents = Vec::with_capacity(pages.len());
+    for (number, page) in pages.iter().enumerate() {
+        let mut content = Content::new();
+        content.save_state();
+        content.transform([scale, 0.0, 0.0, scale, margin, page_height - margin]);
+        table.draw(&mut content, &mut fonts, header, page);
+        content.restore_state();
+        if options.page_numbers {
+            let footer = format!("Page {} of {}", number + 1, pages.len());
+            let x = (page_width - fonts[0].width(&footer, FOOTER_SIZE)) / 2.0;
+            let y = (margin / 2.0 - FOOTER_SIZE / 3.0).max(4.0);
+            content.set_fill_gray(0.4);
+            show_text(
+                &mut content,
+                &mut fonts[0],
+                0,
+                &footer,
+                FOOTER_SIZE,
+                x,
+                y,
+                false,
+            );
+        }
+        contents.push(content.finish());
+    }
+
+    let mut next_id = 1;
+    let mut alloc = || {
+        let id = Ref::new(next_id);
+        next_id += 1;
+        id
+    };
+    let catalog_id = alloc();
+    let tree_id = alloc();
+    let info_id = alloc();
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(tree_id);
+    let mut info = pdf.document_info(info_id);
+    if let Some(title) = &options.title {
+        info.title(TextStr(title));
+    }
+    info.producer(TextStr("Aspiring Investments"));
+    info.finish();
+
+    let mut font_ids = Vec::new();
+    for (index, face) in fonts.iter().enumerate() {
+        if !face.used.is_empty() {
+            let id = face.write(
+                &mut pdf,
+                &mut alloc,
+                if index == 0 {
+                    "DejaVuSans"
+                } else {
+                    "DejaVuSans-Bold"
+                },
+            )?;
+            font_ids.push((font_name(index), id));
+        }
+    }
+
+    let page_ids: Vec<Ref> = contents.iter().map(|_| alloc()).collect();
+    pdf.pages(tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+    for (page_id, content) in page_ids.iter().zip(&contents) {
+        let content_id = alloc();
+        let mut page = pdf.page(*page_id);
+        page.media_box(Rect::new(0.0, 0.0, page_width, page_height))
+            .parent(tree_id)
+            .contents(content_id);
+        let mut resources = page.resources();
+        let mut resource_fonts = resources.fonts();
+        for (name, id) in &font_ids {
+            resource_fonts.pair(Name(name.as_bytes()), *id);
+        }
+        resource_fonts.finish();
+        resources.finish();
+        page.finish();
+        pdf.stream(content_id, &deflate(content)?)
+            .filter(Filter::FlateDecode);
+    }
+
+    Ok((pdf.finish(), contents.len()))
+}
+
+/// The rows and columns printed on one page, besides the repeated header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PagePlan {
+    rows: Range<usize>,
+    cols: Range<usize>,
+}
+
+/// Splits the rows after the header into pages, and the columns into bands
+/// of pages, in reading order: down the rows, then across.
+fn paginate(
+    heights: &[f32],
+    widths: &[f32],
+    header: usize,
+    area_width: f32,
+    area_height: f32,
+) -> Vec<PagePlan> {
+    let header_height: f32 = heights[..header].iter().sum();
+    let row_pages: Vec<Range<usize>> = split(&heights[header..], area_height - header_height)
+        .into_iter()
+        .map(|rows| rows.start + header..rows.end + header)
+        .collect();
+    let col_bands = split(widths, area_width);
+
+    let mut pages = Vec::new();
+    for cols in &col_bands {
+        for rows in &row_pages {
+            pages.push(PagePlan {
+                rows: rows.clone(),
+                cols: cols.clone(),
+            });
+        }
+    }
+    if pages.is_empty() {
+        pages.push(PagePlan {
+            rows: 0..0,
+            cols: 0..0,
+        });
+    }
+    pages
+}
+
+/// Consecutive runs of `sizes` that fit in `limit`, with at least one item
+/// in each.
+fn split(sizes: &[f32], limit: f32) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    let mut used = 0.0;
+    for (index, size) in sizes.iter().enumerate() {
+        if index > start && used + size > limit {
+            runs.push(start..index);
+            start = index;
+            used = 0.0;
+        }
+        used += size;
+    }
+    if start < sizes.len() {
+        runs.push(start..sizes.len());
+    }
+    runs
+}
+
+/// The grid measured in points, with each cell's text broken into lines.
+struct Table<'a> {
+    grid: &'a Grid,
+    widths: Vec<f32>,
+    heights: Vec<f32>,
+    /// Grid column index of each sheet column.
+    columns: HashMap<u32, usize>,
+    lines: Vec<Vec<Vec<String>>>,
+}
+
+impl<'a> Table<'a> {
+    fn new(grid: &'a Grid, fonts: &[FontFace; 2]) -> Self {
+        let widths: Vec<f32> = grid
+            .columns
+            .iter()
+            .map(|column| column.width as f32 * PT_PER_PX)
+            .collect();
+        let columns: HashMap<u32, usize> = grid
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, column)| (column.col, index))
+            .collect();
+
+        let mut heights = Vec::with_capacity(grid.rows.len());
+        let mut lines = Vec::with_capacity(grid.rows.len());
+        for row in &grid.rows {
+            let mut content_height: f32 = 0.0;
+            let row_lines: Vec<Vec<String>> = row
+                .cells
+                .iter()
+                .map(|cell| {
+                    let start = columns[&cell.coord.col];
+                    let end = (start + cell.colspan as usize).min(widths.len());
+                    let padding = padding(&cell.style);
+                    let width = widths[start..end].iter().sum::<f32>() - padding[1] - padding[3];
+                    let size = font_size(&cell.style);
+                    let cell_lines =
+                        wrap(&fonts[cell.style.bold as usize], &cell.text, size, width);
+                    if cell.rowspan <= 1 {
+                        let height = cell_lines.len() as f32 * size * LINE_HEIGHT;
+                        content_height = content_height.max(height + padding[0] + padding[2]);
+                    }
+                    cell_lines
+                })
+                .collect();
+            heights.push(match row.height {
+                Some(height) => height as f32 * PT_PER_PX,
+                None => content_height.max(MIN_ROW_HEIGHT),
+            });
+            lines.push(row_lines);
+        }
+
+        Table {
+            grid,
+            widths,
+            heights,
+            columns,
+            lines,
+        }
+    }
+
+    fn draw(
+        &self,
+        content: &mut Content,
+        fonts: &mut [FontFace; 2],
+        header: usize,
+        page: &PagePlan,
+    ) {
+        let rows: Vec<usize> = (0..header).chain(page.rows.clone()).collect();
+        let mut tops = HashMap::new();
+        let mut y = 0.0;
+        for (position, &row) in rows.iter().enumerate() {
+            tops.insert(row, (y, position));
+            y += self.heights[row];
+        }
+        let mut lefts = HashMap::new();
+        let mut x = 0.0;
+        for col in page.cols.clone() {
+            lefts.insert(col, x);
+            x += self.widths[col];
+        }
+
+        // Each cell's box on this page; spans are cut at the page's edges
+        let mut boxes = Vec::new();
+        for &row in &rows {
+            for (index, cell) in self.grid.rows[row].cells.iter().enumerate() {
+                let start = self.columns[&cell.coord.col];
+                let Some(&left) = lefts.get(&start) else {
+                    continue;
+                };
+                let width: f32 = (start..start + cell.colspan as usize)
+                    .take_while(|col| page.cols.contains(col))
+                    .map(|col| self.widths[col])
+                    .sum();
+                // Rows of the span that follow on this page
+                let (top, position) = tops[&row];
+                let height: f32 = (row..row + cell.rowspan as usize)
+                    .enumerate()
+                    .take_while(|(offset, span_row)| {
+                        tops.get(span_row)
+                            .is_some_and(|(_, p)| *p == position + offset)
+                    })
+                    .map(|(_, span_row)| self.heights[span_row])
+                    .sum();
+                boxes.push((row, index, left, top, width, height));
+            }
+        }
+
+        let base = &self.grid.default_style;
+        for &(row, index, x, y, width, height) in &boxes {
+            let style = &self.grid.rows[row].cells[index].style;
+            if let Some(rgb) = style.background.or(base.background) {
+                set_fill(content, rgb);
+                content.rect(x, -y - height, width, height).fill_nonzero();
+            }
+        }
+
+        for &(row, index, x, y, width, height) in &boxes {
+            let lines = &self.lines[row][index];
+            if lines.iter().all(String::is_empty) {
+                continue;
+            }
+            let style = &self.grid.rows[row].cells[index].style;
+            let face = style.bold as usize;
+            let size = font_size(style);
+            let padding = padding(style);
+            let line_height = size * LINE_HEIGHT;
+            let block = lines.len() as f32 * line_height;
+            let offset = match style.vertical_align.as_deref() {
+                Some("top") => padding[0],
+                Some("middle") => (height - block) / 2.0,
+                _ => height - padding[2] - block,
+            };
+            let ascent = fonts[face].ascent() * size;
+
+            content.save_state();
+            content
+                .rect(x, -y - height, width, height)
+                .clip_nonzero()
+                .end_path();
+            set_fill(content, style.color.unwrap_or(0));
+            for (number, line) in lines.iter().enumerate() {
+                let text_width = fonts[face].width(line, size);
+                let left = match style.align {
+                    Align::Left => x + padding[3],
+                    Align::Center => x + (width - text_width) / 2.0,
+                    Align::Right => x + width - padding[1] - text_width,
+                };
+                let baseline =
+                    y + offset + number as f32 * line_height + (line_height - size) / 2.0 + ascent;
+                show_text(
+                    content,
+                    &mut fonts[face],
+                    face,
+                    line,
+                    size,
+                    left,
+                    -baseline,
+                    style.italic,
+                );
+            }
+            content.restore_state();
+        }
+
+        content.set_line_cap(LineCapStyle::ProjectingSquareCap);
+        for &(row, index, x, y, width, height) in &boxes {
+            let style = &self.grid.rows[row].cells[index].style;
+            let (top, bottom) = (-y, -y - height);
+            let edges = [
+                (x, top, x + width, top),
+                (x + width, top, x + width, bottom),
+                (x, bottom, x + width, bottom),
+                (x, top, x, bottom),
+            ];
+            for (border, (x1, y1, x2, y2)) in style.borders.iter().zip(edges) {
+                let Some(border) = border.as_deref().and_then(Border::parse) else {
+                    continue;
+                };
+                content.save_state();
+                content.set_line_width(border.width);
+                if let Some(dash) = border.dash {
+                    content.set_dash_pattern(dash, 0.0);
+                }
+                let (r, g, b) = rgb(border.color);
+                content.set_stroke_rgb(r, g, b);
+                content.move_to(x1, y1).line_to(x2, y2).stroke();
+                content.restore_state();
+            }
+        }
+    }
+}
+
+/// A CSS border such as `1px solid rgb(0,0,0)`.
+struct Border {
+    width: f32,
+    dash: Option<[f32; 2]>,
+    color: u32,
+}
+
+impl Border {
+    fn parse(css: &str) -> Option<Self> {
+        let (rest, color) = match css.find("rgb(").or_else(|| css.find('#')) {
+            Some(start) => {
+                let end = match css[start..].find(|c: char| c == ')' || c.is_whitespace()) {
+                    Some(end) if css[start..].starts_with("rgb(") => start + end + 1,
+                    Some(end) => start + end,
+                    None => css.len(),
+                };
+                let color = css.get(start..end).and_then(parse_rgb);
+                (
+                    format!("{} {}", &css[..start], css.get(end..).unwrap_or("")),
+                    color,
+                )
+            }
+            None => (css.to_string(), None),
+        };
+
+        let mut width = PT_PER_PX;
+        let mut dash = None;
+        for token in rest.split_whitespace() {
+            match token {
+                "none" | "hidden" => return None,
+                "thin" => width = PT_PER_PX,
+                "medium" => width = 3.0 * PT_PER_PX,
+                "thick" => width = 5.0 * PT_PER_PX,
+                "dashed" => dash = Some([3.0, 2.0]),
+                "dotted" => dash = Some([1.0, 1.0]),
+                _ => {
+                    if let Some(px) = token
+                        .strip_suffix("px")
+                        .and_then(|px| px.parse::<f32>().ok())
+                    {
+                        width = px * PT_PER_PX;
+                    }
+                }
+            }
+        }
+        if width <= 0.0 {
+            return None;
+        }
+        Some(Border {
+            width,
+            dash: dash.map(|[on, off]| [on * width, off * width]),
+            color: color.unwrap_or(0),
+        })
+    }
+}
+
+/// One of the embedded faces, and the glyphs the document has used from it.
+struct FontFace {
+    data: &'static [u8],
+    face: Face<'static>,
+    used: BTreeMap<u16, char>,
+}
+
+impl FontFace {
+    fn new(data: &'static [u8]) -> Result<Self, PdfError> {
+        let face = Face::parse(data, 0).map_err(|err| PdfError::Font(err.to_string()))?;
+        Ok(FontFace {
+            data,
+            face,
+            used: BTreeMap::new(),
+        })
+    }
+
+    fn glyph(&self, c: char) -> u16 {
+        self.face.glyph_index(c).map_or(0, |glyph| glyph.0)
+    }
+
+    /// In thousandths of the font size, as PDF widths are given.
+    fn advance(&self, glyph: u16) -> f32 {
+        let advance = self.face.glyph_hor_advance(GlyphId(glyph)).unwrap_or(0);
+        advance as f32 * 1000.0 / self.face.units_per_em() as f32
+    }
+
+    fn units(&self, value: i16) -> f32 {
+        value as f32 * 1000.0 / self.face.units_per_em() as f32
+    }
+
+    fn ascent(&self) -> f32 {
+        self.units(self.face.ascender()) / 1000.0
+    }
+
+    fn width(&self, text: &str, size: f32) -> f32 {
+        text.chars()
+            .map(|c| self.advance(self.glyph(c)))
+            .sum::<f32>()
+            * size
+            / 1000.0
+    }
+
+    /// Two-byte glyph ids for the `Identity-H` encoding.
+    fn encode(&mut self, text: &str) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(text.len() * 2);
+        for c in text.chars() {
+            let glyph = self.glyph(c);
+            self.used.entry(glyph).or_insert(c);
+            encoded.extend(glyph.to_be_bytes());
+        }
+        encoded
+    }
+
+    /// Writes the font as a Type 0 font with a TrueType descendant, embedding
+    /// only the glyphs used.
+    fn write(
+        &self,
+        pdf: &mut Pdf,
+        alloc: &mut impl FnMut() -> Ref,
+        name: &str,
+    ) -> Result<Ref, PdfError> {
+        let (type0_id, cid_id, descriptor_id, cmap_id, file_id) =
+            (alloc(), alloc(), alloc(), alloc(), alloc());
+        let glyphs: Vec<u16> = std::iter::once(0)
+            .chain(self.used.keys().copied())
+            .collect();
+        let base_font = format!("{}+{}", subset_tag(&glyphs), name);
+        let base_font = Name(base_font.as_bytes());
+
+        pdf.type0_font(type0_id)
+            .base_font(base_font)
+            .encoding_predefined(Name(b"Identity-H"))
+            .descendant_font(cid_id)
+            .to_unicode(cmap_id);
+
+        let mut cid_font = pdf.cid_font(cid_id);
+        cid_font
+            .subtype(CidFontType::Type2)
+            .base_font(base_font)
+            .system_info(SYSTEM_INFO)
+            .font_descriptor(descriptor_id)
+            .default_width(0.0)
+            .cid_to_gid_map_predefined(Name(b"Identity"));
+        let mut widths = cid_font.widths();
+        for &glyph in self.used.keys() {
+            widths.consecutive(glyph, [self.advance(glyph)]);
+        }
+        widths.finish();
+        cid_font.finish();
+
+        let bbox = self.face.global_bounding_box();
+        pdf.font_descriptor(descriptor_id)
+            .name(base_font)
+            .flags(FontFlags::SYMBOLIC)
+            .bbox(Rect::new(
+                self.units(bbox.x_min),
+                self.units(bbox.y_min),
+                self.units(bbox.x_max),
+                self.units(bbox.y_max),
+            ))
+            .italic_angle(0.0)
+            .ascent(self.units(self.face.ascender()))
+            .descent(self.units(self.face.descender()))
+            .cap_height(self.units(self.face.capital_height().unwrap_or(self.face.ascender())))
+            .stem_v(if self.face.is_bold() { 140.0 } else { 80.0 })
+            .font_file2(file_id);
+
+        pdf.stream(cmap_id, &deflate(&self.to_unicode())?)
+            .filter(Filter::FlateDecode);
+
+        let subset = subsetter::subset(self.data, 0, subsetter::Profile::pdf(&glyphs))
+            .map_err(|err| PdfError::Font(err.to_string()))?;
+        pdf.stream(file_id, &deflate(&subset)?)
+            .filter(Filter::FlateDecode);
+        Ok(type0_id)
+    }
+}
+
+impl FontFace {
+    /// Maps the glyphs back to text, for copying and searching. This is the
+    /// minimal form from the PDF specification, which readers parse most
+    /// reliably.
+    fn to_unicode(&self) -> Vec<u8> {
+        let mut cmap = String::from(concat!(
+            "/CIDInit /ProcSet findresource begin\n",
+            "12 dict begin\n",
+            "begincmap\n",
+            "/CIDSystemInfo << /Registry (Adobe) /Ordering (UCS) /Supplement 0 >> def\n",
+            "/CMapName /Adobe-Identity-UCS def\n",
+            "/CMapType 2 def\n",
+            "1 begincodespacerange\n",
+            "<0000> <FFFF>\n",
+            "endcodespacerange\n",
+        ));
+        let used: Vec<(&u16, &char)> = self.used.iter().collect();
+        for chunk in used.chunks(100) {
+            cmap.push_str(&format!("{} beginbfchar\n", chunk.len()));
+            for (glyph, c) in chunk {
+                let text: String = c
+                    .encode_utf16(&mut [0; 2])
+                    .iter()
+                    .map(|unit| format!("{:04X}", unit))
+                    .collect();
+                cmap.push_str(&format!("<{:04X}> <{}>\n", glyph, text));
+            }
+            cmap.push_str("endbfchar\n");
+        }
+        cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend\n");
+        cmap.into_bytes()
+    }
+}
+
+fn font_name(face: usize) -> String {
+    format!("F{}", face)
+}
+
+/// Subset fonts are named with six capital letters that differ between
+/// subsets.
+fn subset_tag(glyphs: &[u16]) -> String {
+    let hash = glyphs.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, glyph| {
+        (hash ^ *glyph as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    (0..6)
+        .map(|i| (b'A' + (hash >> (i * 5) & 0x1f) as u8 % 26) as char)
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn show_text(
+    content: &mut Content,
+    face: &mut FontFace,
+    index: usize,
+    text: &str,
+    size: f32,
+    x: f32,
+    y: f32,
+    italic: bool,
+) {
+    let skew = if italic { ITALIC_SKEW } else { 0.0 };
+    let font = font_name(index);
+    content.begin_text();
+    content.set_font(Name(font.as_bytes()), size);
+    content.set_text_matrix([1.0, 0.0, skew, 1.0, x, y]);
+    content.show(Str(&face.encode(text)));
+    content.end_text();
+}
+
+/// Breaks text at newlines, and between words where it is wider than
+/// `width`. A word too long for the cell is left whole and clipped.
+fn wrap(face: &FontFace, text: &str, size: f32, width: f32) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if !line.is_empty() && face.width(&candidate, size) > width {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            } else {
+                line = candidate;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+fn font_size(style: &CellStyle) -> f32 {
+    style
+        .font_size
+        .map(|size| size as f32)
+        .filter(|size| *size > 0.0)
+        .unwrap_or(DEFAULT_FONT_SIZE)
+}
+
+/// Top, right, bottom and left padding in points, from CSS shorthand.
+fn padding(style: &CellStyle) -> [f32; 4] {
+    let Some(css) = style.padding.as_deref() else {
+        return DEFAULT_PADDING;
+    };
+    let values: Option<Vec<f32>> = css
+        .split_whitespace()
+        .map(|value| {
+            let px = value.strip_suffix("px").unwrap_or(value);
+            px.parse::<f32>().ok().map(|px| px * PT_PER_PX)
+        })
+        .collect();
+    match values.as_deref() {
+        Some([all]) => [*all; 4],
+        Some([vertical, horizontal]) => [*vertical, *horizontal, *vertical, *horizontal],
+        Some([top, horizontal, bottom]) => [*top, *horizontal, *bottom, *horizontal],
+        Some([top, right, bottom, left]) => [*top, *right, *bottom, *left],
+        _ => DEFAULT_PADDING,
+    }
+}
+
+fn rgb(color: u32) -> (f32, f32, f32) {
+    let channel = |shift: u32| (color >> shift & 0xff) as f32 / 255.0;
+    (channel(16), channel(8), channel(0))
+}
+
+fn set_fill(content: &mut Content, color: u32) {
+    let (r, g, b) = rgb(color);
+    content.set_fill_rgb(r, g, b);
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socialcalc::{recalc, SpreadsheetSave};
+    use lopdf::Document;
+
+    fn sample(content: &str) -> Sheet {
+        let mut save = SpreadsheetSave::parse(content).unwrap();
+        recalc(&mut save.sheet);
+        save.sheet
+    }
+
+    /// The text of each page, as a reader extracts it.
+    fn pages(sheet: &Sheet, options: &PdfOptions) -> Vec<String> {
+        let (bytes, count) = to_pdf(sheet, None, options).unwrap();
+        let document = Document::load_mem(&bytes).unwrap();
+        let numbers: Vec<u32> = document.get_pages().keys().copied().collect();
+        assert_eq!(numbers.len(), count);
+        numbers
+            .iter()
+            .map(|number| document.extract_text(&[*number]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn text_is_embedded_and_extractable() {
+        let sheet = sample(include_str!("testdata/budget.msc"));
+        let (bytes, _) = to_pdf(&sheet, None, &PdfOptions::default()).unwrap();
+        let document = Document::load_mem(&bytes).unwrap();
+        let fonts = document
+            .get_page_fonts(document.page_iter().next().unwrap())
+            .unwrap();
+        assert_eq!(fonts.len(), 2);
+        for font in fonts.values() {
+            let base_font = font.get(b"BaseFont").unwrap().as_name().unwrap();
+            // Subset fonts carry a tag, e.g. `ABCDEF+DejaVuSans`
+            assert_eq!(base_font[6], b'+');
+        }
+
+        let text = pages(&sheet, &PdfOptions::default());
+        assert_eq!(text.len(), 1);
+        for expected in ["Item", "1,200.00", "83.9%", "Page 1 of 1"] {
+            assert!(
+                text[0].contains(expected),
+                "{} missing from {:?}",
+                expected,
+                text[0]
+            );
+        }
+    }
+
+    #[test]
+    fn header_rows_repeat_on_every_page() {
+        let mut lines = vec!["cell:A1:t:Amount".to_string()];
+        lines.extend((2..=150).map(|row| format!("cell:A{}:v:{}", row, row)));
+        let sheet = Sheet::parse(&lines.join("\n")).unwrap();
+        let options = PdfOptions {
+            header_rows: 1,
+            ..Default::default()
+        };
+
+        let text = pages(&sheet, &options);
+        assert!(text.len() > 1);
+        for (number, page) in text.iter().enumerate() {
+            assert!(
+                page.starts_with("Amount\n"),
+                "page {} is {:?}",
+                number + 1,
+                page
+            );
+            assert!(page.contains(&format!("Page {} of {}", number + 1, text.len())));
+        }
+        assert!(text.last().unwrap().contains("\n150\n"));
+
+        let text = pages(&sheet, &PdfOptions::default());
+        assert!(!text[1].contains("Amount"));
+    }
+
+    #[test]
+    fn wide_sheets_split_across_pages_unless_fit_to_width() {
+        let sheet = sample(include_str!("testdata/styled.msc"));
+        assert!(pages(&sheet, &PdfOptions::default()).len() > 1);
+
+        let options = PdfOptions {
+            fit_width: true,
+            ..Default::default()
+        };
+        let text = pages(&sheet, &options);
+        assert_eq!(text.len(), 1);
+        // AB12 is narrow enough to wrap
+        assert!(text[0].contains("far\naway"));
+
+        let options = PdfOptions {
+            landscape: true,
+            page_size: PageSize::A3,
+            ..Default::default()
+        };
+        assert!(pages(&sheet, &options).len() < pages(&sheet, &PdfOptions::default()).len());
+    }
+
+    #[test]
+    fn margins_must_leave_room() {
+        let options = PdfOptions {
+            margin: 300.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            to_pdf(&Sheet::default(), None, &options),
+            Err(PdfError::NoRoom)
+        ));
+    }
+
+    #[test]
+    fn pages_run_down_then_across() {
+        let pages = paginate(&[10.0; 5], &[40.0, 40.0, 40.0], 1, 80.0, 30.0);
+        let plan: Vec<(Range<usize>, Range<usize>)> = pages
+            .into_iter()
+            .map(|page| (page.rows, page.cols))
+            .collect();
+        assert_eq!(
+            plan,
+            [(1..3, 0..2), (3..5, 0..2), (1..3, 2..3), (3..5, 2..3)]
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! A sheet laid out as it displays: the visible rows and columns, merged
+//! cells, formatted values and resolved styles. The HTML and PDF output
+//! are both drawn from this.
+
+use std::collections::{HashMap, HashSet};
+
+use super::format::{format_general, format_value};
+use super::style::{font_size_pt, layout_property, parse_rgb, Font};
+use super::{Cell, CellCoord, CellRange, DataType, NumberKind, Sheet, Value};
+
+/// SocialCalc's column width when neither the column nor the sheet sets one.
+pub const DEFAULT_COL_WIDTH: f64 = 80.0;
+
+/// Larger areas are cut off after the last row, or column, that fits.
+const MAX_RENDER_CELLS: usize = 250_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CellStyle {
+    pub italic: bool,
+    pub bold: bool,
+    /// In points.
+    pub font_size: Option<f64>,
+    pub font_family: Option<String>,
+    /// `0xrrggbb`.
+    pub color: Option<u32>,
+    pub background: Option<u32>,
+    /// CSS borders, top, right, bottom and left.
+    pub borders: [Option<String>; 4],
+    /// CSS padding, such as `2px 4px`.
+    pub padding: Option<String>,
+    pub align: Align,
+    /// `top`, `middle` or `bottom`.
+    pub vertical_align: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridCell {
+    pub coord: CellCoord,
+    pub text: String,
+    /// Target of a link cell; only `http`, `https` and `mailto` URLs.
+    pub link: Option<String>,
+    /// Spans in visible columns and rows.
+    pub colspan: u32,
+    pub rowspan: u32,
+    pub style: CellStyle,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridColumn {
+    pub col: u32,
+    /// In pixels.
+    pub width: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridRow {
+    pub row: u32,
+    /// In pixels; `None` fits the content.
+    pub height: Option<f64>,
+    /// Every visible cell of the row that is not covered by a merge.
+    pub cells: Vec<GridCell>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grid {
+    pub columns: Vec<GridColumn>,
+    pub rows: Vec<GridRow>,
+    /// The style of a cell with no formatting of its own.
+    pub default_style: CellStyle,
+    /// Whether rows were left out because the area was too large.
+    pub truncated: bool,
+}
+
+/// Lays out `range`, or the used area of the sheet when `None`.
+pub fn layout(sheet: &Sheet, range: Option<CellRange>) -> Grid {
+    let default_style = cell_style(sheet, &Cell::default());
+    let Some(range) = range.or_else(|| sheet.used_range()) else {
+        return Grid {
+            default_style,
+            ..Default::default()
+        };
+    };
+    let hidden = |hide: Option<&String>| hide.is_some_and(|hide| hide == "yes");
+
+    // Only as many rows and columns as fit are looked at, however large
+    // the range
+    let mut columns: Vec<GridColumn> = (range.start.col..=range.end.col)
+        .filter(|col| !hidden(sheet.cols.get(col).and_then(|c| c.hide.as_ref())))
+        .map(|col| GridColumn {
+            col,
+            width: pixels(sheet.cols.get(&col).and_then(|c| c.width.as_deref()))
+                .or_else(|| pixels(sheet.attributes.defaultcolwidth.as_deref()))
+                .unwrap_or(DEFAULT_COL_WIDTH),
+        })
+        .take(MAX_RENDER_CELLS + 1)
+        .collect();
+    let mut truncated = columns.len() > MAX_RENDER_CELLS;
+    columns.truncate(MAX_RENDER_CELLS);
+    let max_rows = MAX_RENDER_CELLS / columns.len().max(1);
+    let mut visible_rows: Vec<u32> = (range.start.row..=range.end.row)
+        .filter(|row| !hidden(sheet.rows.get(row).and_then(|r| r.hide.as_ref())))
+        .take(max_rows + 1)
+        .collect();
+    truncated |= visible_rows.len() > max_rows;
+    visible_rows.truncate(max_rows);
+    let range = CellRange::new(
+        range.start,
+        CellCoord::new(
+            columns.last().map_or(range.start.col, |column| column.col),
+            visible_rows.last().copied().unwrap_or(range.start.row),
+        ),
+    );
+    let visible_cols: Vec<u32> = columns.iter().map(|column| column.col).collect();
+
+    let within = |visible: &[u32], start: u32, end: u32| {
+        let from = visible.partition_point(|&at| at < start);
+        let to = visible.partition_point(|&at| at <= end);
+        visible[from..to].to_vec()
+    };
+
+    // Cells hidden under a merge, and each merge's span in visible cells
+    let mut covered = HashSet::new();
+    let mut spans = HashMap::new();
+    for (coord, cell) in sheet.cells.range(range.start..=range.end) {
+        let (colspan, rowspan) = (cell.colspan.unwrap_or(1), cell.rowspan.unwrap_or(1));
+        if (colspan <= 1 && rowspan <= 1)
+            || !range.contains(*coord)
+            || visible_cols.binary_search(&coord.col).is_err()
+            || visible_rows.binary_search(&coord.row).is_err()
+        {
+            continue;
+        }
+        let end = CellCoord::new(
+            coord.col.saturating_add(colspan - 1).min(range.end.col),
+            coord.row.saturating_add(rowspan - 1).min(range.end.row),
+        );
+        let merge_cols = within(&visible_cols, coord.col, end.col);
+        let merge_rows = within(&visible_rows, coord.row, end.row);
+        for &row in &merge_rows {
+            covered.extend(
+                merge_cols
+                    .iter()
+                    .map(|&col| CellCoord::new(col, row))
+                    .filter(|covered| covered != coord),
+            );
+        }
+        spans.insert(*coord, (merge_cols.len() as u32, merge_rows.len() as u32));
+    }
+
+    let rows = visible_rows
+        .iter()
+        .map(|&row| GridRow {
+            row,
+            height: pixels(sheet.rows.get(&row).and_then(|r| r.height.as_deref()))
+                .or_else(|| pixels(sheet.attributes.defaultrowheight.as_deref())),
+            cells: columns
+                .iter()
+                .map(|column| CellCoord::new(column.col, row))
+                .filter(|coord| !covered.contains(coord))
+                .map(|coord| {
+                    let (colspan, rowspan) = spans.get(&coord).copied().unwrap_or((1, 1));
+                    let empty = Cell::default();
+                    let cell = sheet.cells.get(&coord).unwrap_or(&empty);
+                    let (text, link, format_color) = display(sheet, cell);
+                    let mut style = cell_style(sheet, cell);
+                    style.color = format_color.or(style.color);
+                    GridCell {
+                        coord,
+                        text,
+                        link,
+                        colspan,
+                        rowspan,
+                        style,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    Grid {
+        columns,
+        rows,
+        default_style,
+        truncated,
+    }
+}
+
+fn pixels(size: Option<&str>) -> Option<f64> {
+    size?.trim().parse().ok().filter(|px: &f64| *px > 0.0)
+}
+
+/// The text a cell shows, formatted as it would be on screen.
+pub fn display_text(sheet: &Sheet, cell: &Cell) -> String {
+    display(sheet, cell).0
+}
+
+/// The text a cell shows, the link it carries, and the color its number
+/// format asks for.
+fn display(sheet: &Sheet, cell: &Cell) -> (String, Option<String>, Option<u32>) {
+    let format = |n: Option<u32>| {
+        n.and_then(|n| sheet.valueformats.get(&n))
+            .map(String::as_str)
+    };
+    if cell.datatype == DataType::Empty {
+        return (String::new(), None, None);
+    }
+
+    let value = Value::from_cell(cell);
+    if let Value::Text(text) = &value {
+        let format = format(
+            cell.textvalueformat
+                .or(sheet.attributes.defaulttextvalueformat),
+        );
+        return match format {
+            Some("hidden") => (String::new(), None, None),
+            Some("formula") if cell.datatype == DataType::Formula => {
+                (format!("={}", cell.formula), None, None)
+            }
+            Some("text-link") => link(text),
+            _ if cell.valuetype == "tl" => link(text),
+            _ => (text.clone(), None, None),
+        };
+    }
+
+    match format(
+        cell.nontextvalueformat
+            .or(sheet.attributes.defaultnontextvalueformat),
+    ) {
+        Some("hidden") => return (String::new(), None, None),
+        Some("formula") if cell.datatype == DataType::Formula => {
+            return (format!("={}", cell.formula), None, None)
+        }
+        _ => {}
+    }
+    match (&value, sheet.number_format(cell)) {
+        (Value::Number(n, kind), Some(format)) if *kind != NumberKind::Logical => {
+            let (text, color) = format_value(*n, &format);
+            (text, None, color)
+        }
+        (Value::Number(n, NumberKind::Plain), None) => (format_general(*n), None, None),
+        _ => (value.to_string(), None, None),
+    }
+}
+
+/// Link cells hold a URL, or `description<URL>`.
+fn link(text: &str) -> (String, Option<String>, Option<u32>) {
+    let (description, url) = match text.strip_suffix('>').and_then(|t| t.rsplit_once('<')) {
+        Some((description, url)) => (description.trim(), url.trim()),
+        None => (text, text.trim()),
+    };
+    let safe = ["http://", "https://", "mailto:"]
+        .iter()
+        .any(|scheme| url.len() > scheme.len() && url[..scheme.len()].eq_ignore_ascii_case(scheme));
+    let description = if description.is_empty() {
+        url
+    } else {
+        description
+    };
+    (description.to_string(), safe.then(|| url.to_string()), None)
+}
+
+/// The cell's style after the sheet defaults.
+pub fn cell_style(sheet: &Sheet, cell: &Cell) -> CellStyle {
+    let attributes = &sheet.attributes;
+    let mut style = CellStyle::default();
+
+    if let Some(font) = cell
+        .font
+        .or(attributes.defaultfont)
+        .and_then(|n| sheet.fonts.get(&n))
+    {
+        let font = Font::parse(font);
+        style.italic = font.italic();
+        style.bold = font.bold();
+        style.font_size = font.size.and_then(font_size_pt);
+        style.font_family = font.family.map(str::to_string);
+    }
+
+    let color = |n: Option<u32>| {
+        n.and_then(|n| sheet.colors.get(&n))
+            .and_then(|c| parse_rgb(c))
+    };
+    style.color = color(cell.color.or(attributes.defaultcolor));
+    style.background = color(cell.bgcolor.or(attributes.defaultbgcolor));
+
+    if let Some(borders) = &cell.borders {
+        let border = |n: Option<u32>| n.and_then(|n| sheet.borders.get(&n)).cloned();
+        style.borders = [
+            border(borders.top),
+            border(borders.right),
+            border(borders.bottom),
+            border(borders.left),
+        ];
+    }
+
+    if let Some(layout) = cell
+        .layout
+        .or(attributes.defaultlayout)
+        .and_then(|n| sheet.layouts.get(&n))
+    {
+        style.padding = layout_property(layout, "padding")
+            .filter(|padding| !padding.contains('*'))
+            .map(str::to_string);
+        style.vertical_align = layout_property(layout, "vertical-align").map(str::to_string);
+    }
+
+    let text = matches!(Value::from_cell(cell), Value::Text(_));
+    let default_format = if text {
+        attributes.defaulttextformat
+    } else {
+        attributes.defaultnontextformat
+    };
+    style.align = match cell
+        .cellformat
+        .or(default_format)
+        .and_then(|n| sheet.cellformats.get(&n))
+        .map(String::as_str)
+    {
+        Some("left") => Align::Left,
+        Some("center") => Align::Center,
+        Some("right") => Align::Right,
+        _ if text || cell.datatype == DataType::Empty => Align::Left,
+        _ => Align::Right,
+    };
+
+    style
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_ranges_are_cut_off() {
+        let sheet = Sheet::parse("cell:A1:t:x\nsheet:c:1:r:1000000000").unwrap();
+        let grid = layout(&sheet, None);
+        assert!(grid.rows.len() <= MAX_RENDER_CELLS);
+
+        let range = CellRange::new(CellCoord::new(1, 1), CellCoord::new(1, u32::MAX));
+        let grid = layout(&sheet, Some(range));
+        assert!(grid.truncated);
+        assert_eq!(grid.rows.len(), MAX_RENDER_CELLS);
+        assert_eq!(grid.rows[0].cells[0].text, "x");
+
+        let range = CellRange::new(CellCoord::new(1, 1), CellCoord::new(u32::MAX, 2));
+        let grid = layout(&sheet, Some(range));
+        assert!(grid.truncated);
+        assert_eq!(grid.columns.len(), MAX_RENDER_CELLS);
+        assert_eq!(grid.rows.len(), 1);
+    }
+
+    #[test]
+    fn huge_merges_are_cut_off() {
+        let sheet = Sheet::parse("cell:A1:t:x:colspan:20000:rowspan:20000\ncell:C3:t:y").unwrap();
+        let range = CellRange::new(CellCoord::new(1, 1), CellCoord::new(4, 5));
+        let grid = layout(&sheet, Some(range));
+        assert_eq!(grid.rows.len(), 5);
+        let anchor = &grid.rows[0].cells[0];
+        assert_eq!((anchor.colspan, anchor.rowspan), (4, 5));
+        assert!(grid.rows[1..].iter().all(|row| row.cells.is_empty()));
+
+        let sheet = Sheet::parse("cell:A1:t:x:colspan:20000:rowspan:20000").unwrap();
+        let grid = layout(&sheet, None);
+        let cells: usize = grid.rows.iter().map(|row| row.cells.len()).sum();
+        assert_eq!(cells, 1);
+    }
+}
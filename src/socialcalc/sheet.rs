@@ -0,0 +1,609 @@
+use std::collections::BTreeMap;
+
+use super::{
+    decode_from_save, encode_for_save, CellCoord, CellRange, ParseError, MAX_IMPORT_CELLS,
+};
+
+/// What a cell holds, matching SocialCalc's `datatype`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DataType {
+    #[default]
+    Empty,
+    /// A number (`v`).
+    Value,
+    /// Text (`t`).
+    Text,
+    /// A formula whose last computed result is in `datavalue` (`f`).
+    Formula,
+    /// A number typed with formatting, such as `$1.50`, kept in `formula` (`c`).
+    Constant,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Borders {
+    pub top: Option<u32>,
+    pub right: Option<u32>,
+    pub bottom: Option<u32>,
+    pub left: Option<u32>,
+}
+
+/// One `cell:` line. Style attributes are 1-based indexes into the sheet's
+/// style tables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cell {
+    pub datatype: DataType,
+    /// `n`, `nd`, `n%`, `t`, `th`, `e#DIV/0!`, ...
+    pub valuetype: String,
+    pub datavalue: String,
+    pub formula: String,
+    pub readonly: bool,
+    pub errors: Option<String>,
+    pub borders: Option<Borders>,
+    pub layout: Option<u32>,
+    pub font: Option<u32>,
+    pub color: Option<u32>,
+    pub bgcolor: Option<u32>,
+    pub cellformat: Option<u32>,
+    pub textvalueformat: Option<u32>,
+    pub nontextvalueformat: Option<u32>,
+    pub colspan: Option<u32>,
+    pub rowspan: Option<u32>,
+    pub cssc: Option<String>,
+    pub csss: Option<String>,
+    pub modified: Option<String>,
+    pub comment: Option<String>,
+    /// Trailing attributes this parser does not know, kept verbatim.
+    pub unknown: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColAttributes {
+    pub width: Option<String>,
+    pub hide: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RowAttributes {
+    pub height: Option<String>,
+    pub hide: Option<String>,
+}
+
+/// The `sheet:` line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SheetAttributes {
+    pub lastcol: u32,
+    pub lastrow: u32,
+    pub defaultcolwidth: Option<String>,
+    pub defaultrowheight: Option<String>,
+    pub defaulttextformat: Option<u32>,
+    pub defaultnontextformat: Option<u32>,
+    pub defaultlayout: Option<u32>,
+    pub defaultfont: Option<u32>,
+    pub defaultnontextvalueformat: Option<u32>,
+    pub defaulttextvalueformat: Option<u32>,
+    pub defaultcolor: Option<u32>,
+    pub defaultbgcolor: Option<u32>,
+    pub circularreferencecell: Option<String>,
+    pub recalc: Option<String>,
+    pub needsrecalc: Option<String>,
+    pub usermaxcol: Option<u32>,
+    pub usermaxrow: Option<u32>,
+    pub unknown: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamedRange {
+    pub name: String,
+    pub description: String,
+    pub definition: String,
+}
+
+/// The `sheet` part of a save: cells plus the shared style tables they
+/// index into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sheet {
+    pub version: Option<String>,
+    pub cells: BTreeMap<CellCoord, Cell>,
+    pub cols: BTreeMap<u32, ColAttributes>,
+    pub rows: BTreeMap<u32, RowAttributes>,
+    pub attributes: SheetAttributes,
+    pub borders: BTreeMap<u32, String>,
+    pub cellformats: BTreeMap<u32, String>,
+    pub layouts: BTreeMap<u32, String>,
+    pub fonts: BTreeMap<u32, String>,
+    pub colors: BTreeMap<u32, String>,
+    pub valueformats: BTreeMap<u32, String>,
+    pub names: Vec<NamedRange>,
+    pub copiedfrom: Option<String>,
+    /// Lines of a type this parser does not know, kept verbatim.
+    pub unknown_lines: Vec<String>,
+}
+
+/// The index of `value` in a style table, adding it if missing.
+pub(super) fn intern(table: &mut BTreeMap<u32, String>, value: String) -> u32 {
+    if let Some((n, _)) = table.iter().find(|(_, existing)| **existing == value) {
+        return *n;
+    }
+    let n = table.keys().next_back().map_or(1, |n| n + 1);
+    table.insert(n, value);
+    n
+}
+
+struct Fields<'a> {
+    parts: std::str::Split<'a, char>,
+    line: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next(&mut self, what: &str) -> Result<&'a str, ParseError> {
+        self.parts
+            .next()
+            .ok_or_else(|| ParseError::line(self.line, format!("missing {}", what)))
+    }
+
+    fn number(&mut self, what: &str) -> Result<u32, ParseError> {
+        let value = self.next(what)?;
+        value
+            .parse()
+            .map_err(|_| ParseError::line(self.line, format!("invalid {} '{}'", what, value)))
+    }
+
+    fn optional_number(&mut self, what: &str) -> Result<Option<u32>, ParseError> {
+        match self.next(what)? {
+            "" => Ok(None),
+            value => value
+                .parse()
+                .map(Some)
+                .map_err(|_| ParseError::line(self.line, format!("invalid {} '{}'", what, value))),
+        }
+    }
+
+    fn rest(&mut self) -> Option<String> {
+        let rest: Vec<&str> = self.parts.by_ref().collect();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(rest.join(":"))
+        }
+    }
+}
+
+impl Sheet {
+    pub fn parse(content: &str) -> Result<Self, ParseError> {
+        let mut sheet = Sheet::default();
+
+        for (index, line) in content.lines().enumerate() {
+            let line_no = index + 1;
+            if line.is_empty() {
+                continue;
+            }
+
+            let (kind, rest) = line.split_once(':').unwrap_or((line, ""));
+            let mut fields = Fields {
+                parts: rest.split(':'),
+                line: line_no,
+            };
+
+            match kind {
+                "version" => sheet.version = Some(rest.to_string()),
+                "cell" => {
+                    let coord = fields.next("coordinate")?;
+                    let coord = CellCoord::parse(coord).ok_or_else(|| {
+                        ParseError::line(line_no, format!("invalid coordinate '{}'", coord))
+                    })?;
+                    let cell = parse_cell(&mut fields)?;
+                    sheet.cells.insert(coord, cell);
+                }
+                "col" => {
+                    let name = fields.next("column")?;
+                    let col = super::column_number(name).ok_or_else(|| {
+                        ParseError::line(line_no, format!("invalid column '{}'", name))
+                    })?;
+                    let attributes = sheet.cols.entry(col).or_default();
+                    while let Some(tag) = fields.parts.next() {
+                        match tag {
+                            "w" => attributes.width = Some(fields.next("width")?.to_string()),
+                            "hide" => attributes.hide = Some(fields.next("hide")?.to_string()),
+                            _ => {
+                                return Err(ParseError::line(
+                                    line_no,
+                                    format!("unknown column attribute '{}'", tag),
+                                ))
+                            }
+                        }
+                    }
+                }
+                "row" => {
+                    let row = fields.number("row")?;
+                    let attributes = sheet.rows.entry(row).or_default();
+                    while let Some(tag) = fields.parts.next() {
+                        match tag {
+                            "h" => attributes.height = Some(fields.next("height")?.to_string()),
+                            "hide" => attributes.hide = Some(fields.next("hide")?.to_string()),
+                            _ => {
+                                return Err(ParseError::line(
+                                    line_no,
+                                    format!("unknown row attribute '{}'", tag),
+                                ))
+                            }
+                        }
+                    }
+                }
+                "sheet" => sheet.attributes = parse_sheet_attributes(&mut fields)?,
+                "border" | "cellformat" | "layout" | "font" | "color" | "valueformat" => {
+                    let (number, value) = rest.split_once(':').ok_or_else(|| {
+                        ParseError::line(line_no, format!("missing {} value", kind))
+                    })?;
+                    let number = number.parse::<u32>().map_err(|_| {
+                        ParseError::line(line_no, format!("invalid {} number '{}'", kind, number))
+                    })?;
+                    let table = match kind {
+                        "border" => &mut sheet.borders,
+                        "cellformat" => &mut sheet.cellformats,
+                        "layout" => &mut sheet.layouts,
+                        "font" => &mut sheet.fonts,
+                        "color" => &mut sheet.colors,
+                        _ => &mut sheet.valueformats,
+                    };
+                    // Only value formats are encoded; layouts legitimately
+                    // contain ':' and are stored as the rest of the line
+                    let value = if kind == "valueformat" {
+                        decode_from_save(value)
+                    } else {
+                        value.to_string()
+                    };
+                    table.insert(number, value);
+                }
+                "name" => {
+                    let name = decode_from_save(fields.next("name")?);
+                    let description = decode_from_save(fields.next("description")?);
+                    let definition = decode_from_save(fields.next("definition")?);
+                    sheet.names.push(NamedRange {
+                        name,
+                        description,
+                        definition,
+                    });
+                }
+                "copiedfrom" => sheet.copiedfrom = Some(rest.to_string()),
+                _ => sheet.unknown_lines.push(line.to_string()),
+            }
+        }
+
+        Ok(sheet)
+    }
+
+    /// From `A1` to the last used row and column, or `None` for an empty
+    /// sheet. The size the sheet declares widens this only if it is no
+    /// larger than an importable sheet.
+    pub fn used_range(&self) -> Option<CellRange> {
+        let mut lastrow = self.cells.keys().map(|coord| coord.row).max().unwrap_or(0);
+        let mut lastcol = self.cells.keys().map(|coord| coord.col).max().unwrap_or(0);
+        let declared = (
+            lastrow.max(self.attributes.lastrow),
+            lastcol.max(self.attributes.lastcol),
+        );
+        if u64::from(declared.0) * u64::from(declared.1) <= MAX_IMPORT_CELLS as u64 {
+            (lastrow, lastcol) = declared;
+        }
+        if lastrow == 0 || lastcol == 0 {
+            return None;
+        }
+        Some(CellRange::new(
+            CellCoord::new(1, 1),
+            CellCoord::new(lastcol, lastrow),
+        ))
+    }
+
+    /// The number format a numeric cell displays with: its own value format,
+    /// the sheet default, or one matching its value type.
+    pub fn number_format(&self, cell: &Cell) -> Option<String> {
+        if !cell.valuetype.starts_with('n') {
+            return None;
+        }
+        let explicit = cell
+            .nontextvalueformat
+            .or(self.attributes.defaultnontextvalueformat)
+            .and_then(|n| self.valueformats.get(&n));
+        match explicit.map(String::as_str) {
+            Some("General" | "general" | "formula" | "hidden" | "") => None,
+            Some(format) if format.starts_with("text-") => None,
+            Some(format) => Some(format.to_string()),
+            None => match cell.valuetype.as_str() {
+                "nd" => Some("d-mmm-yyyy".to_string()),
+                "nt" => Some("[h]:mm:ss".to_string()),
+                "ndt" => Some("d-mmm-yyyy h:mm:ss".to_string()),
+                "n%" => Some("0.00%".to_string()),
+                "n$" => Some("[$$]#,##0.00".to_string()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Writes the sheet in the order SocialCalc itself saves, so files it
+    /// produced serialize back unchanged.
+    pub fn serialize(&self) -> String {
+        let mut lines = Vec::new();
+
+        if let Some(version) = &self.version {
+            lines.push(format!("version:{}", version));
+        }
+        for (coord, cell) in &self.cells {
+            let attributes = serialize_cell(cell);
+            if !attributes.is_empty() {
+                lines.push(format!("cell:{}{}", coord, attributes));
+            }
+        }
+        for (col, attributes) in &self.cols {
+            let name = super::column_name(*col);
+            if let Some(width) = &attributes.width {
+                lines.push(format!("col:{}:w:{}", name, width));
+            }
+            if let Some(hide) = &attributes.hide {
+                lines.push(format!("col:{}:hide:{}", name, hide));
+            }
+        }
+        for (row, attributes) in &self.rows {
+            if let Some(height) = &attributes.height {
+                lines.push(format!("row:{}:h:{}", row, height));
+            }
+            if let Some(hide) = &attributes.hide {
+                lines.push(format!("row:{}:hide:{}", row, hide));
+            }
+        }
+        lines.push(serialize_sheet_attributes(&self.attributes));
+
+        let tables = [
+            ("border", &self.borders),
+            ("cellformat", &self.cellformats),
+            ("layout", &self.layouts),
+            ("font", &self.fonts),
+            ("color", &self.colors),
+        ];
+        for (kind, table) in tables {
+            for (number, value) in table {
+                lines.push(format!("{}:{}:{}", kind, number, value));
+            }
+        }
+        for (number, value) in &self.valueformats {
+            lines.push(format!("valueformat:{}:{}", number, encode_for_save(value)));
+        }
+        for name in &self.names {
+            lines.push(format!(
+                "name:{}:{}:{}",
+                encode_for_save(&name.name),
+                encode_for_save(&name.description),
+                encode_for_save(&name.definition)
+            ));
+        }
+        if let Some(copiedfrom) = &self.copiedfrom {
+            lines.push(format!("copiedfrom:{}", copiedfrom));
+        }
+        lines.extend(self.unknown_lines.iter().cloned());
+
+        let mut content = lines.join("\n");
+        content.push('\n');
+        content
+    }
+}
+
+fn parse_cell(fields: &mut Fields<'_>) -> Result<Cell, ParseError> {
+    let mut cell = Cell::default();
+
+    while let Some(tag) = fields.parts.next() {
+        match tag {
+            "v" => {
+                cell.datatype = DataType::Value;
+                cell.valuetype = "n".to_string();
+                cell.datavalue = decode_from_save(fields.next("value")?);
+            }
+            "t" => {
+                cell.datatype = DataType::Text;
+                cell.valuetype = "t".to_string();
+                cell.datavalue = decode_from_save(fields.next("text")?);
+            }
+            "vt" => {
+                cell.valuetype = fields.next("value type")?.to_string();
+                cell.datavalue = decode_from_save(fields.next("value")?);
+                cell.datatype = if cell.valuetype.starts_with('n') {
+                    DataType::Value
+                } else {
+                    DataType::Text
+                };
+            }
+            "vtf" | "vtc" => {
+                cell.datatype = if tag == "vtf" {
+                    DataType::Formula
+                } else {
+                    DataType::Constant
+                };
+                cell.valuetype = fields.next("value type")?.to_string();
+                cell.datavalue = decode_from_save(fields.next("value")?);
+                cell.formula = decode_from_save(fields.next("formula")?);
+            }
+            "ro" => cell.readonly = fields.next("readonly")? == "yes",
+            "e" => cell.errors = Some(decode_from_save(fields.next("errors")?)),
+            "b" => {
+                cell.borders = Some(Borders {
+                    top: fields.optional_number("top border")?,
+                    right: fields.optional_number("right border")?,
+                    bottom: fields.optional_number("bottom border")?,
+                    left: fields.optional_number("left border")?,
+                })
+            }
+            "l" => cell.layout = Some(fields.number("layout")?),
+            "f" => cell.font = Some(fields.number("font")?),
+            "c" => cell.color = Some(fields.number("color")?),
+            "bg" => cell.bgcolor = Some(fields.number("background color")?),
+            "cf" => cell.cellformat = Some(fields.number("cell format")?),
+            "tvf" => cell.textvalueformat = Some(fields.number("text value format")?),
+            "ntvf" => cell.nontextvalueformat = Some(fields.number("value format")?),
+            "colspan" => cell.colspan = Some(fields.number("colspan")?),
+            "rowspan" => cell.rowspan = Some(fields.number("rowspan")?),
+            "cssc" => cell.cssc = Some(fields.next("css class")?.to_string()),
+            "csss" => cell.csss = Some(decode_from_save(fields.next("css style")?)),
+            "mod" => cell.modified = Some(fields.next("mod")?.to_string()),
+            "comment" => cell.comment = Some(decode_from_save(fields.next("comment")?)),
+            _ => {
+                // Attribute arity is unknown, so the rest of the line is kept whole
+                let rest = fields.rest();
+                cell.unknown = Some(match rest {
+                    Some(rest) => format!("{}:{}", tag, rest),
+                    None => tag.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(cell)
+}
+
+fn serialize_cell(cell: &Cell) -> String {
+    let mut out = String::new();
+    let value = encode_for_save(&cell.datavalue);
+
+    match cell.datatype {
+        DataType::Empty => {}
+        DataType::Value if cell.valuetype == "n" => out.push_str(&format!(":v:{}", value)),
+        DataType::Text if cell.valuetype == "t" => out.push_str(&format!(":t:{}", value)),
+        DataType::Value | DataType::Text => {
+            out.push_str(&format!(":vt:{}:{}", cell.valuetype, value))
+        }
+        DataType::Formula | DataType::Constant => {
+            let tag = if cell.datatype == DataType::Formula {
+                "vtf"
+            } else {
+                "vtc"
+            };
+            out.push_str(&format!(
+                ":{}:{}:{}:{}",
+                tag,
+                cell.valuetype,
+                value,
+                encode_for_save(&cell.formula)
+            ));
+        }
+    }
+
+    if cell.readonly {
+        out.push_str(":ro:yes");
+    }
+    if let Some(errors) = &cell.errors {
+        out.push_str(&format!(":e:{}", encode_for_save(errors)));
+    }
+    if let Some(borders) = &cell.borders {
+        let side = |side: Option<u32>| side.map(|n| n.to_string()).unwrap_or_default();
+        out.push_str(&format!(
+            ":b:{}:{}:{}:{}",
+            side(borders.top),
+            side(borders.right),
+            side(borders.bottom),
+            side(borders.left)
+        ));
+    }
+
+    let numbered = [
+        ("l", cell.layout),
+        ("f", cell.font),
+        ("c", cell.color),
+        ("bg", cell.bgcolor),
+        ("cf", cell.cellformat),
+        ("ntvf", cell.nontextvalueformat),
+        ("tvf", cell.textvalueformat),
+        ("colspan", cell.colspan),
+        ("rowspan", cell.rowspan),
+    ];
+    for (tag, value) in numbered {
+        if let Some(value) = value {
+            out.push_str(&format!(":{}:{}", tag, value));
+        }
+    }
+
+    if let Some(cssc) = &cell.cssc {
+        out.push_str(&format!(":cssc:{}", cssc));
+    }
+    if let Some(csss) = &cell.csss {
+        out.push_str(&format!(":csss:{}", encode_for_save(csss)));
+    }
+    if let Some(modified) = &cell.modified {
+        out.push_str(&format!(":mod:{}", modified));
+    }
+    if let Some(comment) = &cell.comment {
+        out.push_str(&format!(":comment:{}", encode_for_save(comment)));
+    }
+    if let Some(unknown) = &cell.unknown {
+        out.push(':');
+        out.push_str(unknown);
+    }
+
+    out
+}
+
+fn parse_sheet_attributes(fields: &mut Fields<'_>) -> Result<SheetAttributes, ParseError> {
+    let mut attributes = SheetAttributes::default();
+
+    while let Some(tag) = fields.parts.next() {
+        match tag {
+            "c" => attributes.lastcol = fields.number("last column")?,
+            "r" => attributes.lastrow = fields.number("last row")?,
+            "w" => attributes.defaultcolwidth = Some(fields.next("column width")?.to_string()),
+            "h" => attributes.defaultrowheight = Some(fields.next("row height")?.to_string()),
+            "tf" => attributes.defaulttextformat = Some(fields.number("text format")?),
+            "ntf" => attributes.defaultnontextformat = Some(fields.number("value format")?),
+            "layout" => attributes.defaultlayout = Some(fields.number("layout")?),
+            "font" => attributes.defaultfont = Some(fields.number("font")?),
+            "ntvf" => attributes.defaultnontextvalueformat = Some(fields.number("value format")?),
+            "tvf" => attributes.defaulttextvalueformat = Some(fields.number("text format")?),
+            "color" => attributes.defaultcolor = Some(fields.number("color")?),
+            "bgcolor" => attributes.defaultbgcolor = Some(fields.number("background color")?),
+            "circularreferencecell" => {
+                attributes.circularreferencecell = Some(fields.next("cell")?.to_string())
+            }
+            "recalc" => attributes.recalc = Some(fields.next("recalc")?.to_string()),
+            "needsrecalc" => attributes.needsrecalc = Some(fields.next("needsrecalc")?.to_string()),
+            "usermaxcol" => attributes.usermaxcol = Some(fields.number("max column")?),
+            "usermaxrow" => attributes.usermaxrow = Some(fields.number("max row")?),
+            _ => {
+                let value = fields.next(tag)?.to_string();
+                attributes.unknown.push((tag.to_string(), value));
+            }
+        }
+    }
+
+    Ok(attributes)
+}
+
+fn serialize_sheet_attributes(attributes: &SheetAttributes) -> String {
+    let mut line = format!("sheet:c:{}:r:{}", attributes.lastcol, attributes.lastrow);
+    let number = |value: Option<u32>| value.map(|n| n.to_string());
+
+    let ordered = [
+        ("w", attributes.defaultcolwidth.clone()),
+        ("h", attributes.defaultrowheight.clone()),
+        ("tf", number(attributes.defaulttextformat)),
+        ("ntf", number(attributes.defaultnontextformat)),
+        ("layout", number(attributes.defaultlayout)),
+        ("font", number(attributes.defaultfont)),
+        ("ntvf", number(attributes.defaultnontextvalueformat)),
+        ("tvf", number(attributes.defaulttextvalueformat)),
+        ("color", number(attributes.defaultcolor)),
+        ("bgcolor", number(attributes.defaultbgcolor)),
+        (
+            "circularreferencecell",
+            attributes.circularreferencecell.clone(),
+        ),
+        ("recalc", attributes.recalc.clone()),
+        ("needsrecalc", attributes.needsrecalc.clone()),
+        ("usermaxcol", number(attributes.usermaxcol)),
+        ("usermaxrow", number(attributes.usermaxrow)),
+    ];
+    for (tag, value) in ordered {
+        if let Some(value) = value {
+            line.push_str(&format!(":{}:{}", tag, value));
+        }
+    }
+    for (tag, value) in &attributes.unknown {
+        line.push_str(&format!(":{}:{}", tag, value));
+    }
+
+    line
+}
@@ -0,0 +1,94 @@
+//! The entries of a sheet's style tables, which are CSS fragments.
+
+/// A `font:` entry, `style weight size family`, where `*` means the
+/// default, e.g. `italic bold 12pt Arial,Helvetica,sans-serif`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Font<'a> {
+    pub style: Option<&'a str>,
+    pub weight: Option<&'a str>,
+    pub size: Option<&'a str>,
+    pub family: Option<&'a str>,
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(font: &'a str) -> Self {
+        let mut parts = font
+            .splitn(4, ' ')
+            .map(|part| Some(part.trim()).filter(|part| !part.is_empty() && *part != "*"));
+        Font {
+            style: parts.next().flatten(),
+            weight: parts.next().flatten(),
+            size: parts.next().flatten(),
+            family: parts.next().flatten(),
+        }
+    }
+
+    pub fn italic(&self) -> bool {
+        self.style == Some("italic")
+    }
+
+    pub fn bold(&self) -> bool {
+        self.weight == Some("bold")
+    }
+}
+
+/// Converts a CSS font size (`10pt`, `12px` or a keyword) to points.
+pub fn font_size_pt(size: &str) -> Option<f64> {
+    let named = match size {
+        "xx-small" => Some(7.0),
+        "x-small" => Some(7.5),
+        "small" => Some(10.0),
+        "medium" => Some(12.0),
+        "large" => Some(14.0),
+        "x-large" => Some(18.0),
+        "xx-large" => Some(24.0),
+        _ => None,
+    };
+    named
+        .or_else(|| size.strip_suffix("pt").and_then(|pt| pt.parse().ok()))
+        .or_else(|| {
+            size.strip_suffix("px")
+                .and_then(|px| px.parse::<f64>().ok())
+                .map(|px| px * 0.75)
+        })
+}
+
+/// Parses `rgb(r,g,b)` or `#rrggbb` into `0xrrggbb`.
+pub fn parse_rgb(color: &str) -> Option<u32> {
+    let color = color.trim();
+    if let Some(hex) = color.strip_prefix('#') {
+        return match hex.len() {
+            6 => u32::from_str_radix(hex, 16).ok(),
+            _ => None,
+        };
+    }
+    let channels = color.strip_prefix("rgb(")?.strip_suffix(')')?;
+    let mut rgb = 0u32;
+    let mut count = 0;
+    for channel in channels.split(',') {
+        rgb = rgb << 8 | channel.trim().parse::<u8>().ok()? as u32;
+        count += 1;
+    }
+    (count == 3).then_some(rgb)
+}
+
+/// Formats `0xrrggbb` the way SocialCalc stores colors.
+pub fn rgb_string(rgb: u32) -> String {
+    format!(
+        "rgb({},{},{})",
+        rgb >> 16 & 0xff,
+        rgb >> 8 & 0xff,
+        rgb & 0xff
+    )
+}
+
+/// Reads one property of a `layout:` entry, such as `vertical-align` from
+/// `padding:* * * *;vertical-align:top;`. `*` counts as unset.
+pub fn layout_property<'a>(layout: &'a str, name: &str) -> Option<&'a str> {
+    layout
+        .split(';')
+        .filter_map(|declaration| declaration.split_once(':'))
+        .find(|(property, _)| property.trim() == name)
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty() && *value != "*")
+}
@@ -0,0 +1,130 @@
+//! `{{name}}` placeholders in template sheets, such as `{{company}}`, filled
+//! in when a file is made from the template. They may appear in text cells
+//! and in formulas, where the value is inserted as written.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+use super::{DataType, Sheet};
+
+/// Placeholder names are letters, digits and `_`, up to this long.
+const MAX_NAME_LENGTH: usize = 64;
+
+/// The names of the placeholders a sheet uses, in order.
+pub fn placeholders(sheet: &Sheet) -> Vec<String> {
+    let mut names = BTreeSet::new();
+    for cell in sheet.cells.values() {
+        let text = match cell.datatype {
+            DataType::Text => &cell.datavalue,
+            DataType::Formula => &cell.formula,
+            _ => continue,
+        };
+        names.extend(find(text).into_iter().map(|(_, name)| name.to_string()));
+    }
+    names.into_iter().collect()
+}
+
+/// Fills in the placeholders `values` has; others are left as they are. A
+/// text cell holding just a placeholder becomes a number if its value is
+/// one. Formulas need recalculating afterwards.
+pub fn fill(sheet: &mut Sheet, values: &HashMap<String, String>) {
+    for cell in sheet.cells.values_mut() {
+        match cell.datatype {
+            DataType::Text => {
+                let number = whole_placeholder(&cell.datavalue)
+                    .and_then(|name| values.get(name))
+                    .and_then(|value| value.trim().parse::<f64>().ok())
+                    .filter(|n| n.is_finite());
+                if let Some(n) = number {
+                    cell.datatype = DataType::Value;
+                    cell.valuetype = "n".to_string();
+                    cell.datavalue = n.to_string();
+                } else {
+                    cell.datavalue = substitute(&cell.datavalue, values);
+                }
+            }
+            DataType::Formula => cell.formula = substitute(&cell.formula, values),
+            _ => {}
+        }
+    }
+}
+
+/// Each placeholder in `text`, with where it is.
+fn find(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(start) = text[from..].find("{{").map(|at| from + at) {
+        let Some(end) = text[start + 2..].find("}}").map(|at| start + 2 + at) else {
+            break;
+        };
+        let name = text[start + 2..end].trim();
+        if is_name(name) {
+            found.push((start..end + 2, name));
+            from = end + 2;
+        } else {
+            from = start + 2;
+        }
+    }
+    found
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn whole_placeholder(text: &str) -> Option<&str> {
+    let text = text.trim();
+    match find(text).as_slice() {
+        [(range, name)] if *range == (0..text.len()) => Some(name),
+        _ => None,
+    }
+}
+
+fn substitute(text: &str, values: &HashMap<String, String>) -> String {
+    let mut filled = String::with_capacity(text.len());
+    let mut last = 0;
+    for (range, name) in find(text) {
+        if let Some(value) = values.get(name) {
+            filled.push_str(&text[last..range.start]);
+            filled.push_str(value);
+            last = range.end;
+        }
+    }
+    filled.push_str(&text[last..]);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socialcalc::{recalc, CellCoord};
+
+    #[test]
+    fn fills_text_and_formulas() {
+        let mut sheet = Sheet::parse(
+            "cell:A1:t:{{ company }} budget\ncell:A2:t:{{fiscal_year}}\ncell:A3:vtf:n:0:A2+{{ offset}}\ncell:A4:t:{{not a name}} {{ missing }}",
+        )
+        .unwrap();
+        assert_eq!(
+            placeholders(&sheet),
+            ["company", "fiscal_year", "missing", "offset"]
+        );
+
+        let values = HashMap::from([
+            ("company".to_string(), "Acme: Ltd".to_string()),
+            ("fiscal_year".to_string(), "2027".to_string()),
+            ("offset".to_string(), "1".to_string()),
+        ]);
+        fill(&mut sheet, &values);
+        recalc(&mut sheet);
+
+        let cell = |coord| &sheet.cells[&CellCoord::parse(coord).unwrap()];
+        assert_eq!(cell("A1").datavalue, "Acme: Ltd budget");
+        assert_eq!(cell("A2").datatype, DataType::Value);
+        assert_eq!(cell("A3").datavalue, "2028");
+        assert_eq!(cell("A4").datavalue, "{{not a name}} {{ missing }}");
+        assert_eq!(placeholders(&sheet), ["missing"]);
+    }
+}
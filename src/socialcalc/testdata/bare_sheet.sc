@@ -0,0 +1,5 @@
+version:1.5
+cell:A1:v:10
+cell:A2:v:32
+cell:A3:vtf:n:42:A1+A2
+sheet:c:1:r:3
@@ -0,0 +1,305 @@
+//! Workbooks: a JSON manifest listing SocialCalc sheet files in order, with
+//! named ranges shared by all of them.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{rename_sheet_references, CellCoord, DataType, NamedRange, Sheet};
+
+const FORMAT: &str = "socialcalc-workbook";
+const VERSION: u32 = 1;
+const MAX_SHEETS: usize = 256;
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum WorkbookError {
+    #[error("not a workbook manifest")]
+    NotWorkbook,
+    #[error("unsupported workbook version {0}")]
+    Version(u32),
+    #[error("invalid sheet name '{0}'")]
+    InvalidName(String),
+    #[error("invalid range name '{0}'")]
+    InvalidRangeName(String),
+    #[error("a sheet named '{0}' already exists")]
+    Duplicate(String),
+    #[error("no sheet named '{0}'")]
+    NotFound(String),
+    #[error("position {0} is out of range")]
+    Position(usize),
+    #[error("a workbook holds at most {MAX_SHEETS} sheets")]
+    TooManySheets,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkbookSheet {
+    /// The name formulas use to refer to the sheet.
+    pub name: String,
+    /// The sheet's file, relative to the owner's home.
+    pub fname: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedName {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub definition: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Workbook {
+    format: String,
+    pub version: u32,
+    pub sheets: Vec<WorkbookSheet>,
+    #[serde(default)]
+    pub names: Vec<SharedName>,
+}
+
+impl Default for Workbook {
+    fn default() -> Self {
+        Workbook {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            sheets: Vec::new(),
+            names: Vec::new(),
+        }
+    }
+}
+
+impl Workbook {
+    pub fn parse(content: &str) -> Result<Self, WorkbookError> {
+        let workbook: Workbook =
+            serde_json::from_str(content).map_err(|_| WorkbookError::NotWorkbook)?;
+        if workbook.format != FORMAT {
+            return Err(WorkbookError::NotWorkbook);
+        }
+        if workbook.version != VERSION {
+            return Err(WorkbookError::Version(workbook.version));
+        }
+        Ok(workbook)
+    }
+
+    pub fn serialize(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// Sheet names compare without case, as references in formulas do.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        let name = name.to_uppercase();
+        self.sheets
+            .iter()
+            .position(|sheet| sheet.name.to_uppercase() == name)
+    }
+
+    pub fn sheet(&self, name: &str) -> Option<&WorkbookSheet> {
+        self.position(name).map(|index| &self.sheets[index])
+    }
+
+    /// Inserts a sheet at `position`, or after the last sheet.
+    pub fn add_sheet(
+        &mut self,
+        name: &str,
+        fname: &str,
+        position: Option<usize>,
+    ) -> Result<(), WorkbookError> {
+        validate_name(name)?;
+        if self.position(name).is_some() {
+            return Err(WorkbookError::Duplicate(name.to_string()));
+        }
+        if self.sheets.len() >= MAX_SHEETS {
+            return Err(WorkbookError::TooManySheets);
+        }
+        let position = position.unwrap_or(self.sheets.len());
+        if position > self.sheets.len() {
+            return Err(WorkbookError::Position(position));
+        }
+        self.sheets.insert(
+            position,
+            WorkbookSheet {
+                name: name.to_string(),
+                fname: fname.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Takes a sheet out of the workbook; its file is left alone.
+    pub fn remove_sheet(&mut self, name: &str) -> Result<WorkbookSheet, WorkbookError> {
+        let index = self
+            .position(name)
+            .ok_or_else(|| WorkbookError::NotFound(name.to_string()))?;
+        Ok(self.sheets.remove(index))
+    }
+
+    pub fn move_sheet(&mut self, name: &str, position: usize) -> Result<(), WorkbookError> {
+        let index = self
+            .position(name)
+            .ok_or_else(|| WorkbookError::NotFound(name.to_string()))?;
+        if position >= self.sheets.len() {
+            return Err(WorkbookError::Position(position));
+        }
+        let sheet = self.sheets.remove(index);
+        self.sheets.insert(position, sheet);
+        Ok(())
+    }
+
+    /// Renames a sheet and rewrites the shared names that refer to it. The
+    /// sheets' own formulas are rewritten with `rename_in_sheet`.
+    pub fn rename_sheet(&mut self, name: &str, new_name: &str) -> Result<String, WorkbookError> {
+        validate_name(new_name)?;
+        let index = self
+            .position(name)
+            .ok_or_else(|| WorkbookError::NotFound(name.to_string()))?;
+        if self.position(new_name).is_some_and(|other| other != index) {
+            return Err(WorkbookError::Duplicate(new_name.to_string()));
+        }
+
+        let old_name = std::mem::replace(&mut self.sheets[index].name, new_name.to_string());
+        for shared in &mut self.names {
+            if let Some(definition) =
+                rename_sheet_references(&shared.definition, &old_name, new_name)
+            {
+                shared.definition = definition;
+            }
+        }
+        Ok(old_name)
+    }
+
+    /// Adds or replaces a shared name; an empty definition removes it.
+    /// Names follow the rules formulas read them by: a letter, then letters,
+    /// digits and underscores, and not a cell reference.
+    pub fn set_name(
+        &mut self,
+        name: &str,
+        description: &str,
+        definition: &str,
+    ) -> Result<(), WorkbookError> {
+        let valid = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+            && CellCoord::parse(name).is_none()
+            && !name.eq_ignore_ascii_case("TRUE")
+            && !name.eq_ignore_ascii_case("FALSE");
+        if !valid {
+            return Err(WorkbookError::InvalidRangeName(name.to_string()));
+        }
+        let upper = name.to_uppercase();
+        self.names
+            .retain(|shared| shared.name.to_uppercase() != upper);
+        if !definition.is_empty() {
+            self.names.push(SharedName {
+                name: upper,
+                description: description.to_string(),
+                definition: definition.to_string(),
+            });
+            self.names.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        Ok(())
+    }
+
+    /// The shared names in the form sheets keep their own.
+    pub fn named_ranges(&self) -> Vec<NamedRange> {
+        self.names
+            .iter()
+            .map(|shared| NamedRange {
+                name: shared.name.clone(),
+                description: shared.description.clone(),
+                definition: shared.definition.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Rewrites a sheet's formulas and named ranges that refer to sheet `old`.
+/// Returns whether anything changed.
+pub fn rename_in_sheet(sheet: &mut Sheet, old: &str, new: &str) -> bool {
+    let mut changed = false;
+    for cell in sheet.cells.values_mut() {
+        if cell.datatype != DataType::Formula {
+            continue;
+        }
+        if let Some(formula) = rename_sheet_references(&cell.formula, old, new) {
+            cell.formula = formula;
+            changed = true;
+        }
+    }
+    for name in &mut sheet.names {
+        if let Some(definition) = rename_sheet_references(&name.definition, old, new) {
+            name.definition = definition;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Names are quoted in formulas when they contain spaces or punctuation,
+/// but `!`, `'`, `:` and control characters are not allowed.
+pub fn validate_name(name: &str) -> Result<(), WorkbookError> {
+    let valid = !name.trim().is_empty()
+        && name.trim() == name
+        && name.chars().count() <= MAX_NAME_LEN
+        && !name
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '!' | '\'' | ':'));
+    if valid {
+        Ok(())
+    } else {
+        Err(WorkbookError::InvalidName(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sheets_are_ordered_and_named_without_case() {
+        let mut workbook = Workbook::default();
+        workbook
+            .add_sheet("Income", "book/income.msc", None)
+            .unwrap();
+        workbook
+            .add_sheet("Costs", "book/costs.msc", Some(0))
+            .unwrap();
+        assert_eq!(
+            workbook.add_sheet("costs", "x.msc", None),
+            Err(WorkbookError::Duplicate("costs".to_string()))
+        );
+        assert!(workbook.add_sheet("a!b", "x.msc", None).is_err());
+
+        workbook.move_sheet("INCOME", 0).unwrap();
+        let names: Vec<&str> = workbook.sheets.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["Income", "Costs"]);
+
+        let parsed = Workbook::parse(&workbook.serialize()).unwrap();
+        assert_eq!(parsed, workbook);
+        assert_eq!(
+            Workbook::parse("{\"sheets\":[]}"),
+            Err(WorkbookError::NotWorkbook)
+        );
+    }
+
+    #[test]
+    fn renaming_rewrites_references() {
+        let mut workbook = Workbook::default();
+        workbook.add_sheet("Costs", "costs.msc", None).unwrap();
+        workbook.set_name("total", "", "Costs!B1:B9").unwrap();
+        assert!(workbook.set_name("B2", "", "Costs!A1").is_err());
+
+        workbook.rename_sheet("costs", "Running Costs").unwrap();
+        assert_eq!(workbook.names[0].definition, "'Running Costs'!B1:B9");
+
+        let mut sheet = Sheet::parse(
+            "cell:A1:vtf:n:0:SUM(costs!A1\\cA3)&\"Costs!A1\"\ncell:A2:vtf:n:0:Other!A1",
+        )
+        .unwrap();
+        assert!(rename_in_sheet(&mut sheet, "Costs", "Running Costs"));
+        let formula = |coord: &str| {
+            sheet.cells[&CellCoord::parse(coord).unwrap()]
+                .formula
+                .clone()
+        };
+        assert_eq!(formula("A1"), "SUM('Running Costs'!A1:A3)&\"Costs!A1\"");
+        assert_eq!(formula("A2"), "Other!A1");
+    }
+}
@@ -0,0 +1,678 @@
+//! Conversion between SocialCalc sheets and Excel `.xlsx` workbooks.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Cursor, Read};
+
+use calamine::{Data, Reader, Xlsx, XlsxError as ReadError};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::XmlVersion;
+use rust_xlsxwriter::{
+    Color, Format, FormatAlign, FormatBorder, Formula, Workbook, Worksheet, XlsxError as WriteError,
+};
+
+use super::formula::{check_formula, format_number};
+use super::style::{font_size_pt, layout_property, parse_rgb, Font};
+use super::{
+    column_name, recalc, Cell, CellCoord, CellRange, DataType, FormulaError, ImportedSheet,
+    NamedRange, NumberKind, Sheet, SpreadsheetSave, Value, MAX_IMPORT_CELLS,
+};
+
+/// Excel's limit on worksheet name length.
+const MAX_SHEET_NAME: usize = 31;
+/// Workbooks with a larger part, decompressed, are rejected.
+const MAX_XML_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Writes `sheet` as a single-worksheet workbook. Formulas this engine can
+/// evaluate are written as formulas with their computed result; others are
+/// written as values.
+pub fn to_xlsx(sheet: &Sheet, name: &str) -> Result<Vec<u8>, WriteError> {
+    let mut workbook = Workbook::new();
+    let worksheet = workbook.add_worksheet();
+    let name = worksheet_name(name);
+    worksheet.set_name(&name)?;
+
+    for (col, attributes) in &sheet.cols {
+        let col = excel_col(*col)?;
+        if let Some(width) = attributes.width.as_deref().and_then(|w| w.parse().ok()) {
+            worksheet.set_column_width_pixels(col, width)?;
+        }
+        if attributes.hide.as_deref() == Some("yes") {
+            worksheet.set_column_hidden(col)?;
+        }
+    }
+    for (row, attributes) in &sheet.rows {
+        if let Some(height) = attributes.height.as_deref().and_then(|h| h.parse().ok()) {
+            worksheet.set_row_height_pixels(row - 1, height)?;
+        }
+        if attributes.hide.as_deref() == Some("yes") {
+            worksheet.set_row_hidden(row - 1)?;
+        }
+    }
+
+    for (coord, cell) in &sheet.cells {
+        let (row, col) = (coord.row - 1, excel_col(coord.col)?);
+        let format = cell_format(sheet, cell);
+
+        let colspan = cell.colspan.unwrap_or(1).max(1);
+        let rowspan = cell.rowspan.unwrap_or(1).max(1);
+        if colspan > 1 || rowspan > 1 {
+            let last_col = excel_col(coord.col + colspan - 1)?;
+            worksheet.merge_range(row, col, row + rowspan - 1, last_col, "", &format)?;
+        }
+
+        write_cell(worksheet, row, col, cell, &format)?;
+    }
+
+    for named in &sheet.names {
+        let definition = named
+            .definition
+            .strip_prefix('=')
+            .unwrap_or(&named.definition);
+        if let Some(range) = CellRange::parse(definition) {
+            let reference = format!(
+                "='{}'!${}${}:${}${}",
+                name.replace('\'', "''"),
+                column_name(range.start.col),
+                range.start.row,
+                column_name(range.end.col),
+                range.end.row
+            );
+            workbook.define_name(&named.name, &reference)?;
+        }
+    }
+
+    workbook.save_to_buffer()
+}
+
+fn write_cell(
+    worksheet: &mut Worksheet,
+    row: u32,
+    col: u16,
+    cell: &Cell,
+    format: &Format,
+) -> Result<(), WriteError> {
+    let value = Value::from_cell(cell);
+
+    if cell.datatype == DataType::Formula && check_formula(&cell.formula).is_ok() {
+        let formula = Formula::new(format!("={}", cell.formula)).set_result(value.to_string());
+        worksheet.write_formula_with_format(row, col, formula, format)?;
+        return Ok(());
+    }
+
+    match value {
+        Value::Blank => worksheet.write_blank(row, col, format)?,
+        Value::Number(n, NumberKind::Logical) => {
+            worksheet.write_boolean_with_format(row, col, n != 0.0, format)?
+        }
+        Value::Number(n, _) => worksheet.write_number_with_format(row, col, n, format)?,
+        Value::Text(text) => worksheet.write_string_with_format(row, col, text, format)?,
+        Value::Error(error) => {
+            worksheet.write_string_with_format(row, col, error.as_str(), format)?
+        }
+    };
+    Ok(())
+}
+
+fn excel_col(col: u32) -> Result<u16, WriteError> {
+    u16::try_from(col - 1).map_err(|_| WriteError::RowColumnLimitError)
+}
+
+/// Excel forbids `[]:*?/\` in worksheet names and limits their length.
+fn worksheet_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .filter(|c| !matches!(c, '[' | ']' | ':' | '*' | '?' | '/' | '\\'))
+        .take(MAX_SHEET_NAME)
+        .collect();
+    let name = name.trim_matches('\'').trim();
+    if name.is_empty() {
+        "Sheet1".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Translates the cell's SocialCalc styles, falling back to the sheet
+/// defaults, into an Excel format.
+fn cell_format(sheet: &Sheet, cell: &Cell) -> Format {
+    let defaults = &sheet.attributes;
+    let mut format = Format::new();
+
+    let font = cell.font.or(defaults.defaultfont);
+    if let Some(font) = font.and_then(|n| sheet.fonts.get(&n)) {
+        format = apply_font(format, font);
+    }
+    if let Some(color) = cell
+        .color
+        .or(defaults.defaultcolor)
+        .and_then(|n| sheet.colors.get(&n))
+        .and_then(|color| parse_color(color))
+    {
+        format = format.set_font_color(color);
+    }
+    if let Some(color) = cell
+        .bgcolor
+        .or(defaults.defaultbgcolor)
+        .and_then(|n| sheet.colors.get(&n))
+        .and_then(|color| parse_color(color))
+    {
+        format = format.set_background_color(color);
+    }
+
+    let text = matches!(Value::from_cell(cell), Value::Text(_));
+    let alignment = cell.cellformat.or(if text {
+        defaults.defaulttextformat
+    } else {
+        defaults.defaultnontextformat
+    });
+    match alignment
+        .and_then(|n| sheet.cellformats.get(&n))
+        .map(String::as_str)
+    {
+        Some("left") => format = format.set_align(FormatAlign::Left),
+        Some("center") => format = format.set_align(FormatAlign::Center),
+        Some("right") => format = format.set_align(FormatAlign::Right),
+        _ => {}
+    }
+    if let Some(layout) = cell
+        .layout
+        .or(defaults.defaultlayout)
+        .and_then(|n| sheet.layouts.get(&n))
+    {
+        match layout_property(layout, "vertical-align") {
+            Some("top") => format = format.set_align(FormatAlign::Top),
+            Some("middle") => format = format.set_align(FormatAlign::VerticalCenter),
+            _ => {}
+        }
+    }
+
+    if let Some(borders) = &cell.borders {
+        let border = |n: Option<u32>| n.and_then(|n| sheet.borders.get(&n));
+        if let Some(spec) = border(borders.top) {
+            let (style, color) = parse_border(spec);
+            format = format.set_border_top(style).set_border_top_color(color);
+        }
+        if let Some(spec) = border(borders.right) {
+            let (style, color) = parse_border(spec);
+            format = format.set_border_right(style).set_border_right_color(color);
+        }
+        if let Some(spec) = border(borders.bottom) {
+            let (style, color) = parse_border(spec);
+            format = format
+                .set_border_bottom(style)
+                .set_border_bottom_color(color);
+        }
+        if let Some(spec) = border(borders.left) {
+            let (style, color) = parse_border(spec);
+            format = format.set_border_left(style).set_border_left_color(color);
+        }
+    }
+
+    if let Some(number_format) = sheet.number_format(cell) {
+        format = format.set_num_format(number_format);
+    }
+
+    format
+}
+
+fn apply_font(mut format: Format, font: &str) -> Format {
+    let font = Font::parse(font);
+    if font.italic() {
+        format = format.set_italic();
+    }
+    if font.bold() {
+        format = format.set_bold();
+    }
+    if let Some(size) = font.size.and_then(font_size_pt) {
+        format = format.set_font_size(size);
+    }
+    if let Some(family) = font.family {
+        let family = family.split(',').next().unwrap_or(family);
+        format = format.set_font_name(family.trim().trim_matches(['"', '\'']));
+    }
+    format
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    parse_rgb(color).map(Color::RGB)
+}
+
+/// SocialCalc borders are CSS, e.g. `1px solid rgb(0,0,0)`.
+fn parse_border(spec: &str) -> (FormatBorder, Color) {
+    let style = if spec.contains("dashed") {
+        FormatBorder::Dashed
+    } else if spec.contains("dotted") {
+        FormatBorder::Dotted
+    } else if spec.contains("double") {
+        FormatBorder::Double
+    } else if spec.starts_with("2px") || spec.starts_with("3px") {
+        FormatBorder::Medium
+    } else {
+        FormatBorder::Thin
+    };
+    let color = spec
+        .find("rgb(")
+        .or_else(|| spec.find('#'))
+        .and_then(|at| parse_color(&spec[at..]))
+        .unwrap_or(Color::Black);
+    (style, color)
+}
+
+/// Converts every worksheet of an `.xlsx` workbook. Values, formulas this
+/// engine understands, merged cells and simple named ranges are kept;
+/// everything else is listed in each sheet's warnings.
+pub fn from_xlsx(data: &[u8]) -> Result<Vec<ImportedSheet>, ReadError> {
+    let mut reports = survey(data)?;
+    let mut workbook = Xlsx::new(Cursor::new(data))?;
+    let defined_names = workbook.defined_names().to_vec();
+
+    let mut imported = Vec::new();
+    for name in workbook.sheet_names() {
+        let mut sheet = Sheet {
+            version: Some("1.5".to_string()),
+            ..Default::default()
+        };
+        let mut warnings = reports.remove(&name).unwrap_or_default();
+
+        let mut cells = workbook.worksheet_cells_reader(&name)?;
+        let mut too_many = false;
+        while let Some(record) = cells.next_cell_with_formula()? {
+            let (row, col) = record.pos;
+            let coord = CellCoord::new(col + 1, row + 1);
+            let formula = record
+                .formula
+                .as_deref()
+                .map(|formula| formula.strip_prefix('=').unwrap_or(formula))
+                .filter(|formula| !formula.is_empty());
+            let cell = import_value(&Data::from(record.value));
+            if cell.datatype == DataType::Empty && formula.is_none() {
+                continue;
+            }
+            if sheet.cells.len() >= MAX_IMPORT_CELLS {
+                too_many = true;
+                break;
+            }
+            if cell.datatype != DataType::Empty {
+                sheet.cells.insert(coord, cell);
+            }
+
+            let Some(formula) = formula else {
+                continue;
+            };
+            match check_formula(formula) {
+                Ok(()) => {
+                    let cell = sheet.cells.entry(coord).or_insert_with(|| Cell {
+                        valuetype: "n".to_string(),
+                        datavalue: "0".to_string(),
+                        ..Default::default()
+                    });
+                    cell.datatype = DataType::Formula;
+                    cell.formula = formula.to_string();
+                }
+                Err(reason) => warnings.push(format!(
+                    "{}: formula ={} kept as its value ({})",
+                    coord, formula, reason
+                )),
+            }
+        }
+        drop(cells);
+
+        if too_many {
+            sheet.cells.clear();
+            imported.push(ImportedSheet {
+                name,
+                save: SpreadsheetSave::new(sheet),
+                warnings: vec![format!("Sheet has more than {} cells", MAX_IMPORT_CELLS)],
+            });
+            continue;
+        }
+
+        for dimensions in workbook.merge_cells_by_sheet_name(&name)? {
+            let (start_row, start_col) = dimensions.start;
+            let (end_row, end_col) = dimensions.end;
+            let coord = CellCoord::new(start_col + 1, start_row + 1);
+            let cell = sheet.cells.entry(coord).or_default();
+            cell.colspan = Some(end_col - start_col + 1).filter(|span| *span > 1);
+            cell.rowspan = Some(end_row - start_row + 1).filter(|span| *span > 1);
+        }
+
+        for (defined, reference) in &defined_names {
+            let Some((sheet_name, range)) = reference.trim_start_matches('=').rsplit_once('!')
+            else {
+                continue;
+            };
+            if sheet_name.trim_matches('\'') != name {
+                continue;
+            }
+            if let Some(range) = CellRange::parse(&range.replace('$', "")) {
+                sheet.names.push(NamedRange {
+                    name: defined.to_ascii_uppercase(),
+                    description: String::new(),
+                    definition: range.to_string(),
+                });
+            } else {
+                warnings.push(format!("Named range {} is not a simple range", defined));
+            }
+        }
+
+        if let Some(range) = sheet.used_range() {
+            sheet.attributes.lastcol = range.end.col;
+            sheet.attributes.lastrow = range.end.row;
+        }
+        recalc(&mut sheet);
+
+        imported.push(ImportedSheet {
+            name,
+            save: SpreadsheetSave::new(sheet),
+            warnings,
+        });
+    }
+
+    Ok(imported)
+}
+
+/// What each worksheet has that importing leaves out, by worksheet name.
+/// Every part of the workbook is read here first, so that none is larger
+/// than `MAX_XML_BYTES` when calamine reads it.
+fn survey(data: &[u8]) -> Result<HashMap<String, Vec<String>>, ReadError> {
+    let invalid = |_| ReadError::Unexpected("not a zip archive");
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(invalid)?;
+    let mut workbook = String::new();
+    let mut relationships = String::new();
+    let mut worksheets = HashMap::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index(index).map_err(invalid)?;
+        let path = file.name().to_string();
+        let mut xml = Vec::new();
+        file.take(MAX_XML_BYTES + 1).read_to_end(&mut xml)?;
+        if xml.len() as u64 > MAX_XML_BYTES {
+            return Err(ReadError::Unexpected("workbook is too large"));
+        }
+        let xml = String::from_utf8_lossy(&xml);
+        match path.as_str() {
+            "xl/workbook.xml" => workbook = xml.into_owned(),
+            "xl/_rels/workbook.xml.rels" => relationships = xml.into_owned(),
+            _ if path.starts_with("xl/worksheets/") && path.ends_with(".xml") => {
+                worksheets.insert(path, unsupported(&xml)?);
+            }
+            _ => {}
+        }
+    }
+
+    let mut targets = HashMap::new();
+    for element in elements(&relationships, b"Relationship")? {
+        if let (Some(id), Some(target)) = (attribute(&element, "Id"), attribute(&element, "Target"))
+        {
+            let path = match target.strip_prefix('/') {
+                Some(absolute) => absolute.to_string(),
+                None => format!("xl/{}", target),
+            };
+            targets.insert(id, path);
+        }
+    }
+    let mut reports = HashMap::new();
+    for element in elements(&workbook, b"sheet")? {
+        let report = attribute(&element, "r:id")
+            .and_then(|id| targets.get(&id))
+            .and_then(|path| worksheets.remove(path));
+        if let (Some(name), Some(report)) = (attribute(&element, "name"), report) {
+            reports.insert(name, report);
+        }
+    }
+    Ok(reports)
+}
+
+/// Warnings for the formatting and features of a worksheet's XML that
+/// importing leaves out.
+fn unsupported(xml: &str) -> Result<Vec<String>, ReadError> {
+    let mut formatted_cells = 0;
+    let mut widths = false;
+    let mut heights = false;
+    let mut hidden = false;
+    let mut features = BTreeSet::new();
+
+    let mut reader = quick_xml::Reader::from_str(xml);
+    loop {
+        let element = match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element) => element,
+            Event::Eof => break,
+            _ => continue,
+        };
+        let is = |name: &str, value: &str| attribute(&element, name).as_deref() == Some(value);
+        match element.local_name().as_ref() {
+            b"c" if attribute(&element, "s").is_some_and(|style| style != "0") => {
+                formatted_cells += 1
+            }
+            b"col" => {
+                widths |= is("customWidth", "1");
+                hidden |= is("hidden", "1");
+            }
+            b"row" => {
+                heights |= is("customHeight", "1");
+                hidden |= is("hidden", "1");
+            }
+            b"conditionalFormatting" => {
+                features.insert("conditional formatting");
+            }
+            b"dataValidation" => {
+                features.insert("data validation");
+            }
+            b"hyperlink" => {
+                features.insert("hyperlinks");
+            }
+            b"drawing" => {
+                features.insert("charts, images or shapes");
+            }
+            b"legacyDrawing" => {
+                features.insert("comments");
+            }
+            b"tablePart" => {
+                features.insert("tables");
+            }
+            b"autoFilter" => {
+                features.insert("filters");
+            }
+            b"sheetProtection" => {
+                features.insert("sheet protection");
+            }
+            _ => {}
+        }
+    }
+
+    let mut warnings = Vec::new();
+    if formatted_cells > 0 {
+        warnings.push(format!(
+            "Fonts, colors, borders and number formats are not imported ({} formatted {})",
+            formatted_cells,
+            if formatted_cells == 1 {
+                "cell"
+            } else {
+                "cells"
+            }
+        ));
+    }
+    if widths || heights {
+        let sizes = match (widths, heights) {
+            (true, true) => "Column widths and row heights",
+            (true, false) => "Column widths",
+            _ => "Row heights",
+        };
+        warnings.push(format!("{} are not imported", sizes));
+    }
+    if hidden {
+        warnings.push("Hidden rows and columns are shown".to_string());
+    }
+    if !features.is_empty() {
+        let features: Vec<_> = features.into_iter().collect();
+        warnings.push(format!("Not imported: {}", features.join(", ")));
+    }
+    Ok(warnings)
+}
+
+/// Every `name` element of `xml`.
+fn elements<'a>(xml: &'a str, name: &[u8]) -> Result<Vec<BytesStart<'a>>, ReadError> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut found = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == name =>
+            {
+                found.push(element)
+            }
+            Event::Eof => return Ok(found),
+            _ => {}
+        }
+    }
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    let attribute = element.try_get_attribute(name).ok()??;
+    attribute
+        .decoded_and_normalized_value(XmlVersion::default(), element.decoder())
+        .ok()
+        .map(|value| value.into_owned())
+}
+
+fn import_value(data: &Data) -> Cell {
+    let number = |n: f64, valuetype: &str| Cell {
+        datatype: DataType::Value,
+        valuetype: valuetype.to_string(),
+        datavalue: format_number(n),
+        ..Default::default()
+    };
+
+    match data {
+        Data::Int(n) => number(*n as f64, "n"),
+        Data::Float(n) => number(*n, "n"),
+        Data::Bool(b) => number(if *b { 1.0 } else { 0.0 }, "nl"),
+        Data::DateTime(datetime) => {
+            let serial = datetime.as_f64();
+            let valuetype = if datetime.is_duration() || serial < 1.0 {
+                "nt"
+            } else if serial.fract() == 0.0 {
+                "nd"
+            } else {
+                "ndt"
+            };
+            number(serial, valuetype)
+        }
+        Data::String(text) | Data::DateTimeIso(text) | Data::DurationIso(text) => Cell {
+            datatype: DataType::Text,
+            valuetype: "t".to_string(),
+            datavalue: text.clone(),
+            ..Default::default()
+        },
+        Data::Error(error) => {
+            let error = FormulaError::from_name(&error.to_string()).unwrap_or(FormulaError::Value);
+            Cell {
+                datatype: DataType::Value,
+                valuetype: format!("e{}", error.as_str()),
+                datavalue: error.as_str().to_string(),
+                ..Default::default()
+            }
+        }
+        Data::Empty => Cell::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(content: &str) -> Sheet {
+        let mut save = SpreadsheetSave::parse(content).unwrap();
+        recalc(&mut save.sheet);
+        save.sheet
+    }
+
+    fn part(xlsx: &[u8], name: &str) -> String {
+        let mut archive = zip::ZipArchive::new(Cursor::new(xlsx)).unwrap();
+        let mut xml = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut xml)
+            .unwrap();
+        xml
+    }
+
+    fn cell<'a>(sheet: &'a Sheet, coord: &str) -> &'a Cell {
+        &sheet.cells[&CellCoord::parse(coord).unwrap()]
+    }
+
+    #[test]
+    fn exports_formats_widths_fonts_and_merges() {
+        let budget = to_xlsx(&sample(include_str!("testdata/budget.msc")), "Budget").unwrap();
+        let styles = part(&budget, "xl/styles.xml");
+        assert!(styles.contains(r##"formatCode="#,##0.00""##));
+        assert!(styles.contains(r#"formatCode="0.0%""#));
+        assert!(styles.contains("<b/>"));
+        let worksheet = part(&budget, "xl/worksheets/sheet1.xml");
+        assert!(worksheet.contains(r#"<col min="1" max="1" width="22.85546875" customWidth="1"/>"#));
+        assert!(worksheet.contains("<f>B2/B$5</f>"));
+        assert!(worksheet.contains("<f>SUM(B2:B4)</f><v>1430</v>"));
+
+        let styled = to_xlsx(&sample(include_str!("testdata/styled.msc")), "Styled").unwrap();
+        let styles = part(&styled, "xl/styles.xml");
+        assert!(styles.contains(r#"<sz val="14"/>"#));
+        assert!(styles.contains(r#"<name val="Verdana"/>"#));
+        let worksheet = part(&styled, "xl/worksheets/sheet1.xml");
+        assert!(worksheet.contains(r#"<mergeCell ref="A1:C1"/>"#));
+        assert!(worksheet.contains(r#"<mergeCell ref="C2:C3"/>"#));
+        assert!(worksheet.contains(r#"hidden="1""#));
+    }
+
+    #[test]
+    fn imports_values_formulas_merges_and_names() {
+        let original = sample(include_str!("testdata/budget.msc"));
+        let imported = from_xlsx(&to_xlsx(&original, "Budget").unwrap()).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].name, "Budget");
+        let sheet = &imported[0].save.sheet;
+
+        assert_eq!(cell(sheet, "A2").datavalue, "Rent");
+        assert_eq!(cell(sheet, "B3").datavalue, "12.5");
+        assert_eq!(cell(sheet, "C2").formula, "B2/B$5");
+        assert_eq!(cell(sheet, "B5").formula, "SUM(B2:B4)");
+        assert_eq!(cell(sheet, "B5").datavalue, "1430");
+        assert_eq!(sheet.names[0].name, "TOTAL");
+        assert_eq!(sheet.names[0].definition, "B5");
+
+        let warnings = &imported[0].warnings;
+        assert!(warnings.iter().any(|w| w.contains("(12 formatted cells)")));
+        assert!(warnings.contains(&"Column widths are not imported".to_string()));
+
+        let styled =
+            from_xlsx(&to_xlsx(&sample(include_str!("testdata/styled.msc")), "S").unwrap())
+                .unwrap();
+        let sheet = &styled[0].save.sheet;
+        assert_eq!(cell(sheet, "A1").colspan, Some(3));
+        assert_eq!(cell(sheet, "C2").rowspan, Some(2));
+        assert_eq!(cell(sheet, "AB12").datavalue, "far away");
+        assert!(styled[0]
+            .warnings
+            .contains(&"Hidden rows and columns are shown".to_string()));
+    }
+
+    #[test]
+    fn sparse_cells_far_apart_import() {
+        let mut workbook = Workbook::new();
+        let worksheet = workbook.add_worksheet();
+        worksheet.write_string(0, 0, "first").unwrap();
+        worksheet.write_number(1_048_575, 16_383, 2.0).unwrap();
+        worksheet.write_url(1, 0, "https://example.com").unwrap();
+        let imported = from_xlsx(&workbook.save_to_buffer().unwrap()).unwrap();
+
+        let sheet = &imported[0].save.sheet;
+        assert_eq!(sheet.cells.len(), 3);
+        assert_eq!(cell(sheet, "XFD1048576").datavalue, "2");
+        assert_eq!(
+            imported[0].warnings,
+            [
+                "Fonts, colors, borders and number formats are not imported (1 formatted cell)",
+                "Not imported: hyperlinks"
+            ]
+        );
+    }
+}
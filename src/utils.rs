@@ -12,13 +12,6 @@ pub fn generate_random_string(length: usize) -> String {
         .collect()
 }
 
-pub fn sanitize_filename(filename: &str) -> String {
-    filename
-        .chars()
-        .filter(|c| c.is_alphanumeric() || *c == '.' || *c == '-' || *c == '_')
-        .collect()
-}
-
 pub fn validate_email(email: &str) -> bool {
     email.contains('@') && email.len() > 3
 }
@@ -26,3 +19,64 @@ pub fn validate_email(email: &str) -> bool {
 pub fn format_file_path(user_id: &str, path: &str) -> String {
     format!("user-{}/{}", user_id, path.trim_start_matches('/'))
 }
+
+/// Escapes `%`, `_` and `\` so the input matches literally inside a
+/// `LIKE` pattern.
+pub fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Converts a shell-style name pattern (`*` and `?`) into a `LIKE` pattern,
+/// escaping everything else.
+pub fn glob_to_like(pattern: &str) -> String {
+    let mut like = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        match c {
+            '*' => like.push('%'),
+            '?' => like.push('_'),
+            '%' | '_' | '\\' => {
+                like.push('\\');
+                like.push(c);
+            }
+            _ => like.push(c),
+        }
+    }
+    like
+}
+
+pub fn escape_html(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_patterns_match_wildcards_only_where_meant() {
+        assert_eq!(escape_like("50%_off\\"), "50\\%\\_off\\\\");
+        assert_eq!(escape_like("plain"), "plain");
+
+        assert_eq!(glob_to_like("*.msc"), "%.msc");
+        assert_eq!(glob_to_like("q?_100%"), "q_\\_100\\%");
+        assert_eq!(glob_to_like("a\\b*"), "a\\\\b%");
+    }
+}
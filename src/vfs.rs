@@ -0,0 +1,248 @@
+use std::fmt;
+
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+const ROOT: &str = "home";
+const SECURE_STORE: &str = "securestore";
+
+pub const SECURE_STORE_PREFIX: &str = "home/securestore/";
+pub const MAX_SEGMENT_LENGTH: usize = 255;
+/// Matches the width of `files.path`.
+pub const MAX_PATH_LENGTH: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VfsPathError {
+    #[error("File name is empty")]
+    Empty,
+    #[error("File name may not contain '..'")]
+    ParentSegment,
+    #[error("File name may not contain '/'")]
+    NestedName,
+    #[error("File name contains invalid characters")]
+    InvalidCharacter,
+    #[error("File name segment is too long")]
+    SegmentTooLong,
+    #[error("File name is too long")]
+    TooLong,
+    #[error("File name is in a reserved namespace")]
+    Reserved,
+}
+
+/// A validated path in a user's file tree. Paths are always rooted at
+/// `home`, NFC-normalized, and free of empty, `.` and `..` segments.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VfsPath(String);
+
+impl VfsPath {
+    /// The user's home directory.
+    pub fn root() -> Self {
+        VfsPath(ROOT.to_string())
+    }
+
+    /// Parses a client-supplied file name relative to `home/`.
+    pub fn home(fname: &str) -> Result<Self, VfsPathError> {
+        let segments = normalize(fname)?;
+        if segments.is_empty() {
+            return Err(VfsPathError::Empty);
+        }
+        Self::root().extend(segments)
+    }
+
+    /// Parses a client-supplied folder relative to `home/`; an empty folder
+    /// is the home directory itself.
+    pub fn home_dir(folder: &str) -> Result<Self, VfsPathError> {
+        Self::root().extend(normalize(folder)?)
+    }
+
+    /// A file in an app's secure store. Both names must be single segments.
+    pub fn secure_store(app_name: &str, fname: &str) -> Result<Self, VfsPathError> {
+        Self::secure_store_dir(app_name)?.extend(vec![segment(fname)?])
+    }
+
+    pub fn secure_store_dir(app_name: &str) -> Result<Self, VfsPathError> {
+        let path = format!("{}{}", SECURE_STORE_PREFIX, segment(app_name)?);
+        Self::checked(path)
+    }
+
+    /// Resolves `name` inside this directory.
+    pub fn join(&self, name: &str) -> Result<Self, VfsPathError> {
+        let segments = normalize(name)?;
+        if segments.is_empty() {
+            return Err(VfsPathError::Empty);
+        }
+        self.extend(segments)
+    }
+
+    /// Wraps a path read back from the database, which is addressed exactly
+    /// as stored even if it predates validation.
+    pub fn from_stored(path: String) -> Self {
+        VfsPath(path)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The path relative to `home/`, as clients name files.
+    pub fn fname(&self) -> &str {
+        self.0.strip_prefix("home/").unwrap_or("")
+    }
+
+    pub fn file_name(&self) -> &str {
+        self.0.rsplit('/').next().unwrap_or(&self.0)
+    }
+
+    /// The prefix shared by every path inside this directory.
+    pub fn dir_prefix(&self) -> String {
+        format!("{}/", self.0)
+    }
+
+    pub fn is_secure_store(&self) -> bool {
+        self.0.starts_with(SECURE_STORE_PREFIX)
+    }
+
+    /// Appends segments produced by `normalize`. The secure store can only
+    /// be entered through `secure_store`, never from client-supplied names.
+    fn extend(&self, segments: Vec<String>) -> Result<Self, VfsPathError> {
+        if self.0 == ROOT && segments.first().is_some_and(|first| first == SECURE_STORE) {
+            return Err(VfsPathError::Reserved);
+        }
+
+        let mut path = self.0.clone();
+        for segment in segments {
+            path.push('/');
+            path.push_str(&segment);
+        }
+        Self::checked(path)
+    }
+
+    fn checked(path: String) -> Result<Self, VfsPathError> {
+        if path.len() > MAX_PATH_LENGTH {
+            return Err(VfsPathError::TooLong);
+        }
+        Ok(VfsPath(path))
+    }
+}
+
+impl fmt::Display for VfsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Splits a name into NFC-normalized segments, dropping empty and `.`
+/// segments.
+fn normalize(name: &str) -> Result<Vec<String>, VfsPathError> {
+    let name: String = name.nfc().collect();
+    let mut segments = Vec::new();
+
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(VfsPathError::ParentSegment),
+            segment if segment.chars().any(|c| c.is_control() || c == '\\') => {
+                return Err(VfsPathError::InvalidCharacter)
+            }
+            segment if segment.len() > MAX_SEGMENT_LENGTH => {
+                return Err(VfsPathError::SegmentTooLong)
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+
+    Ok(segments)
+}
+
+fn segment(name: &str) -> Result<String, VfsPathError> {
+    if name.contains('/') {
+        return Err(VfsPathError::NestedName);
+    }
+    normalize(name)?.pop().ok_or(VfsPathError::Empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn name() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                Just("".to_string()),
+                Just(".".to_string()),
+                Just("..".to_string()),
+                Just("securestore".to_string()),
+                Just("e\u{301}".to_string()),
+                "[a-zA-Z0-9 ._\\-\\\\é\u{0}]{1,12}",
+                "[a-z]{250,260}",
+            ],
+            0..8,
+        )
+        .prop_map(|segments| segments.join("/"))
+    }
+
+    proptest! {
+        #[test]
+        fn parsed_paths_are_normalized(fname in name()) {
+            if let Ok(path) = VfsPath::home(&fname) {
+                let s = path.as_str();
+                prop_assert!(s.starts_with("home/"));
+                prop_assert!(s.len() <= MAX_PATH_LENGTH);
+                prop_assert!(!path.is_secure_store());
+                prop_assert!(s.nfc().eq(s.chars()));
+                for segment in s.split('/') {
+                    prop_assert!(!matches!(segment, "" | "." | ".."));
+                    prop_assert!(segment.len() <= MAX_SEGMENT_LENGTH);
+                    prop_assert!(!segment.chars().any(|c| c.is_control() || c == '\\'));
+                }
+            }
+        }
+
+        #[test]
+        fn parsing_is_idempotent(fname in name()) {
+            if let Ok(path) = VfsPath::home(&fname) {
+                prop_assert_eq!(VfsPath::home(path.fname()), Ok(path));
+            }
+        }
+
+        #[test]
+        fn parent_segments_are_rejected(fname in name()) {
+            if fname.split('/').any(|segment| segment == "..") {
+                prop_assert!(VfsPath::home(&fname).is_err());
+            }
+        }
+
+        #[test]
+        fn secure_store_is_unreachable_from_home(fname in name()) {
+            let direct = format!("securestore/{}", fname);
+            let dotted = format!("./securestore/{}", fname);
+            prop_assert!(VfsPath::home(&direct).is_err());
+            prop_assert!(VfsPath::home(&dotted).is_err());
+        }
+
+        #[test]
+        fn secure_store_paths_stay_inside_the_app(app in name(), fname in name()) {
+            if let Ok(path) = VfsPath::secure_store(&app, &fname) {
+                let dir = VfsPath::secure_store_dir(&app).unwrap();
+                prop_assert!(path.as_str().starts_with(&dir.dir_prefix()));
+                prop_assert!(!path.file_name().contains('/'));
+                prop_assert_eq!(path.as_str().matches('/').count(), 3);
+            }
+        }
+
+        #[test]
+        fn joined_paths_stay_inside_the_directory(folder in name(), fname in name()) {
+            if let (Ok(dir), Ok(_)) = (VfsPath::home_dir(&folder), VfsPath::home(&fname)) {
+                if let Ok(path) = dir.join(&fname) {
+                    prop_assert!(path.as_str().starts_with(&dir.dir_prefix()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equivalent_unicode_names_match() {
+        assert_eq!(VfsPath::home("caf\u{e9}"), VfsPath::home("cafe\u{301}"));
+    }
+}